@@ -11,11 +11,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .await
         .expect("Failed to connect");
 
-    socket.send("Hello".into()).await?;
+    socket.send("Hello").await?;
     let repl: String = socket.recv().await?.try_into()?;
     dbg!(repl);
 
-    socket.send("NewHello".into()).await?;
+    socket.send("NewHello").await?;
     let repl: String = socket.recv().await?.try_into()?;
     dbg!(repl);
     Ok(())
@@ -12,11 +12,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("Failed to connect");
     println!("Connected to server");
 
-    socket.send("Hello".into()).await?;
+    socket.send("Hello").await?;
     let repl: String = socket.recv().await?.try_into()?;
     dbg!(repl);
 
-    socket.send("NewHello".into()).await?;
+    socket.send("NewHello").await?;
     let repl: String = socket.recv().await?.try_into()?;
     dbg!(repl);
     Ok(())
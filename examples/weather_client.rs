@@ -1,14 +1,16 @@
 use std::convert::TryInto;
 use std::error::Error;
-use zeromq::Socket;
+use zeromq::{BlockingRecv, SocketFrontend};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let mut socket = zeromq::SubSocket::connect("127.0.0.1:5556")
+    let mut socket = zeromq::SubSocket::new();
+    socket
+        .connect("127.0.0.1:5556")
         .await
         .expect("Failed to connect");
 
-    socket.subscribe("").await?;
+    socket.subscribe(b"").await?;
 
     for i in 0..10 {
         println!("Message {}", i);
@@ -15,13 +15,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let temperature = rng.gen_range(-80, 135);
         let relhumidity = rng.gen_range(10, 60);
         let message = format!("{} {} {}", zipcode, temperature, relhumidity);
-        socket.send(message.into())?;
+        socket.send(message)?;
         tokio::time::delay_for(Duration::from_millis(100)).await;
     }
-    drop(socket);
-
-    tokio::time::delay_for(Duration::from_secs(2)).await;
-
-    println!("Shutdown");
-    Ok(())
 }
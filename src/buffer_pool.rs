@@ -0,0 +1,167 @@
+//! Read and write buffers reused across connections instead of being allocated for each one
+use crate::codec::ZmqCodec;
+use bytes::BytesMut;
+use crossbeam::queue::SegQueue;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_util::codec::{Framed, FramedParts};
+
+/// Counters of buffer pool activity
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BufferPoolStats {
+    /// Buffers allocated because pool had no idle one
+    pub allocated: u64,
+    /// Buffers handed out again after previous connection returned them
+    pub reused: u64,
+    /// Buffers dropped because they grew far beyond pool capacity
+    pub trimmed: u64,
+    /// Buffers currently waiting in the pool
+    pub idle: usize,
+}
+
+struct PoolInner {
+    buffers: SegQueue<BytesMut>,
+    capacity: usize,
+    max_idle: usize,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    trimmed: AtomicU64,
+}
+
+/// Pool of connection buffers shared by sockets it is configured on.
+/// Buffers that grew for large messages are trimmed back once idle,
+/// so resident memory stays proportional to the number of connections.
+/// Handle is cheap to clone, all clones share the same pool
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+impl BufferPool {
+    /// Pool of buffers of `capacity` bytes keeping at most `max_idle` unused ones
+    pub fn new(capacity: usize, max_idle: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                buffers: SegQueue::new(),
+                capacity,
+                max_idle,
+                allocated: AtomicU64::new(0),
+                reused: AtomicU64::new(0),
+                trimmed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            allocated: self.inner.allocated.load(Ordering::Relaxed),
+            reused: self.inner.reused.load(Ordering::Relaxed),
+            trimmed: self.inner.trimmed.load(Ordering::Relaxed),
+            idle: self.inner.buffers.len(),
+        }
+    }
+
+    pub(crate) fn take(&self) -> BytesMut {
+        match self.inner.buffers.pop() {
+            Ok(buffer) => {
+                self.inner.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            Err(_) => {
+                self.inner.allocated.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(self.inner.capacity)
+            }
+        }
+    }
+
+    pub(crate) fn put(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        // Drained buffers only report capacity left after their consumed part.
+        // Reserving reclaims the whole allocation, or allocates anew if decoded
+        // messages still hold on to it
+        buffer.reserve(self.inner.capacity);
+        if buffer.capacity() > self.inner.capacity * 2 {
+            self.inner.trimmed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // Length is checked without locking so pool may slightly overshoot under contention
+        if self.inner.buffers.len() < self.inner.max_idle {
+            self.inner.buffers.push(buffer);
+        }
+    }
+
+    /// Connection with buffers taken from the pool
+    pub(crate) fn framed<S>(&self, stream: S, codec: ZmqCodec) -> Framed<S, ZmqCodec> {
+        let mut parts = FramedParts::new(stream, codec);
+        parts.read_buf = self.take();
+        parts.write_buf = self.take();
+        Framed::from_parts(parts)
+    }
+
+    /// Swaps buffer that grew for a large message for a pooled one once it is drained.
+    /// Memory of the read buffer is mostly held by the decoded message by then,
+    /// so it is left to the message instead of being returned to the pool
+    pub(crate) fn shrink<S>(
+        &self,
+        framed: Framed<S, ZmqCodec>,
+        received: bool,
+    ) -> Framed<S, ZmqCodec> {
+        let mut parts = framed.into_parts();
+        let buffer = if received {
+            &mut parts.read_buf
+        } else {
+            &mut parts.write_buf
+        };
+        if buffer.is_empty() {
+            let grown = std::mem::replace(buffer, self.take());
+            if received {
+                self.inner.trimmed.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.put(grown);
+            }
+        }
+        Framed::from_parts(parts)
+    }
+
+    /// Takes buffers back from a closed connection
+    pub(crate) fn release<S>(&self, framed: Framed<S, ZmqCodec>) {
+        let parts = framed.into_parts();
+        self.put(parts.read_buf);
+        self.put(parts.write_buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reuse_and_trim() {
+        let pool = BufferPool::new(1024, 1);
+        let first = pool.take();
+        let second = pool.take();
+        assert!(first.capacity() >= 1024);
+        pool.put(first);
+        pool.put(second);
+        assert_eq!(
+            BufferPoolStats {
+                allocated: 2,
+                reused: 0,
+                trimmed: 0,
+                idle: 1,
+            },
+            pool.stats()
+        );
+
+        let mut buffer = pool.take();
+        assert_eq!(1, pool.stats().reused);
+        buffer.extend_from_slice(&[0u8; 64 * 1024]);
+        pool.put(buffer);
+        assert_eq!(1, pool.stats().trimmed);
+        assert_eq!(0, pool.stats().idle);
+    }
+}
@@ -0,0 +1,445 @@
+use async_trait::async_trait;
+use crossbeam::queue::SegQueue;
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::lock::Mutex;
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::dealer_router::DealerPeer;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::fair_queue::{start_fair_queue, PeerQueueSender};
+use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
+use crate::stats::Stats;
+use crate::util::*;
+use crate::{util, MultiPeer, SocketBackend, SocketFrontend};
+use crate::{SocketType, ZmqResult};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Backend shared by thread safe sockets (CLIENT, SERVER, SCATTER and GATHER).
+/// All of them only accept single frame messages
+pub(crate) struct ThreadSafeSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, DealerPeer>,
+    pub(crate) round_robin: SegQueue<PeerIdentity>,
+    pub(crate) peer_queue_in: PeerQueueSender,
+    pub(crate) socket_type: SocketType,
+}
+
+impl ThreadSafeSocketBackend {
+    pub(crate) fn new(socket_type: SocketType, peer_queue_in: PeerQueueSender) -> Self {
+        Self {
+            peers: DashMap::new(),
+            round_robin: SegQueue::new(),
+            peer_queue_in,
+            socket_type,
+        }
+    }
+
+    pub(crate) async fn send_to(
+        &self,
+        peer_id: &PeerIdentity,
+        message: ZmqMessage,
+        stats: &Stats,
+    ) -> ZmqResult<()> {
+        let send_lock = match self.peers.get(peer_id) {
+            Some(peer) => peer.send_lock.clone(),
+            None => {
+                return Err(ZmqError::ReturnToSender {
+                    reason: "Destination peer not found by routing id",
+                    message,
+                })
+            }
+        };
+        // Waiting for room is per peer, a stuck peer doesn't hold up sends to others
+        let _turn = send_lock.lock().await;
+        let message = Message::Message(message);
+        util::send_to_peer(
+            &self.peers,
+            peer_id,
+            |peer| &mut peer.send_queue,
+            message,
+            stats,
+        )
+        .await
+    }
+
+    /// Sends message to the next peer in round robin order
+    pub(crate) async fn send_round_robin(
+        &self,
+        message: ZmqMessage,
+        stats: &Stats,
+    ) -> ZmqResult<()> {
+        // Disconnected peers are skipped cause SegQueue don't have an api to delete them
+        loop {
+            let next_peer_id = match self.round_robin.pop() {
+                Ok(peer) => peer,
+                Err(_) => {
+                    return Err(ZmqError::ReturnToSender {
+                        reason: "Not connected to peers. Unable to send messages",
+                        message,
+                    })
+                }
+            };
+            if self.peers.contains_key(&next_peer_id) {
+                self.round_robin.push(next_peer_id.clone());
+                return self.send_to(&next_peer_id, message, stats).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MultiPeer for ThreadSafeSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (in_queue, in_queue_receiver) = bounded_queue(hwm.recv);
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        self.peers.insert(
+            peer_id.clone(),
+            DealerPeer {
+                send_queue: out_queue,
+                recv_queue_in: in_queue,
+                send_lock: Arc::default(),
+                _io_close_handle: stop_handle,
+            },
+        );
+        self.round_robin.push(peer_id.clone());
+        let registered = self
+            .peer_queue_in
+            .clone()
+            .try_send((peer_id.clone(), in_queue_receiver));
+        if registered.is_err() {
+            // Fair queue is stopped once socket is dropped. Connection finished
+            // handshake too late, so it gets closed right away
+            self.peers.remove(peer_id);
+        }
+
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+#[async_trait]
+impl SocketBackend for ThreadSafeSocketBackend {
+    async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
+        if self.socket_type == SocketType::SCATTER {
+            // SCATTER socket never receives messages. Silently discard them
+            return;
+        }
+        // Multipart messages are not allowed for these sockets.
+        // They are still queued so recv can report them with an error
+        match message {
+            Message::Message(_) | Message::MultipartMessage(_) => (),
+            _ => return,
+        };
+        // Clone sender so DashMap lock is not held while waiting for queue capacity
+        let recv_queue = self
+            .peers
+            .get(peer_id)
+            .map(|peer| peer.recv_queue_in.clone());
+        if let Some(mut recv_queue) = recv_queue {
+            // Fair queue might be already stopped. Message is dropped in such case
+            let _ = recv_queue.send(message).await;
+        }
+    }
+
+    fn socket_type(&self) -> SocketType {
+        self.socket_type
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
+
+/// Receives next fair queued message. Lock makes it possible to recv from several tasks at once
+pub(crate) async fn recv_from(
+    fair_queue: &Mutex<mpsc::Receiver<(PeerIdentity, Message)>>,
+    timeout: Option<Duration>,
+) -> ZmqResult<(PeerIdentity, ZmqMessage)> {
+    let next = async { fair_queue.lock().await.next().await };
+    match util::with_timeout(timeout, next).await? {
+        Some((peer_id, Message::Message(m))) => Ok((peer_id, m)),
+        Some((_peer_id, Message::MultipartMessage(_))) => Err(ZmqError::Socket(
+            "Multipart messages are not supported by this socket type",
+        )),
+        Some((_peer_id, _)) => Err(ZmqError::Other("Wrong message type received")),
+        None => Err(ZmqError::NoMessage),
+    }
+}
+
+/// Thread safe socket that can serve many clients.
+/// All methods take &self so socket can be shared between tasks using Arc
+pub struct ServerSocket {
+    backend: Arc<ThreadSafeSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+    fair_queue: Mutex<mpsc::Receiver<(PeerIdentity, Message)>>,
+}
+
+impl Drop for ServerSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl ServerSocket {
+    /// Receives next message together with routing id of the client that sent it
+    pub async fn recv(&self) -> ZmqResult<(PeerIdentity, ZmqMessage)> {
+        recv_from(&self.fair_queue, self.options.recv_timeout).await
+    }
+
+    /// Sends single frame message to the client with given routing id
+    pub async fn send(&self, routing_id: &PeerIdentity, message: ZmqMessage) -> ZmqResult<()> {
+        let send = self
+            .backend
+            .send_to(routing_id, message, &self.options.stats);
+        util::with_timeout(self.options.send_timeout, send).await?
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for ServerSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
+        Self {
+            backend: Arc::new(ThreadSafeSocketBackend::new(SocketType::SERVER, peer_in)),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+            fair_queue: Mutex::new(fair_queue),
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::SERVER
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
+
+/// Thread safe counterpart of ServerSocket.
+/// Messages are distributed round robin if connected to several servers
+pub struct ClientSocket {
+    backend: Arc<ThreadSafeSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+    fair_queue: Mutex<mpsc::Receiver<(PeerIdentity, Message)>>,
+}
+
+impl Drop for ClientSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl ClientSocket {
+    pub async fn send(&self, message: ZmqMessage) -> ZmqResult<()> {
+        let send = self.backend.send_round_robin(message, &self.options.stats);
+        util::with_timeout(self.options.send_timeout, send).await?
+    }
+
+    pub async fn recv(&self) -> ZmqResult<ZmqMessage> {
+        let (_peer_id, message) = recv_from(&self.fair_queue, self.options.recv_timeout).await?;
+        Ok(message)
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for ClientSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
+        Self {
+            backend: Arc::new(ThreadSafeSocketBackend::new(SocketType::CLIENT, peer_in)),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+            fair_queue: Mutex::new(fair_queue),
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::CLIENT
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
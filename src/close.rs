@@ -0,0 +1,100 @@
+//! What happened to queued messages when socket was closed, see `SocketFrontend::close`
+use crate::context::Running;
+use crate::util::PeerIdentity;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Messages that were queued for the peer when socket was closed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCloseReport {
+    pub peer_id: PeerIdentity,
+    /// Written out and flushed within linger period
+    pub flushed: usize,
+    /// Discarded, either because linger period was over or there was none
+    pub dropped: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloseReport {
+    pub peers: Vec<PeerCloseReport>,
+}
+
+impl CloseReport {
+    pub fn flushed(&self) -> usize {
+        self.peers.iter().map(|peer| peer.flushed).sum()
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.peers.iter().map(|peer| peer.dropped).sum()
+    }
+}
+
+/// How long close waits for connections to report once linger is over
+const REPORT_GRACE_PERIOD: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct Reports {
+    closing: AtomicBool,
+    /// Connection tasks that didn't finish yet
+    running: Running,
+    peers: Mutex<Vec<PeerCloseReport>>,
+}
+
+/// Shared by connections of a socket. Reports are only kept once socket is being closed
+#[derive(Clone, Default)]
+pub(crate) struct CloseReports {
+    reports: Arc<Reports>,
+}
+
+impl CloseReports {
+    /// Report of connection task, handed in once the task is done
+    pub(crate) fn connection(&self, peer_id: PeerIdentity) -> ConnectionReport {
+        self.reports.running.started();
+        ConnectionReport {
+            reports: self.reports.clone(),
+            peer_id,
+            flushed: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Has to be called before socket drops its peers so their connections report back
+    pub(crate) fn start_closing(&self) -> Self {
+        self.reports.closing.store(true, Ordering::SeqCst);
+        self.clone()
+    }
+
+    /// Completes once every connection task has handed in its report
+    pub(crate) async fn finished(&self) {
+        self.reports.running.finished().await
+    }
+
+    /// Waits for connections that didn't report yet, expected to be called after linger
+    pub(crate) async fn collect(self) -> CloseReport {
+        let _ = tokio::time::timeout(REPORT_GRACE_PERIOD, self.finished()).await;
+        CloseReport {
+            peers: std::mem::take(&mut *self.reports.peers.lock().unwrap()),
+        }
+    }
+}
+
+pub(crate) struct ConnectionReport {
+    reports: Arc<Reports>,
+    peer_id: PeerIdentity,
+    pub(crate) flushed: usize,
+    pub(crate) dropped: usize,
+}
+
+impl Drop for ConnectionReport {
+    fn drop(&mut self) {
+        if self.reports.closing.load(Ordering::SeqCst) {
+            self.reports.peers.lock().unwrap().push(PeerCloseReport {
+                peer_id: self.peer_id.clone(),
+                flushed: self.flushed,
+                dropped: self.dropped,
+            });
+        }
+        self.reports.running.done();
+    }
+}
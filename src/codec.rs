@@ -3,12 +3,12 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Display;
+use std::sync::{Arc, OnceLock};
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::message::*;
-use crate::SocketType;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum ZmqMechanism {
     NULL,
     PLAIN,
@@ -17,11 +17,11 @@ pub(crate) enum ZmqMechanism {
 
 impl Display for ZmqMechanism {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return match self {
+        match self {
             ZmqMechanism::NULL => write!(f, "NULL"),
             ZmqMechanism::PLAIN => write!(f, "PLAIN"),
             ZmqMechanism::CURVE => write!(f, "CURVE"),
-        };
+        }
     }
 }
 
@@ -33,19 +33,27 @@ impl TryFrom<Vec<u8>> for ZmqMechanism {
         // mechanism-char = "A"-"Z" | DIGIT
         //                  | "-" | "_" | "." | "+" | %x0
         // according to https://rfc.zeromq.org/spec:23/ZMTP/
-        let mechanism = unsafe { String::from_utf8_unchecked(mech.to_vec()) };
-        match mechanism.as_str() {
-            "NULL" => Ok(ZmqMechanism::NULL),
-            "PLAIN" => Ok(ZmqMechanism::PLAIN),
-            "CURVE" => Ok(ZmqMechanism::CURVE),
+        match mech {
+            b"NULL" => Ok(ZmqMechanism::NULL),
+            b"PLAIN" => Ok(ZmqMechanism::PLAIN),
+            b"CURVE" => Ok(ZmqMechanism::CURVE),
             _ => Err(ZmqError::Other("Failed to parse ZmqMechanism")),
         }
     }
 }
 
+/// ZMTP (major, minor) protocol version
+pub type ZmtpVersion = (u8, u8);
+
+/// Newest protocol version we speak and announce in our greeting
+pub const ZMTP_VERSION: ZmtpVersion = (3, 1);
+
+/// Default limit of received message size (ZMQ_MAXMSGSIZE)
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 30;
+
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct ZmqGreeting {
-    pub version: (u8, u8),
+    pub version: ZmtpVersion,
     pub mechanism: ZmqMechanism,
     pub as_server: bool,
 }
@@ -53,18 +61,28 @@ pub(crate) struct ZmqGreeting {
 impl Default for ZmqGreeting {
     fn default() -> Self {
         Self {
-            version: (3, 0),
+            version: ZMTP_VERSION,
             mechanism: ZmqMechanism::NULL,
             as_server: false,
         }
     }
 }
 
+impl ZmqGreeting {
+    pub fn new(mechanism: ZmqMechanism, as_server: bool) -> Self {
+        Self {
+            mechanism,
+            as_server,
+            ..Default::default()
+        }
+    }
+}
+
 impl TryFrom<Bytes> for ZmqGreeting {
     type Error = ZmqError;
 
     fn try_from(value: Bytes) -> Result<Self, Self::Error> {
-        if !(value[0] == 0xff && value[9] == 0x7f) {
+        if value.len() < 64 || !(value[0] == 0xff && value[9] == 0x7f) {
             return Err(ZmqError::Codec("Failed to parse greeting"));
         }
         Ok(ZmqGreeting {
@@ -94,107 +112,356 @@ impl From<ZmqGreeting> for BytesMut {
 #[derive(Debug, Clone)]
 pub(crate) enum Message {
     Greeting(ZmqGreeting),
-    Command(ZmqCommand),
+    Command(ZmtpCommand),
     Message(ZmqMessage),
     MultipartMessage(Vec<ZmqMessage>),
+    /// Message queued for many peers at once, e.g. by PUB. Queues share it
+    /// instead of holding copies of its frames
+    Shared(Arc<SharedMessage>),
 }
 
-#[derive(Debug, Copy, Clone)]
-pub(crate) enum ZmqCommandName {
-    READY,
+/// Frames of message sent to many peers, encoded once for all of the connections
+/// that don't encrypt them
+#[derive(Debug)]
+pub(crate) struct SharedMessage {
+    frames: Vec<ZmqMessage>,
+    encoded: OnceLock<Bytes>,
 }
 
-impl From<ZmqCommandName> for String {
-    fn from(c_name: ZmqCommandName) -> Self {
-        match c_name {
-            ZmqCommandName::READY => "READY".into(),
+impl SharedMessage {
+    pub(crate) fn new(frames: Vec<ZmqMessage>) -> Self {
+        Self {
+            frames,
+            encoded: OnceLock::new(),
         }
     }
+
+    pub(crate) fn frames(&self) -> &[ZmqMessage] {
+        &self.frames
+    }
+
+    /// Frames as they are written to unencrypted connection, encoded by the first one
+    pub(crate) fn encoded(&self) -> &Bytes {
+        self.encoded.get_or_init(|| {
+            let mut encoded = BytesMut::with_capacity(frames_len(&self.frames));
+            let last = self.frames.len().saturating_sub(1);
+            for (idx, frame) in self.frames.iter().enumerate() {
+                let more = if idx != last { 0b0000_0001 } else { 0 };
+                ZmqCodec::_write_frame(&frame.data, &mut encoded, more);
+            }
+            encoded.freeze()
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct ZmqCommand {
-    pub name: ZmqCommandName,
-    pub properties: HashMap<String, String>,
+/// Size of frame on the wire including its header
+fn frame_len(len: usize) -> usize {
+    if len > 255 {
+        len + 9
+    } else {
+        len + 2
+    }
 }
 
-impl ZmqCommand {
-    pub fn ready(socket: SocketType) -> Self {
-        let mut properties = HashMap::new();
-        properties.insert("Socket-Type".into(), format!("{}", socket));
-        Self {
-            name: ZmqCommandName::READY,
-            properties,
+fn frames_len(frames: &[ZmqMessage]) -> usize {
+    frames.iter().map(|frame| frame_len(frame.data.len())).sum()
+}
+
+impl Message {
+    /// Number of bytes message takes once encoded without encryption
+    pub(crate) fn encoded_len(&self) -> usize {
+        match self {
+            Message::Greeting(_) => 64,
+            Message::Command(command) => frame_len(command.body().len()),
+            Message::Message(message) => frame_len(message.data.len()),
+            Message::MultipartMessage(messages) => frames_len(messages),
+            Message::Shared(message) => frames_len(message.frames()),
+        }
+    }
+
+    /// Makes peer identity and properties of the connection available on every frame of received message
+    pub(crate) fn attach_origin(&mut self, origin: &Arc<Origin>) {
+        match self {
+            Message::Message(message) => message.origin = Some(origin.clone()),
+            Message::MultipartMessage(messages) => {
+                for message in messages {
+                    message.origin = Some(origin.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Frames of user message. None for greeting and commands
+    pub(crate) fn into_frames(self) -> Option<Vec<ZmqMessage>> {
+        match self {
+            Message::Message(message) => Some(vec![message]),
+            Message::MultipartMessage(messages) => Some(messages),
+            Message::Shared(message) => Some(message.frames().to_vec()),
+            _ => None,
         }
     }
 }
 
-impl TryFrom<BytesMut> for ZmqCommand {
-    type Error = ZmqError;
+impl From<Vec<ZmqMessage>> for Message {
+    fn from(mut frames: Vec<ZmqMessage>) -> Self {
+        if frames.len() == 1 {
+            Message::Message(frames.pop().unwrap())
+        } else {
+            Message::MultipartMessage(frames)
+        }
+    }
+}
 
-    fn try_from(mut buf: BytesMut) -> Result<Self, Self::Error> {
-        let command_len = buf.get_u8() as usize;
-        // command-name-char = ALPHA according to https://rfc.zeromq.org/spec:23/ZMTP/
-        let command_name =
-            unsafe { String::from_utf8_unchecked(buf.split_to(command_len).to_vec()) };
-        let command = match command_name.as_str() {
-            "READY" => ZmqCommandName::READY,
-            _ => return Err(ZmqError::Codec("Uknown command received")),
-        };
-        let mut properties = HashMap::new();
+/// Metadata properties carried by READY-like commands, e.g. `Socket-Type` or custom `X-` ones
+pub type Properties = HashMap<String, Vec<u8>>;
+
+/// Peers may not flood us with metadata. Limits are applied to both received and sent properties
+const MAX_PROPERTIES: usize = 64;
+const MAX_PROPERTY_VALUE_SIZE: usize = 64 * 1024;
 
-        while !buf.is_empty() {
-            // Collect command properties
-            let prop_len = buf.get_u8() as usize;
-            let property = unsafe { String::from_utf8_unchecked(buf.split_to(prop_len).to_vec()) };
+/// ZMTP command decoded from a frame with command flag set
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ZmtpCommand {
+    Ready(Properties),
+    Error {
+        reason: String,
+    },
+    Subscribe(Bytes),
+    Cancel(Bytes),
+    /// TTL is in deciseconds. Context is echoed back by PONG
+    Ping {
+        ttl: u16,
+        context: Bytes,
+    },
+    Pong {
+        context: Bytes,
+    },
+    Join(Bytes),
+    Leave(Bytes),
+    /// Bodies of security handshake commands are specific to the mechanism
+    Hello(Bytes),
+    Welcome(Bytes),
+    Initiate(Properties),
+    /// Commands we don't know are kept as is so that newer peers can extend the protocol.
+    /// READY and INITIATE end up here as well when codec keeps raw metadata
+    Unknown {
+        name: String,
+        body: Bytes,
+    },
+}
 
-            let prop_val_len = buf.get_u32() as usize;
-            let prop_value =
-                unsafe { String::from_utf8_unchecked(buf.split_to(prop_val_len).to_vec()) };
-            properties.insert(property, prop_value);
+impl ZmtpCommand {
+    pub fn error(reason: &str) -> Self {
+        // Reason is limited to 255 bytes by the spec
+        let mut end = reason.len().min(255);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        ZmtpCommand::Error {
+            reason: reason[..end].to_string(),
         }
-        Ok(Self {
-            name: command,
-            properties,
-        })
     }
-}
 
-impl From<ZmqCommand> for BytesMut {
-    fn from(command: ZmqCommand) -> Self {
-        let mut message_len = 0;
+    pub fn subscribe(topic: &[u8]) -> Self {
+        ZmtpCommand::Subscribe(Bytes::copy_from_slice(topic))
+    }
+
+    pub fn cancel(topic: &[u8]) -> Self {
+        ZmtpCommand::Cancel(Bytes::copy_from_slice(topic))
+    }
 
-        let command_name: String = command.name.into();
-        message_len += command_name.len() + 1;
-        for (prop, val) in command.properties.iter() {
-            message_len += prop.len() + 1;
-            message_len += val.len() + 4;
+    /// Context is at most 16 bytes long
+    pub fn ping(ttl: u16, context: &[u8]) -> Self {
+        ZmtpCommand::Ping {
+            ttl,
+            context: Bytes::copy_from_slice(&context[..context.len().min(16)]),
         }
+    }
 
-        let long_message = message_len > 255;
+    pub fn join(group: &[u8]) -> Self {
+        ZmtpCommand::Join(Bytes::copy_from_slice(group))
+    }
 
-        let mut bytes = BytesMut::new();
-        if long_message {
-            bytes.reserve(message_len + 9);
-            bytes.put_u8(0x06);
-            bytes.put_u64(message_len as u64);
-        } else {
-            bytes.reserve(message_len + 2);
-            bytes.put_u8(0x04);
-            bytes.put_u8(message_len as u8);
+    pub fn leave(group: &[u8]) -> Self {
+        ZmtpCommand::Leave(Bytes::copy_from_slice(group))
+    }
+
+    /// Command with opaque body, e.g. encrypted READY of CURVE
+    #[cfg_attr(not(feature = "curve"), allow(dead_code))]
+    pub fn raw(name: &str, body: Bytes) -> Self {
+        ZmtpCommand::Unknown {
+            name: name.to_string(),
+            body,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            ZmtpCommand::Ready(_) => "READY",
+            ZmtpCommand::Error { .. } => "ERROR",
+            ZmtpCommand::Subscribe(_) => "SUBSCRIBE",
+            ZmtpCommand::Cancel(_) => "CANCEL",
+            ZmtpCommand::Ping { .. } => "PING",
+            ZmtpCommand::Pong { .. } => "PONG",
+            ZmtpCommand::Join(_) => "JOIN",
+            ZmtpCommand::Leave(_) => "LEAVE",
+            ZmtpCommand::Hello(_) => "HELLO",
+            ZmtpCommand::Welcome(_) => "WELCOME",
+            ZmtpCommand::Initiate(_) => "INITIATE",
+            ZmtpCommand::Unknown { name, .. } => name,
+        }
+    }
+}
+
+impl TryFrom<Bytes> for ZmtpCommand {
+    type Error = ZmqError;
+
+    fn try_from(buf: Bytes) -> Result<Self, ZmqError> {
+        Self::decode(buf, false)
+    }
+}
+
+impl ZmtpCommand {
+    fn decode(mut buf: Bytes, raw_metadata: bool) -> Result<Self, ZmqError> {
+        if buf.is_empty() {
+            return Err(ZmqError::Codec("Empty command frame"));
+        }
+        let command_len = buf.get_u8() as usize;
+        if buf.len() < command_len {
+            return Err(ZmqError::Codec("Truncated command name"));
+        }
+        let name = buf.split_to(command_len);
+        // command-name-char = ALPHA according to https://rfc.zeromq.org/spec:23/ZMTP/
+        if name.is_empty() || !name.iter().all(u8::is_ascii_alphabetic) {
+            return Err(ZmqError::Codec("Malformed command name"));
+        }
+        let command = match name.as_ref() {
+            b"READY" if !raw_metadata => ZmtpCommand::Ready(parse_properties(buf)?),
+            b"INITIATE" if !raw_metadata => ZmtpCommand::Initiate(parse_properties(buf)?),
+            b"ERROR" => {
+                // Be lenient to reasons not matching their length byte, it's only diagnostics
+                let reason = buf.get(1..).unwrap_or(&[]);
+                ZmtpCommand::Error {
+                    reason: String::from_utf8_lossy(reason).into_owned(),
+                }
+            }
+            b"SUBSCRIBE" => ZmtpCommand::Subscribe(buf),
+            b"CANCEL" => ZmtpCommand::Cancel(buf),
+            b"PING" => {
+                if buf.len() < 2 {
+                    return Err(ZmqError::Codec("Malformed PING command"));
+                }
+                ZmtpCommand::Ping {
+                    ttl: buf.get_u16(),
+                    context: buf,
+                }
+            }
+            b"PONG" => ZmtpCommand::Pong { context: buf },
+            b"JOIN" => ZmtpCommand::Join(buf),
+            b"LEAVE" => ZmtpCommand::Leave(buf),
+            b"HELLO" => ZmtpCommand::Hello(buf),
+            b"WELCOME" => ZmtpCommand::Welcome(buf),
+            _ => ZmtpCommand::Unknown {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                body: buf,
+            },
         };
-        bytes.put_u8(command_name.len() as u8);
-        bytes.extend_from_slice(command_name.as_ref());
-        for (prop, val) in command.properties.iter() {
-            bytes.put_u8(prop.len() as u8);
-            bytes.extend_from_slice(prop.as_ref());
-            bytes.put_u32(val.len() as u32);
-            bytes.extend_from_slice(val.as_ref());
+        Ok(command)
+    }
+
+    /// Command frame body without frame header
+    fn body(&self) -> BytesMut {
+        let name = self.name();
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+        match self {
+            ZmtpCommand::Ready(properties) | ZmtpCommand::Initiate(properties) => {
+                encode_properties(properties, &mut bytes)
+            }
+            ZmtpCommand::Error { reason } => {
+                bytes.put_u8(reason.len() as u8);
+                bytes.extend_from_slice(reason.as_bytes());
+            }
+            ZmtpCommand::Ping { ttl, context } => {
+                bytes.put_u16(*ttl);
+                bytes.extend_from_slice(context);
+            }
+            ZmtpCommand::Subscribe(data)
+            | ZmtpCommand::Cancel(data)
+            | ZmtpCommand::Join(data)
+            | ZmtpCommand::Leave(data)
+            | ZmtpCommand::Hello(data)
+            | ZmtpCommand::Welcome(data)
+            | ZmtpCommand::Pong { context: data }
+            | ZmtpCommand::Unknown { body: data, .. } => bytes.extend_from_slice(data),
         }
         bytes
     }
 }
 
+/// Checks property against the spec (property-name-char = ALPHA | DIGIT | "-" | "_" | "." | "+")
+/// and our size limits
+pub(crate) fn validate_property(name: &str, value: &[u8]) -> Result<(), ZmqError> {
+    let valid_name = !name.is_empty()
+        && name.len() <= 255
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"-_.+".contains(&c));
+    if valid_name && value.len() <= MAX_PROPERTY_VALUE_SIZE {
+        Ok(())
+    } else {
+        Err(ZmqError::InvalidProperty(name.to_string()))
+    }
+}
+
+/// Parses metadata properties of READY-like commands
+pub(crate) fn parse_properties(mut buf: Bytes) -> Result<Properties, ZmqError> {
+    let malformed = || ZmqError::Codec("Malformed command property");
+    let mut properties = HashMap::new();
+    while !buf.is_empty() {
+        if properties.len() == MAX_PROPERTIES {
+            return Err(ZmqError::Codec("Too many command properties"));
+        }
+        let prop_len = buf.get_u8() as usize;
+        if buf.len() < prop_len + 4 {
+            return Err(malformed());
+        }
+        let property =
+            String::from_utf8(buf.split_to(prop_len).to_vec()).map_err(|_| malformed())?;
+        let prop_val_len = buf.get_u32() as usize;
+        if buf.len() < prop_val_len {
+            return Err(malformed());
+        }
+        let prop_value = buf.split_to(prop_val_len).to_vec();
+        validate_property(&property, &prop_value).map_err(|_| malformed())?;
+        properties.insert(property, prop_value);
+    }
+    Ok(properties)
+}
+
+pub(crate) fn encode_properties(properties: &Properties, dst: &mut BytesMut) {
+    for (prop, val) in properties.iter() {
+        dst.put_u8(prop.len() as u8);
+        dst.extend_from_slice(prop.as_ref());
+        dst.put_u32(val.len() as u32);
+        dst.extend_from_slice(val.as_ref());
+    }
+}
+
+/// Flags of a frame protected by FrameCipher
+pub(crate) const CIPHER_FLAG_MORE: u8 = 0x01;
+pub(crate) const CIPHER_FLAG_COMMAND: u8 = 0x02;
+
+/// Protects frames once security mechanism established session keys.
+/// Every frame is replaced with an opaque one carrying original flags inside
+pub(crate) trait FrameCipher: Send {
+    fn encrypt(&mut self, flags: u8, data: &[u8]) -> Bytes;
+    /// Returns flags and body of the original frame
+    fn decrypt(&mut self, data: &[u8]) -> Result<(u8, Bytes), ZmqError>;
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Frame {
     command: bool,
@@ -204,30 +471,96 @@ struct Frame {
 
 #[derive(Debug)]
 enum DecoderState {
+    // Signature is checked as soon as it arrives so that non-ZMTP peers are dropped early
+    GreetingSignature,
     Greeting,
     FrameHeader,
     FrameLen(Frame),
     Frame(Frame),
+    // Large frame handed out in pieces as they arrive
+    FrameChunk { offset: u64, frame_len: u64 },
 }
 
-#[derive(Debug)]
 pub(crate) struct ZmqCodec {
     state: DecoderState,
     waiting_for: usize, // Number of bytes needed to decode frame
     // Needed to store incoming multipart message
     // This allows to incapsulate it's processing inside codec and not expose
     // internal details to higher levels
-    buffered_message: Option<Message>,
+    buffered_message: Option<Vec<ZmqMessage>>,
+    cipher: Option<Box<dyn FrameCipher>>,
+    // Encrypting mechanisms carry metadata inside boxes that only they can open
+    raw_metadata: bool,
+    // Largest message peer may send, sum of all frames for multipart ones
+    max_message_size: usize,
+    // Size of frames of multipart message buffered so far
+    buffered_size: usize,
+    // Single frame messages larger than this are not buffered but yielded in chunks
+    streaming_threshold: Option<usize>,
+    // Version both peers speak. ZMTP 3.0 peers expect subscriptions
+    // as messages starting with 1 or 0 byte
+    version: ZmtpVersion,
 }
 
 impl ZmqCodec {
     pub fn new() -> Self {
         Self {
-            state: DecoderState::Greeting,
-            waiting_for: 64, // len of the greeting frame,
+            state: DecoderState::GreetingSignature,
+            waiting_for: 10, // len of the greeting signature
             buffered_message: None,
+            cipher: None,
+            raw_metadata: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            buffered_size: 0,
+            streaming_threshold: None,
+            version: ZMTP_VERSION,
         }
     }
+
+    /// Codec for ZMTP 2.0 peers, whose greeting is exchanged outside of it
+    pub fn zmtp2() -> Self {
+        Self {
+            state: DecoderState::FrameHeader,
+            waiting_for: 1,
+            version: (2, 0),
+            ..Self::new()
+        }
+    }
+
+    /// Settles on the older of our and peer's announced versions and adjusts encoding to it.
+    /// Peers announcing newer versions are expected to downgrade to ours
+    pub fn negotiate_version(&mut self, peer_version: ZmtpVersion) -> ZmtpVersion {
+        self.version = ZMTP_VERSION.min(peer_version);
+        self.version
+    }
+
+    /// Protocol version negotiated for this connection
+    pub fn version(&self) -> ZmtpVersion {
+        self.version
+    }
+
+    /// Frames declaring more than limit are rejected before any buffer is allocated for them
+    pub fn set_max_message_size(&mut self, limit: usize) {
+        self.max_message_size = limit;
+    }
+
+    /// Yields message frames larger than threshold in chunks as soon as they arrive.
+    /// Applies to unencrypted single frame messages, frames of multipart messages are buffered
+    pub fn set_streaming_threshold(&mut self, threshold: Option<usize>) {
+        self.streaming_threshold = threshold;
+    }
+
+    /// Leaves READY and INITIATE bodies unparsed for security mechanism to handle
+    #[cfg_attr(not(feature = "curve"), allow(dead_code))]
+    pub fn keep_raw_metadata(&mut self) {
+        self.raw_metadata = true;
+    }
+
+    /// Every frame sent or received after this call goes through cipher
+    #[cfg_attr(not(feature = "curve"), allow(dead_code))]
+    pub fn set_cipher(&mut self, cipher: Box<dyn FrameCipher>) {
+        self.cipher = Some(cipher);
+    }
 }
 
 impl Decoder for ZmqCodec {
@@ -235,70 +568,140 @@ impl Decoder for ZmqCodec {
     type Error = ZmqError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < self.waiting_for {
-            src.reserve(self.waiting_for - src.len());
-            return Ok(None);
-        }
-        match self.state {
-            DecoderState::Greeting => {
-                if src[0] != 0xff {
-                    return Err(ZmqError::Codec("Bad first byte of greeting"));
-                }
-                self.state = DecoderState::FrameHeader;
-                self.waiting_for = 1;
-                Ok(Some(Message::Greeting(ZmqGreeting::try_from(
-                    src.split_to(64).freeze(),
-                )?)))
+        // Loop rather than recursion so that a burst of tiny frames can't exhaust the stack
+        loop {
+            if src.len() < self.waiting_for {
+                src.reserve(self.waiting_for - src.len());
+                return Ok(None);
             }
-            DecoderState::FrameHeader => {
-                let flags = src.get_u8();
-                let command = (flags & 0b0000_0100) != 0;
-                let long = (flags & 0b0000_0010) != 0;
-                let more = (flags & 0b0000_0001) != 0;
-
-                if more && self.buffered_message.is_none() {
-                    self.buffered_message = Some(Message::MultipartMessage(Vec::new()));
+            match self.state {
+                DecoderState::GreetingSignature => {
+                    if src[0] != 0xff || src[9] & 0x01 == 0 {
+                        return Err(ZmqError::Codec("Bad greeting signature"));
+                    }
+                    self.state = DecoderState::Greeting;
+                    self.waiting_for = 64; // len of the whole greeting
                 }
-                let frame = Frame {
-                    command,
-                    long,
-                    more,
-                };
-                self.state = DecoderState::FrameLen(frame);
-                self.waiting_for = if frame.long { 8 } else { 1 };
-                return self.decode(src);
-            }
-            DecoderState::FrameLen(frame) => {
-                self.state = DecoderState::Frame(frame);
-                self.waiting_for = if frame.long {
-                    src.get_u64() as usize
-                } else {
-                    src.get_u8() as usize
-                };
-                return self.decode(src);
-            }
-            DecoderState::Frame(frame) => {
-                let data = src.split_to(self.waiting_for);
-                self.state = DecoderState::FrameHeader;
-                self.waiting_for = 1;
-                if frame.command {
-                    Ok(Some(Message::Command(ZmqCommand::try_from(data)?)))
-                } else if frame.more {
-                    // cache incoming multipart message
-                    match &mut self.buffered_message {
-                        Some(Message::MultipartMessage(message)) => message.push(data.into()),
-                        _ => panic!("Corrupted decoder state"),
+                DecoderState::Greeting => {
+                    self.state = DecoderState::FrameHeader;
+                    self.waiting_for = 1;
+                    return Ok(Some(Message::Greeting(ZmqGreeting::try_from(
+                        src.split_to(64).freeze(),
+                    )?)));
+                }
+                DecoderState::FrameHeader => {
+                    let flags = src.get_u8();
+                    let command = (flags & 0b0000_0100) != 0;
+                    let long = (flags & 0b0000_0010) != 0;
+                    let more = (flags & 0b0000_0001) != 0;
+                    // ZMTP 2.0 has no command frames so its command bit is reserved as well
+                    if flags & 0b1111_1000 != 0 || (command && self.version < (3, 0)) {
+                        return Err(ZmqError::Codec("Reserved frame flags set"));
+                    }
+
+                    let frame = Frame {
+                        command,
+                        long,
+                        more,
+                    };
+                    self.state = DecoderState::FrameLen(frame);
+                    self.waiting_for = if frame.long { 8 } else { 1 };
+                }
+                DecoderState::FrameLen(frame) => {
+                    let len = if frame.long {
+                        src.get_u64()
+                    } else {
+                        src.get_u8() as u64
+                    };
+                    let size = len.saturating_add(self.buffered_size as u64);
+                    if size > self.max_message_size as u64 {
+                        return Err(ZmqError::MessageTooLarge {
+                            size,
+                            limit: self.max_message_size,
+                        });
                     }
-                    return self.decode(src);
-                } else {
-                    if let Some(Message::MultipartMessage(mut message)) =
-                        self.buffered_message.take()
-                    {
-                        message.push(data.into());
-                        Ok(Some(Message::MultipartMessage(message)))
+                    let streamed = !frame.command
+                        && !frame.more
+                        && self.buffered_message.is_none()
+                        && self.cipher.is_none()
+                        && self
+                            .streaming_threshold
+                            .is_some_and(|threshold| len > threshold as u64);
+                    if streamed {
+                        self.state = DecoderState::FrameChunk {
+                            offset: 0,
+                            frame_len: len,
+                        };
+                        self.waiting_for = 1;
+                        continue;
+                    }
+                    self.state = DecoderState::Frame(frame);
+                    self.waiting_for = len as usize;
+                }
+                DecoderState::FrameChunk { offset, frame_len } => {
+                    let remaining = frame_len - offset;
+                    let data = src.split_to(src.len().min(remaining as usize)).freeze();
+                    let chunk = FrameChunk {
+                        offset,
+                        frame_len,
+                        last: data.len() as u64 == remaining,
+                    };
+                    self.state = if chunk.last {
+                        DecoderState::FrameHeader
                     } else {
-                        Ok(Some(Message::Message(data.into())))
+                        DecoderState::FrameChunk {
+                            offset: offset + data.len() as u64,
+                            frame_len,
+                        }
+                    };
+                    let mut message = ZmqMessage::from(data);
+                    message.chunk = Some(chunk);
+                    return Ok(Some(Message::Message(message)));
+                }
+                DecoderState::Frame(frame) => {
+                    // Payload is handed out as a slice of the read buffer rather than copied.
+                    // Whole frame is in src here since we reserved room for it before reading
+                    let data = src.split_to(self.waiting_for).freeze();
+                    self.state = DecoderState::FrameHeader;
+                    self.waiting_for = 1;
+                    let (frame, data) = match &mut self.cipher {
+                        Some(cipher) => {
+                            let (flags, data) = cipher.decrypt(&data)?;
+                            let frame = Frame {
+                                command: flags & CIPHER_FLAG_COMMAND != 0,
+                                long: frame.long,
+                                more: flags & CIPHER_FLAG_MORE != 0,
+                            };
+                            (frame, data)
+                        }
+                        None => (frame, data),
+                    };
+                    if frame.command {
+                        // Commands are always single frame
+                        if frame.more || self.buffered_message.is_some() {
+                            return Err(ZmqError::Codec("Command frame inside multipart message"));
+                        }
+                        return Ok(Some(Message::Command(ZmtpCommand::decode(
+                            data,
+                            self.raw_metadata,
+                        )?)));
+                    }
+                    if frame.more {
+                        // cache incoming multipart message
+                        self.buffered_size += data.len();
+                        self.buffered_message
+                            .get_or_insert_with(Vec::new)
+                            .push(data.into());
+                        continue;
                     }
+                    return Ok(Some(match self.buffered_message.take() {
+                        Some(mut message) => {
+                            self.buffered_size = 0;
+                            message.push(data.into());
+                            Message::MultipartMessage(message)
+                        }
+                        None => Message::Message(data.into()),
+                    }));
                 }
             }
         }
@@ -306,17 +709,38 @@ impl Decoder for ZmqCodec {
 }
 
 impl ZmqCodec {
-    fn _encode_message(&mut self, message: ZmqMessage, dst: &mut BytesMut, more: bool) {
+    fn _encode_frame(&mut self, data: &[u8], dst: &mut BytesMut, more: bool, command: bool) {
+        if let Some(cipher) = &mut self.cipher {
+            let mut flags = 0;
+            if more {
+                flags |= CIPHER_FLAG_MORE;
+            }
+            if command {
+                flags |= CIPHER_FLAG_COMMAND;
+            }
+            // Encrypted frame is always a single final message frame
+            let data = cipher.encrypt(flags, data);
+            return Self::_write_frame(&data, dst, 0);
+        }
         let mut flags: u8 = 0;
         if more {
             flags |= 0b0000_0001;
         }
-        let len = message.data.len();
+        if command {
+            flags |= 0b0000_0100;
+        }
+        Self::_write_frame(data, dst, flags)
+    }
+
+    fn _write_frame(data: &[u8], dst: &mut BytesMut, flags: u8) {
+        dst.reserve(frame_len(data.len()));
+        Self::_write_header(data.len(), dst, flags);
+        dst.extend_from_slice(data);
+    }
+
+    fn _write_header(len: usize, dst: &mut BytesMut, mut flags: u8) {
         if len > 255 {
             flags |= 0b0000_0010;
-            dst.reserve(len + 9);
-        } else {
-            dst.reserve(len + 2);
         }
         dst.put_u8(flags);
         if len > 255 {
@@ -324,7 +748,18 @@ impl ZmqCodec {
         } else {
             dst.put_u8(len as u8);
         }
-        dst.extend_from_slice(message.data.as_ref());
+    }
+
+    /// Cipher has to see every frame, so encrypted frames can't bypass the codec
+    pub(crate) fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Header of unencrypted frame whose payload is written to the stream right after it
+    pub(crate) fn frame_header(len: usize, more: bool) -> BytesMut {
+        let mut header = BytesMut::with_capacity(9);
+        Self::_write_header(len, &mut header, more as u8);
+        header
     }
 }
 
@@ -333,17 +768,401 @@ impl Encoder for ZmqCodec {
     type Error = ZmqError;
 
     fn encode(&mut self, message: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // Whole multipart message is serialized into a single allocation in one pass
+        if let Message::MultipartMessage(_) | Message::Shared(_) = &message {
+            dst.reserve(message.encoded_len());
+        }
         match message {
             Message::Greeting(payload) => dst.unsplit(payload.into()),
-            Message::Message(message) => self._encode_message(message, dst, false),
-            Message::Command(command) => dst.unsplit(command.into()),
+            Message::Message(message) => self._encode_frame(&message.data, dst, false, false),
+            Message::Command(command) if self.version < (3, 1) => match &command {
+                ZmtpCommand::Subscribe(topic) | ZmtpCommand::Cancel(topic) => {
+                    let mut data = BytesMut::with_capacity(topic.len() + 1);
+                    data.put_u8(matches!(command, ZmtpCommand::Subscribe(_)) as u8);
+                    data.extend_from_slice(topic);
+                    self._encode_frame(&data, dst, false, false)
+                }
+                // ZMTP 2.0 has no commands at all
+                _ if self.version < (3, 0) => {}
+                _ => self._encode_frame(&command.body(), dst, false, true),
+            },
+            Message::Command(command) => self._encode_frame(&command.body(), dst, false, true),
             Message::MultipartMessage(parts) => {
-                let last_element = parts.len() - 1;
+                // Message without frames has nothing to put on the wire
+                let last_element = parts.len().saturating_sub(1);
                 for (idx, part) in parts.into_iter().enumerate() {
-                    self._encode_message(part, dst, idx != last_element);
+                    self._encode_frame(&part.data, dst, idx != last_element, false);
+                }
+            }
+            Message::Shared(message) if self.cipher.is_none() => {
+                dst.extend_from_slice(message.encoded())
+            }
+            Message::Shared(message) => {
+                let last_element = message.frames().len().saturating_sub(1);
+                for (idx, part) in message.frames().iter().enumerate() {
+                    self._encode_frame(&part.data, dst, idx != last_element, false);
                 }
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Simple xorshift keeps inputs reproducible without pulling rng into dev dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn valid_stream() -> BytesMut {
+        let mut codec = ZmqCodec::new();
+        let mut stream = BytesMut::new();
+        let messages = vec![
+            Message::Greeting(ZmqGreeting::new(ZmqMechanism::NULL, false)),
+            Message::Command(ZmtpCommand::Ready(
+                vec![("Socket-Type".to_string(), b"SUB".to_vec())]
+                    .into_iter()
+                    .collect(),
+            )),
+            Message::Command(ZmtpCommand::subscribe(b"topic")),
+            Message::Command(ZmtpCommand::ping(10, b"context")),
+            Message::Command(ZmtpCommand::error("Go away")),
+            Message::Message("Hello".into()),
+            Message::Message(vec![7u8; 300].into()),
+            Message::MultipartMessage(vec!["topic".into(), "part".into(), "".into()]),
+        ];
+        for message in messages {
+            codec.encode(message, &mut stream).unwrap();
+        }
+        stream
+    }
+
+    /// Feeds input in random chunks until decoder gives up or input is exhausted
+    fn decode_all(mut codec: ZmqCodec, input: &[u8], rng: &mut Rng) {
+        codec.set_max_message_size(1 << 20);
+        let mut src = BytesMut::new();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let chunk = 1 + rng.below(rest.len().min(32));
+            src.extend_from_slice(&rest[..chunk]);
+            rest = &rest[chunk..];
+            loop {
+                match codec.decode(&mut src) {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(_) => return,
+                }
+            }
+        }
+        let _ = codec.decode_eof(&mut src);
+    }
+
+    #[test]
+    fn test_decode_valid_stream() {
+        let mut codec = ZmqCodec::new();
+        let mut src = valid_stream();
+        let mut decoded = Vec::new();
+        while let Some(message) = codec.decode(&mut src).unwrap() {
+            decoded.push(message);
+        }
+        assert_eq!(8, decoded.len());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decode_random_input() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let len = rng.below(512);
+            let mut input: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            decode_all(ZmqCodec::zmtp2(), &input, &mut rng);
+            let mut codec = ZmqCodec::new();
+            codec.negotiate_version((3, 1));
+            decode_all(codec, &input, &mut rng);
+            // Get past greeting checks more often
+            if input.len() > 10 {
+                input[0] = 0xff;
+                input[9] = 0x7f;
+            }
+            decode_all(ZmqCodec::new(), &input, &mut rng);
+        }
+    }
+
+    #[test]
+    fn test_decode_mutated_stream() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let valid = valid_stream();
+        for _ in 0..5000 {
+            let mut input = valid.to_vec();
+            for _ in 0..1 + rng.below(4) {
+                let position = rng.below(input.len());
+                match rng.below(4) {
+                    0 => input[position] = rng.next() as u8,
+                    1 => input[position] ^= 1 << rng.below(8),
+                    2 => {
+                        input.remove(position);
+                    }
+                    _ => input.insert(position, rng.next() as u8),
+                }
+            }
+            if rng.below(4) == 0 {
+                input.truncate(rng.below(input.len()));
+            }
+            decode_all(ZmqCodec::new(), &input, &mut rng);
+            let mut codec = ZmqCodec::new();
+            codec.set_streaming_threshold(Some(16));
+            decode_all(codec, &input, &mut rng);
+        }
+    }
+
+    #[test]
+    fn test_decode_without_copying() {
+        let mut encoder = ZmqCodec::zmtp2();
+        let mut stream = BytesMut::new();
+        let large = vec![7u8; 64 * 1024];
+        encoder
+            .encode(Message::Message(large.clone().into()), &mut stream)
+            .unwrap();
+        encoder
+            .encode(
+                Message::MultipartMessage(vec!["topic".into(), large.clone().into()]),
+                &mut stream,
+            )
+            .unwrap();
+
+        // Frame spans reads, decoder asks for room for all of it
+        let mut decoder = ZmqCodec::zmtp2();
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&stream[..1000]);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+        assert!(src.capacity() >= large.len());
+        src.extend_from_slice(&stream[1000..]);
+
+        let buffer = src.as_ptr() as usize..src.as_ptr() as usize + src.len();
+        let in_buffer = |data: &Bytes| buffer.contains(&(data.as_ptr() as usize));
+        match decoder.decode(&mut src).unwrap() {
+            Some(Message::Message(message)) => {
+                assert_eq!(large, message.data.as_ref());
+                assert!(in_buffer(&message.data));
+            }
+            other => panic!("Unexpected decode result: {:?}", other),
+        }
+        match decoder.decode(&mut src).unwrap() {
+            Some(Message::MultipartMessage(messages)) => {
+                assert_eq!(b"topic", messages[0].data.as_ref());
+                assert_eq!(large, messages[1].data.as_ref());
+                assert!(messages.iter().all(|message| in_buffer(&message.data)));
+            }
+            other => panic!("Unexpected decode result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_streaming_reassembly() {
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        let large: Vec<u8> = (0..1_000_000).map(|_| rng.next() as u8).collect();
+        let mut encoder = ZmqCodec::zmtp2();
+        let mut stream = BytesMut::new();
+        let messages = vec![
+            Message::Message("small".into()),
+            Message::Message(large.clone().into()),
+            Message::MultipartMessage(vec!["topic".into(), large.clone().into()]),
+            Message::Message(large.clone().into()),
+        ];
+        for message in messages {
+            encoder.encode(message, &mut stream).unwrap();
+        }
+
+        let mut decoder = ZmqCodec::zmtp2();
+        decoder.set_streaming_threshold(Some(64 * 1024));
+        let mut src = BytesMut::new();
+        let mut rest = &stream[..];
+        let mut decoded = Vec::new();
+        while !rest.is_empty() {
+            let read = (1 + rng.below(100_000)).min(rest.len());
+            src.extend_from_slice(&rest[..read]);
+            rest = &rest[read..];
+            while let Some(message) = decoder.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        let mut partial: Option<Vec<u8>> = None;
+        let mut chunks = 0;
+        for message in decoded {
+            match message {
+                Message::Message(message) => match message.chunk() {
+                    Some(chunk) => {
+                        chunks += 1;
+                        let frame = partial.get_or_insert_with(Vec::new);
+                        assert_eq!(frame.len() as u64, chunk.offset);
+                        assert_eq!(large.len() as u64, chunk.frame_len);
+                        frame.extend_from_slice(&message.data);
+                        if chunk.last {
+                            frames.push(partial.take().unwrap());
+                        }
+                    }
+                    None => frames.push(message.data.to_vec()),
+                },
+                Message::MultipartMessage(messages) => {
+                    assert!(messages.iter().all(|message| message.chunk().is_none()));
+                    frames.push(messages[1].data.to_vec());
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+        assert!(chunks > 2);
+        assert!(partial.is_none());
+        assert_eq!(4, frames.len());
+        assert_eq!(b"small", frames[0].as_slice());
+        assert!(frames[1..].iter().all(|frame| frame == &large));
+    }
+
+    /// Leaves frames as is, so that measurements show the codec's own overhead
+    struct PassthroughCipher;
+
+    impl FrameCipher for PassthroughCipher {
+        fn encrypt(&mut self, flags: u8, data: &[u8]) -> Bytes {
+            let mut frame = BytesMut::with_capacity(data.len() + 1);
+            frame.put_u8(flags);
+            frame.extend_from_slice(data);
+            frame.freeze()
+        }
+
+        fn decrypt(&mut self, data: &[u8]) -> Result<(u8, Bytes), ZmqError> {
+            let (flags, data) = data.split_first().ok_or(ZmqError::Codec("Empty frame"))?;
+            // Decryption produces a new buffer just like this copy does
+            Ok((*flags, Bytes::copy_from_slice(data)))
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_decode`
+    #[test]
+    #[ignore]
+    fn bench_decode() {
+        const TOTAL: usize = 256 << 20;
+        for &encrypted in &[false, true] {
+            for &size in &[64usize, 64 * 1024] {
+                let mut encoder = ZmqCodec::zmtp2();
+                let mut decoder = ZmqCodec::zmtp2();
+                if encrypted {
+                    encoder.set_cipher(Box::new(PassthroughCipher));
+                    decoder.set_cipher(Box::new(PassthroughCipher));
+                }
+                let mut stream = BytesMut::new();
+                let count = TOTAL / size;
+                for i in 0..count {
+                    let message = if i % 2 == 0 {
+                        Message::Message(vec![0u8; size].into())
+                    } else {
+                        Message::MultipartMessage(vec![vec![0u8; size / 2].into(); 2])
+                    };
+                    encoder.encode(message, &mut stream).unwrap();
+                }
+                let stream = stream.freeze();
+
+                let started = std::time::Instant::now();
+                let mut src = BytesMut::new();
+                let mut decoded = 0;
+                // Socket reads hand over at most 64 KB at once, so large frames span reads
+                for chunk in stream.chunks(64 * 1024) {
+                    src.extend_from_slice(chunk);
+                    while let Some(message) = decoder.decode(&mut src).unwrap() {
+                        decoded += 1;
+                        drop(message);
+                    }
+                }
+                let elapsed = started.elapsed();
+                assert_eq!(count, decoded);
+                println!(
+                    "{:>6} B messages{}: {:>10.0} msg/s, {:>8.1} MB/s",
+                    size,
+                    if encrypted { " (cipher)" } else { "" },
+                    count as f64 / elapsed.as_secs_f64(),
+                    TOTAL as f64 / elapsed.as_secs_f64() / (1 << 20) as f64
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_roundtrip() {
+        let mut properties = Properties::new();
+        properties.insert("Socket-Type".into(), b"DEALER".to_vec());
+        properties.insert("X-Custom".into(), vec![0, 1, 2]);
+        let commands = vec![
+            ZmtpCommand::Ready(properties.clone()),
+            ZmtpCommand::Initiate(properties),
+            ZmtpCommand::error("Go away"),
+            ZmtpCommand::subscribe(b"topic"),
+            ZmtpCommand::cancel(b""),
+            ZmtpCommand::ping(100, b"context"),
+            ZmtpCommand::Pong {
+                context: Bytes::from_static(b"context"),
+            },
+            ZmtpCommand::join(b"group"),
+            ZmtpCommand::leave(b"group"),
+            ZmtpCommand::Hello(Bytes::from_static(b"\x05admin\x06secret")),
+            ZmtpCommand::Welcome(Bytes::new()),
+            ZmtpCommand::raw("FUTURE", Bytes::from_static(b"\x00\x01body")),
+        ];
+        for command in commands {
+            let decoded = ZmtpCommand::try_from(command.body().freeze()).unwrap();
+            assert_eq!(command, decoded);
+        }
+
+        // Bodies only security mechanism can read stay untouched
+        let ready = ZmtpCommand::raw("READY", Bytes::from_static(b"\x00\x00\x00\x00box"));
+        assert_eq!(
+            ready,
+            ZmtpCommand::decode(ready.body().freeze(), true).unwrap()
+        );
+        assert!(ZmtpCommand::try_from(ready.body().freeze()).is_err());
+
+        let long_reason = "\u{e9}".repeat(200);
+        match ZmtpCommand::error(&long_reason) {
+            ZmtpCommand::Error { reason } => assert_eq!(254, reason.len()),
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reject_malformed_frames() {
+        let cases: &[&[u8]] = &[
+            b"\x08\x00",                  // reserved flag bit
+            b"\x04\x00",                  // empty command
+            b"\x04\x03\x0aREADY",         // truncated command name
+            b"\x04\x05\xffREAD",          // non UTF-8 command name
+            b"\x05\x06\x05READY",         // command with more flag
+            b"\x01\x01a\x04\x05\x04PING", // command inside multipart message
+        ];
+        for case in cases {
+            let mut codec = ZmqCodec::new();
+            codec.state = DecoderState::FrameHeader;
+            codec.waiting_for = 1;
+            let mut src = BytesMut::from(*case);
+            assert!(
+                matches!(codec.decode(&mut src), Err(ZmqError::Codec(_))),
+                "{:?}",
+                case
+            );
+        }
+        let mut src = BytesMut::from(&b"\x04\x05\x04PING"[..]);
+        assert!(ZmqCodec::zmtp2().decode(&mut src).is_err());
+    }
+}
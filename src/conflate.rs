@@ -0,0 +1,168 @@
+//! Queues that keep only the newest messages, see `SocketOptions::conflate`
+//! and `QueueFullPolicy::DropOldest`
+use crate::error::ZmqError;
+use crate::message::ZmqMessage;
+use crate::ZmqResult;
+use futures::channel::mpsc;
+use futures::task::{Context, Poll, Waker};
+use futures::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+struct RingState<T> {
+    values: VecDeque<T>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// Holds at most `capacity` values. Newer value pushes out the oldest one that
+/// wasn't taken yet. Values put before close are still taken afterwards
+pub(crate) struct Ring<T> {
+    capacity: usize,
+    state: Mutex<RingState<T>>,
+}
+
+impl<T> Ring<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            state: Mutex::new(RingState {
+                values: VecDeque::new(),
+                closed: false,
+                waker: None,
+            }),
+        }
+    }
+
+    /// Returns true if the oldest value was dropped to make room
+    pub(crate) fn put(&self, value: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let dropped = state.values.len() >= self.capacity;
+        if dropped {
+            state.values.pop_front();
+        }
+        state.values.push_back(value);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        dropped
+    }
+
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_take(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.values.pop_front() {
+            Some(value) => Poll::Ready(Some(value)),
+            None if state.closed => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Moves everything that arrives in the queue to the ring, so the queue never fills up
+/// and only the latest value waits for the reader
+async fn relay<T>(mut queue: mpsc::Receiver<T>, ring: Arc<Ring<T>>) {
+    while let Some(value) = queue.next().await {
+        ring.put(value);
+    }
+    ring.close();
+}
+
+/// Moves values from the ring to the queue as the queue makes room,
+/// so it's the ring that drops values once reader of the queue falls behind.
+/// Value is taken only once there is room for it, until then newer ones can replace it
+pub(crate) async fn forward<T>(ring: Arc<Ring<T>>, mut queue: mpsc::Sender<T>) {
+    while futures::future::poll_fn(|cx| queue.poll_ready(cx))
+        .await
+        .is_ok()
+    {
+        let value = match futures::future::poll_fn(|cx| ring.poll_take(cx)).await {
+            Some(value) => value,
+            None => break,
+        };
+        if queue.start_send(value).is_err() {
+            break;
+        }
+    }
+}
+
+/// Receiving end of a socket or connection queue
+pub(crate) enum QueueReceiver<T> {
+    Queue(mpsc::Receiver<T>),
+    Conflated(Arc<Ring<T>>),
+}
+
+impl<T: Send + 'static> QueueReceiver<T> {
+    /// Takes messages from the queue as is, or only the newest of them if conflate is set.
+    /// Conflated queue is drained by a separate task
+    pub(crate) fn new(queue: mpsc::Receiver<T>, conflate: bool) -> Self {
+        if !conflate {
+            return Self::Queue(queue);
+        }
+        let ring = Arc::new(Ring::new(1));
+        tokio::spawn(relay(queue, ring.clone()));
+        Self::Conflated(ring)
+    }
+}
+
+impl<T> Stream for QueueReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        match self.get_mut() {
+            Self::Queue(queue) => queue.poll_next_unpin(cx),
+            Self::Conflated(ring) => ring.poll_take(cx),
+        }
+    }
+}
+
+/// Conflated queues keep single frame messages only, newer frames would replace older ones
+pub(crate) fn check_frames(conflate: bool, frames: &[ZmqMessage]) -> ZmqResult<()> {
+    if conflate && frames.len() > 1 {
+        return Err(ZmqError::ConflateMultipart);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::SinkExt;
+
+    #[tokio::test]
+    async fn test_keeps_newest() {
+        let (mut sender, receiver) = mpsc::channel(1);
+        let queue = QueueReceiver::new(receiver, true);
+        for i in 0..10u32 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        // Values relayed while nobody was reading replaced each other
+        let received: Vec<u32> = queue.collect().await;
+        assert_eq!(Some(&9), received.last());
+        assert!(received.len() < 10);
+    }
+
+    #[tokio::test]
+    async fn test_forward_drops_oldest() {
+        let ring = Arc::new(Ring::new(3));
+        let (sender, receiver) = mpsc::channel(0);
+        for i in 0..10u32 {
+            assert_eq!(i >= 3, ring.put(i));
+        }
+        ring.close();
+        let ((), received) = futures::join!(forward(ring, sender), receiver.collect::<Vec<u32>>());
+        assert_eq!(vec![7, 8, 9], received);
+    }
+}
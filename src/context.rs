@@ -0,0 +1,271 @@
+//! Shared defaults and lifetime of sockets, see `Context`
+use crate::error::ZmqError;
+use crate::options::SocketOptions;
+use crate::{SocketBackend, SocketFrontend, ZmqResult};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::watch;
+
+/// Count of tasks that are still running. Last one to finish wakes up everybody waiting
+pub(crate) struct Running {
+    count: AtomicUsize,
+    finished: watch::Sender<()>,
+    finished_receiver: watch::Receiver<()>,
+}
+
+impl Default for Running {
+    fn default() -> Self {
+        let (finished, finished_receiver) = watch::channel(());
+        Self {
+            count: AtomicUsize::new(0),
+            finished,
+            finished_receiver,
+        }
+    }
+}
+
+impl Running {
+    pub(crate) fn started(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn done(&self) {
+        if self.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _ = self.finished.broadcast(());
+        }
+    }
+
+    pub(crate) async fn finished(&self) {
+        // Subscribed before the count is checked, so the last task can't finish in between
+        let mut finished = self.finished_receiver.clone();
+        while self.count.load(Ordering::SeqCst) > 0 {
+            if finished.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+/// Listeners, handshakes, reconnect loops and connections that are still running,
+/// together with signal telling them to stop
+struct TaskSet {
+    stop: watch::Sender<bool>,
+    stopped: watch::Receiver<bool>,
+    running: Running,
+}
+
+impl Default for TaskSet {
+    fn default() -> Self {
+        let (stop, stopped) = watch::channel(false);
+        Self {
+            stop,
+            stopped,
+            running: Running::default(),
+        }
+    }
+}
+
+impl TaskSet {
+    fn is_stopped(&self) -> bool {
+        *self.stopped.borrow()
+    }
+
+    fn stop(&self) {
+        let _ = self.stop.broadcast(true);
+    }
+
+    /// Completes once stop is signalled
+    fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stopped = self.stopped.clone();
+        async move {
+            while let Some(value) = stopped.recv().await {
+                if value {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn finished(&self) {
+        self.running.finished().await
+    }
+}
+
+/// Keeps the task counted while it is alive
+pub(crate) struct TaskGuard {
+    tasks: Arc<TaskSet>,
+}
+
+impl TaskGuard {
+    fn new(tasks: &Arc<TaskSet>) -> Self {
+        tasks.running.started();
+        Self {
+            tasks: tasks.clone(),
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.tasks.running.done();
+    }
+}
+
+/// Tasks started by a single socket. Dropping the socket signals them to stop,
+/// close waits until they are all done
+#[derive(Clone, Default)]
+pub(crate) struct SocketTasks {
+    tasks: Arc<TaskSet>,
+}
+
+impl SocketTasks {
+    pub(crate) fn task(&self) -> TaskGuard {
+        TaskGuard::new(&self.tasks)
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.tasks.is_stopped()
+    }
+
+    pub(crate) fn stop(&self) {
+        self.tasks.stop()
+    }
+
+    pub(crate) fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.tasks.stopped()
+    }
+
+    pub(crate) async fn finished(&self) {
+        self.tasks.finished().await
+    }
+}
+
+/// Guards of the task for its socket and for context socket was created by
+pub(crate) struct TrackedTask {
+    _socket: TaskGuard,
+    _context: Option<TaskGuard>,
+}
+
+impl TrackedTask {
+    pub(crate) fn new(socket: &SocketTasks, context: Option<&ContextHandle>) -> Self {
+        Self {
+            _socket: socket.task(),
+            _context: context.map(ContextHandle::task),
+        }
+    }
+}
+
+/// Part of a socket that term shuts down, so its connections get closed
+pub(crate) trait Terminate: Send + Sync {
+    fn terminate(&self);
+}
+
+impl<B: SocketBackend> Terminate for B {
+    fn terminate(&self) {
+        self.shutdown();
+    }
+}
+
+struct Shared {
+    tasks: Arc<TaskSet>,
+    /// Backends of sockets that bound or connected
+    backends: Mutex<Vec<Weak<dyn Terminate>>>,
+}
+
+/// Part of the context that socket options carry to the tasks socket starts
+#[derive(Clone)]
+pub(crate) struct ContextHandle {
+    shared: Arc<Shared>,
+}
+
+impl ContextHandle {
+    pub(crate) fn check_terminated(&self) -> ZmqResult<()> {
+        if self.shared.tasks.is_stopped() {
+            return Err(ZmqError::Terminated);
+        }
+        Ok(())
+    }
+
+    /// Backend gets shut down on term together with the rest of context's sockets
+    pub(crate) fn register(&self, backend: Arc<dyn Terminate>) {
+        let backend = Arc::downgrade(&backend);
+        let mut backends = self.shared.backends.lock().unwrap();
+        backends.retain(|registered| registered.strong_count() > 0);
+        if !backends
+            .iter()
+            .any(|registered| registered.ptr_eq(&backend))
+        {
+            backends.push(backend);
+        }
+    }
+
+    /// Term waits until every task holding a guard is done
+    pub(crate) fn task(&self) -> TaskGuard {
+        TaskGuard::new(&self.shared.tasks)
+    }
+
+    pub(crate) fn terminated(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.shared.tasks.stopped()
+    }
+}
+
+/// Creates sockets with common default options and terminates them all at once.
+/// Sockets created without a context work the same, except there is nothing to terminate them
+pub struct Context {
+    options: SocketOptions,
+    handle: ContextHandle,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::with_options(SocketOptions::default())
+    }
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Options every socket of the context starts with
+    pub fn with_options(options: SocketOptions) -> Self {
+        Self {
+            options,
+            handle: ContextHandle {
+                shared: Arc::new(Shared {
+                    tasks: Arc::new(TaskSet::default()),
+                    backends: Mutex::new(Vec::new()),
+                }),
+            },
+        }
+    }
+
+    /// Socket with context's default options, e.g. `context.socket::<PubSocket>()`
+    pub fn socket<S: SocketFrontend>(&self) -> ZmqResult<S> {
+        self.socket_with_options(self.options.clone())
+    }
+
+    /// Socket with options other than context's defaults
+    pub fn socket_with_options<S: SocketFrontend>(
+        &self,
+        mut options: SocketOptions,
+    ) -> ZmqResult<S> {
+        self.handle.check_terminated()?;
+        options.context = Some(self.handle.clone());
+        S::try_with_options(options)
+    }
+
+    /// Stops accepting and reconnecting, closes connections of all sockets once their
+    /// queued messages are written out or linger is over, and waits for socket tasks
+    /// to finish. Sockets stay usable as values but bind and connect fail afterwards
+    pub async fn term(self) {
+        let shared = &self.handle.shared;
+        shared.tasks.stop();
+        let backends = std::mem::take(&mut *shared.backends.lock().unwrap());
+        for backend in backends.iter().filter_map(Weak::upgrade) {
+            backend.terminate();
+        }
+        shared.tasks.finished().await;
+    }
+}
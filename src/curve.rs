@@ -0,0 +1,452 @@
+//! CurveZMQ security mechanism (https://rfc.zeromq.org/spec/26/).
+//! Peers authenticate with long term 25519 keys and agree on short term session keys
+//! that protect every frame sent after handshake
+use crate::codec::*;
+use crate::error::*;
+use crate::options::SocketOptions;
+use crate::security::{self, Credentials};
+use crate::util::ZmqStream;
+use crate::z85::z85_encode;
+use crate::ZmqResult;
+use bytes::{BufMut, Bytes, BytesMut};
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::aead::{Aead, OsRng};
+use crypto_box::{Nonce, PublicKey, SalsaBox, SecretKey};
+use futures::{SinkExt, StreamExt};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use tokio_util::codec::Framed;
+
+pub(crate) const KEY_SIZE: usize = 32;
+
+const HELLO_NONCE: &[u8; 16] = b"CurveZMQHELLO---";
+const WELCOME_NONCE: &[u8; 8] = b"WELCOME-";
+const COOKIE_NONCE: &[u8; 8] = b"COOKIE--";
+const INITIATE_NONCE: &[u8; 16] = b"CurveZMQINITIATE";
+const VOUCH_NONCE: &[u8; 8] = b"VOUCH---";
+const READY_NONCE: &[u8; 16] = b"CurveZMQREADY---";
+const CLIENT_MESSAGE_NONCE: &[u8; 16] = b"CurveZMQMESSAGEC";
+const SERVER_MESSAGE_NONCE: &[u8; 16] = b"CurveZMQMESSAGES";
+const MESSAGE_HEADER: &[u8; 8] = b"\x07MESSAGE";
+
+/// Poly1305 tag prepended to every box
+const BOX_OVERHEAD: usize = 16;
+const HELLO_SIZE: usize = 2 + 72 + KEY_SIZE + 8 + 64 + BOX_OVERHEAD;
+const COOKIE_SIZE: usize = 16 + 2 * KEY_SIZE + BOX_OVERHEAD;
+const WELCOME_SIZE: usize = 16 + KEY_SIZE + COOKIE_SIZE + BOX_OVERHEAD;
+const VOUCH_SIZE: usize = 2 * KEY_SIZE + BOX_OVERHEAD;
+const INITIATE_MIN_SIZE: usize = COOKIE_SIZE + 8 + KEY_SIZE + 16 + VOUCH_SIZE + BOX_OVERHEAD;
+
+fn short_nonce(prefix: &[u8; 16], counter: u64) -> Nonce {
+    let mut nonce = [0u8; 24];
+    nonce[..16].copy_from_slice(prefix);
+    nonce[16..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+fn long_nonce(prefix: &[u8; 8], random: &[u8]) -> Nonce {
+    let mut nonce = [0u8; 24];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(random);
+    *Nonce::from_slice(&nonce)
+}
+
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn public_key(bytes: &[u8]) -> PublicKey {
+    let bytes: [u8; KEY_SIZE] = bytes.try_into().expect("Key slice has wrong length");
+    PublicKey::from(bytes)
+}
+
+fn counter(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().expect("Nonce slice has wrong length"))
+}
+
+/// Encrypts frames with session keys agreed during handshake
+pub(crate) struct CurveCipher {
+    session: SalsaBox,
+    send_prefix: &'static [u8; 16],
+    recv_prefix: &'static [u8; 16],
+    send_nonce: u64,
+    recv_nonce: u64,
+}
+
+impl FrameCipher for CurveCipher {
+    fn encrypt(&mut self, flags: u8, data: &[u8]) -> Bytes {
+        let nonce = self.send_nonce;
+        self.send_nonce += 1;
+        let mut plaintext = Vec::with_capacity(data.len() + 1);
+        plaintext.push(flags);
+        plaintext.extend_from_slice(data);
+        let ciphertext = self
+            .session
+            .encrypt(&short_nonce(self.send_prefix, nonce), plaintext.as_slice())
+            .expect("Failed to encrypt message");
+        let mut message = BytesMut::with_capacity(MESSAGE_HEADER.len() + 8 + ciphertext.len());
+        message.extend_from_slice(MESSAGE_HEADER);
+        message.put_u64(nonce);
+        message.extend_from_slice(&ciphertext);
+        message.freeze()
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> ZmqResult<(u8, Bytes)> {
+        if data.len() < MESSAGE_HEADER.len() + 8 + BOX_OVERHEAD + 1
+            || &data[..MESSAGE_HEADER.len()] != MESSAGE_HEADER
+        {
+            return Err(ZmqError::Curve("Malformed MESSAGE command"));
+        }
+        let nonce = counter(&data[8..16]);
+        // Strictly increasing nonces protect from replayed messages
+        if nonce <= self.recv_nonce {
+            return Err(ZmqError::Curve("Invalid MESSAGE nonce"));
+        }
+        let plaintext = self
+            .session
+            .decrypt(&short_nonce(self.recv_prefix, nonce), &data[16..])
+            .map_err(|_| ZmqError::Curve("Failed to open MESSAGE box"))?;
+        // Only authenticated messages move the window, forged nonces leave it as is
+        self.recv_nonce = nonce;
+        let flags = plaintext[0];
+        // Plaintext buffer is taken over as is, flags byte is skipped by slicing
+        Ok((flags, Bytes::from(plaintext).slice(1..)))
+    }
+}
+
+/// Generates new long term CURVE keypair. Returns (public, secret) keys in Z85 text form
+/// same as `zmq_curve_keypair` does
+pub fn curve_keypair() -> (String, String) {
+    let secret_key = SecretKey::generate(&mut OsRng);
+    let public_key = z85_encode(secret_key.public_key().as_bytes()).expect("Key has valid length");
+    let secret_key = z85_encode(secret_key.as_bytes()).expect("Key has valid length");
+    (public_key, secret_key)
+}
+
+/// Returns body of the expected command. Codec leaves boxes of READY and INITIATE unparsed
+async fn next_command<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    expected: &str,
+) -> ZmqResult<Bytes> {
+    match socket.next().await {
+        Some(Ok(Message::Command(ZmtpCommand::Error { reason }))) => {
+            Err(ZmqError::Rejected(reason))
+        }
+        Some(Ok(Message::Command(command))) if command.name() == expected => match command {
+            ZmtpCommand::Hello(body)
+            | ZmtpCommand::Welcome(body)
+            | ZmtpCommand::Unknown { body, .. } => Ok(body),
+            _ => Err(ZmqError::Curve("Unexpected handshake command")),
+        },
+        Some(Ok(Message::Command(_))) => Err(ZmqError::Curve("Unexpected handshake command")),
+        Some(Ok(_)) => Err(ZmqError::Curve("Unexpected handshake message")),
+        Some(Err(e)) => Err(e),
+        None => Err(ZmqError::Other("No reply from server")),
+    }
+}
+
+/// Client side of the handshake. Returns metadata of the server.
+/// Once handshake is done every frame on the socket is encrypted
+pub(crate) async fn client<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    metadata: Properties,
+    public_key_bytes: &[u8; KEY_SIZE],
+    secret_key: &[u8; KEY_SIZE],
+    server_key: &[u8; KEY_SIZE],
+) -> ZmqResult<Properties> {
+    socket.codec_mut().keep_raw_metadata();
+    let secret_key = SecretKey::from(*secret_key);
+    let server_key = PublicKey::from(*server_key);
+    let short_secret = SecretKey::generate(&mut OsRng);
+    let short_public = short_secret.public_key();
+    let mut nonce = 1u64;
+
+    let hello_box = SalsaBox::new(&server_key, &short_secret)
+        .encrypt(&short_nonce(HELLO_NONCE, nonce), &[0u8; 64][..])
+        .expect("Failed to encrypt HELLO");
+    let mut hello = BytesMut::with_capacity(HELLO_SIZE);
+    hello.extend_from_slice(&[1, 0]);
+    // Padding makes HELLO bigger than WELCOME so server can't be used for amplification
+    hello.extend_from_slice(&[0u8; 72]);
+    hello.extend_from_slice(short_public.as_bytes());
+    hello.put_u64(nonce);
+    hello.extend_from_slice(&hello_box);
+    socket
+        .send(Message::Command(ZmtpCommand::Hello(hello.freeze())))
+        .await?;
+    nonce += 1;
+
+    let welcome = next_command(socket, "WELCOME").await?;
+    if welcome.len() != WELCOME_SIZE {
+        return Err(ZmqError::Curve("Malformed WELCOME command"));
+    }
+    let welcome_plaintext = SalsaBox::new(&server_key, &short_secret)
+        .decrypt(&long_nonce(WELCOME_NONCE, &welcome[..16]), &welcome[16..])
+        .map_err(|_| ZmqError::Curve("Failed to open WELCOME box"))?;
+    let server_short = public_key(&welcome_plaintext[..KEY_SIZE]);
+    let cookie = &welcome_plaintext[KEY_SIZE..];
+    let session = SalsaBox::new(&server_short, &short_secret);
+
+    let vouch_nonce = random_bytes();
+    let mut vouch_plaintext = Vec::with_capacity(2 * KEY_SIZE);
+    vouch_plaintext.extend_from_slice(short_public.as_bytes());
+    vouch_plaintext.extend_from_slice(server_key.as_bytes());
+    let vouch = SalsaBox::new(&server_short, &secret_key)
+        .encrypt(
+            &long_nonce(VOUCH_NONCE, &vouch_nonce),
+            vouch_plaintext.as_slice(),
+        )
+        .expect("Failed to encrypt vouch");
+    let mut initiate_plaintext = BytesMut::new();
+    initiate_plaintext.extend_from_slice(public_key_bytes);
+    initiate_plaintext.extend_from_slice(&vouch_nonce);
+    initiate_plaintext.extend_from_slice(&vouch);
+    encode_properties(&metadata, &mut initiate_plaintext);
+    let initiate_box = session
+        .encrypt(
+            &short_nonce(INITIATE_NONCE, nonce),
+            initiate_plaintext.as_ref(),
+        )
+        .expect("Failed to encrypt INITIATE");
+    let mut initiate = BytesMut::with_capacity(COOKIE_SIZE + 8 + initiate_box.len());
+    initiate.extend_from_slice(cookie);
+    initiate.put_u64(nonce);
+    initiate.extend_from_slice(&initiate_box);
+    socket
+        .send(Message::Command(ZmtpCommand::raw(
+            "INITIATE",
+            initiate.freeze(),
+        )))
+        .await?;
+    nonce += 1;
+
+    let ready = next_command(socket, "READY").await?;
+    if ready.len() < 8 + BOX_OVERHEAD {
+        return Err(ZmqError::Curve("Malformed READY command"));
+    }
+    let server_nonce = counter(&ready[..8]);
+    let ready_plaintext = session
+        .decrypt(&short_nonce(READY_NONCE, server_nonce), &ready[8..])
+        .map_err(|_| ZmqError::Curve("Failed to open READY box"))?;
+    let properties = parse_properties(Bytes::from(ready_plaintext))?;
+
+    socket.codec_mut().set_cipher(Box::new(CurveCipher {
+        session,
+        send_prefix: CLIENT_MESSAGE_NONCE,
+        recv_prefix: SERVER_MESSAGE_NONCE,
+        send_nonce: nonce,
+        recv_nonce: server_nonce,
+    }));
+    Ok(properties)
+}
+
+/// Server side of the handshake. Returns metadata of the client.
+/// Once handshake is done every frame on the socket is encrypted
+pub(crate) async fn server<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    metadata: Properties,
+    public_key_bytes: &[u8; KEY_SIZE],
+    secret_key: &[u8; KEY_SIZE],
+    options: &SocketOptions,
+    address: Option<SocketAddr>,
+) -> ZmqResult<Properties> {
+    socket.codec_mut().keep_raw_metadata();
+    let secret_key = SecretKey::from(*secret_key);
+
+    let hello = next_command(socket, "HELLO").await?;
+    if hello.len() != HELLO_SIZE || hello[0] != 1 {
+        return Err(ZmqError::Curve("Malformed HELLO command"));
+    }
+    let client_short = public_key(&hello[74..74 + KEY_SIZE]);
+    let hello_nonce = counter(&hello[106..114]);
+    let hello_plaintext = SalsaBox::new(&client_short, &secret_key)
+        .decrypt(&short_nonce(HELLO_NONCE, hello_nonce), &hello[114..])
+        .map_err(|_| ZmqError::Curve("Failed to open HELLO box"))?;
+    if hello_plaintext.iter().any(|b| *b != 0) {
+        return Err(ZmqError::Curve("Malformed HELLO command"));
+    }
+
+    let short_secret = SecretKey::generate(&mut OsRng);
+    // Cookie lets stateless servers recover short term keys from INITIATE.
+    // Handshake state is kept in memory here but cookie still has to be valid for the client
+    let cookie_key = SecretKey::generate(&mut OsRng);
+    let cookie_box = SalsaBox::new(&cookie_key.public_key(), &cookie_key);
+    let cookie_nonce = random_bytes();
+    let mut cookie_plaintext = Vec::with_capacity(2 * KEY_SIZE);
+    cookie_plaintext.extend_from_slice(client_short.as_bytes());
+    cookie_plaintext.extend_from_slice(short_secret.as_bytes());
+    let mut cookie = Vec::with_capacity(COOKIE_SIZE);
+    cookie.extend_from_slice(&cookie_nonce);
+    cookie.extend_from_slice(
+        &cookie_box
+            .encrypt(
+                &long_nonce(COOKIE_NONCE, &cookie_nonce),
+                cookie_plaintext.as_slice(),
+            )
+            .expect("Failed to encrypt cookie"),
+    );
+
+    let mut welcome_plaintext = Vec::with_capacity(KEY_SIZE + COOKIE_SIZE);
+    welcome_plaintext.extend_from_slice(short_secret.public_key().as_bytes());
+    welcome_plaintext.extend_from_slice(&cookie);
+    let welcome_nonce = random_bytes();
+    let welcome_box = SalsaBox::new(&client_short, &secret_key)
+        .encrypt(
+            &long_nonce(WELCOME_NONCE, &welcome_nonce),
+            welcome_plaintext.as_slice(),
+        )
+        .expect("Failed to encrypt WELCOME");
+    let mut welcome = BytesMut::with_capacity(WELCOME_SIZE);
+    welcome.extend_from_slice(&welcome_nonce);
+    welcome.extend_from_slice(&welcome_box);
+    socket
+        .send(Message::Command(ZmtpCommand::Welcome(welcome.freeze())))
+        .await?;
+
+    let initiate = next_command(socket, "INITIATE").await?;
+    if initiate.len() < INITIATE_MIN_SIZE {
+        return Err(ZmqError::Curve("Malformed INITIATE command"));
+    }
+    let cookie_plaintext_received = cookie_box
+        .decrypt(
+            &long_nonce(COOKIE_NONCE, &initiate[..16]),
+            &initiate[16..COOKIE_SIZE],
+        )
+        .map_err(|_| ZmqError::Curve("Invalid cookie in INITIATE"))?;
+    if cookie_plaintext_received != cookie_plaintext {
+        return Err(ZmqError::Curve("Invalid cookie in INITIATE"));
+    }
+    let initiate_nonce = counter(&initiate[COOKIE_SIZE..COOKIE_SIZE + 8]);
+    if initiate_nonce <= hello_nonce {
+        return Err(ZmqError::Curve("Invalid INITIATE nonce"));
+    }
+    let session = SalsaBox::new(&client_short, &short_secret);
+    let initiate_plaintext = session
+        .decrypt(
+            &short_nonce(INITIATE_NONCE, initiate_nonce),
+            &initiate[COOKIE_SIZE + 8..],
+        )
+        .map_err(|_| ZmqError::Curve("Failed to open INITIATE box"))?;
+    let client_key = public_key(&initiate_plaintext[..KEY_SIZE]);
+    let vouch_nonce = &initiate_plaintext[KEY_SIZE..KEY_SIZE + 16];
+    let vouch = &initiate_plaintext[KEY_SIZE + 16..KEY_SIZE + 16 + VOUCH_SIZE];
+    let vouch_plaintext = SalsaBox::new(&client_key, &short_secret)
+        .decrypt(&long_nonce(VOUCH_NONCE, vouch_nonce), vouch)
+        .map_err(|_| ZmqError::Curve("Failed to open vouch box"))?;
+    // Vouch proves that owner of client long term key created this session with this server
+    if &vouch_plaintext[..KEY_SIZE] != client_short.as_bytes()
+        || &vouch_plaintext[KEY_SIZE..] != public_key_bytes
+    {
+        return Err(ZmqError::Curve("Invalid vouch in INITIATE"));
+    }
+    let properties =
+        parse_properties(Bytes::from(initiate_plaintext).slice(KEY_SIZE + 16 + VOUCH_SIZE..))?;
+    let credentials = Credentials::Curve {
+        public_key: *client_key.as_bytes(),
+    };
+    let user_id = security::authenticate(socket, options, address, credentials).await?;
+
+    let mut nonce = 1u64;
+    let mut ready_plaintext = BytesMut::new();
+    encode_properties(&metadata, &mut ready_plaintext);
+    let ready_box = session
+        .encrypt(&short_nonce(READY_NONCE, nonce), ready_plaintext.as_ref())
+        .expect("Failed to encrypt READY");
+    let mut ready = BytesMut::with_capacity(8 + ready_box.len());
+    ready.put_u64(nonce);
+    ready.extend_from_slice(&ready_box);
+    socket
+        .send(Message::Command(ZmtpCommand::raw("READY", ready.freeze())))
+        .await?;
+    nonce += 1;
+
+    socket.codec_mut().set_cipher(Box::new(CurveCipher {
+        session,
+        send_prefix: SERVER_MESSAGE_NONCE,
+        recv_prefix: CLIENT_MESSAGE_NONCE,
+        send_nonce: nonce,
+        recv_nonce: initiate_nonce,
+    }));
+    Ok(security::attach_user_id(properties, user_id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cipher_pair() -> (CurveCipher, CurveCipher) {
+        let client_secret = SecretKey::generate(&mut OsRng);
+        let server_secret = SecretKey::generate(&mut OsRng);
+        let client = CurveCipher {
+            session: SalsaBox::new(&server_secret.public_key(), &client_secret),
+            send_prefix: CLIENT_MESSAGE_NONCE,
+            recv_prefix: SERVER_MESSAGE_NONCE,
+            send_nonce: 3,
+            recv_nonce: 1,
+        };
+        let server = CurveCipher {
+            session: SalsaBox::new(&client_secret.public_key(), &server_secret),
+            send_prefix: SERVER_MESSAGE_NONCE,
+            recv_prefix: CLIENT_MESSAGE_NONCE,
+            send_nonce: 2,
+            recv_nonce: 2,
+        };
+        (client, server)
+    }
+
+    #[test]
+    fn test_curve_keypair() {
+        let (public_key, secret_key) = curve_keypair();
+        assert_eq!(40, public_key.len());
+        assert_eq!(40, secret_key.len());
+        let secret_key: [u8; KEY_SIZE] = crate::z85::z85_decode(&secret_key)
+            .unwrap()
+            .as_slice()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            public_key,
+            z85_encode(SecretKey::from(secret_key).public_key().as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let (mut client, mut server) = cipher_pair();
+        let encrypted = client.encrypt(CIPHER_FLAG_MORE, b"Hello");
+        assert_eq!(MESSAGE_HEADER, &encrypted[..8]);
+        let (flags, data) = server.decrypt(&encrypted).unwrap();
+        assert_eq!(CIPHER_FLAG_MORE, flags);
+        assert_eq!(b"Hello", data.as_ref());
+
+        let encrypted = server.encrypt(0, b"World");
+        let (flags, data) = client.decrypt(&encrypted).unwrap();
+        assert_eq!(0, flags);
+        assert_eq!(b"World", data.as_ref());
+    }
+
+    #[test]
+    fn test_replayed_message_rejected() {
+        let (mut client, mut server) = cipher_pair();
+        let encrypted = client.encrypt(0, b"Hello");
+        server.decrypt(&encrypted).unwrap();
+        assert!(server.decrypt(&encrypted).is_err());
+
+        let mut tampered = client.encrypt(0, b"Hello").to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(server.decrypt(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_forged_nonce_keeps_window() {
+        let (mut client, mut server) = cipher_pair();
+        let encrypted = client.encrypt(0, b"Hello");
+        let mut forged = encrypted.to_vec();
+        forged[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(server.decrypt(&forged).is_err());
+        let (_, data) = server.decrypt(&encrypted).unwrap();
+        assert_eq!(b"Hello", data.as_ref());
+    }
+}
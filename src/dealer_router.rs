@@ -1,23 +1,29 @@
 use async_trait::async_trait;
+use crossbeam::queue::SegQueue;
 use dashmap::DashMap;
 use futures::channel::{mpsc, oneshot};
-use futures::lock::Mutex;
 use futures::SinkExt;
 use std::convert::TryInto;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
 
+use crate::close::CloseReport;
 use crate::codec::*;
+use crate::conflate::{self, QueueReceiver};
+use crate::endpoint::Endpoint;
 use crate::error::*;
+use crate::fair_queue::{start_fair_queue, PeerQueueSender};
 use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
 use crate::util::*;
 use crate::{util, MultiPeer, SocketBackend, SocketFrontend};
 use crate::{Socket, SocketType, ZmqResult};
-use futures::stream::{FuturesUnordered, StreamExt};
+use futures::stream::StreamExt;
+use tokio::net::{TcpListener, TcpStream};
 
 struct RouterSocketBackend {
-    pub(crate) peers: Arc<DashMap<PeerIdentity, Peer>>,
+    pub(crate) peers: DashMap<PeerIdentity, DealerPeer>,
+    pub(crate) peer_queue_in: PeerQueueSender,
 }
 
 #[async_trait]
@@ -25,22 +31,34 @@ impl MultiPeer for RouterSocketBackend {
     async fn peer_connected(
         &self,
         peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
     ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
-        let default_queue_size = 100;
-        let (out_queue, out_queue_receiver) = mpsc::channel(default_queue_size);
-        let (in_queue, in_queue_receiver) = mpsc::channel(default_queue_size);
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (in_queue, in_queue_receiver) = bounded_queue(hwm.recv);
         let (stop_handle, stop_callback) = oneshot::channel::<bool>();
 
-        self.peers.insert(
+        let replaced = self.peers.insert(
             peer_id.clone(),
-            Peer {
-                identity: peer_id.clone(),
+            DealerPeer {
                 send_queue: out_queue,
-                recv_queue: Arc::new(Mutex::new(in_queue_receiver)),
                 recv_queue_in: in_queue,
+                send_lock: Arc::default(),
                 _io_close_handle: stop_handle,
             },
         );
+        if let Some(replaced) = replaced {
+            util::hand_over(replaced._io_close_handle);
+        }
+        let registered = self
+            .peer_queue_in
+            .clone()
+            .try_send((peer_id.clone(), in_queue_receiver));
+        if registered.is_err() {
+            // Fair queue is stopped once socket is dropped. Connection finished
+            // handshake too late, so it gets closed right away
+            self.peers.remove(peer_id);
+        }
 
         (out_queue_receiver, stop_callback)
     }
@@ -48,18 +66,24 @@ impl MultiPeer for RouterSocketBackend {
     async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
         self.peers.remove(peer_id);
     }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
 }
 
 #[async_trait]
 impl SocketBackend for RouterSocketBackend {
     async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
-        self.peers
-            .get_mut(peer_id)
-            .expect("Not found peer by id")
-            .recv_queue_in
-            .send(message)
-            .await
-            .expect("Failed to send");
+        // Clone sender so DashMap lock is not held while waiting for queue capacity
+        let recv_queue = self
+            .peers
+            .get(peer_id)
+            .map(|peer| peer.recv_queue_in.clone());
+        if let Some(mut recv_queue) = recv_queue {
+            // Fair queue might be already stopped. Message is dropped in such case
+            let _ = recv_queue.send(message).await;
+        }
     }
 
     fn socket_type(&self) -> SocketType {
@@ -73,100 +97,431 @@ impl SocketBackend for RouterSocketBackend {
 
 pub struct RouterSocket {
     backend: Arc<RouterSocketBackend>,
-    _accept_close_handle: Option<oneshot::Sender<bool>>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+    fair_queue: mpsc::Receiver<(PeerIdentity, Message)>,
 }
 
 impl Drop for RouterSocket {
     fn drop(&mut self) {
+        self.options.tasks.stop();
         self.backend.shutdown();
     }
 }
 
 #[async_trait]
 impl SocketFrontend for RouterSocket {
-    fn new() -> Self {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
         Self {
             backend: Arc::new(RouterSocketBackend {
-                peers: Arc::new(DashMap::new()),
+                peers: DashMap::new(),
+                peer_queue_in: peer_in,
             }),
-            _accept_close_handle: None,
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+            fair_queue,
         }
     }
 
-    async fn bind(&mut self, endpoint: &str) -> ZmqResult<()> {
-        let stop_handle = util::start_accepting_connections(endpoint, self.backend.clone()).await?;
-        self._accept_close_handle = Some(stop_handle);
+    fn socket_type() -> SocketType {
+        SocketType::ROUTER
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
         Ok(())
     }
 
-    async fn connect(&mut self, _endpoint: &str) -> ZmqResult<()> {
-        unimplemented!()
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
     }
 }
 
 impl RouterSocket {
-    pub async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
-        println!("Try recv multipart");
-        let mut messages = FuturesUnordered::new();
-        for peer in self.backend.peers.iter() {
-            let peer_id = peer.identity.clone();
-            let recv_queue = peer.recv_queue.clone();
-            messages.push(async move { (peer_id, recv_queue.lock().await.next().await) });
-        }
-        loop {
-            if messages.is_empty() {
-                // TODO block to wait for connections
-                return Err(ZmqError::NoMessage);
-            }
-            match messages.next().await {
-                Some((peer_id, Some(Message::MultipartMessage(messages)))) => {
-                    let mut envelope = vec![ZmqMessage {
-                        data: peer_id.into(),
-                    }];
-                    envelope.extend(messages);
-                    return Ok(envelope);
-                }
-                Some((peer_id, None)) => {
-                    println!("Peer disconnected {:?}", peer_id);
-                    self.backend.peers.remove(&peer_id);
-                }
-                Some((_peer_id, _)) => todo!(),
-                None => continue,
-            };
+    /// Receives next message fair queued across all peers
+    /// together with identity of the peer that sent it
+    pub async fn recv(&mut self) -> ZmqResult<(PeerIdentity, Vec<ZmqMessage>)> {
+        match util::with_timeout(self.options.recv_timeout, self.fair_queue.next()).await? {
+            Some((peer_id, Message::Message(m))) => Ok((peer_id, vec![m])),
+            Some((peer_id, Message::MultipartMessage(messages))) => Ok((peer_id, messages)),
+            Some((_peer_id, _)) => Err(ZmqError::Other("Wrong message type received")),
+            None => Err(ZmqError::NoMessage),
         }
     }
 
-    pub async fn send_multipart(&mut self, messages: Vec<ZmqMessage>) -> ZmqResult<()> {
-        assert!(messages.len() > 2);
-        let peer_id: PeerIdentity = messages[0].data.to_vec().try_into()?;
-        match self.backend.peers.get_mut(&peer_id) {
+    /// Sends message to the peer with given identity.
+    /// Message is silently dropped if peer is unknown or it's queue is full,
+    /// unless `router_mandatory` is set
+    pub async fn send_to(
+        &mut self,
+        peer_id: &PeerIdentity,
+        messages: Vec<ZmqMessage>,
+    ) -> ZmqResult<()> {
+        let result = match self.backend.peers.get_mut(peer_id) {
             Some(mut peer) => {
-                peer.send_queue
-                    .try_send(Message::MultipartMessage(messages[1..].to_vec()))?;
+                self.options.stats.enqueued(peer_id);
+                match peer.send_queue.try_send(messages.into()) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        self.options.stats.not_enqueued(peer_id);
+                        if e.is_full() {
+                            Err(ZmqError::PeerQueueFull(peer_id.clone()))
+                        } else {
+                            // Peer is being disconnected
+                            Err(ZmqError::PeerUnreachable(peer_id.clone()))
+                        }
+                    }
+                }
+            }
+            None => Err(ZmqError::PeerUnreachable(peer_id.clone())),
+        };
+        match result {
+            Err(ZmqError::PeerQueueFull(peer_id)) if !self.options.router_mandatory => {
+                self.options.stats.dropped(&peer_id);
                 Ok(())
             }
-            None => return Err(ZmqError::Other("Destination client not found by identity")),
+            Err(_) if !self.options.router_mandatory => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Receives message with identity of the sender as first frame
+    pub async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        let (peer_id, messages) = self.recv().await?;
+        let mut envelope = vec![ZmqMessage::from(Vec::from(peer_id))];
+        envelope.extend(messages);
+        Ok(envelope)
+    }
+
+    /// Sends message to the peer which identity is given in first frame
+    pub async fn send_multipart(&mut self, mut messages: Vec<ZmqMessage>) -> ZmqResult<()> {
+        if messages.len() < 2 {
+            return Err(ZmqError::Socket(
+                "Message should contain identity frame and at least one data frame",
+            ));
+        }
+        let peer_id: PeerIdentity = messages.remove(0).data.to_vec().try_into()?;
+        self.send_to(&peer_id, messages).await
+    }
+}
+
+pub(crate) struct DealerPeer {
+    pub(crate) send_queue: mpsc::Sender<Message>,
+    pub(crate) recv_queue_in: mpsc::Sender<Message>,
+    /// Tasks sharing a thread safe socket take turns waiting for room in the queue,
+    /// see `util::poll_send`
+    pub(crate) send_lock: Arc<futures::lock::Mutex<()>>,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+struct DealerSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, DealerPeer>,
+    pub(crate) round_robin: SegQueue<PeerIdentity>,
+    pub(crate) peer_queue_in: PeerQueueSender,
+}
+
+#[async_trait]
+impl MultiPeer for DealerSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (in_queue, in_queue_receiver) = bounded_queue(hwm.recv);
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        self.peers.insert(
+            peer_id.clone(),
+            DealerPeer {
+                send_queue: out_queue,
+                recv_queue_in: in_queue,
+                send_lock: Arc::default(),
+                _io_close_handle: stop_handle,
+            },
+        );
+        self.round_robin.push(peer_id.clone());
+        let registered = self
+            .peer_queue_in
+            .clone()
+            .try_send((peer_id.clone(), in_queue_receiver));
+        if registered.is_err() {
+            // Fair queue is stopped once socket is dropped. Connection finished
+            // handshake too late, so it gets closed right away
+            self.peers.remove(peer_id);
         }
+
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+#[async_trait]
+impl SocketBackend for DealerSocketBackend {
+    async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
+        // Clone sender so DashMap lock is not held while waiting for queue capacity
+        let recv_queue = self
+            .peers
+            .get(peer_id)
+            .map(|peer| peer.recv_queue_in.clone());
+        if let Some(mut recv_queue) = recv_queue {
+            // Fair queue might be already stopped. Message is dropped in such case
+            let _ = recv_queue.send(message).await;
+        }
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::DEALER
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
     }
 }
 
 pub struct DealerSocket {
-    pub(crate) _inner: Framed<TcpStream, ZmqCodec>,
+    backend: Arc<DealerSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+    fair_queue: QueueReceiver<(PeerIdentity, Message)>,
 }
 
-impl DealerSocket {
-    pub async fn bind(_endpoint: &str) -> ZmqResult<Self> {
-        todo!()
+impl Drop for DealerSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
     }
 }
 
 #[async_trait]
 impl Socket for DealerSocket {
-    async fn send(&mut self, _m: ZmqMessage) -> ZmqResult<()> {
-        unimplemented!()
+    async fn send(&mut self, m: ZmqMessage) -> ZmqResult<()> {
+        self.send_multipart(vec![m]).await
+    }
+
+    /// Sends all frames of the message to the next peer in round robin order.
+    /// Frames are sent as is. No delimiter frame is added
+    async fn send_multipart(&mut self, messages: Vec<ZmqMessage>) -> ZmqResult<()> {
+        // In normal scenario this will always be only 1 iteration
+        // There can be special case when peer has disconnected and his id is still in RR queue
+        // This happens because SegQueue don't have an api to delete items from queue.
+        loop {
+            let next_peer_id = match self.backend.round_robin.pop() {
+                Ok(peer) => peer,
+                Err(_) => return Err(ZmqError::Socket("Not connected to peers")),
+            };
+            if self.backend.peers.contains_key(&next_peer_id) {
+                self.backend.round_robin.push(next_peer_id.clone());
+                let send = util::send_to_peer(
+                    &self.backend.peers,
+                    &next_peer_id,
+                    |peer| &mut peer.send_queue,
+                    messages.into(),
+                    &self.options.stats,
+                );
+                return util::with_timeout(self.options.send_timeout, send).await?;
+            }
+        }
+    }
+
+    /// Receives all frames of the next message fair queued across all peers
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        match util::with_timeout(self.options.recv_timeout, self.fair_queue.next()).await? {
+            Some((_peer_id, message)) => {
+                let frames = message
+                    .into_frames()
+                    .ok_or(ZmqError::Other("Wrong message type received"))?;
+                conflate::check_frames(self.options.conflate, &frames)?;
+                Ok(frames)
+            }
+            None => Err(ZmqError::NoMessage),
+        }
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for DealerSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
+        let conflate = options.conflates_recv(SocketType::DEALER);
+        let fair_queue = QueueReceiver::new(fair_queue, conflate);
+        Self {
+            backend: Arc::new(DealerSocketBackend {
+                peers: DashMap::new(),
+                round_robin: SegQueue::new(),
+                peer_queue_in: peer_in,
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+            fair_queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::DEALER
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
     }
 
-    async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
-        unimplemented!()
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
     }
 }
@@ -0,0 +1,289 @@
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EndpointError {
+    #[error("Unknown transport: {0}")]
+    UnknownTransport(String),
+    #[error("Malformed host: {0}")]
+    MalformedHost(String),
+    #[error("Invalid port: {0}")]
+    InvalidPort(String),
+    #[error("{0}")]
+    Syntax(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Host {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Domain(String),
+    /// `*` or empty host. Means all interfaces when binding
+    Wildcard,
+}
+
+impl From<IpAddr> for Host {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Host::Ipv4(ip),
+            IpAddr::V6(ip) => Host::Ipv6(ip),
+        }
+    }
+}
+
+impl FromStr for Host {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, EndpointError> {
+        if s.is_empty() || s == "*" {
+            return Ok(Host::Wildcard);
+        }
+        if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            return inner
+                .parse::<Ipv6Addr>()
+                .map(Host::Ipv6)
+                .map_err(|_| EndpointError::MalformedHost(s.to_string()));
+        }
+        if let Ok(ip) = s.parse::<Ipv4Addr>() {
+            return Ok(Host::Ipv4(ip));
+        }
+        let valid_domain = s.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+        if valid_domain {
+            Ok(Host::Domain(s.to_string()))
+        } else {
+            Err(EndpointError::MalformedHost(s.to_string()))
+        }
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Host::Ipv4(ip) => write!(f, "{}", ip),
+            Host::Ipv6(ip) => write!(f, "[{}]", ip),
+            Host::Domain(name) => write!(f, "{}", name),
+            Host::Wildcard => write!(f, "*"),
+        }
+    }
+}
+
+/// Parsed zmq endpoint like `tcp://127.0.0.1:5555`, `tls://host:5555`, `ws://host:5555/path`,
+/// `ipc:///tmp/socket`, `inproc://name` or `udp://host:5555`.
+/// Bare `host:port` strings are treated as tcp endpoints for compatibility
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Tcp(Host, u16),
+    Tls(Host, u16),
+    /// WebSocket endpoint with host, port and path
+    Ws(Host, u16, String),
+    /// WebSocket over TLS endpoint with host, port and path
+    Wss(Host, u16, String),
+    Ipc(PathBuf),
+    Inproc(String),
+    /// Datagram endpoint. Only RADIO and DISH sockets can use it
+    Udp(Host, u16),
+}
+
+fn parse_host_port(address: &str) -> Result<(Host, u16), EndpointError> {
+    let index = address
+        .rfind(':')
+        .ok_or(EndpointError::Syntax("Endpoint is missing port"))?;
+    let (host, port) = (&address[..index], &address[index + 1..]);
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| EndpointError::InvalidPort(port.to_string()))?;
+    Ok((host.parse()?, port))
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, EndpointError> {
+        let (scheme, address) = match s.find("://") {
+            Some(index) => (&s[..index], &s[index + 3..]),
+            None => ("tcp", s),
+        };
+        match scheme {
+            "tcp" => {
+                let (host, port) = parse_host_port(address)?;
+                Ok(Endpoint::Tcp(host, port))
+            }
+            "tls" => {
+                let (host, port) = parse_host_port(address)?;
+                Ok(Endpoint::Tls(host, port))
+            }
+            "ws" | "wss" => {
+                let (address, path) = match address.find('/') {
+                    Some(index) => (&address[..index], &address[index..]),
+                    None => (address, "/"),
+                };
+                let (host, port) = parse_host_port(address)?;
+                if scheme == "ws" {
+                    Ok(Endpoint::Ws(host, port, path.to_string()))
+                } else {
+                    Ok(Endpoint::Wss(host, port, path.to_string()))
+                }
+            }
+            "udp" => {
+                let (host, port) = parse_host_port(address)?;
+                Ok(Endpoint::Udp(host, port))
+            }
+            "ipc" if address.is_empty() => Err(EndpointError::Syntax("Endpoint is missing path")),
+            "ipc" => Ok(Endpoint::Ipc(PathBuf::from(address))),
+            "inproc" if address.is_empty() => {
+                Err(EndpointError::Syntax("Endpoint is missing name"))
+            }
+            "inproc" => Ok(Endpoint::Inproc(address.to_string())),
+            _ => Err(EndpointError::UnknownTransport(scheme.to_string())),
+        }
+    }
+}
+
+impl Endpoint {
+    /// IP address and port of network endpoints with numeric host,
+    /// e.g. local address of the listener returned by bind
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let (host, port) = match self {
+            Endpoint::Tcp(host, port)
+            | Endpoint::Tls(host, port)
+            | Endpoint::Ws(host, port, _)
+            | Endpoint::Wss(host, port, _)
+            | Endpoint::Udp(host, port) => (host, *port),
+            Endpoint::Ipc(_) | Endpoint::Inproc(_) => return None,
+        };
+        match host {
+            Host::Ipv4(ip) => Some(SocketAddr::new((*ip).into(), port)),
+            Host::Ipv6(ip) => Some(SocketAddr::new((*ip).into(), port)),
+            Host::Domain(_) | Host::Wildcard => None,
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(host, port) => write!(f, "tcp://{}:{}", host, port),
+            Endpoint::Tls(host, port) => write!(f, "tls://{}:{}", host, port),
+            Endpoint::Ws(host, port, path) => write!(f, "ws://{}:{}{}", host, port, path),
+            Endpoint::Wss(host, port, path) => write!(f, "wss://{}:{}{}", host, port, path),
+            Endpoint::Ipc(path) => write!(f, "ipc://{}", path.display()),
+            Endpoint::Inproc(name) => write!(f, "inproc://{}", name),
+            Endpoint::Udp(host, port) => write!(f, "udp://{}:{}", host, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp() {
+        let endpoint: Endpoint = "tcp://127.0.0.1:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Tcp(Host::Ipv4(Ipv4Addr::LOCALHOST), 5555),
+            endpoint
+        );
+        assert_eq!("tcp://127.0.0.1:5555", endpoint.to_string());
+
+        let endpoint: Endpoint = "tcp://[::1]:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Tcp(Host::Ipv6(Ipv6Addr::LOCALHOST), 5555),
+            endpoint
+        );
+        assert_eq!("tcp://[::1]:5555", endpoint.to_string());
+
+        let endpoint: Endpoint = "tls://broker.internal:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Tls(Host::Domain("broker.internal".to_string()), 5555),
+            endpoint
+        );
+        assert_eq!("tls://broker.internal:5555", endpoint.to_string());
+
+        let endpoint: Endpoint = "tcp://broker.internal:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Tcp(Host::Domain("broker.internal".to_string()), 5555),
+            endpoint
+        );
+        assert_eq!(None, endpoint.socket_addr());
+        let endpoint: Endpoint = "ws://[::1]:5555/zmq".parse().unwrap();
+        assert_eq!(Some("[::1]:5555".parse().unwrap()), endpoint.socket_addr());
+    }
+
+    #[test]
+    fn test_parse_ws() {
+        let endpoint: Endpoint = "ws://127.0.0.1:5555/zmq".parse().unwrap();
+        assert_eq!(
+            Endpoint::Ws(Host::Ipv4(Ipv4Addr::LOCALHOST), 5555, "/zmq".to_string()),
+            endpoint
+        );
+        assert_eq!("ws://127.0.0.1:5555/zmq", endpoint.to_string());
+
+        let endpoint: Endpoint = "wss://localhost:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Wss(Host::Domain("localhost".to_string()), 5555, "/".to_string()),
+            endpoint
+        );
+    }
+
+    #[test]
+    fn test_parse_wildcard() {
+        let endpoint: Endpoint = "tcp://*:5555".parse().unwrap();
+        assert_eq!(Endpoint::Tcp(Host::Wildcard, 5555), endpoint);
+        assert_eq!("tcp://*:5555", endpoint.to_string());
+
+        let endpoint: Endpoint = "tcp://:5555".parse().unwrap();
+        assert_eq!(Endpoint::Tcp(Host::Wildcard, 5555), endpoint);
+    }
+
+    #[test]
+    fn test_parse_bare_address() {
+        let endpoint: Endpoint = "127.0.0.1:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Tcp(Host::Ipv4(Ipv4Addr::LOCALHOST), 5555),
+            endpoint
+        );
+    }
+
+    #[test]
+    fn test_parse_ipc_inproc() {
+        let endpoint: Endpoint = "ipc:///tmp/service.sock".parse().unwrap();
+        assert_eq!(Endpoint::Ipc(PathBuf::from("/tmp/service.sock")), endpoint);
+        assert_eq!("ipc:///tmp/service.sock", endpoint.to_string());
+
+        let endpoint: Endpoint = "inproc://service".parse().unwrap();
+        assert_eq!(Endpoint::Inproc("service".to_string()), endpoint);
+
+        let endpoint: Endpoint = "udp://239.0.0.1:5555".parse().unwrap();
+        assert_eq!(
+            Endpoint::Udp(Host::Ipv4(Ipv4Addr::new(239, 0, 0, 1)), 5555),
+            endpoint
+        );
+        assert_eq!("udp://239.0.0.1:5555", endpoint.to_string());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Err(EndpointError::UnknownTransport("pgm".to_string())),
+            "pgm://127.0.0.1:5555".parse::<Endpoint>()
+        );
+        assert_eq!(
+            Err(EndpointError::InvalidPort("port".to_string())),
+            "tcp://127.0.0.1:port".parse::<Endpoint>()
+        );
+        assert_eq!(
+            Err(EndpointError::MalformedHost("::1".to_string())),
+            "tcp://::1:5555".parse::<Endpoint>()
+        );
+        assert!("tcp://127.0.0.1".parse::<Endpoint>().is_err());
+        assert!("ipc://".parse::<Endpoint>().is_err());
+    }
+}
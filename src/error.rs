@@ -1,11 +1,65 @@
 use crate::codec::Message;
-use crate::ZmqMessage;
+use crate::endpoint::{Endpoint, EndpointError};
+use crate::socks::SocksError;
+use crate::util::PeerIdentity;
+use crate::z85::Z85Error;
+use crate::{SocketType, ZmqMessage};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum ZmqError {
     #[error("Malformed socket address")]
     Address(#[from] std::net::AddrParseError),
+    #[error("Malformed endpoint: {0}")]
+    Endpoint(#[from] EndpointError),
+    #[error("Malformed IP network: {0}")]
+    IpNetwork(String),
+    #[error("Failed to resolve host {0}")]
+    HostResolution(String),
+    #[error("None of the addresses of host {0} accepted connection")]
+    HostUnreachable(String),
+    #[error("SOCKS proxy error: {0}")]
+    Socks(SocksError),
+    #[error("Security mechanism mismatch: expected {expected}, peer uses {actual}")]
+    MechanismMismatch { expected: String, actual: String },
+    #[error("Authentication failed: {0}")]
+    Authentication(String),
+    #[error("Peer rejected connection: {0}")]
+    Rejected(String),
+    #[error("Message of {size} bytes exceeds limit of {limit} bytes")]
+    MessageTooLarge { size: u64, limit: usize },
+    #[error("Invalid handshake property {0:?}")]
+    InvalidProperty(String),
+    #[error("Peer with identity {0:?} is already connected")]
+    DuplicateIdentity(PeerIdentity),
+    #[error("CURVE error: {0}")]
+    Curve(&'static str),
+    #[error("Malformed key: {0}")]
+    Z85(#[from] Z85Error),
+    #[error("TLS handshake failed: {0}")]
+    Tls(String),
+    #[error("WebSocket handshake failed: {0}")]
+    WebSocket(String),
+    #[error("{ours} socket can't talk to {theirs} peer")]
+    InvalidPeerSocketType {
+        ours: SocketType,
+        theirs: SocketType,
+    },
+    #[error("Option {option} is not supported by {socket_type} socket")]
+    UnsupportedOption {
+        option: &'static str,
+        socket_type: SocketType,
+    },
+    #[error("Invalid value of option {0}")]
+    InvalidOption(&'static str),
+    #[error("Peer didn't complete handshake in time")]
+    HandshakeTimeout,
+    #[error("Operation timed out")]
+    Timeout,
+    #[error("Connection to peer lost")]
+    ConnectionLost,
+    #[error("Multipart messages can't be conflated")]
+    ConflateMultipart,
     #[error("Network error")]
     Network(#[from] std::io::Error),
     #[error("{0}")]
@@ -14,11 +68,33 @@ pub enum ZmqError {
     Socket(&'static str),
     #[error("{0}")]
     BufferFull(&'static str),
+    #[error("Queue of peer {0:?} is full")]
+    PeerQueueFull(PeerIdentity),
+    #[error("Peer {0:?} is not connected")]
+    PeerUnreachable(PeerIdentity),
+    #[error("Socket is not bound to {0}")]
+    NotBound(Endpoint),
+    #[error("Socket is not connected to {0}")]
+    NotConnected(Endpoint),
+    #[error("Socket already has maximum number of peers")]
+    PeerLimitReached,
+    #[error("Context was terminated")]
+    Terminated,
+    #[error("Socket was closed")]
+    SocketClosed,
     #[error("Failed to deliver message cause of {reason}")]
     ReturnToSender {
         reason: &'static str,
         message: ZmqMessage,
     },
+    #[error("Message is not valid UTF-8: {error}")]
+    InvalidUtf8 {
+        message: ZmqMessage,
+        error: std::str::Utf8Error,
+    },
+    #[cfg(feature = "serde")]
+    #[error("Failed to serialize or deserialize message: {0}")]
+    Serde(Box<dyn std::error::Error + Send + Sync>),
     #[error("{0}")]
     Other(&'static str),
     #[error("No message received")]
@@ -1,7 +1,11 @@
+use crate::codec::Message;
+use crate::util::PeerIdentity;
+use futures::channel::{mpsc, oneshot};
 use futures::task::{ArcWake, Context, Poll, Waker};
 use futures::Stream;
+use futures::{SinkExt, StreamExt};
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::atomic;
 use std::sync::{Arc, Mutex};
@@ -9,6 +13,8 @@ use std::sync::{Arc, Mutex};
 struct QueueInner<S, K> {
     ready_queue: BinaryHeap<PriorityStream<S, K>>,
     pending_streams: HashMap<usize, PriorityStream<S, K>>,
+    // Streams woken before they were moved to pending_streams
+    early_wakeups: HashSet<usize>,
     waker: Option<Waker>,
 }
 
@@ -29,11 +35,13 @@ where
 {
     fn wake_by_ref(arc_self: &Arc<Self>) {
         let mut inner = arc_self.inner.lock().unwrap();
-        let s = inner
-            .pending_streams
-            .remove(&arc_self.index)
-            .expect("Corrupted index given to waker");
-        inner.ready_queue.push(s);
+        match inner.pending_streams.remove(&arc_self.index) {
+            Some(s) => inner.ready_queue.push(s),
+            None => {
+                // Stream is still being polled. It will be returned to ready queue right away
+                inner.early_wakeups.insert(arc_self.index);
+            }
+        }
         if let Some(waker) = inner.waker.take() {
             waker.wake_by_ref();
         }
@@ -55,7 +63,7 @@ impl<S, K> Eq for PriorityStream<S, K> {}
 
 impl<S, K> PartialOrd for PriorityStream<S, K> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.priority.partial_cmp(&self.priority)
+        Some(self.cmp(other))
     }
 }
 impl<S, K> Ord for PriorityStream<S, K> {
@@ -67,8 +75,8 @@ impl<S, K> Ord for PriorityStream<S, K> {
 impl<S, T, K> Stream for FairQueue<S, K>
 where
     T: Send,
-    S: Stream<Item = T> + Send,
-    K: Unpin + Clone + Send,
+    S: Stream<Item = T> + Send + 'static,
+    K: Unpin + Clone + Send + 'static,
 {
     type Item = (K, T);
 
@@ -81,7 +89,7 @@ where
                 match inner.ready_queue.pop() {
                     Some(s) => s,
                     None => {
-                        return if inner.pending_streams.len() > 0 {
+                        return if !inner.pending_streams.is_empty() {
                             Poll::Pending
                         } else {
                             Poll::Ready(None)
@@ -105,13 +113,14 @@ where
                 }
                 Poll::Ready(None) => continue,
                 Poll::Pending => {
-                    stream
-                        .inner
-                        .lock()
-                        .unwrap()
-                        .pending_streams
-                        .insert(s.priority, s);
-                    return Poll::Pending;
+                    // Other streams might still have some data ready
+                    let mut inner = stream.inner.lock().unwrap();
+                    if inner.early_wakeups.remove(&s.priority) {
+                        inner.ready_queue.push(s);
+                    } else {
+                        inner.pending_streams.insert(s.priority, s);
+                    }
+                    continue;
                 }
             }
         }
@@ -125,6 +134,7 @@ impl<S, K> FairQueue<S, K> {
             inner: Arc::new(Mutex::new(QueueInner {
                 ready_queue: BinaryHeap::new(),
                 pending_streams: HashMap::new(),
+                early_wakeups: HashSet::new(),
                 waker: None,
             })),
         }
@@ -137,13 +147,89 @@ impl<S, K> FairQueue<S, K> {
             key: k,
             stream: Box::pin(s),
         });
-        match &inner.waker {
-            Some(w) => w.wake_by_ref(),
-            None => (),
-        };
+        if let Some(w) = &inner.waker {
+            w.wake_by_ref();
+        }
+    }
+}
+
+pub(crate) struct FairQueueProcessor {
+    pub(crate) fair_queue_stream: FairQueue<mpsc::Receiver<Message>, PeerIdentity>,
+    pub(crate) socket_incoming_queue: mpsc::Sender<(PeerIdentity, Message)>,
+    pub(crate) peer_queue_in: mpsc::Receiver<(PeerIdentity, mpsc::Receiver<Message>)>,
+    pub(crate) _io_close_handle: oneshot::Receiver<bool>,
+}
+
+pub(crate) async fn process_fair_queue_messages(mut processor: FairQueueProcessor) {
+    let mut stop_callback = processor._io_close_handle;
+    let mut waiting_for_clients = true;
+    let mut waiting_for_data = true;
+    loop {
+        tokio::select! {
+            _ = &mut stop_callback => {
+                break;
+            },
+            peer_in = processor.peer_queue_in.next(), if waiting_for_clients => {
+                match peer_in {
+                    Some((peer_id, receiver)) => {
+                        processor.fair_queue_stream.insert(peer_id, receiver);
+                        waiting_for_data = true;
+                    },
+                    None => {
+                        // Channel for newly connected clients was closed
+                        // so we no longer wait for the to arrive
+                        waiting_for_clients = false;
+                    },
+                };
+            },
+            message = processor.fair_queue_stream.next(), if waiting_for_data => {
+                match message {
+                    Some(m) => {
+                        processor.socket_incoming_queue.send(m).await.expect("Failed to deliver message");
+                    },
+                    None => {
+                        // This is the case when there are no connected clients
+                        // We should sleep and wait for new clients to connect
+                        // This is handled by 2nd branch of select
+                        if waiting_for_clients {
+                            waiting_for_data = false;
+                        } else {
+                            // We're not waiting for client and have no data...
+                            // stop the loop and cleanup
+                            break;
+                        };
+                    }
+                };
+            }
+        }
     }
 }
 
+/// Channel used to register incoming queues of newly connected peers in fair queue
+pub(crate) type PeerQueueSender = mpsc::Sender<(PeerIdentity, mpsc::Receiver<Message>)>;
+
+/// Spawns a task fair queueing messages of all peers into a single queue.
+/// Returns a sender to register incoming queues of new peers, receiver of fair queued messages
+/// and a handle that stops processing once dropped
+pub(crate) fn start_fair_queue(
+    queue_size: usize,
+) -> (
+    PeerQueueSender,
+    mpsc::Receiver<(PeerIdentity, Message)>,
+    oneshot::Sender<bool>,
+) {
+    let (queue_sender, fair_queue) = mpsc::channel(queue_size);
+    let (peer_in, peer_out) = mpsc::channel(queue_size);
+    let (fair_queue_close_handle, fqueue_close_recevier) = oneshot::channel();
+    tokio::spawn(process_fair_queue_messages(FairQueueProcessor {
+        fair_queue_stream: FairQueue::new(),
+        socket_incoming_queue: queue_sender,
+        peer_queue_in: peer_out,
+        _io_close_handle: fqueue_close_recevier,
+    }));
+    (peer_in, fair_queue, fair_queue_close_handle)
+}
+
 #[cfg(test)]
 mod test {
     use crate::fair_queue::FairQueue;
@@ -207,4 +293,27 @@ mod test {
             ]
         );
     }
+
+    #[tokio::test]
+    async fn test_fair_queue_pending_stream() {
+        let (mut sender, receiver) = futures::channel::mpsc::channel::<&str>(10);
+        let a = futures::stream::iter(vec!["a1", "a2", "a3"]).boxed();
+        let b = receiver.boxed();
+
+        let mut f_queue = FairQueue::new();
+        f_queue.insert(1, a);
+        f_queue.insert(2, b);
+
+        // Stream without data should not block other streams
+        let mut results = Vec::new();
+        for _ in 0..3 {
+            results.push(f_queue.next().await.unwrap());
+        }
+        assert_eq!(results, vec![(1, "a1"), (1, "a2"), (1, "a3")]);
+
+        sender.try_send("b1").unwrap();
+        assert_eq!(f_queue.next().await, Some((2, "b1")));
+        drop(sender);
+        assert_eq!(f_queue.next().await, None);
+    }
 }
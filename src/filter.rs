@@ -0,0 +1,181 @@
+//! IP allow/deny lists checked for every accepted connection before any handshake
+use crate::error::*;
+use crate::ZmqResult;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// IP network in CIDR notation, e.g. `10.0.0.0/8` or `fe80::/10`.
+/// Address without prefix length matches just that address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, normalize(*address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                masked(u32::from(network) as u128, 32, self.prefix)
+                    == masked(u32::from(address) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                masked(u128::from(network), 128, self.prefix)
+                    == masked(u128::from(address), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, width: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        bits >> (width - prefix)
+    }
+}
+
+/// IPv4 clients of dual-stack listeners show up as IPv4-mapped IPv6 addresses
+fn normalize(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => address,
+        },
+        _ => address,
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ZmqError;
+
+    fn from_str(s: &str) -> Result<Self, ZmqError> {
+        let malformed = || ZmqError::IpNetwork(s.to_string());
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address = normalize(address.parse::<IpAddr>().map_err(|_| malformed())?);
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| malformed())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(malformed());
+        }
+        Ok(Self { address, prefix })
+    }
+}
+
+#[derive(Default)]
+struct FilterLists {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+/// Allow and deny lists of IP networks for incoming connections.
+/// Deny list wins. Once allow list has any entry only addresses from it are accepted.
+/// Handle is shared with socket's listeners so changes apply to live sockets immediately
+#[derive(Clone, Default)]
+pub struct AcceptFilter {
+    lists: Arc<RwLock<FilterLists>>,
+    denied: Arc<AtomicU64>,
+}
+
+impl AcceptFilter {
+    /// Accepts connections from network, e.g. `192.168.0.0/16`
+    pub fn allow(&self, network: &str) -> ZmqResult<()> {
+        let network = network.parse()?;
+        self.lists.write().unwrap().allow.push(network);
+        Ok(())
+    }
+
+    /// Rejects connections from network, e.g. `10.0.0.13`
+    pub fn deny(&self, network: &str) -> ZmqResult<()> {
+        let network = network.parse()?;
+        self.lists.write().unwrap().deny.push(network);
+        Ok(())
+    }
+
+    /// Removes all entries from both lists so every connection is accepted again
+    pub fn clear(&self) {
+        let mut lists = self.lists.write().unwrap();
+        lists.allow.clear();
+        lists.deny.clear();
+    }
+
+    /// Number of connections closed because of the lists
+    pub fn denied_count(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    /// Checks address of accepted connection and counts rejected ones
+    pub(crate) fn check(&self, address: &IpAddr) -> bool {
+        let lists = self.lists.read().unwrap();
+        let allowed = !lists.deny.iter().any(|network| network.contains(address))
+            && (lists.allow.is_empty()
+                || lists.allow.iter().any(|network| network.contains(address)));
+        if !allowed {
+            self.denied.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_network() {
+        let network: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains(&ip("10.1.200.3")));
+        assert!(!network.contains(&ip("10.2.0.1")));
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(&ip("8.8.8.8")));
+
+        let network: IpNetwork = "fe80::/10".parse().unwrap();
+        assert!(network.contains(&ip("fe80::1")));
+        assert!(!network.contains(&ip("2001:db8::1")));
+        assert!(!network.contains(&ip("10.1.0.1")));
+
+        let single: IpNetwork = "127.0.0.1".parse().unwrap();
+        assert!(single.contains(&ip("127.0.0.1")));
+        assert!(single.contains(&ip("::ffff:127.0.0.1")));
+        assert!(!single.contains(&ip("127.0.0.2")));
+
+        for malformed in &["10.0.0.0/33", "::/129", "10.0.0/8", "host/8", "10.0.0.0/"] {
+            assert!(matches!(
+                malformed.parse::<IpNetwork>(),
+                Err(ZmqError::IpNetwork(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_filter_lists() {
+        let filter = AcceptFilter::default();
+        assert!(filter.check(&ip("192.168.1.1")));
+
+        filter.allow("192.168.0.0/16").unwrap();
+        filter.deny("192.168.1.13").unwrap();
+        assert!(filter.check(&ip("192.168.1.1")));
+        assert!(!filter.check(&ip("192.168.1.13")));
+        assert!(!filter.check(&ip("10.0.0.1")));
+        assert_eq!(2, filter.denied_count());
+
+        filter.clear();
+        assert!(filter.check(&ip("10.0.0.1")));
+        assert_eq!(2, filter.denied_count());
+    }
+}
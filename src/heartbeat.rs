@@ -0,0 +1,76 @@
+//! ZMTP heartbeats (PING/PONG commands) detecting dead peers on idle connections
+use crate::codec::*;
+use crate::options::SocketOptions;
+use std::time::Duration;
+use tokio::time::{Instant, Interval};
+
+/// TTL travels in deciseconds
+fn to_deciseconds(duration: Duration) -> u16 {
+    (duration.as_millis() / 100).min(u16::MAX as u128) as u16
+}
+
+/// Liveness of a single connection. PINGs are only sent when heartbeat interval is configured,
+/// while TTL announced in peer's PINGs is honored in any case.
+/// ZMTP 3.0 has no PING command so such connections are never heartbeated
+pub(crate) struct Heartbeat {
+    interval: Option<Duration>,
+    timeout: Duration,
+    ttl: u16,
+    peer_ttl: Option<Duration>,
+    last_received: Instant,
+    ticker: Option<Interval>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(options: &SocketOptions, version: ZmtpVersion) -> Self {
+        let interval = options.heartbeat_interval.filter(|_| version >= (3, 1));
+        Self {
+            interval,
+            timeout: options.heartbeat_timeout.or(interval).unwrap_or_default(),
+            ttl: options.heartbeat_ttl.map(to_deciseconds).unwrap_or(0),
+            peer_ttl: None,
+            last_received: Instant::now(),
+            ticker: interval
+                .map(|period| tokio::time::interval_at(Instant::now() + period, period)),
+        }
+    }
+
+    /// Any traffic from the peer proves it is alive
+    pub(crate) fn received(&mut self, message: &Message) {
+        self.last_received = Instant::now();
+        if let Message::Command(ZmtpCommand::Ping { ttl, .. }) = message {
+            let ttl = Duration::from_millis(*ttl as u64 * 100);
+            if ttl == Duration::from_millis(0) {
+                return;
+            }
+            self.peer_ttl = Some(ttl);
+            if self.ticker.is_none() {
+                let period = ttl / 2;
+                self.ticker = Some(tokio::time::interval_at(Instant::now() + period, period));
+            }
+        }
+    }
+
+    /// Resolves when it's time to check the peer. Never resolves if heartbeats are off
+    pub(crate) async fn tick(&mut self) {
+        match &mut self.ticker {
+            Some(ticker) => {
+                ticker.tick().await;
+            }
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Peer didn't send anything within heartbeat timeout or TTL it asked for
+    pub(crate) fn expired(&self) -> bool {
+        let idle = self.last_received.elapsed();
+        (self.interval.is_some() && idle > self.timeout)
+            || self.peer_ttl.is_some_and(|ttl| idle > ttl)
+    }
+
+    /// PING to send on this tick if we are the one heartbeating
+    pub(crate) fn ping(&self) -> Option<Message> {
+        self.interval
+            .map(|_| Message::Command(ZmtpCommand::ping(self.ttl, b"")))
+    }
+}
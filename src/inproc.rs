@@ -0,0 +1,130 @@
+//! In-process transport. Connections are in-memory duplex streams
+//! handed from connecting socket to the bound one through a global registry
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::options::SocketOptions;
+use crate::transport::{Listener, PendingStream, Transport};
+use crate::util::BoxedStream;
+use crate::ZmqResult;
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{FutureExt, StreamExt};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+enum InprocEndpoint {
+    Bound(mpsc::UnboundedSender<BoxedStream>),
+    /// Connections made before bind. They are passed to binder once it appears
+    Pending(Vec<BoxedStream>),
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<String, InprocEndpoint>> = Mutex::new(HashMap::new());
+}
+
+/// Opens connection to inproc endpoint with given name.
+/// If nobody is bound to it yet connection waits in registry until bind happens
+pub(crate) fn connect(name: &str) -> ZmqResult<BoxedStream> {
+    let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+    let server: BoxedStream = Box::new(server);
+    let mut registry = REGISTRY.lock().expect("Inproc registry is poisoned");
+    match registry.get_mut(name) {
+        Some(InprocEndpoint::Bound(binder)) => {
+            if let Err(e) = binder.unbounded_send(server) {
+                // Binder is gone but hasn't cleaned up yet. Wait for the next one
+                registry.insert(
+                    name.to_string(),
+                    InprocEndpoint::Pending(vec![e.into_inner()]),
+                );
+            }
+        }
+        Some(InprocEndpoint::Pending(pending)) => pending.push(server),
+        None => {
+            registry.insert(name.to_string(), InprocEndpoint::Pending(vec![server]));
+        }
+    }
+    Ok(Box::new(client))
+}
+
+fn bind(name: &str) -> ZmqResult<mpsc::UnboundedReceiver<BoxedStream>> {
+    let (sender, receiver) = mpsc::unbounded();
+    let mut registry = REGISTRY.lock().expect("Inproc registry is poisoned");
+    match registry.remove(name) {
+        Some(InprocEndpoint::Bound(binder)) if !binder.is_closed() => {
+            registry.insert(name.to_string(), InprocEndpoint::Bound(binder));
+            return Err(ZmqError::Socket("Inproc endpoint is already bound"));
+        }
+        Some(InprocEndpoint::Pending(pending)) => {
+            for stream in pending {
+                sender
+                    .unbounded_send(stream)
+                    .expect("Receiver can't be dropped at this point");
+            }
+        }
+        _ => (),
+    }
+    registry.insert(name.to_string(), InprocEndpoint::Bound(sender));
+    Ok(receiver)
+}
+
+fn unbind(name: &str) {
+    let mut registry = REGISTRY.lock().expect("Inproc registry is poisoned");
+    if let Some(InprocEndpoint::Bound(_)) = registry.get(name) {
+        registry.remove(name);
+    }
+}
+
+/// In-process transport. Endpoint is unbound once listener is dropped
+pub(crate) struct InprocTransport;
+
+#[async_trait]
+impl Transport for InprocTransport {
+    async fn connect(
+        &self,
+        endpoint: Endpoint,
+        _options: &SocketOptions,
+    ) -> ZmqResult<BoxedStream> {
+        match endpoint {
+            Endpoint::Inproc(name) => connect(&name),
+            _ => unreachable!("Endpoint is not served by inproc transport"),
+        }
+    }
+
+    async fn bind(
+        &self,
+        endpoint: Endpoint,
+        _options: &SocketOptions,
+    ) -> ZmqResult<(Box<dyn Listener>, Endpoint)> {
+        let name = match &endpoint {
+            Endpoint::Inproc(name) => name.clone(),
+            _ => unreachable!("Endpoint is not served by inproc transport"),
+        };
+        let incoming = bind(&name)?;
+        Ok((Box::new(InprocListener { name, incoming }), endpoint))
+    }
+}
+
+struct InprocListener {
+    name: String,
+    incoming: mpsc::UnboundedReceiver<BoxedStream>,
+}
+
+#[async_trait]
+impl Listener for InprocListener {
+    async fn accept(&mut self) -> ZmqResult<(PendingStream, Option<SocketAddr>)> {
+        match self.incoming.next().await {
+            Some(stream) => Ok((futures::future::ok(stream).boxed(), None)),
+            None => Err(ZmqError::Socket("Inproc endpoint was unbound")),
+        }
+    }
+}
+
+impl Drop for InprocListener {
+    fn drop(&mut self) {
+        unbind(&self.name);
+    }
+}
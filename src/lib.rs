@@ -1,39 +1,101 @@
 #![deny(warnings)]
 #![recursion_limit = "1024"]
+#![allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
 #[macro_use]
 extern crate enum_primitive_derive;
-use num_traits::ToPrimitive;
 
 use async_trait::async_trait;
+use bytes::BytesMut;
 use futures::channel::{mpsc, oneshot};
 use std::convert::TryFrom;
 use std::fmt::{Debug, Display};
-use std::net::SocketAddr;
 use tokio_util::codec::Framed;
 
+mod buffer_pool;
+mod client_server;
+mod close;
 mod codec;
+mod conflate;
+mod context;
+#[cfg(feature = "curve")]
+mod curve;
 mod dealer_router;
+mod endpoint;
 mod error;
 mod fair_queue;
+mod filter;
+mod heartbeat;
+mod inproc;
 mod message;
+mod monitor;
+mod options;
+mod pair;
 mod r#pub;
+mod pull;
+mod push;
+mod radio_dish;
 mod rep;
 mod req;
+mod scatter_gather;
+mod security;
+#[cfg(feature = "serde")]
+mod serde_format;
+mod socks;
+mod stats;
+mod stream;
 mod sub;
+mod subscriptions;
+mod tls;
+mod transport;
+mod udp;
 pub mod util;
+mod ws;
+mod xpub;
+mod xsub;
+mod z85;
+mod zmtp2;
 
 #[cfg(test)]
 mod tests;
 
+pub use crate::buffer_pool::{BufferPool, BufferPoolStats};
+pub use crate::client_server::*;
+pub use crate::close::{CloseReport, PeerCloseReport};
+pub use crate::codec::Properties;
 use crate::codec::*;
+pub use crate::context::Context;
+#[cfg(feature = "curve")]
+pub use crate::curve::curve_keypair;
 pub use crate::dealer_router::*;
+pub use crate::endpoint::{Endpoint, EndpointError, Host};
 pub use crate::error::ZmqError;
+pub use crate::filter::{AcceptFilter, IpNetwork};
+pub use crate::monitor::{SocketEvent, SocketMonitor};
+use crate::options::HighWaterMarks;
+pub use crate::options::{FlushStrategy, PeerLimit, QueueFullPolicy, SocketBuffers, SocketOptions};
+pub use crate::pair::*;
+pub use crate::pull::*;
+pub use crate::push::*;
 pub use crate::r#pub::*;
+pub use crate::radio_dish::*;
 pub use crate::rep::*;
 pub use crate::req::*;
+pub use crate::scatter_gather::*;
+pub use crate::security::{AuthRequest, AuthResult, Authenticator, Credentials};
+#[cfg(feature = "serde")]
+pub use crate::serde_format::*;
+pub use crate::socks::{SocksError, SocksProxy};
+pub use crate::stats::{PeerStats, SocketStats};
+pub use crate::stream::*;
 pub use crate::sub::*;
-use crate::util::*;
+pub use crate::udp::MAX_DATAGRAM_SIZE;
+pub use crate::util::{ConnectionInfo, ConnectionState, PeerIdentity};
+pub use crate::xpub::*;
+pub use crate::xsub::*;
+pub use crate::z85::{z85_decode, z85_encode, Z85Error};
 pub use message::*;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
 
 pub type ZmqResult<T> = Result<T, ZmqError>;
 
@@ -51,6 +113,38 @@ pub enum SocketType {
     XPUB = 9,
     XSUB = 10,
     STREAM = 11,
+    SERVER = 12,
+    CLIENT = 13,
+    RADIO = 14,
+    DISH = 15,
+    GATHER = 16,
+    SCATTER = 17,
+}
+
+impl SocketType {
+    /// Socket types this one is allowed to talk to according to ZMTP.
+    /// Handshake with any other peer type is rejected
+    pub fn compatible_peers(self) -> &'static [SocketType] {
+        use SocketType::*;
+        match self {
+            PAIR => &[PAIR],
+            PUB | XPUB => &[SUB, XSUB],
+            SUB | XSUB => &[PUB, XPUB],
+            REQ => &[REP, ROUTER],
+            REP => &[REQ, DEALER],
+            DEALER => &[REP, DEALER, ROUTER],
+            ROUTER => &[REQ, DEALER, ROUTER],
+            PULL => &[PUSH],
+            PUSH => &[PULL],
+            STREAM => &[],
+            SERVER => &[CLIENT],
+            CLIENT => &[SERVER],
+            RADIO => &[DISH],
+            DISH => &[RADIO],
+            GATHER => &[SCATTER],
+            SCATTER => &[GATHER],
+        }
+    }
 }
 
 impl TryFrom<&str> for SocketType {
@@ -70,6 +164,12 @@ impl TryFrom<&str> for SocketType {
             "XPUB" => SocketType::XPUB,
             "XSUB" => SocketType::XSUB,
             "STREAM" => SocketType::STREAM,
+            "SERVER" => SocketType::SERVER,
+            "CLIENT" => SocketType::CLIENT,
+            "RADIO" => SocketType::RADIO,
+            "DISH" => SocketType::DISH,
+            "GATHER" => SocketType::GATHER,
+            "SCATTER" => SocketType::SCATTER,
             _ => return Err(ZmqError::Codec("Unknown socket type")),
         })
     }
@@ -90,17 +190,26 @@ impl Display for SocketType {
             SocketType::XPUB => write!(f, "XPUB"),
             SocketType::XSUB => write!(f, "XSUB"),
             SocketType::STREAM => write!(f, "STREAM"),
+            SocketType::SERVER => write!(f, "SERVER"),
+            SocketType::CLIENT => write!(f, "CLIENT"),
+            SocketType::RADIO => write!(f, "RADIO"),
+            SocketType::DISH => write!(f, "DISH"),
+            SocketType::GATHER => write!(f, "GATHER"),
+            SocketType::SCATTER => write!(f, "SCATTER"),
         }
     }
 }
 
 #[async_trait]
-trait MultiPeer: SocketBackend {
+trait MultiPeer: SocketBackend + context::Terminate {
     async fn peer_connected(
         &self,
         peer_id: &PeerIdentity,
+        version: ZmtpVersion,
+        hwm: HighWaterMarks,
     ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>);
     async fn peer_disconnected(&self, peer_id: &PeerIdentity);
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool;
 }
 
 #[async_trait]
@@ -111,37 +220,332 @@ trait SocketBackend: Send + Sync {
     fn shutdown(&self);
 }
 
+/// Frames of received message when caller expects just one
+fn single_frame(mut frames: Vec<ZmqMessage>) -> ZmqResult<ZmqMessage> {
+    if frames.len() == 1 {
+        Ok(frames.pop().unwrap())
+    } else {
+        Err(ZmqError::Other(
+            "Multipart message received. Use recv_multipart instead",
+        ))
+    }
+}
+
+/// Text of the message. Message is given back in error if it's not valid UTF-8
+fn into_string(message: ZmqMessage) -> ZmqResult<String> {
+    match std::str::from_utf8(&message.data) {
+        Ok(text) => Ok(text.to_owned()),
+        Err(error) => Err(ZmqError::InvalidUtf8 { message, error }),
+    }
+}
+
 #[async_trait]
 pub trait Socket: Send {
     async fn send(&mut self, message: ZmqMessage) -> ZmqResult<()>;
-    async fn recv(&mut self) -> ZmqResult<ZmqMessage>;
+
+    /// Sends frames as a single message. All frames but the last one go out with `more` flag set
+    async fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()>;
+
+    /// Sends text as single frame message. Text is copied right into the frame
+    async fn send_str(&mut self, text: &str) -> ZmqResult<()> {
+        self.send(text.into()).await
+    }
+
+    /// Receives single frame message.
+    /// Multipart messages should be received with recv_multipart
+    async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        single_frame(self.recv_multipart().await?)
+    }
+
+    /// Receives single frame message as UTF-8 text.
+    /// Multipart messages are refused the same way recv does
+    async fn recv_string(&mut self) -> ZmqResult<String> {
+        into_string(self.recv().await?)
+    }
+
+    /// Appends payload of single frame message to the buffer and returns its size.
+    /// Capacity of the buffer is reused, so clearing it between calls avoids allocations
+    async fn recv_into(&mut self, buf: &mut BytesMut) -> ZmqResult<usize> {
+        let message = self.recv().await?;
+        buf.extend_from_slice(&message.data);
+        Ok(message.data.len())
+    }
+
+    /// Receives all frames of the next message
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>>;
+
+    /// Sends value serialized into single frame message
+    #[cfg(feature = "serde")]
+    async fn send_serde<F, T>(&mut self, format: F, value: &T) -> ZmqResult<()>
+    where
+        Self: Sized,
+        F: SerdeFormat,
+        T: serde::Serialize + Sync + ?Sized,
+    {
+        let data = format.serialize(value)?;
+        self.send(data.into()).await
+    }
+
+    /// Receives value serialized into single frame message.
+    /// Malformed message is consumed, next call gets the next message
+    #[cfg(feature = "serde")]
+    async fn recv_serde<F, T>(&mut self, format: F) -> ZmqResult<T>
+    where
+        Self: Sized,
+        F: SerdeFormat,
+        T: serde::de::DeserializeOwned,
+    {
+        format.deserialize(&self.recv().await?.data)
+    }
 }
 
 #[async_trait]
-pub trait BlockingRecv {
-    async fn recv(&mut self) -> ZmqResult<ZmqMessage>;
+pub trait BlockingRecv: Send {
+    /// Receives single frame message.
+    /// Multipart messages should be received with recv_multipart
+    async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        single_frame(self.recv_multipart().await?)
+    }
+
+    /// Receives single frame message as UTF-8 text.
+    /// Multipart messages are refused the same way recv does
+    async fn recv_string(&mut self) -> ZmqResult<String> {
+        into_string(self.recv().await?)
+    }
+
+    /// Appends payload of single frame message to the buffer and returns its size.
+    /// Capacity of the buffer is reused, so clearing it between calls avoids allocations
+    async fn recv_into(&mut self, buf: &mut BytesMut) -> ZmqResult<usize> {
+        let message = self.recv().await?;
+        buf.extend_from_slice(&message.data);
+        Ok(message.data.len())
+    }
+
+    /// Receives all frames of the next message
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>>;
+
+    /// Receives value serialized into single frame message.
+    /// Malformed message is consumed, next call gets the next message
+    #[cfg(feature = "serde")]
+    async fn recv_serde<F, T>(&mut self, format: F) -> ZmqResult<T>
+    where
+        F: SerdeFormat,
+        T: serde::de::DeserializeOwned,
+    {
+        format.deserialize(&self.recv().await?.data)
+    }
+
+    /// Receives topic frame followed by serialized value, as sent by `send_serde_with_topic`
+    #[cfg(feature = "serde")]
+    async fn recv_serde_with_topic<F, T>(&mut self, format: F) -> ZmqResult<(ZmqMessage, T)>
+    where
+        F: SerdeFormat,
+        T: serde::de::DeserializeOwned,
+    {
+        let mut frames = self.recv_multipart().await?;
+        if frames.len() != 2 {
+            return Err(ZmqError::Other("Expected topic and payload frames"));
+        }
+        let value = format.deserialize(&frames[1].data)?;
+        Ok((frames.swap_remove(0), value))
+    }
 }
 
 #[async_trait]
-pub trait BlockingSend {
-    async fn send(&mut self, message: ZmqMessage) -> ZmqResult<()>;
+pub trait BlockingSend: Send {
+    async fn send<M>(&mut self, message: M) -> ZmqResult<()>
+    where
+        M: Into<ZmqMessage> + Send;
+
+    /// Sends text as single frame message. Text is copied right into the frame
+    async fn send_str(&mut self, text: &str) -> ZmqResult<()> {
+        self.send(text).await
+    }
+
+    /// Sends frames as a single message. All frames but the last one go out with `more` flag set
+    async fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()>;
+
+    /// Sends value serialized into single frame message
+    #[cfg(feature = "serde")]
+    async fn send_serde<F, T>(&mut self, format: F, value: &T) -> ZmqResult<()>
+    where
+        F: SerdeFormat,
+        T: serde::Serialize + Sync + ?Sized,
+    {
+        let data = format.serialize(value)?;
+        self.send(data).await
+    }
 }
 
 pub trait NonBlockingSend {
-    fn send(&mut self, message: ZmqMessage) -> ZmqResult<()>;
+    fn send<M: Into<ZmqMessage>>(&mut self, message: M) -> ZmqResult<()>;
+
+    /// Sends text as single frame message. Text is copied right into the frame
+    fn send_str(&mut self, text: &str) -> ZmqResult<()> {
+        self.send(text)
+    }
+
+    /// Sends frames as a single message. All frames but the last one go out with `more` flag set
+    fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()>;
+
+    /// Sends value serialized into single frame message
+    #[cfg(feature = "serde")]
+    fn send_serde<F, T>(&mut self, format: F, value: &T) -> ZmqResult<()>
+    where
+        F: SerdeFormat,
+        T: serde::Serialize + ?Sized,
+    {
+        self.send(format.serialize(value)?)
+    }
+
+    /// Sends topic frame followed by serialized value, so that subscribers filter on topic alone
+    #[cfg(feature = "serde")]
+    fn send_serde_with_topic<F, T>(&mut self, topic: &str, format: F, value: &T) -> ZmqResult<()>
+    where
+        F: SerdeFormat,
+        T: serde::Serialize + ?Sized,
+    {
+        let payload = format.serialize(value)?;
+        self.send_multipart(vec![topic.into(), payload.into()])
+    }
 }
 
 pub trait NonBlockingRecv {
-    fn recv(&mut self) -> ZmqResult<ZmqMessage>;
+    /// Receives single frame message.
+    /// Multipart messages should be received with recv_multipart
+    fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        single_frame(self.recv_multipart()?)
+    }
+
+    /// Receives single frame message as UTF-8 text.
+    /// Multipart messages are refused the same way recv does
+    fn recv_string(&mut self) -> ZmqResult<String> {
+        into_string(self.recv()?)
+    }
+
+    /// Appends payload of single frame message to the buffer and returns its size.
+    /// Capacity of the buffer is reused, so clearing it between calls avoids allocations
+    fn recv_into(&mut self, buf: &mut BytesMut) -> ZmqResult<usize> {
+        let message = self.recv()?;
+        buf.extend_from_slice(&message.data);
+        Ok(message.data.len())
+    }
+
+    /// Receives all frames of the next message
+    fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>>;
 }
 
 #[async_trait]
 pub trait SocketFrontend {
-    fn new() -> Self;
+    fn new() -> Self
+    where
+        Self: Sized,
+    {
+        Self::with_options(SocketOptions::default())
+    }
+
+    /// Panics if options are not supported by this socket type, see `try_with_options`
+    fn with_options(options: SocketOptions) -> Self;
+
+    /// Same as `with_options` but fails if options are not supported by this socket type
+    fn try_with_options(options: SocketOptions) -> ZmqResult<Self>
+    where
+        Self: Sized,
+    {
+        options.validate(Self::socket_type())?;
+        Ok(Self::with_options(options))
+    }
+
+    fn socket_type() -> SocketType
+    where
+        Self: Sized;
+
+    /// Options socket was created with. Authenticator and handshake properties
+    /// set on the socket afterwards are included
+    fn options(&self) -> &SocketOptions;
+
+    /// Identity announced to peers, see `SocketOptions::identity`
+    fn identity(&self) -> Option<&PeerIdentity> {
+        self.options().identity.as_ref()
+    }
+
+    /// Closes socket once messages queued for peers are written out
+    /// or linger period is over, see `SocketOptions::linger`.
+    /// Returns after listeners, handshakes and connections of the socket are finished,
+    /// reporting how many queued messages of every peer were flushed and dropped
+    async fn close(self) -> CloseReport
+    where
+        Self: Sized;
 
-    /// Opens port described by endpoint and starts a coroutine to accept new connections on it
-    async fn bind(&mut self, endpoint: &str) -> ZmqResult<()>;
+    /// Opens port described by endpoint and starts a coroutine to accept new connections on it.
+    /// Returns endpoint that was actually bound with wildcard host and port resolved
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint>;
     async fn connect(&mut self, endpoint: &str) -> ZmqResult<()>;
+
+    /// Starts accepting connections on listener that was set up by the caller.
+    /// Returns endpoint listener is bound to
+    async fn bind_listener(&mut self, listener: tokio::net::TcpListener) -> ZmqResult<Endpoint>;
+
+    /// Stops accepting connections on endpoint returned by bind or passed to it.
+    /// Peers that already connected through it stay connected
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()>;
+
+    /// Closes connection made by connect to the endpoint and stops reconnecting to it.
+    /// Messages queued for the peer are dropped unless linger lets them out
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()>;
+
+    /// Performs ZMTP handshake over connection that was established by the caller
+    async fn connect_stream(&mut self, stream: tokio::net::TcpStream) -> ZmqResult<()>;
+
+    /// Endpoint resolved by the most recent successful bind
+    fn last_endpoint(&self) -> Option<&Endpoint>;
+
+    /// Endpoints socket currently listens on, one for every bind that wasn't unbound
+    fn bound_endpoints(&self) -> Vec<Endpoint>;
+
+    /// Connections made by connect that weren't disconnected, in the order they were made
+    fn connections(&self) -> Vec<ConnectionInfo>;
+
+    /// Local address of the listener opened by the most recent successful bind,
+    /// if it was bound to a network endpoint
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.last_endpoint().and_then(Endpoint::socket_addr)
+    }
+
+    /// Installs authenticator for connections made by subsequent bind/connect calls
+    fn set_authenticator(&mut self, authenticator: std::sync::Arc<dyn Authenticator>);
+
+    /// IP allow/deny lists for incoming connections. Can be updated while socket is bound
+    fn accept_filter(&self) -> &AcceptFilter {
+        &self.options().accept_filter
+    }
+
+    /// Kernel buffer sizes of TCP connections. Can be updated while socket is bound
+    /// or connected, existing connections keep their buffers
+    fn socket_buffers(&self) -> &SocketBuffers {
+        &self.options().socket_buffers
+    }
+
+    /// Limit of connected peers, see `SocketOptions::max_peers`. Can be updated while socket is bound
+    fn peer_limit(&self) -> &PeerLimit {
+        &self.options().peer_limit
+    }
+
+    /// Stream of connection lifecycle events. Events that happen while it is not polled
+    /// are queued up to a limit and dropped past it, so socket never waits for a monitor
+    fn monitor(&self) -> SocketMonitor {
+        self.options().monitor.subscribe()
+    }
+
+    /// Message and byte counters of the socket and of every connected peer.
+    /// UDP peers aren't counted
+    fn stats(&self) -> SocketStats {
+        self.options().stats.snapshot()
+    }
+
+    /// Adds metadata property sent to peers in READY command by subsequent bind/connect calls.
+    /// Peers read it from properties of messages received from us
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()>;
 }
 
 pub async fn proxy(_s1: Box<dyn Socket>, _s2: Box<dyn Socket>) -> ZmqResult<()> {
@@ -1,15 +1,105 @@
+use crate::codec::{Properties, ZmqMechanism};
+use crate::util::PeerIdentity;
 use bytes::{Bytes, BytesMut};
+use std::borrow::Cow;
 use std::convert::TryFrom;
+use std::net::SocketAddr;
 use std::string::FromUtf8Error;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct ZmqMessage {
     pub data: Bytes,
+    // Connection message was received from
+    pub(crate) origin: Option<Arc<Origin>>,
+    pub(crate) chunk: Option<FrameChunk>,
+}
+
+/// Peer that sent the message. Shared by all messages received over the same connection
+#[derive(Debug)]
+pub(crate) struct Origin {
+    pub(crate) peer_id: PeerIdentity,
+    pub(crate) peer_address: Option<SocketAddr>,
+    pub(crate) mechanism: ZmqMechanism,
+    pub(crate) properties: Properties,
+}
+
+/// Position of a received piece of frame larger than streaming threshold.
+/// Chunks of a frame arrive in order, one after another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameChunk {
+    /// Offset of chunk data within the frame
+    pub offset: u64,
+    /// Size of the whole frame
+    pub frame_len: u64,
+    /// Chunk completes the frame
+    pub last: bool,
+}
+
+impl ZmqMessage {
+    /// Properties peer sent in READY command. None for messages not received from a peer
+    pub fn properties(&self) -> Option<&Properties> {
+        self.origin.as_ref().map(|origin| &origin.properties)
+    }
+
+    /// Identity of the peer message was received from, same as socket uses to track the peer.
+    /// None for messages not received from a peer
+    pub fn peer_id(&self) -> Option<&PeerIdentity> {
+        self.origin.as_ref().map(|origin| &origin.peer_id)
+    }
+
+    /// Value of peer's handshake property, e.g. `Socket-Type` or `User-Id`
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties()?.get(name).map(Vec::as_slice)
+    }
+
+    /// Remote address of the connection. None for transports without one, e.g. inproc
+    pub fn peer_address(&self) -> Option<SocketAddr> {
+        self.origin.as_ref()?.peer_address
+    }
+
+    /// Metadata of the connection like `zmq_msg_gets` in libzmq provides.
+    /// `Peer-Address`, `Routing-Id` and `Mechanism` come from our side of the connection,
+    /// `User-Id` is only set by authenticator. Other names are looked up among properties
+    /// peer sent in handshake. None for messages not received from a peer
+    pub fn metadata(&self, name: &str) -> Option<Cow<'_, [u8]>> {
+        let origin = self.origin.as_ref()?;
+        match name {
+            "Peer-Address" => origin
+                .peer_address
+                .map(|address| Cow::Owned(address.ip().to_string().into_bytes())),
+            "Routing-Id" => Some(Cow::Borrowed(origin.peer_id.as_ref())),
+            "Mechanism" => Some(Cow::Owned(origin.mechanism.to_string().into_bytes())),
+            _ => origin
+                .properties
+                .get(name)
+                .map(|value| Cow::Borrowed(value.as_slice())),
+        }
+    }
+
+    /// Set when message carries just a part of a large frame, see `SocketOptions::streaming_threshold`
+    pub fn chunk(&self) -> Option<FrameChunk> {
+        self.chunk
+    }
+
+    /// Frames of a multipart message, e.g. `ZmqMessage::multipart(["topic", "payload"])`.
+    /// All frames but the last one go out with `more` flag set
+    pub fn multipart<I, T>(frames: I) -> Vec<ZmqMessage>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<ZmqMessage>,
+    {
+        frames.into_iter().map(Into::into).collect()
+    }
 }
 
 impl From<Bytes> for ZmqMessage {
     fn from(data: Bytes) -> Self {
-        Self { data }
+        Self {
+            data,
+            origin: None,
+            chunk: None,
+        }
     }
 }
 
@@ -37,6 +127,12 @@ impl From<&str> for ZmqMessage {
     }
 }
 
+impl From<ZmqMessage> for Bytes {
+    fn from(m: ZmqMessage) -> Self {
+        m.data
+    }
+}
+
 impl From<ZmqMessage> for Vec<u8> {
     fn from(m: ZmqMessage) -> Self {
         m.data.to_vec()
@@ -50,3 +146,24 @@ impl TryFrom<ZmqMessage> for String {
         String::from_utf8(m.into())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let data = Bytes::from_static(b"payload");
+        let message = ZmqMessage::from(data.clone());
+        // Bytes go in and out without copying
+        assert_eq!(data.as_ptr(), Bytes::from(message.clone()).as_ptr());
+        assert_eq!(b"payload".to_vec(), Vec::from(message.clone()));
+        assert_eq!("payload", String::try_from(message).unwrap());
+        assert!(String::try_from(ZmqMessage::from(vec![0xff, 0xfe])).is_err());
+
+        let frames = ZmqMessage::multipart(vec!["topic".to_string(), "payload".to_string()]);
+        assert_eq!(2, frames.len());
+        assert_eq!(&b"topic"[..], &frames[0].data[..]);
+        assert_eq!(&b"payload"[..], &frames[1].data[..]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Connection lifecycle events, see `SocketFrontend::monitor`
+use crate::endpoint::Endpoint;
+use crate::util::PeerIdentity;
+use futures::channel::mpsc;
+use futures::task::{Context, Poll};
+use futures::{Stream, StreamExt};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Events that happen to connections of a socket.
+/// Peer address is known for accepted connections only
+#[derive(Debug, Clone, PartialEq)]
+pub enum SocketEvent {
+    /// Connection to the endpoint was made by connect or reconnect
+    Connected { endpoint: Endpoint },
+    /// Attempt to connect to the endpoint failed, including its handshake.
+    /// `ConnectRetried` follows unless socket is closed
+    ConnectFailed { endpoint: Endpoint, reason: String },
+    /// Connecting to the endpoint again after the delay
+    ConnectRetried { endpoint: Endpoint, delay: Duration },
+    /// Listener bound to the endpoint accepted connection
+    Accepted {
+        endpoint: Endpoint,
+        peer_address: Option<SocketAddr>,
+    },
+    /// Listener bound to the endpoint failed to accept connection and keeps listening,
+    /// unless it is followed by `Closed`
+    AcceptFailed { endpoint: Endpoint, reason: String },
+    HandshakeSucceeded {
+        peer_id: PeerIdentity,
+        peer_address: Option<SocketAddr>,
+    },
+    HandshakeFailed {
+        peer_address: Option<SocketAddr>,
+        reason: String,
+    },
+    /// Peer completed handshake but was disconnected as socket reached its peer limit,
+    /// or another peer has their identity
+    PeerRejected {
+        peer_id: PeerIdentity,
+        peer_address: Option<SocketAddr>,
+    },
+    /// Queue of the peer is full so PUB or XPUB started dropping messages meant for them.
+    /// Reported again only after a message gets through, see `SocketOptions::queue_full_policy`
+    MessagesDropped { peer_id: PeerIdentity },
+    /// Connection of the peer broke on error, `Disconnected` follows
+    ConnectionFailed {
+        peer_id: PeerIdentity,
+        peer_address: Option<SocketAddr>,
+        reason: String,
+    },
+    /// Connection of the peer is closed, whether it was lost or closed by the socket
+    Disconnected {
+        peer_id: PeerIdentity,
+        peer_address: Option<SocketAddr>,
+    },
+    /// Listener bound to the endpoint stopped accepting connections
+    Closed { endpoint: Endpoint },
+}
+
+/// Events that weren't read yet are dropped once this many are queued
+const MONITOR_QUEUE_SIZE: usize = 128;
+
+/// Handle shared by socket's connections to report events to its monitors
+#[derive(Clone, Default)]
+pub(crate) struct Monitor {
+    monitors: Arc<Mutex<Vec<mpsc::Sender<SocketEvent>>>>,
+}
+
+impl Monitor {
+    pub(crate) fn subscribe(&self) -> SocketMonitor {
+        let (sender, receiver) = mpsc::channel(MONITOR_QUEUE_SIZE);
+        self.monitors.lock().unwrap().push(sender);
+        SocketMonitor { receiver }
+    }
+
+    /// Never waits for monitors. Full ones miss the event, dropped ones are forgotten
+    pub(crate) fn emit(&self, event: SocketEvent) {
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.retain_mut(|monitor| match monitor.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(e) => !e.is_disconnected(),
+        });
+    }
+}
+
+/// Stream of events returned by `SocketFrontend::monitor`.
+/// Ends once socket and all of its connections are gone
+pub struct SocketMonitor {
+    receiver: mpsc::Receiver<SocketEvent>,
+}
+
+impl Stream for SocketMonitor {
+    type Item = SocketEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SocketEvent>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dropped_monitor_is_forgotten() {
+        let monitor = Monitor::default();
+        let kept = monitor.subscribe();
+        drop(monitor.subscribe());
+        let event = SocketEvent::Closed {
+            endpoint: "tcp://127.0.0.1:5555".parse().unwrap(),
+        };
+        monitor.emit(event.clone());
+        assert_eq!(1, monitor.monitors.lock().unwrap().len());
+
+        // Full monitor misses events instead of blocking
+        for _ in 0..MONITOR_QUEUE_SIZE * 2 {
+            monitor.emit(event.clone());
+        }
+        drop(monitor);
+        let received = futures::executor::block_on(kept.collect::<Vec<_>>());
+        assert!(received.len() <= MONITOR_QUEUE_SIZE + 1);
+        assert_eq!(Some(&event), received.first());
+    }
+}
@@ -0,0 +1,891 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls;
+
+use crate::buffer_pool::BufferPool;
+use crate::close::CloseReports;
+use crate::codec::{self, Properties};
+use crate::context::{ContextHandle, SocketTasks, TrackedTask};
+use crate::error::ZmqError;
+use crate::filter::AcceptFilter;
+use crate::message::ZmqMessage;
+use crate::monitor::Monitor;
+use crate::security::{
+    Authenticator, PlainCallbackAuthenticator, Security, StaticPlainAuthenticator,
+};
+use crate::socks::SocksProxy;
+use crate::stats::Stats;
+use crate::transport;
+use crate::util::PeerIdentity;
+use crate::{SocketType, ZmqResult};
+use socket2::SockRef;
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HIGH_WATER_MARK: usize = 100;
+const DEFAULT_LISTEN_BACKLOG: i32 = 1024;
+const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When messages queued for a peer get written to the connection
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlushStrategy {
+    /// Every message is written on its own as soon as it is queued
+    #[default]
+    PerMessage,
+    /// Messages already waiting in the queue are encoded back to back and written together
+    /// once they add up to given number of bytes or the queue runs empty.
+    /// Saves syscalls on streams of small messages without delaying the last one
+    Batched(usize),
+}
+
+/// What PUB and XPUB do with a message for subscriber whose queue is full.
+/// Dropped messages are counted in `PeerStats::messages_dropped`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueFullPolicy {
+    /// Subscriber misses the new message
+    #[default]
+    DropNewest,
+    /// Oldest message waiting in subscriber's queue makes room for the new one.
+    /// Suits feeds where only recent data matters
+    DropOldest,
+    /// Send fails with `ZmqError::PeerQueueFull` and message isn't sent to anyone,
+    /// so it can be retried later (ZMQ_XPUB_NODROP)
+    Error,
+}
+
+/// TCP keepalive settings, see `SocketOptions::tcp_keepalive`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct TcpKeepalive {
+    pub(crate) enabled: Option<bool>,
+    pub(crate) idle: Option<Duration>,
+    pub(crate) interval: Option<Duration>,
+    pub(crate) count: Option<u32>,
+}
+
+/// Kernel buffer sizes of TCP connections (SO_SNDBUF and SO_RCVBUF).
+/// Handle is shared with socket's listeners and reconnect loops, so changing sizes
+/// affects connections established afterwards. Connections that are already
+/// established keep their buffers
+#[derive(Clone, Default)]
+pub struct SocketBuffers {
+    requested: Arc<[AtomicUsize; 2]>,
+    granted: Arc<[AtomicUsize; 2]>,
+}
+
+const SEND: usize = 0;
+const RECV: usize = 1;
+
+impl SocketBuffers {
+    fn with_size(&self, direction: usize, size: usize) -> Self {
+        let buffers = Self::default();
+        for d in &[SEND, RECV] {
+            let value = self.requested[*d].load(Ordering::Relaxed);
+            buffers.requested[*d].store(value, Ordering::Relaxed);
+        }
+        buffers.requested[direction].store(size, Ordering::Relaxed);
+        buffers
+    }
+
+    /// Sets SO_SNDBUF for new connections. Zero leaves OS default
+    pub fn set_send_buffer_size(&self, size: usize) {
+        self.requested[SEND].store(size, Ordering::Relaxed);
+    }
+
+    /// Sets SO_RCVBUF for new connections. Zero leaves OS default
+    pub fn set_recv_buffer_size(&self, size: usize) {
+        self.requested[RECV].store(size, Ordering::Relaxed);
+    }
+
+    /// SO_SNDBUF kernel granted to the most recent connection it was set on.
+    /// Kernel may round requested size, e.g. Linux doubles it for bookkeeping
+    pub fn send_buffer_size(&self) -> Option<usize> {
+        Some(self.granted[SEND].load(Ordering::Relaxed)).filter(|size| *size > 0)
+    }
+
+    /// SO_RCVBUF kernel granted to the most recent connection it was set on
+    pub fn recv_buffer_size(&self) -> Option<usize> {
+        Some(self.granted[RECV].load(Ordering::Relaxed)).filter(|size| *size > 0)
+    }
+
+    /// Applies requested sizes to the connection and records what kernel granted
+    pub(crate) fn apply(&self, socket: &SockRef) -> ZmqResult<()> {
+        let size = self.requested[SEND].load(Ordering::Relaxed);
+        if size > 0 {
+            socket.set_send_buffer_size(size)?;
+            let granted = socket.send_buffer_size()?;
+            self.granted[SEND].store(granted, Ordering::Relaxed);
+        }
+        let size = self.requested[RECV].load(Ordering::Relaxed);
+        if size > 0 {
+            socket.set_recv_buffer_size(size)?;
+            let granted = socket.recv_buffer_size()?;
+            self.granted[RECV].store(granted, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Number of peers socket lets connect, see `SocketOptions::max_peers`.
+/// Limit can be changed while socket is bound. Peers above lowered limit stay
+/// connected, new ones are refused until enough of them are gone
+#[derive(Clone)]
+pub struct PeerLimit {
+    max: Arc<AtomicUsize>,
+    connected: Arc<AtomicUsize>,
+}
+
+impl Default for PeerLimit {
+    fn default() -> Self {
+        Self {
+            max: Arc::new(AtomicUsize::new(usize::MAX)),
+            connected: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl PeerLimit {
+    /// None lifts the limit
+    pub fn set_max_peers(&self, max: Option<usize>) {
+        self.max.store(max.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        Some(self.max.load(Ordering::Relaxed)).filter(|max| *max != usize::MAX)
+    }
+
+    /// Peers that were let in and are still connected
+    pub fn connected(&self) -> usize {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Takes place of the peer, None once limit is reached
+    pub(crate) fn acquire(&self) -> Option<PeerSlot> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.connected
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connected| {
+                Some(connected + 1).filter(|_| connected < max)
+            })
+            .ok()?;
+        Some(PeerSlot {
+            connected: self.connected.clone(),
+        })
+    }
+
+    /// Same limit, but peers are counted for a single socket
+    fn for_socket(&self) -> Self {
+        let limit = Self::default();
+        limit.set_max_peers(self.max_peers());
+        limit
+    }
+}
+
+/// Place of connected peer, freed once connection is gone
+pub(crate) struct PeerSlot {
+    connected: Arc<AtomicUsize>,
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        self.connected.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Number of messages queues of a connection hold, see `SocketOptions::send_hwm`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct HighWaterMarks {
+    pub(crate) send: usize,
+    pub(crate) recv: usize,
+}
+
+/// Settings applied to the socket and every connection it creates.
+/// Should be configured before bind/connect
+#[derive(Clone, Default)]
+pub struct SocketOptions {
+    pub(crate) ipv6: bool,
+    pub(crate) ipv6_only: bool,
+    pub(crate) socks_proxy: Option<SocksProxy>,
+    pub(crate) tcp_keepalive: TcpKeepalive,
+    tcp_nodelay: Option<bool>,
+    pub(crate) socket_buffers: SocketBuffers,
+    listen_backlog: Option<i32>,
+    pub(crate) tos: Option<u8>,
+    pub(crate) security: Security,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) zap_domain: String,
+    pub(crate) accept_filter: AcceptFilter,
+    pub(crate) monitor: Monitor,
+    pub(crate) close_reports: CloseReports,
+    pub(crate) stats: Stats,
+    pub(crate) peer_limit: PeerLimit,
+    pub(crate) tasks: SocketTasks,
+    /// Set for sockets created by `Context`
+    pub(crate) context: Option<ContextHandle>,
+    pub(crate) heartbeat_interval: Option<Duration>,
+    pub(crate) heartbeat_timeout: Option<Duration>,
+    pub(crate) heartbeat_ttl: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    reconnect_interval: Option<Duration>,
+    reconnect_interval_max: Option<Duration>,
+    pub(crate) immediate: bool,
+    pub(crate) conflate: bool,
+    linger: Option<Duration>,
+    pub(crate) recv_timeout: Option<Duration>,
+    pub(crate) send_timeout: Option<Duration>,
+    send_hwm: Option<usize>,
+    recv_hwm: Option<usize>,
+    pub(crate) zmtp2_fallback: bool,
+    pub(crate) identity: Option<PeerIdentity>,
+    pub(crate) identity_handover: bool,
+    pub(crate) router_mandatory: bool,
+    pub(crate) probe_router: bool,
+    pub(crate) req_relaxed: bool,
+    pub(crate) req_correlate: bool,
+    pub(crate) xpub_verbose: bool,
+    pub(crate) xpub_verboser: bool,
+    pub(crate) queue_full_policy: QueueFullPolicy,
+    pub(crate) welcome_message: Option<ZmqMessage>,
+    pub(crate) handshake_properties: Properties,
+    max_message_size: Option<usize>,
+    pub(crate) flush_strategy: FlushStrategy,
+    pub(crate) buffer_pool: Option<BufferPool>,
+    pub(crate) streaming_threshold: Option<usize>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls_server_config: Option<Arc<rustls::ServerConfig>>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls_client_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl SocketOptions {
+    /// Makes wildcard binds listen on IPv6 (`[::]`) instead of IPv4 (`0.0.0.0`)
+    pub fn ipv6(mut self, enabled: bool) -> Self {
+        self.ipv6 = enabled;
+        self
+    }
+
+    /// Restricts IPv6 listeners to IPv6 connections only (IPV6_V6ONLY).
+    /// By default IPv6 listeners are dual-stack and accept IPv4 connections as well
+    pub fn ipv6_only(mut self, enabled: bool) -> Self {
+        self.ipv6_only = enabled;
+        self
+    }
+
+    /// Disables Nagle's algorithm on TCP connections (TCP_NODELAY), so small messages
+    /// go out without waiting for the previous ones to be acknowledged. On by default
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_nodelay = Some(enabled);
+        self
+    }
+
+    /// Kernel send buffer size of TCP connections (SO_SNDBUF). Can be changed on live
+    /// socket through `SocketFrontend::socket_buffers`, which also reports granted size
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.socket_buffers = self.socket_buffers.with_size(SEND, size);
+        self
+    }
+
+    /// Kernel receive buffer size of TCP connections (SO_RCVBUF), see `send_buffer_size`
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.socket_buffers = self.socket_buffers.with_size(RECV, size);
+        self
+    }
+
+    /// Number of peers socket accepts or takes through `connect_stream` before it starts
+    /// refusing them. Refused peers complete the handshake, get ERROR command and are
+    /// disconnected right away. Can be changed on live socket through
+    /// `SocketFrontend::peer_limit`. Unlimited by default
+    pub fn max_peers(mut self, max: usize) -> Self {
+        self.peer_limit = self.peer_limit.for_socket();
+        self.peer_limit.set_max_peers(Some(max));
+        self
+    }
+
+    /// Number of TCP connections kernel keeps waiting to be accepted (ZMQ_BACKLOG).
+    /// Connections arriving once it is full are dropped, so sockets many peers
+    /// reconnect to at once may need larger one. Defaults to 1024,
+    /// kernel may cap it, e.g. with net.core.somaxconn on Linux
+    pub fn listen_backlog(mut self, backlog: i32) -> Self {
+        self.listen_backlog = Some(backlog);
+        self
+    }
+
+    pub(crate) fn effective_listen_backlog(&self) -> i32 {
+        self.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG)
+    }
+
+    /// Type of service byte of packets sent over TCP connections (ZMQ_TOS), e.g.
+    /// DSCP class shifted left by two bits. Set with IP_TOS for IPv4 and IPV6_TCLASS
+    /// for IPv6. Sockets can't be created with it on platforms that support neither
+    pub fn tos(mut self, tos: u8) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Turns TCP keepalive on or off for TCP connections, including `tls://` and `ws://` ones
+    /// (ZMQ_TCP_KEEPALIVE). By default OS setting is kept
+    pub fn tcp_keepalive(mut self, enabled: bool) -> Self {
+        self.tcp_keepalive.enabled = Some(enabled);
+        self
+    }
+
+    /// Idle time before the first keepalive probe (ZMQ_TCP_KEEPALIVE_IDLE).
+    /// Applies only with keepalive turned on
+    pub fn tcp_keepalive_idle(mut self, idle: Duration) -> Self {
+        self.tcp_keepalive.idle = Some(idle);
+        self
+    }
+
+    /// Time between keepalive probes (ZMQ_TCP_KEEPALIVE_INTVL).
+    /// Applies only with keepalive turned on
+    pub fn tcp_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.tcp_keepalive.interval = Some(interval);
+        self
+    }
+
+    /// Number of unanswered probes before connection is dropped (ZMQ_TCP_KEEPALIVE_CNT).
+    /// Applies only with keepalive turned on
+    pub fn tcp_keepalive_count(mut self, count: u32) -> Self {
+        self.tcp_keepalive.count = Some(count);
+        self
+    }
+
+    /// Sends PING to every peer once per interval (ZMQ_HEARTBEAT_IVL).
+    /// Peers that stay silent longer than heartbeat timeout get disconnected
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// How long to wait for any traffic from the peer before dropping it (ZMQ_HEARTBEAT_TIMEOUT).
+    /// Defaults to heartbeat interval
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// Asks peers to drop connection if they don't hear from us within TTL (ZMQ_HEARTBEAT_TTL).
+    /// Sent with PINGs with decisecond precision
+    pub fn heartbeat_ttl(mut self, ttl: Duration) -> Self {
+        self.heartbeat_ttl = Some(ttl);
+        self
+    }
+
+    /// Drops connections that didn't complete ZMTP handshake in time (ZMQ_HANDSHAKE_IVL).
+    /// Defaults to 30 seconds. Zero duration disables the timeout
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait before connecting again once connection made by `connect`
+    /// is lost (ZMQ_RECONNECT_IVL). Defaults to 100 milliseconds. Zero duration disables reconnecting
+    pub fn reconnect_interval(mut self, interval: Duration) -> Self {
+        self.reconnect_interval = Some(interval);
+        self
+    }
+
+    /// Upper bound for the wait between failed reconnect attempts (ZMQ_RECONNECT_IVL_MAX).
+    /// Wait doubles after every failed attempt up to this bound. By default it stays
+    /// at reconnect interval
+    pub fn reconnect_interval_max(mut self, interval: Duration) -> Self {
+        self.reconnect_interval_max = Some(interval);
+        self
+    }
+
+    /// Queues messages only to peers with established connection (ZMQ_IMMEDIATE).
+    /// By default peer connected with `connect` keeps its queue while connection is
+    /// reestablished, so messages sent meanwhile go out once it is back. SUB, XSUB and DISH
+    /// peers are replaced instead, as subscriptions are sent anew on reconnect. With this option
+    /// peer is dropped as soon as connection is lost: round robin senders skip it,
+    /// PUB doesn't count it as subscriber and sends fail once no connected peers are left
+    pub fn immediate(mut self, enabled: bool) -> Self {
+        self.immediate = enabled;
+        self
+    }
+
+    /// Keeps only the newest message in receive queue of SUB, PULL and DEALER sockets
+    /// and in queues of PUB and PUSH peers (ZMQ_CONFLATE). Older messages that weren't
+    /// delivered yet are dropped. Multipart messages can't be conflated, so sending them
+    /// fails and received ones are refused by recv
+    pub fn conflate(mut self, enabled: bool) -> Self {
+        self.conflate = enabled;
+        self
+    }
+
+    /// How long closing socket keeps writing out messages that are still queued (ZMQ_LINGER).
+    /// Applies to `close` as well as to dropping the socket, in which case messages
+    /// are written out in background. Defaults to zero, so queued messages are discarded
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// How long receiving waits for a message before failing with `ZmqError::Timeout`
+    /// (ZMQ_RCVTIMEO). Zero only takes already queued message. By default waits forever.
+    /// Socket stays usable after timeout, REQ keeps waiting for the reply to current request
+    pub fn recv_timeout(mut self, timeout: Duration) -> Self {
+        self.recv_timeout = Some(timeout);
+        self
+    }
+
+    /// How long sending waits for room in peer's queue before failing with `ZmqError::Timeout`
+    /// (ZMQ_SNDTIMEO). Zero only sends if there is room right away. By default waits forever.
+    /// Message that timed out is not queued. Sockets that never wait to send ignore it
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets both send and receive high water marks
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.send_hwm = Some(capacity);
+        self.recv_hwm = Some(capacity);
+        self
+    }
+
+    /// Number of outgoing messages queued for each peer (ZMQ_SNDHWM). Defaults to 100.
+    /// Once peer's queue is full PUB, XPUB and RADIO drop messages for that peer,
+    /// ROUTER drops messages addressed to it and PUSH skips it, failing with
+    /// `ReturnToSender` if every peer is full. DEALER, PAIR, CLIENT and SERVER
+    /// wait until there is room. REQ and REP always have at most one message in flight
+    pub fn send_hwm(mut self, hwm: usize) -> Self {
+        self.send_hwm = Some(hwm);
+        self
+    }
+
+    /// Number of received messages queued for each peer and by the socket itself (ZMQ_RCVHWM).
+    /// Defaults to 100. Once queues are full socket stops reading from the connection,
+    /// so the peer eventually hits its own send high water mark
+    pub fn recv_hwm(mut self, hwm: usize) -> Self {
+        self.recv_hwm = Some(hwm);
+        self
+    }
+
+    /// Identity announced to peers in READY metadata (ZMQ_ROUTING_ID).
+    /// ROUTER peers address us by it and it stays the same across reconnects.
+    /// Identities starting with zero byte are reserved for generated ones.
+    /// Peer connecting with identity that is already in use is rejected
+    /// unless `identity_handover` is enabled
+    pub fn identity(mut self, identity: PeerIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Lets new connection take over identity of already connected peer,
+    /// which gets disconnected (ZMQ_ROUTER_HANDOVER). By default duplicate is rejected.
+    /// Messages still queued for the old connection are dropped, linger doesn't apply to them
+    pub fn identity_handover(mut self, enabled: bool) -> Self {
+        self.identity_handover = enabled;
+        self
+    }
+
+    /// Makes ROUTER send fail instead of dropping the message (ZMQ_ROUTER_MANDATORY).
+    /// Fails with `ZmqError::PeerUnreachable` if peer is unknown and with
+    /// `ZmqError::PeerQueueFull` if its queue is full, in which case send can be retried
+    pub fn router_mandatory(mut self, enabled: bool) -> Self {
+        self.router_mandatory = enabled;
+        self
+    }
+
+    /// Sends empty message to every peer as soon as connection is established,
+    /// including reconnects (ZMQ_PROBE_ROUTER). ROUTER on the other side receives it
+    /// as empty message from our identity, so it learns about us before any other traffic.
+    /// Supported by ROUTER, DEALER and REQ
+    pub fn probe_router(mut self, enabled: bool) -> Self {
+        self.probe_router = enabled;
+        self
+    }
+
+    /// Lets REQ send next request while previous one still waits for reply (ZMQ_REQ_RELAXED).
+    /// Previous request is abandoned and the next one goes to the next peer in round robin
+    /// order, so client can retry when server doesn't respond. By default send fails
+    /// until reply is received
+    pub fn req_relaxed(mut self, enabled: bool) -> Self {
+        self.req_relaxed = enabled;
+        self
+    }
+
+    /// Prefixes every REQ request with request id frame and drops replies
+    /// that don't carry id of the request in progress (ZMQ_REQ_CORRELATE).
+    /// Useful together with `req_relaxed`, so that late reply to abandoned request
+    /// isn't mistaken for reply to the next one. Id frame is never seen by the application
+    pub fn req_correlate(mut self, enabled: bool) -> Self {
+        self.req_correlate = enabled;
+        self
+    }
+
+    /// Passes every subscription received by XPUB to the application (ZMQ_XPUB_VERBOSE).
+    /// By default subscriptions are counted across peers and only the first subscription
+    /// to a topic and the cancel of the last one are passed up
+    pub fn xpub_verbose(mut self, enabled: bool) -> Self {
+        self.xpub_verbose = enabled;
+        self
+    }
+
+    /// Same as `xpub_verbose` but passes every cancel as well (ZMQ_XPUB_VERBOSER)
+    pub fn xpub_verboser(mut self, enabled: bool) -> Self {
+        self.xpub_verboser = enabled;
+        self
+    }
+
+    /// Makes PUB and XPUB send fail with `ZmqError::PeerQueueFull` when queue of
+    /// a matching subscriber is full (ZMQ_XPUB_NODROP). Message isn't sent to anyone then,
+    /// so it can be retried later. By default such subscribers just miss the message.
+    /// Same as `queue_full_policy(QueueFullPolicy::Error)`. Disabling it only
+    /// restores the default if the policy is still `Error`
+    pub fn xpub_nodrop(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.queue_full_policy = QueueFullPolicy::Error;
+        } else if self.queue_full_policy == QueueFullPolicy::Error {
+            self.queue_full_policy = QueueFullPolicy::DropNewest;
+        }
+        self
+    }
+
+    /// What PUB and XPUB do when queue of a matching subscriber is full.
+    /// Defaults to dropping the new message
+    pub fn queue_full_policy(mut self, policy: QueueFullPolicy) -> Self {
+        self.queue_full_policy = policy;
+        self
+    }
+
+    /// Message PUB and XPUB send to every peer as soon as connection is established,
+    /// including reconnects, ahead of any queued messages (ZMQ_XPUB_WELCOME_MSG).
+    /// It's sent regardless of peer's subscriptions
+    pub fn welcome_message(mut self, message: ZmqMessage) -> Self {
+        self.welcome_message = Some(message);
+        self
+    }
+
+    /// Drops connections of peers sending messages larger than limit (ZMQ_MAXMSGSIZE).
+    /// Multipart messages are limited by total size of their frames. Defaults to 1 GiB
+    pub fn max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// How outgoing messages are written to connections. Defaults to flushing every message
+    pub fn flush_strategy(mut self, strategy: FlushStrategy) -> Self {
+        self.flush_strategy = strategy;
+        self
+    }
+
+    /// Takes connection buffers from the pool instead of allocating them for every connection.
+    /// Worth it for sockets with many peers. Same pool can be shared by several sockets
+    pub fn buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Delivers received single frame messages larger than threshold in chunks
+    /// as their bytes arrive, instead of buffering whole frame. See `ZmqMessage::chunk`.
+    /// Chunks of different peers may interleave, they are told apart by message properties
+    pub fn streaming_threshold(mut self, threshold: usize) -> Self {
+        self.streaming_threshold = Some(threshold);
+        self
+    }
+
+    pub(crate) fn effective_max_message_size(&self) -> usize {
+        self.max_message_size
+            .unwrap_or(codec::DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Talks ZMTP 2.0 to peers that announce it, e.g. services built on libzmq 3.2.
+    /// Greeting is then sent in parts to detect peer's version, which some peers may not expect.
+    /// Such connections have no security mechanisms and no heartbeats
+    pub fn zmtp2_fallback(mut self, enabled: bool) -> Self {
+        self.zmtp2_fallback = enabled;
+        self
+    }
+
+    /// Adds application metadata property peers receive in READY command.
+    /// Socket-Type and Identity are managed by the socket itself
+    pub(crate) fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        codec::validate_property(name, value)?;
+        if name.eq_ignore_ascii_case("Socket-Type") || name.eq_ignore_ascii_case("Identity") {
+            return Err(ZmqError::InvalidProperty(name.to_string()));
+        }
+        self.handshake_properties
+            .insert(name.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    pub(crate) fn effective_handshake_timeout(&self) -> Option<Duration> {
+        match self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT) {
+            timeout if timeout == Duration::from_secs(0) => None,
+            timeout => Some(timeout),
+        }
+    }
+
+    pub(crate) fn effective_reconnect_interval(&self) -> Option<Duration> {
+        match self
+            .reconnect_interval
+            .unwrap_or(DEFAULT_RECONNECT_INTERVAL)
+        {
+            interval if interval == Duration::from_secs(0) => None,
+            interval => Some(interval),
+        }
+    }
+
+    /// Wait before the next reconnect attempt after one that failed
+    pub(crate) fn next_reconnect_interval(&self, interval: Duration) -> Duration {
+        match self.reconnect_interval_max {
+            Some(max) if max > interval => (interval * 2).min(max),
+            _ => interval,
+        }
+    }
+
+    /// Whether socket keeps only the newest received message
+    pub(crate) fn conflates_recv(&self, socket_type: SocketType) -> bool {
+        self.conflate
+            && matches!(
+                socket_type,
+                SocketType::SUB | SocketType::PULL | SocketType::DEALER
+            )
+    }
+
+    /// Whether queues of peers keep only the newest message sent
+    pub(crate) fn conflates_send(&self, socket_type: SocketType) -> bool {
+        self.conflate && matches!(socket_type, SocketType::PUB | SocketType::PUSH)
+    }
+
+    pub(crate) fn effective_tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay.unwrap_or(true)
+    }
+
+    /// Handles that report on a single socket are replaced, so sockets created
+    /// with clones of the same options don't share them.
+    /// Panics if options are not supported by the socket type, see `validate`
+    pub(crate) fn for_socket(mut self, socket_type: SocketType) -> Self {
+        if let Err(e) = self.validate(socket_type) {
+            panic!("Invalid options for {} socket: {}", socket_type, e);
+        }
+        self.monitor = Monitor::default();
+        self.close_reports = CloseReports::default();
+        self.stats = Stats::default();
+        self.peer_limit = self.peer_limit.for_socket();
+        self.tasks = SocketTasks::default();
+        self
+    }
+
+    /// Bind and connect fail once context of the socket is terminated
+    pub(crate) fn check_terminated(&self) -> ZmqResult<()> {
+        match &self.context {
+            Some(context) => context.check_terminated(),
+            None => Ok(()),
+        }
+    }
+
+    /// Handshake that was still going on when socket was closed or its context terminated
+    /// must not register the peer
+    pub(crate) fn check_stopped(&self) -> ZmqResult<()> {
+        self.check_terminated()?;
+        if self.tasks.is_stopped() {
+            return Err(ZmqError::SocketClosed);
+        }
+        Ok(())
+    }
+
+    /// Keeps close of the socket and term of its context waiting while returned guard is alive
+    pub(crate) fn track_task(&self) -> TrackedTask {
+        TrackedTask::new(&self.tasks, self.context.as_ref())
+    }
+
+    /// Completes once socket is dropped or its context is terminated
+    pub(crate) fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let socket = self.tasks.stopped();
+        let context = self.context.as_ref().map(ContextHandle::terminated);
+        async move {
+            match context {
+                Some(context) => {
+                    futures::pin_mut!(socket, context);
+                    futures::future::select(socket, context).await;
+                }
+                None => socket.await,
+            }
+        }
+    }
+
+    pub(crate) fn effective_linger(&self) -> Option<Duration> {
+        self.linger
+            .filter(|linger| *linger > Duration::from_secs(0))
+    }
+
+    pub(crate) fn high_water_marks(&self) -> HighWaterMarks {
+        HighWaterMarks {
+            send: self.send_hwm.unwrap_or(DEFAULT_HIGH_WATER_MARK),
+            recv: self.recv_hwm.unwrap_or(DEFAULT_HIGH_WATER_MARK),
+        }
+    }
+
+    /// Checks that options make sense for the socket type.
+    /// Used by `SocketFrontend::try_with_options` and `SocketFrontend::with_options`
+    pub fn validate(&self, socket_type: SocketType) -> ZmqResult<()> {
+        let unsupported = |option| ZmqError::UnsupportedOption {
+            option,
+            socket_type,
+        };
+        if self.send_hwm == Some(0) {
+            return Err(ZmqError::InvalidOption("send_hwm"));
+        }
+        if self.recv_hwm == Some(0) {
+            return Err(ZmqError::InvalidOption("recv_hwm"));
+        }
+        if self.listen_backlog.is_some_and(|backlog| backlog < 0) {
+            return Err(ZmqError::InvalidOption("listen_backlog"));
+        }
+        if self.tos.is_some() && !transport::TOS_SUPPORTED {
+            return Err(ZmqError::Socket(
+                "tos option is not supported on this platform",
+            ));
+        }
+        if self
+            .identity
+            .as_ref()
+            .is_some_and(|identity| identity.as_ref().first() == Some(&0))
+        {
+            return Err(ZmqError::InvalidOption("identity"));
+        }
+        if self.identity_handover && socket_type != SocketType::ROUTER {
+            return Err(unsupported("identity_handover"));
+        }
+        if self.router_mandatory && socket_type != SocketType::ROUTER {
+            return Err(unsupported("router_mandatory"));
+        }
+        if self.probe_router
+            && !matches!(
+                socket_type,
+                SocketType::ROUTER | SocketType::DEALER | SocketType::REQ
+            )
+        {
+            return Err(unsupported("probe_router"));
+        }
+        if self.req_relaxed && socket_type != SocketType::REQ {
+            return Err(unsupported("req_relaxed"));
+        }
+        if self.req_correlate && socket_type != SocketType::REQ {
+            return Err(unsupported("req_correlate"));
+        }
+        if self.xpub_verbose && socket_type != SocketType::XPUB {
+            return Err(unsupported("xpub_verbose"));
+        }
+        if self.xpub_verboser && socket_type != SocketType::XPUB {
+            return Err(unsupported("xpub_verboser"));
+        }
+        let publisher = matches!(socket_type, SocketType::PUB | SocketType::XPUB);
+        if self.queue_full_policy != QueueFullPolicy::DropNewest && !publisher {
+            return Err(unsupported("queue_full_policy"));
+        }
+        if self.welcome_message.is_some() && !publisher {
+            return Err(unsupported("welcome_message"));
+        }
+        if self.conflate && !self.conflates_recv(socket_type) && !self.conflates_send(socket_type) {
+            return Err(unsupported("conflate"));
+        }
+        // STREAM talks raw TCP so there is no ZMTP handshake to configure
+        if socket_type == SocketType::STREAM {
+            if self.security.mechanism() != codec::ZmqMechanism::NULL {
+                return Err(unsupported("security"));
+            }
+            if self.identity.is_some() {
+                return Err(unsupported("identity"));
+            }
+            if self.zmtp2_fallback {
+                return Err(unsupported("zmtp2_fallback"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes outgoing tcp connections through SOCKS5 proxy
+    pub fn socks_proxy(mut self, proxy: SocksProxy) -> Self {
+        self.socks_proxy = Some(proxy);
+        self
+    }
+
+    /// Authenticates to PLAIN server with username and password.
+    /// Credentials are sent in clear text so consider tls:// transport on untrusted networks
+    pub fn plain_credentials(mut self, username: &str, password: &str) -> Self {
+        self.security = Security::PlainClient {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        self
+    }
+
+    /// Acts as PLAIN server accepting only users from the map of usernames to passwords
+    pub fn plain_server(mut self, users: HashMap<String, String>) -> Self {
+        self.security = Security::PlainServer;
+        self.authenticator = Some(Arc::new(StaticPlainAuthenticator(users)));
+        self
+    }
+
+    /// Acts as PLAIN server checking every username and password with the callback
+    pub fn plain_server_with<F, Fut>(mut self, verify: F) -> Self
+    where
+        F: Fn(String, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.security = Security::PlainServer;
+        self.authenticator = Some(Arc::new(PlainCallbackAuthenticator(verify)));
+        self
+    }
+
+    /// Authenticates every peer during handshake, including NULL peers.
+    /// Replaces authenticator installed by `plain_server` or `plain_server_with`.
+    /// Without authenticator PLAIN and CURVE servers accept any client same as libzmq does
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Domain passed to authenticator, lets one authenticator serve several sockets
+    pub fn zap_domain(mut self, domain: &str) -> Self {
+        self.zap_domain = domain.to_string();
+        self
+    }
+
+    /// Acts as CURVE server with long term key pair.
+    /// Clients have to know the public key upfront to connect
+    #[cfg(feature = "curve")]
+    pub fn curve_server(mut self, public_key: [u8; 32], secret_key: [u8; 32]) -> Self {
+        self.security = Security::CurveServer {
+            public_key,
+            secret_key,
+        };
+        self
+    }
+
+    /// Connects as CURVE client with its own long term key pair to server with given public key
+    #[cfg(feature = "curve")]
+    pub fn curve_client(
+        mut self,
+        public_key: [u8; 32],
+        secret_key: [u8; 32],
+        server_key: [u8; 32],
+    ) -> Self {
+        self.security = Security::CurveClient {
+            public_key,
+            secret_key,
+            server_key,
+        };
+        self
+    }
+
+    /// Certificate and key used to accept connections on `tls://` endpoints.
+    /// Client certificate verification for mTLS is configured here as well
+    #[cfg(feature = "tls")]
+    pub fn tls_server_config(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls_server_config = Some(config);
+        self
+    }
+
+    /// Root CAs (and optionally client certificate) used to connect to `tls://` endpoints
+    #[cfg(feature = "tls")]
+    pub fn tls_client_config(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls_client_config = Some(config);
+        self
+    }
+}
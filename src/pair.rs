@@ -0,0 +1,246 @@
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
+use crate::util::*;
+use crate::{util, MultiPeer, Socket, SocketBackend, SocketFrontend, SocketType, ZmqResult};
+use async_trait::async_trait;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use tokio::net::{TcpListener, TcpStream};
+
+pub(crate) struct PairPeer {
+    pub(crate) identity: PeerIdentity,
+    pub(crate) send_queue: mpsc::Sender<Message>,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+pub(crate) struct PairSocketBackend {
+    pub(crate) peer: Mutex<Option<PairPeer>>,
+    pub(crate) queue_sender: mpsc::Sender<Message>,
+}
+
+#[async_trait]
+impl MultiPeer for PairSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        let mut peer = self.peer.lock().unwrap();
+        if peer.is_none() {
+            peer.replace(PairPeer {
+                identity: peer_id.clone(),
+                send_queue: out_queue,
+                _io_close_handle: stop_handle,
+            });
+        }
+        // Otherwise stop handle is dropped here which closes connection with new peer right away.
+        // Only one peer is allowed for PAIR socket
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        let mut peer = self.peer.lock().unwrap();
+        if peer.as_ref().map(|p| &p.identity) == Some(peer_id) {
+            peer.take();
+        }
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peer.lock().unwrap().as_ref().map(|p| &p.identity) == Some(peer_id)
+    }
+}
+
+#[async_trait]
+impl SocketBackend for PairSocketBackend {
+    async fn message_received(&self, _peer_id: &PeerIdentity, message: Message) {
+        // Receiving side might be already dropped. Nothing to do with message in such case
+        let _ = self.queue_sender.clone().send(message).await;
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::PAIR
+    }
+
+    fn shutdown(&self) {
+        self.peer.lock().unwrap().take();
+    }
+}
+
+/// Socket for exclusive communication with a single peer.
+/// Connections from other peers are refused while one is active
+pub struct PairSocket {
+    backend: Arc<PairSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    queue: mpsc::Receiver<Message>,
+}
+
+impl Drop for PairSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+#[async_trait]
+impl Socket for PairSocket {
+    async fn send(&mut self, message: ZmqMessage) -> ZmqResult<()> {
+        if !self.connected() {
+            return Err(ZmqError::ReturnToSender {
+                reason: "Not connected to peer. Unable to send message",
+                message,
+            });
+        }
+        self.send_to_peer(Message::Message(message)).await
+    }
+
+    async fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        if !self.connected() {
+            return Err(ZmqError::Socket("Not connected to peer"));
+        }
+        self.send_to_peer(frames.into()).await
+    }
+
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        match util::with_timeout(self.options.recv_timeout, self.queue.next()).await? {
+            Some(message) => message
+                .into_frames()
+                .ok_or(ZmqError::Other("Wrong message type received")),
+            None => Err(ZmqError::NoMessage),
+        }
+    }
+}
+
+impl PairSocket {
+    fn connected(&self) -> bool {
+        self.backend.peer.lock().unwrap().is_some()
+    }
+
+    /// Waits for room in peer's queue, see `util::poll_send`
+    async fn send_to_peer(&mut self, message: Message) -> ZmqResult<()> {
+        let mut message = Some(message);
+        let send =
+            futures::future::poll_fn(|cx| match self.backend.peer.lock().unwrap().as_mut() {
+                Some(peer) => util::poll_send(
+                    &mut peer.send_queue,
+                    cx,
+                    &mut message,
+                    Some((&self.options.stats, &peer.identity)),
+                ),
+                None => Poll::Ready(Err(ZmqError::ConnectionLost)),
+            });
+        util::with_timeout(self.options.send_timeout, send).await?
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for PairSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (queue_sender, queue) = mpsc::channel(recv_hwm);
+        Self {
+            backend: Arc::new(PairSocketBackend {
+                peer: Mutex::new(None),
+                queue_sender,
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::PAIR
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
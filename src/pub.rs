@@ -1,5 +1,13 @@
+use crate::close::CloseReport;
 use crate::codec::*;
+use crate::conflate::{self, Ring};
+use crate::endpoint::Endpoint;
+use crate::error::ZmqError;
 use crate::message::*;
+use crate::monitor::SocketEvent;
+use crate::options::{HighWaterMarks, QueueFullPolicy, SocketOptions};
+use crate::security::Authenticator;
+use crate::subscriptions::Subscriptions;
 use crate::util::*;
 use crate::{
     util, MultiPeer, NonBlockingSend, SocketBackend, SocketFrontend, SocketType, ZmqResult,
@@ -7,65 +15,301 @@ use crate::{
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::channel::{mpsc, oneshot};
-use std::net::SocketAddr;
-use std::sync::Arc;
+use futures::task::{noop_waker_ref, Context};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Where publish puts messages for the subscriber
+pub(crate) enum SubscriberQueue {
+    /// Shared rather than cloned so `publish_await` can wait for room without
+    /// holding the subscriber. Every clone of a sender gets one more slot past send_hwm
+    Queue(Arc<Mutex<mpsc::Sender<Message>>>),
+    /// With `QueueFullPolicy::DropOldest`. Messages are forwarded from the ring
+    /// to the connection's queue as it makes room
+    Ring(Arc<Ring<Message>>),
+}
+
+impl SubscriberQueue {
+    /// Returns true if a message was dropped, either this one or the oldest queued
+    fn push(&mut self, message: Message) -> bool {
+        match self {
+            // Closed queue belongs to peer that is being disconnected
+            Self::Queue(queue) => {
+                matches!(queue.lock().unwrap().try_send(message), Err(e) if e.is_full())
+            }
+            Self::Ring(ring) => ring.put(message),
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        match self {
+            Self::Queue(queue) => {
+                let mut cx = Context::from_waker(noop_waker_ref());
+                queue.lock().unwrap().poll_ready(&mut cx).is_pending()
+            }
+            Self::Ring(_) => false,
+        }
+    }
+}
+
+impl Drop for SubscriberQueue {
+    fn drop(&mut self) {
+        // Messages in the ring are still forwarded, the same as ones left in a queue
+        if let Self::Ring(ring) = self {
+            ring.close();
+        }
+    }
+}
 
 pub(crate) struct Subscriber {
-    pub(crate) subscriptions: Vec<Vec<u8>>,
-    pub(crate) send_queue: mpsc::Sender<Message>,
+    pub(crate) send_queue: SubscriberQueue,
+    /// Subscriber is missing messages and monitors were told so
+    pub(crate) dropping: bool,
     pub(crate) _io_close_handle: futures::channel::oneshot::Sender<bool>,
 }
 
 pub(crate) struct PubSocketBackend {
-    subscribers: DashMap<PeerIdentity, Subscriber>,
+    pub(crate) subscribers: DashMap<PeerIdentity, Subscriber>,
+    pub(crate) subscriptions: RwLock<Subscriptions>,
+    options: SocketOptions,
+}
+
+/// Kind of subscription change received from the peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SubscriptionUpdate {
+    Subscribe,
+    Cancel,
+}
+
+/// Extracts subscription change from received message.
+/// ZMTP 3.1 peers send SUBSCRIBE/CANCEL commands while older peers send messages
+/// where first byte is 1 for subscribe and 0 for unsubscribe.
+/// Returns None if message is not a subscription message
+pub(crate) fn parse_subscription(message: &Message) -> Option<(SubscriptionUpdate, &[u8])> {
+    match message {
+        Message::Command(ZmtpCommand::Subscribe(topic)) => {
+            Some((SubscriptionUpdate::Subscribe, topic))
+        }
+        Message::Command(ZmtpCommand::Cancel(topic)) => Some((SubscriptionUpdate::Cancel, topic)),
+        Message::Message(message) => match message.data.split_first() {
+            Some((1, topic)) => Some((SubscriptionUpdate::Subscribe, topic)),
+            Some((0, topic)) => Some((SubscriptionUpdate::Cancel, topic)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Updates subscriptions of the peer according to received subscription message.
+/// Returns parsed subscription change or None if message is not a valid subscription message
+/// or cancels subscription the peer doesn't have
+pub(crate) fn process_subscription<'a>(
+    subscribers: &DashMap<PeerIdentity, Subscriber>,
+    subscriptions: &RwLock<Subscriptions>,
+    peer_id: &PeerIdentity,
+    message: &'a Message,
+) -> Option<(SubscriptionUpdate, &'a [u8])> {
+    let (update, topic) = parse_subscription(message)?;
+    let mut subscriptions = subscriptions.write().unwrap();
+    // Checked under the lock so subscription doesn't outlive peer that disconnects meanwhile
+    if !subscribers.contains_key(peer_id) {
+        return None;
+    }
+    match update {
+        SubscriptionUpdate::Subscribe => subscriptions.subscribe(peer_id, topic),
+        SubscriptionUpdate::Cancel => {
+            if !subscriptions.cancel(peer_id, topic) {
+                return None;
+            }
+        }
+    }
+    Some((update, topic))
+}
+
+/// Forgets the peer and its subscriptions, returns prefixes it was subscribed to
+pub(crate) fn subscriber_disconnected(
+    subscribers: &DashMap<PeerIdentity, Subscriber>,
+    subscriptions: &RwLock<Subscriptions>,
+    peer_id: &PeerIdentity,
+) -> Vec<Vec<u8>> {
+    // Peer reconnecting with the same identity starts without subscriptions
+    let mut subscriptions = subscriptions.write().unwrap();
+    if subscribers.remove(peer_id).is_none() {
+        return Vec::new();
+    }
+    subscriptions.remove_peer(peer_id)
+}
+
+/// Registers new subscriber without any subscriptions
+pub(crate) fn subscriber_connected(
+    subscribers: &DashMap<PeerIdentity, Subscriber>,
+    peer_id: &PeerIdentity,
+    send_hwm: usize,
+    options: &SocketOptions,
+) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+    let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+    let (send_queue, out_queue_receiver) = match options.queue_full_policy {
+        QueueFullPolicy::DropOldest => {
+            // Ring holds the messages, queue only the next one to be written,
+            // so together they hold send_hwm of them
+            let (out_queue, out_queue_receiver) = bounded_queue(1);
+            let ring = Arc::new(Ring::new(send_hwm.saturating_sub(1)));
+            let forward = conflate::forward(ring.clone(), out_queue);
+            let task = options.track_task();
+            let stopped = options.stopped();
+            tokio::spawn(async move {
+                let _task = task;
+                futures::pin_mut!(forward, stopped);
+                futures::future::select(forward, stopped).await;
+            });
+            (SubscriberQueue::Ring(ring), out_queue_receiver)
+        }
+        QueueFullPolicy::DropNewest | QueueFullPolicy::Error => {
+            let (out_queue, out_queue_receiver) = bounded_queue(send_hwm);
+            let out_queue = Arc::new(Mutex::new(out_queue));
+            (SubscriberQueue::Queue(out_queue), out_queue_receiver)
+        }
+    };
+
+    subscribers.insert(
+        peer_id.clone(),
+        Subscriber {
+            send_queue,
+            dropping: false,
+            _io_close_handle: stop_handle,
+        },
+    );
+    (out_queue_receiver, stop_callback)
+}
+
+/// Sends message to every subscriber with matching subscription. Subscription matches
+/// messages it is a prefix of, so empty one matches all of them. Subscriber gets message
+/// once no matter how many of their subscriptions match it.
+/// Subscribers with full queue miss a message as `queue_full_policy` says.
+/// With `QueueFullPolicy::Error` nothing is sent then and error names the subscriber
+/// that has no room
+pub(crate) fn publish(
+    subscribers: &DashMap<PeerIdentity, Subscriber>,
+    subscriptions: &RwLock<Subscriptions>,
+    frames: Vec<ZmqMessage>,
+    options: &SocketOptions,
+) -> ZmqResult<()> {
+    // Subscriptions are matched against the first frame only
+    let topic = frames
+        .first()
+        .map(|frame| frame.data.clone())
+        .unwrap_or_default();
+    let subscriptions = subscriptions.read().unwrap();
+    let matched = subscriptions.matching(&topic);
+    if options.queue_full_policy == QueueFullPolicy::Error {
+        for peer_id in &matched {
+            if let Some(mut subscriber) = subscribers.get_mut(*peer_id) {
+                if subscriber.send_queue.is_full() {
+                    return Err(ZmqError::PeerQueueFull((*peer_id).clone()));
+                }
+            }
+        }
+    }
+    // Subscribers share frames of the message and its encoding
+    let message = Message::Shared(Arc::new(SharedMessage::new(frames)));
+    for peer_id in matched {
+        // Missing peer is being disconnected
+        if let Some(mut subscriber) = subscribers.get_mut(peer_id) {
+            options.stats.enqueued(peer_id);
+            let dropped = subscriber.send_queue.push(message.clone());
+            if dropped {
+                // Either this message or the oldest one, which was counted already
+                options.stats.not_enqueued(peer_id);
+                options.stats.dropped(peer_id);
+                if !subscriber.dropping {
+                    options.monitor.emit(SocketEvent::MessagesDropped {
+                        peer_id: peer_id.clone(),
+                    });
+                }
+            }
+            subscriber.dropping = dropped;
+        }
+    }
+    Ok(())
+}
+
+/// Same as `publish` but waits for room in the queue of every matching subscriber,
+/// up to send timeout for each of them. Returns subscribers that still had no room,
+/// they miss the message. Subscribers are looked up on every poll, so no locks
+/// are held while waiting
+pub(crate) async fn publish_await(
+    subscribers: &DashMap<PeerIdentity, Subscriber>,
+    subscriptions: &RwLock<Subscriptions>,
+    frames: Vec<ZmqMessage>,
+    options: &SocketOptions,
+) -> Vec<PeerIdentity> {
+    let topic = frames
+        .first()
+        .map(|frame| frame.data.clone())
+        .unwrap_or_default();
+    let message = Message::Shared(Arc::new(SharedMessage::new(frames)));
+    // Collect queues first to avoid holding DashMap locks across await points.
+    // Rings never wait, so their subscribers get the message right away
+    let mut queues = Vec::new();
+    for peer_id in subscriptions.read().unwrap().matching(&topic) {
+        let subscriber = match subscribers.get(peer_id) {
+            Some(subscriber) => subscriber,
+            None => continue,
+        };
+        match &subscriber.send_queue {
+            SubscriberQueue::Queue(queue) => queues.push((peer_id.clone(), queue.clone())),
+            SubscriberQueue::Ring(ring) => {
+                options.stats.enqueued(peer_id);
+                if ring.put(message.clone()) {
+                    // Oldest message was pushed out, it was counted already
+                    options.stats.not_enqueued(peer_id);
+                    options.stats.dropped(peer_id);
+                }
+            }
+        }
+    }
+    let sends = queues.into_iter().map(|(peer_id, queue)| {
+        let mut message = Some(message.clone());
+        async move {
+            let send = futures::future::poll_fn(|cx| {
+                poll_send(
+                    &mut queue.lock().unwrap(),
+                    cx,
+                    &mut message,
+                    Some((&options.stats, &peer_id)),
+                )
+            });
+            match util::with_timeout(options.send_timeout, send).await {
+                Err(_) => {
+                    options.stats.dropped(&peer_id);
+                    Some(peer_id)
+                }
+                // Peer that is gone doesn't count as timed out
+                Ok(_) => None,
+            }
+        }
+    });
+    futures::future::join_all(sends)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
 }
 
 #[async_trait]
 impl SocketBackend for PubSocketBackend {
+    /// Messages of peers that are already gone are ignored
     async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
-        let message = match message {
-            Message::Message(m) => m,
-            _ => return,
-        };
-        let data: Vec<u8> = message.into();
-        if data.len() < 1 {
-            return;
-        }
-        match data[0] {
-            1 => {
-                // Subscribe
-                self.subscribers
-                    .get_mut(&peer_id)
-                    .unwrap()
-                    .subscriptions
-                    .push(Vec::from(&data[1..]));
+        match (&message, parse_subscription(&message)) {
+            (_, Some(_)) => {
+                process_subscription(&self.subscribers, &self.subscriptions, peer_id, &message);
             }
-            0 => {
-                // Unsubscribe
-                let mut del_index = None;
-                let sub = Vec::from(&data[1..]);
-                for (idx, subscription) in self
-                    .subscribers
-                    .get(&peer_id)
-                    .unwrap()
-                    .subscriptions
-                    .iter()
-                    .enumerate()
-                {
-                    if &sub == subscription {
-                        del_index = Some(idx);
-                        break;
-                    }
-                }
-                if let Some(index) = del_index {
-                    self.subscribers
-                        .get_mut(&peer_id)
-                        .unwrap()
-                        .subscriptions
-                        .remove(index);
-                }
+            // Subscribers send nothing but subscriptions, so anything else breaks
+            // the protocol. Dropping the peer closes its connection
+            (Message::Message(_) | Message::MultipartMessage(_), None) => {
+                subscriber_disconnected(&self.subscribers, &self.subscriptions, peer_id);
             }
-            _ => return,
+            _ => {}
         }
     }
 
@@ -75,6 +319,7 @@ impl SocketBackend for PubSocketBackend {
 
     fn shutdown(&self) {
         self.subscribers.clear();
+        self.subscriptions.write().unwrap().clear();
     }
 }
 
@@ -83,77 +328,529 @@ impl MultiPeer for PubSocketBackend {
     async fn peer_connected(
         &self,
         peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
     ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
-        let default_queue_size = 100;
-        let (out_queue, out_queue_receiver) = mpsc::channel(default_queue_size);
-        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
-
-        self.subscribers.insert(
-            peer_id.clone(),
-            Subscriber {
-                subscriptions: vec![],
-                send_queue: out_queue,
-                _io_close_handle: stop_handle,
-            },
-        );
-        (out_queue_receiver, stop_callback)
+        subscriber_connected(&self.subscribers, peer_id, hwm.send, &self.options)
     }
 
     async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
-        println!("Client disconnected {:?}", peer_id);
-        self.subscribers.remove(peer_id);
+        subscriber_disconnected(&self.subscribers, &self.subscriptions, peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.subscribers.contains_key(peer_id)
     }
 }
 
 pub struct PubSocket {
     pub(crate) backend: Arc<PubSocketBackend>,
-    _accept_close_handle: Option<oneshot::Sender<bool>>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
 }
 
 impl Drop for PubSocket {
     fn drop(&mut self) {
+        self.options.tasks.stop();
         self.backend.shutdown();
     }
 }
 
+impl PubSocket {
+    /// Same as `send` but waits for room in the queue of every matching subscriber
+    /// rather than dropping the message, up to `SocketOptions::send_timeout`.
+    /// Returns subscribers whose queue stayed full, they miss the message
+    pub async fn send_await<M: Into<ZmqMessage>>(
+        &mut self,
+        message: M,
+    ) -> ZmqResult<Vec<PeerIdentity>> {
+        self.send_multipart_await(vec![message.into()]).await
+    }
+
+    /// Multipart version of `send_await`
+    pub async fn send_multipart_await(
+        &mut self,
+        frames: Vec<ZmqMessage>,
+    ) -> ZmqResult<Vec<PeerIdentity>> {
+        conflate::check_frames(self.options.conflate, &frames)?;
+        Ok(publish_await(
+            &self.backend.subscribers,
+            &self.backend.subscriptions,
+            frames,
+            &self.options,
+        )
+        .await)
+    }
+}
+
 impl NonBlockingSend for PubSocket {
-    fn send(&mut self, message: ZmqMessage) -> ZmqResult<()> {
-        for mut subscriber in self.backend.subscribers.iter_mut() {
-            for sub_filter in &subscriber.subscriptions {
-                if sub_filter.as_slice() == &message.data[0..sub_filter.len()] {
-                    let _res = subscriber
-                        .send_queue
-                        .try_send(Message::Message(message.clone()));
-                    // TODO handle result
-                    break;
-                }
-            }
-        }
-        Ok(())
+    fn send<M: Into<ZmqMessage>>(&mut self, message: M) -> ZmqResult<()> {
+        let frames = vec![message.into()];
+        conflate::check_frames(self.options.conflate, &frames)?;
+        publish(
+            &self.backend.subscribers,
+            &self.backend.subscriptions,
+            frames,
+            &self.options,
+        )
+    }
+
+    fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        conflate::check_frames(self.options.conflate, &frames)?;
+        publish(
+            &self.backend.subscribers,
+            &self.backend.subscriptions,
+            frames,
+            &self.options,
+        )
     }
 }
 
 #[async_trait]
 impl SocketFrontend for PubSocket {
-    fn new() -> Self {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
         Self {
             backend: Arc::new(PubSocketBackend {
                 subscribers: DashMap::new(),
+                subscriptions: RwLock::default(),
+                options: options.clone(),
             }),
-            _accept_close_handle: None,
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
         }
     }
 
-    async fn bind(&mut self, endpoint: &str) -> ZmqResult<()> {
-        let stop_handle = util::start_accepting_connections(endpoint, self.backend.clone()).await?;
-        self._accept_close_handle = Some(stop_handle);
-        Ok(())
+    fn socket_type() -> SocketType {
+        SocketType::PUB
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
     }
 
     async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
-        let addr = endpoint.parse::<SocketAddr>()?;
-        let raw_socket = tokio::net::TcpStream::connect(addr).await?;
-        util::peer_connected(raw_socket, self.backend.clone()).await;
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
         Ok(())
     }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_publish_matches_prefix_of_any_length() {
+        let subscribers = DashMap::new();
+        let peer_id = PeerIdentity::new();
+        let (mut queue, _stop_callback) =
+            subscriber_connected(&subscribers, &peer_id, 10, &SocketOptions::default());
+        let subscriptions = RwLock::new(Subscriptions::default());
+        subscriptions.write().unwrap().subscribe(&peer_id, b"topic");
+        let options = SocketOptions::default();
+        for data in &["", "top", "topic", "topical", "other message"] {
+            let frames = vec![ZmqMessage::from(*data)];
+            publish(&subscribers, &subscriptions, frames, &options).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Some(mut frames) = queue.try_recv().ok().and_then(Message::into_frames) {
+            received.push(frames.remove(0).data);
+        }
+        assert_eq!(vec!["topic", "topical"], received);
+    }
+
+    #[test]
+    fn test_empty_subscription_matches_everything() {
+        let subscribers = DashMap::new();
+        let peer_id = PeerIdentity::new();
+        let (mut queue, _stop_callback) =
+            subscriber_connected(&subscribers, &peer_id, 10, &SocketOptions::default());
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let subscribe = |data: &[u8]| {
+            let message = Message::Message(ZmqMessage::from(data.to_vec()));
+            process_subscription(&subscribers, &subscriptions, &peer_id, &message).unwrap();
+        };
+        subscribe(b"\x01");
+        subscribe(b"\x01topic");
+        let options = SocketOptions::default();
+        let mut sent = |data: &str| {
+            let frames = vec![ZmqMessage::from(data)];
+            publish(&subscribers, &subscriptions, frames, &options).unwrap();
+            let mut received = Vec::new();
+            while let Some(mut frames) = queue.try_recv().ok().and_then(Message::into_frames) {
+                received.push(frames.remove(0).data);
+            }
+            received
+        };
+        assert_eq!(vec![""], sent(""));
+        assert_eq!(vec!["anything"], sent("anything"));
+        // Matches both subscriptions but is delivered once
+        assert_eq!(vec!["topic"], sent("topic"));
+
+        subscribe(b"\x00");
+        assert!(sent("anything").is_empty());
+        assert_eq!(vec!["topic"], sent("topic"));
+    }
+
+    #[test]
+    fn test_multipart_matched_by_first_frame() {
+        let subscribers = DashMap::new();
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let mut queues = Vec::new();
+        for prefix in &[&b"topic"[..], b"header", b"topicheader"] {
+            let peer_id = PeerIdentity::new();
+            let (queue, stop_callback) =
+                subscriber_connected(&subscribers, &peer_id, 10, &SocketOptions::default());
+            subscriptions.write().unwrap().subscribe(&peer_id, prefix);
+            queues.push((queue, stop_callback));
+        }
+        let frames = vec!["topic".into(), "header".into(), "payload".into()];
+        publish(
+            &subscribers,
+            &subscriptions,
+            frames,
+            &SocketOptions::default(),
+        )
+        .unwrap();
+
+        let mut received: Vec<_> = queues
+            .iter_mut()
+            .map(|(queue, _)| {
+                let frames = queue.try_recv().ok().and_then(Message::into_frames)?;
+                Some(
+                    frames
+                        .into_iter()
+                        .map(|frame| frame.data)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        // Frames stay together as one queued message
+        let matched = received
+            .remove(0)
+            .expect("Subscriber of the topic gets the message");
+        assert_eq!(vec!["topic", "header", "payload"], matched);
+        assert_eq!(vec![None, None], received);
+    }
+
+    #[test]
+    fn test_subscription_changes_while_publishing() {
+        const MESSAGES: usize = 1000;
+        let subscribers = DashMap::new();
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let steady = PeerIdentity::new();
+        let changing = PeerIdentity::new();
+        let (mut queue, _stop_callback) =
+            subscriber_connected(&subscribers, &steady, MESSAGES, &SocketOptions::default());
+        let (_changing_queue, _changing_stop_callback) =
+            subscriber_connected(&subscribers, &changing, MESSAGES, &SocketOptions::default());
+        subscriptions.write().unwrap().subscribe(&steady, b"top");
+        let options = SocketOptions::default();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..MESSAGES {
+                    for data in &[&b"\x01topic"[..], b"\x00topic"] {
+                        let message = Message::Message(ZmqMessage::from(data.to_vec()));
+                        process_subscription(&subscribers, &subscriptions, &changing, &message)
+                            .unwrap();
+                    }
+                }
+            });
+            for _ in 0..MESSAGES {
+                let frames = vec![ZmqMessage::from("topic")];
+                publish(&subscribers, &subscriptions, frames, &options).unwrap();
+            }
+        });
+
+        let mut received = 0;
+        while queue.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(MESSAGES, received);
+        assert_eq!(1, subscriptions.read().unwrap().len());
+        subscriber_disconnected(&subscribers, &subscriptions, &steady);
+        assert_eq!(0, subscriptions.read().unwrap().len());
+    }
+
+    #[test]
+    fn test_dropping_reported_once_per_run() {
+        use futures::{FutureExt, StreamExt};
+
+        let subscribers = DashMap::new();
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let peer_id = PeerIdentity::new();
+        let (mut queue, _stop_callback) =
+            subscriber_connected(&subscribers, &peer_id, 2, &SocketOptions::default());
+        subscriptions.write().unwrap().subscribe(&peer_id, b"");
+        let options = SocketOptions::default();
+        let mut monitor = options.monitor.subscribe();
+        for _ in 0..2 {
+            for _ in 0..4 {
+                let frames = vec![ZmqMessage::from("message")];
+                publish(&subscribers, &subscriptions, frames, &options).unwrap();
+            }
+            while queue.try_recv().is_ok() {}
+            // Message that gets through ends the run
+            let frames = vec![ZmqMessage::from("message")];
+            publish(&subscribers, &subscriptions, frames, &options).unwrap();
+            while queue.try_recv().is_ok() {}
+        }
+
+        let mut events = Vec::new();
+        while let Some(Some(event)) = monitor.next().now_or_never() {
+            events.push(event);
+        }
+        let reported = SocketEvent::MessagesDropped { peer_id };
+        assert_eq!(vec![reported.clone(), reported], events);
+        assert_eq!(4, options.stats.snapshot().totals.messages_dropped);
+    }
+
+    #[tokio::test]
+    async fn test_publish_await_times_out_full_subscribers() {
+        use futures::StreamExt;
+        use std::time::Duration;
+
+        let subscribers = DashMap::new();
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let stuck = PeerIdentity::new();
+        let reading = PeerIdentity::new();
+        let (_stuck_queue, _stuck_stop_callback) =
+            subscriber_connected(&subscribers, &stuck, 2, &SocketOptions::default());
+        let (mut queue, _stop_callback) =
+            subscriber_connected(&subscribers, &reading, 2, &SocketOptions::default());
+        subscriptions.write().unwrap().subscribe(&stuck, b"");
+        subscriptions.write().unwrap().subscribe(&reading, b"");
+        let options = SocketOptions::default().send_timeout(Duration::from_millis(50));
+        let reader = tokio::spawn(async move {
+            let mut received = 0;
+            while queue.next().await.is_some() {
+                received += 1;
+                tokio::time::delay_for(Duration::from_millis(5)).await;
+            }
+            received
+        });
+
+        let mut timed_out = Vec::new();
+        for _ in 0..5 {
+            let frames = vec![ZmqMessage::from("message")];
+            timed_out.push(publish_await(&subscribers, &subscriptions, frames, &options).await);
+        }
+        // Only the subscriber that doesn't read misses messages, the other one waits
+        let missed: Vec<usize> = timed_out.iter().map(Vec::len).collect();
+        assert_eq!(vec![0, 0, 1, 1, 1], missed);
+        assert!(timed_out.iter().flatten().all(|peer_id| *peer_id == stuck));
+        assert_eq!(3, options.stats.snapshot().totals.messages_dropped);
+        subscribers.clear();
+        assert_eq!(5, reader.await.unwrap());
+    }
+
+    fn backend() -> PubSocketBackend {
+        PubSocketBackend {
+            subscribers: DashMap::new(),
+            subscriptions: RwLock::default(),
+            options: SocketOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription_after_disconnect_is_ignored() {
+        let backend = backend();
+        let peer_id = PeerIdentity::new();
+        let hwm = HighWaterMarks { send: 10, recv: 10 };
+        let _pipe = backend.peer_connected(&peer_id, ZMTP_VERSION, hwm).await;
+        backend.peer_disconnected(&peer_id).await;
+        let subscribe = Message::Message(ZmqMessage::from(b"\x01topic".to_vec()));
+        backend.message_received(&peer_id, subscribe).await;
+        let cancel = Message::Command(ZmtpCommand::Cancel(b"topic".to_vec().into()));
+        backend.message_received(&peer_id, cancel).await;
+        assert_eq!(0, backend.subscriptions.read().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_subscription_drops_peer() {
+        let backend = backend();
+        let hwm = HighWaterMarks { send: 10, recv: 10 };
+        for data in &[&b""[..], b"\x02topic", b"topic"] {
+            let peer_id = PeerIdentity::new();
+            let other = PeerIdentity::new();
+            let (_queue, stop_callback) = backend.peer_connected(&peer_id, ZMTP_VERSION, hwm).await;
+            let _other_pipe = backend.peer_connected(&other, ZMTP_VERSION, hwm).await;
+            let subscribe = Message::Message(ZmqMessage::from(b"\x01".to_vec()));
+            backend.message_received(&peer_id, subscribe.clone()).await;
+            backend.message_received(&other, subscribe).await;
+
+            let message = Message::Message(ZmqMessage::from(data.to_vec()));
+            backend.message_received(&peer_id, message).await;
+            // Connection of the peer is told to stop, other peer stays subscribed
+            assert!(stop_callback.await.is_err());
+            assert!(!backend.subscribers.contains_key(&peer_id));
+            assert_eq!(1, backend.subscriptions.read().unwrap().len());
+            backend.peer_disconnected(&other).await;
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_fan_out`
+    #[test]
+    #[ignore]
+    fn bench_fan_out() {
+        use tokio_util::codec::Encoder;
+
+        const SUBSCRIBERS: usize = 5000;
+        const ROUNDS: usize = 200;
+        let subscribers = DashMap::new();
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let mut peers = Vec::new();
+        for _ in 0..SUBSCRIBERS {
+            let peer_id = PeerIdentity::new();
+            let (queue, stop_callback) =
+                subscriber_connected(&subscribers, &peer_id, 10, &SocketOptions::default());
+            subscriptions.write().unwrap().subscribe(&peer_id, b"");
+            peers.push((
+                queue,
+                stop_callback,
+                ZmqCodec::new(),
+                bytes::BytesMut::new(),
+            ));
+        }
+        let options = SocketOptions::default();
+        for &parts in &[1usize, 3] {
+            let started = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                let frames = vec![ZmqMessage::from(vec![0u8; 1024 / parts]); parts];
+                publish(&subscribers, &subscriptions, frames, &options).unwrap();
+                // Connection of every peer encodes message into its write buffer
+                for (queue, _, codec, buffer) in peers.iter_mut() {
+                    let message = queue.try_recv().unwrap();
+                    codec.encode(message, buffer).unwrap();
+                    buffer.clear();
+                }
+            }
+            let elapsed = started.elapsed();
+            println!(
+                "{} frame(s) of 1 KB to {} subscribers: {:>10.0} deliveries/s",
+                parts,
+                SUBSCRIBERS,
+                (ROUNDS * SUBSCRIBERS) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_many_subscriptions`
+    #[test]
+    #[ignore]
+    fn bench_many_subscriptions() {
+        const SUBSCRIBERS: usize = 2000;
+        const PREFIXES: usize = 50;
+        const ROUNDS: usize = 20000;
+        let subscribers = DashMap::new();
+        let subscriptions = RwLock::new(Subscriptions::default());
+        let mut queues = Vec::new();
+        for peer in 0..SUBSCRIBERS {
+            let peer_id = PeerIdentity::new();
+            queues.push(subscriber_connected(
+                &subscribers,
+                &peer_id,
+                10,
+                &SocketOptions::default(),
+            ));
+            for prefix in 0..PREFIXES {
+                let topic = format!("sensors/{}/{}", peer, prefix);
+                subscriptions
+                    .write()
+                    .unwrap()
+                    .subscribe(&peer_id, topic.as_bytes());
+            }
+        }
+        let options = SocketOptions::default();
+        let started = std::time::Instant::now();
+        for round in 0..ROUNDS {
+            // Every message has exactly one subscriber
+            let topic = format!(
+                "sensors/{}/{}/reading",
+                round % SUBSCRIBERS,
+                round % PREFIXES
+            );
+            let frames = vec![ZmqMessage::from(topic)];
+            publish(&subscribers, &subscriptions, frames, &options).unwrap();
+            let (queue, _) = &mut queues[round % SUBSCRIBERS];
+            queue.try_recv().unwrap();
+        }
+        let elapsed = started.elapsed();
+        println!(
+            "{} subscribers with {} prefixes each: {:>10.0} sends/s",
+            SUBSCRIBERS,
+            PREFIXES,
+            ROUNDS as f64 / elapsed.as_secs_f64()
+        );
+    }
 }
@@ -0,0 +1,228 @@
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::conflate::{self, QueueReceiver};
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::fair_queue::start_fair_queue;
+use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
+use crate::util::*;
+use crate::{util, BlockingRecv, MultiPeer, SocketBackend, SocketFrontend, SocketType, ZmqResult};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+pub(crate) struct PullPeer {
+    pub(crate) recv_queue_in: mpsc::Sender<Message>,
+    // PULL socket never sends anything but io loop stops once outgoing queue is closed
+    pub(crate) _send_queue: mpsc::Sender<Message>,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+pub(crate) struct PullSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, PullPeer>,
+    pub(crate) peer_queue_in: mpsc::Sender<(PeerIdentity, mpsc::Receiver<Message>)>,
+}
+
+#[async_trait]
+impl SocketBackend for PullSocketBackend {
+    async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
+        // Clone sender so DashMap lock is not held while waiting for queue capacity
+        let recv_queue = self
+            .peers
+            .get(peer_id)
+            .map(|peer| peer.recv_queue_in.clone());
+        if let Some(mut recv_queue) = recv_queue {
+            // Fair queue might be already stopped. Message is dropped in such case
+            let _ = recv_queue.send(message).await;
+        }
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::PULL
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
+
+#[async_trait]
+impl MultiPeer for PullSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        let (out_queue, out_queue_receiver) = mpsc::channel(1);
+        let (in_queue, in_queue_receiver) = bounded_queue(hwm.recv);
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        self.peers.insert(
+            peer_id.clone(),
+            PullPeer {
+                recv_queue_in: in_queue,
+                _send_queue: out_queue,
+                _io_close_handle: stop_handle,
+            },
+        );
+        let registered = self
+            .peer_queue_in
+            .clone()
+            .try_send((peer_id.clone(), in_queue_receiver));
+        if registered.is_err() {
+            // Fair queue is stopped once socket is dropped. Connection finished
+            // handshake too late, so it gets closed right away
+            self.peers.remove(peer_id);
+        }
+
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        // Dropping peer closes its incoming queue so fair queue just skips it afterwards
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+pub struct PullSocket {
+    backend: Arc<PullSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+    fair_queue: QueueReceiver<(PeerIdentity, Message)>,
+}
+
+impl Drop for PullSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+#[async_trait]
+impl BlockingRecv for PullSocket {
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        match util::with_timeout(self.options.recv_timeout, self.fair_queue.next()).await? {
+            Some((_peer_id, message)) => {
+                let frames = message
+                    .into_frames()
+                    .ok_or(ZmqError::Other("Wrong message type received"))?;
+                conflate::check_frames(self.options.conflate, &frames)?;
+                Ok(frames)
+            }
+            None => Err(ZmqError::NoMessage),
+        }
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for PullSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
+        let fair_queue = QueueReceiver::new(fair_queue, options.conflates_recv(SocketType::PULL));
+        Self {
+            backend: Arc::new(PullSocketBackend {
+                peers: DashMap::new(),
+                peer_queue_in: peer_in,
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+            fair_queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::PULL
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
@@ -0,0 +1,226 @@
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::conflate;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
+use crate::util::*;
+use crate::{
+    util, MultiPeer, NonBlockingSend, SocketBackend, SocketFrontend, SocketType, ZmqResult,
+};
+use async_trait::async_trait;
+use crossbeam::queue::SegQueue;
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+pub(crate) struct PushPeer {
+    pub(crate) send_queue: mpsc::Sender<Message>,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+pub(crate) struct PushSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, PushPeer>,
+    pub(crate) round_robin: SegQueue<PeerIdentity>,
+}
+
+#[async_trait]
+impl SocketBackend for PushSocketBackend {
+    async fn message_received(&self, _peer_id: &PeerIdentity, _message: Message) {
+        // PUSH socket never receives messages. Silently discard them
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::PUSH
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
+
+#[async_trait]
+impl MultiPeer for PushSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        self.peers.insert(
+            peer_id.clone(),
+            PushPeer {
+                send_queue: out_queue,
+                _io_close_handle: stop_handle,
+            },
+        );
+        self.round_robin.push(peer_id.clone());
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+pub struct PushSocket {
+    backend: Arc<PushSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+}
+
+impl Drop for PushSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl NonBlockingSend for PushSocket {
+    fn send<M: Into<ZmqMessage>>(&mut self, message: M) -> ZmqResult<()> {
+        let message = message.into();
+        self.send_round_robin(vec![message])
+            .map_err(|mut frames| ZmqError::ReturnToSender {
+                reason: "No connected peers are able to accept message",
+                message: frames.pop().unwrap(),
+            })
+    }
+
+    fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        conflate::check_frames(self.options.conflate, &frames)?;
+        self.send_round_robin(frames)
+            .map_err(|_| ZmqError::Socket("No connected peers are able to accept message"))
+    }
+}
+
+impl PushSocket {
+    /// Gives frames back if no peer accepted them
+    fn send_round_robin(&mut self, frames: Vec<ZmqMessage>) -> Result<(), Vec<ZmqMessage>> {
+        // Each peer is tried at most once. Peers with full queues are skipped.
+        // Disconnected peers are still in RR queue cause SegQueue don't have an api
+        // to delete items from it. Such peers are dropped from the queue here
+        let attempts = self.backend.round_robin.len();
+        for _ in 0..attempts {
+            let next_peer_id = match self.backend.round_robin.pop() {
+                Ok(peer) => peer,
+                Err(_) => break,
+            };
+            if let Some(mut peer) = self.backend.peers.get_mut(&next_peer_id) {
+                self.backend.round_robin.push(next_peer_id.clone());
+                self.options.stats.enqueued(&next_peer_id);
+                match peer.send_queue.try_send(frames.clone().into()) {
+                    Ok(()) => return Ok(()),
+                    Err(_) => self.options.stats.not_enqueued(&next_peer_id),
+                }
+            }
+        }
+        Err(frames)
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for PushSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        Self {
+            backend: Arc::new(PushSocketBackend {
+                peers: DashMap::new(),
+                round_robin: SegQueue::new(),
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::PUSH
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
@@ -0,0 +1,597 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
+use crate::util::*;
+use crate::{udp, util, MultiPeer, SocketBackend, SocketFrontend};
+use crate::{SocketType, ZmqResult};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Maximum length of the group name in bytes
+pub const MAX_GROUP_LENGTH: usize = 16;
+
+fn validate_group(group: &str) -> ZmqResult<()> {
+    if group.len() > MAX_GROUP_LENGTH {
+        return Err(ZmqError::Socket("Group name is too long"));
+    }
+    Ok(())
+}
+
+pub(crate) struct RadioPeer {
+    pub(crate) groups: HashSet<Vec<u8>>,
+    pub(crate) send_queue: mpsc::Sender<Message>,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+pub(crate) struct RadioSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, RadioPeer>,
+}
+
+#[async_trait]
+impl SocketBackend for RadioSocketBackend {
+    async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
+        let command = match message {
+            Message::Command(command) => command,
+            _ => return,
+        };
+        if let Some(mut peer) = self.peers.get_mut(peer_id) {
+            match command {
+                ZmtpCommand::Join(group) => {
+                    peer.groups.insert(group.to_vec());
+                }
+                ZmtpCommand::Leave(group) => {
+                    peer.groups.remove(group.as_ref());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::RADIO
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
+
+#[async_trait]
+impl MultiPeer for RadioSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        self.peers.insert(
+            peer_id.clone(),
+            RadioPeer {
+                groups: HashSet::new(),
+                send_queue: out_queue,
+                _io_close_handle: stop_handle,
+            },
+        );
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+/// Publishes messages to named groups.
+/// Unlike PUB socket groups are matched exactly rather than by prefix
+pub struct RadioSocket {
+    backend: Arc<RadioSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    udp_peers: Vec<(Endpoint, UdpSocket)>,
+}
+
+impl Drop for RadioSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl RadioSocket {
+    /// Sends message to every peer that joined the group.
+    /// Message is dropped for peers with full queues.
+    /// Udp peers can't join groups so they receive every message
+    pub fn send(&mut self, group: &str, message: ZmqMessage) -> ZmqResult<()> {
+        validate_group(group)?;
+        if !self.udp_peers.is_empty() {
+            let datagram = udp::encode(group, &message)?;
+            for (_, socket) in &self.udp_peers {
+                // Datagrams are unreliable anyway so they are dropped if socket is not writable
+                let _res = socket.try_send(&datagram);
+            }
+        }
+        let frames = vec![Bytes::copy_from_slice(group.as_bytes()).into(), message];
+        let message = Message::Shared(Arc::new(SharedMessage::new(frames)));
+        for mut peer in self.backend.peers.iter_mut() {
+            if peer.groups.contains(group.as_bytes()) {
+                self.options.stats.enqueued(peer.key());
+                match peer.send_queue.try_send(message.clone()) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        self.options.stats.not_enqueued(peer.key());
+                        // Otherwise peer is being disconnected
+                        if e.is_full() {
+                            self.options.stats.dropped(peer.key());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for RadioSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        Self {
+            backend: Arc::new(RadioSocketBackend {
+                peers: DashMap::new(),
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            udp_peers: Vec::new(),
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::RADIO
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        if let Endpoint::Udp(..) = endpoint.parse::<Endpoint>()? {
+            return Err(ZmqError::Socket(
+                "RADIO socket can only connect to udp endpoint",
+            ));
+        }
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        if let Endpoint::Udp(host, port) = endpoint.parse::<Endpoint>()? {
+            let socket = udp::connect(host.clone(), port).await?;
+            self.udp_peers.push((Endpoint::Udp(host, port), socket));
+            return Ok(());
+        }
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let parsed = endpoint.parse::<Endpoint>()?;
+        if let Some(position) = self.udp_peers.iter().position(|(e, _)| e == &parsed) {
+            self.udp_peers.remove(position);
+            return Ok(());
+        }
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
+
+pub(crate) struct DishPeer {
+    pub(crate) send_queue: mpsc::Sender<Message>,
+    /// Group changes replayed to the peer when it connected
+    pub(crate) replayed: u64,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+/// Joined groups along with count of joins and leaves made
+#[derive(Default)]
+pub(crate) struct Groups {
+    pub(crate) joined: HashSet<Vec<u8>>,
+    changes: u64,
+}
+
+pub(crate) struct DishSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, DishPeer>,
+    /// Never held across await, peers are sent changes after they are recorded
+    pub(crate) groups: Mutex<Groups>,
+    pub(crate) queue_sender: mpsc::Sender<(String, ZmqMessage)>,
+}
+
+impl DishSocketBackend {
+    /// Remembers group change and forwards it to peers that connected before it was made,
+    /// later ones got it in the replay. Peer that doesn't take it within send timeout
+    /// is dropped, its reconnect replays all groups
+    async fn update_group(
+        &self,
+        join: bool,
+        group: &str,
+        options: &SocketOptions,
+    ) -> ZmqResult<()> {
+        validate_group(group)?;
+        let (command, change) = {
+            let mut groups = self.groups.lock().unwrap();
+            let command = if join {
+                if !groups.joined.insert(group.as_bytes().to_vec()) {
+                    return Err(ZmqError::Socket("Group already joined"));
+                }
+                ZmtpCommand::join(group.as_bytes())
+            } else {
+                if !groups.joined.remove(group.as_bytes()) {
+                    return Err(ZmqError::Socket("Group was not joined"));
+                }
+                ZmtpCommand::leave(group.as_bytes())
+            };
+            groups.changes += 1;
+            (command, groups.changes)
+        };
+        let peer_ids: Vec<PeerIdentity> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.replayed < change)
+            .map(|peer| peer.key().clone())
+            .collect();
+        util::send_to_each(
+            &self.peers,
+            peer_ids,
+            |peer| &mut peer.send_queue,
+            Message::Command(command),
+            options,
+            None,
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Queues message for the frontend if its group is joined
+    async fn deliver(&self, group: String, body: ZmqMessage) {
+        // Message might be in flight when group is left
+        if !self
+            .groups
+            .lock()
+            .unwrap()
+            .joined
+            .contains(group.as_bytes())
+        {
+            return;
+        }
+        // Receiving side might be already dropped. Nothing to do with message in such case
+        let _ = self.queue_sender.clone().send((group, body)).await;
+    }
+}
+
+/// Reads datagrams until handle is dropped and passes them to backend
+fn start_receiving_datagrams(
+    mut socket: UdpSocket,
+    endpoint: Endpoint,
+    backend: Arc<DishSocketBackend>,
+    options: &SocketOptions,
+) -> ListenerHandle {
+    let (handle, stop_callback, closed_handle) = ListenerHandle::new(endpoint);
+    let task = options.track_task();
+    tokio::spawn(async move {
+        let _task = task;
+        let mut stop_callback = stop_callback.fuse();
+        let mut buffer = vec![0u8; udp::MAX_DATAGRAM_SIZE];
+        loop {
+            select! {
+                incoming = socket.recv_from(&mut buffer).fuse() => {
+                    let (size, _) = match incoming {
+                        Ok(received) => received,
+                        // Errors like ICMP port unreachable don't break the socket
+                        Err(_) => continue,
+                    };
+                    if let Some((group, body)) = udp::decode(&buffer[..size]) {
+                        backend.deliver(group, body).await;
+                    }
+                },
+                _ = stop_callback => {
+                    break
+                }
+            }
+        }
+        drop(socket);
+        drop(closed_handle);
+    });
+    handle
+}
+
+#[async_trait]
+impl SocketBackend for DishSocketBackend {
+    async fn message_received(&self, _peer_id: &PeerIdentity, message: Message) {
+        // Every message is expected to consist of group and body frames
+        let mut frames = match message {
+            Message::MultipartMessage(frames) if frames.len() == 2 => frames,
+            _ => return,
+        };
+        let body = frames.pop().unwrap();
+        let group = match String::from_utf8(frames.pop().unwrap().data.to_vec()) {
+            Ok(group) => group,
+            Err(_) => return,
+        };
+        self.deliver(group, body).await;
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::DISH
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
+
+#[async_trait]
+impl MultiPeer for DishSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        // Peer is registered under the lock, so changes made later are sent to it
+        let groups = self.groups.lock().unwrap();
+        // Queue should be big enough to replay all groups to a new peer
+        let (mut out_queue, out_queue_receiver) = bounded_queue(hwm.send.max(groups.joined.len()));
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        for group in groups.joined.iter() {
+            out_queue
+                .try_send(Message::Command(ZmtpCommand::join(group)))
+                .expect("Failed to queue join command");
+        }
+        self.peers.insert(
+            peer_id.clone(),
+            DishPeer {
+                send_queue: out_queue,
+                replayed: groups.changes,
+                _io_close_handle: stop_handle,
+            },
+        );
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+/// Receives messages published by RADIO sockets to joined groups
+pub struct DishSocket {
+    backend: Arc<DishSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    queue: mpsc::Receiver<(String, ZmqMessage)>,
+}
+
+impl Drop for DishSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl DishSocket {
+    /// Joins the group. Can be called before connect.
+    /// In such case group is joined on every new peer
+    pub async fn join(&mut self, group: &str) -> ZmqResult<()> {
+        self.backend.update_group(true, group, &self.options).await
+    }
+
+    pub async fn leave(&mut self, group: &str) -> ZmqResult<()> {
+        self.backend.update_group(false, group, &self.options).await
+    }
+
+    /// Receives next message together with the group it was published to
+    pub async fn recv(&mut self) -> ZmqResult<(String, ZmqMessage)> {
+        util::with_timeout(self.options.recv_timeout, self.queue.next())
+            .await?
+            .ok_or(ZmqError::NoMessage)
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for DishSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (queue_sender, queue) = mpsc::channel(recv_hwm);
+        Self {
+            backend: Arc::new(DishSocketBackend {
+                peers: DashMap::new(),
+                groups: Mutex::new(Groups::default()),
+                queue_sender,
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::DISH
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) = match endpoint.parse::<Endpoint>()? {
+            Endpoint::Udp(host, port) => {
+                util::listener_closed(&Endpoint::Udp(host.clone(), port)).await;
+                let (socket, endpoint) = udp::bind(host, port, &self.options).await?;
+                let handle = start_receiving_datagrams(
+                    socket,
+                    endpoint.clone(),
+                    self.backend.clone(),
+                    &self.options,
+                );
+                (endpoint, handle)
+            }
+            _ => {
+                util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                    .await?
+            }
+        };
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        if let Endpoint::Udp(..) = endpoint.parse::<Endpoint>()? {
+            return Err(ZmqError::Socket("DISH socket can only bind udp endpoint"));
+        }
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
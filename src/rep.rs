@@ -1,12 +1,17 @@
+use crate::close::CloseReport;
 use crate::codec::*;
+use crate::endpoint::Endpoint;
 use crate::error::*;
-use crate::fair_queue::FairQueue;
+use crate::fair_queue::start_fair_queue;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::util::bounded_queue;
 use crate::*;
 use crate::{SocketType, ZmqResult};
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures_util::sink::SinkExt;
 use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::stream::StreamExt;
 
 struct RepPeer {
@@ -16,13 +21,6 @@ struct RepPeer {
     pub(crate) _io_close_handle: futures::channel::oneshot::Sender<bool>,
 }
 
-struct FairQueueProcessor {
-    pub(crate) fair_queue_stream: FairQueue<mpsc::Receiver<Message>, PeerIdentity>,
-    pub(crate) socket_incoming_queue: mpsc::Sender<(PeerIdentity, Message)>,
-    pub(crate) peer_queue_in: mpsc::Receiver<(PeerIdentity, mpsc::Receiver<Message>)>,
-    pub(crate) _io_close_handle: oneshot::Receiver<bool>,
-}
-
 struct RepSocketBackend {
     pub(crate) peers: DashMap<PeerIdentity, RepPeer>,
     pub(crate) peer_queue_in: mpsc::Sender<(PeerIdentity, mpsc::Receiver<Message>)>,
@@ -30,100 +28,120 @@ struct RepSocketBackend {
 
 pub struct RepSocket {
     backend: Arc<RepSocketBackend>,
-    _accept_close_handle: Option<oneshot::Sender<bool>>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
     _fair_queue_close_handle: oneshot::Sender<bool>,
-    current_request: Option<PeerIdentity>,
+    /// Peer the request came from and routing frames of it up to and including delimiter
+    current_request: Option<(PeerIdentity, Vec<ZmqMessage>)>,
     fair_queue: mpsc::Receiver<(PeerIdentity, Message)>,
 }
 
 impl Drop for RepSocket {
     fn drop(&mut self) {
+        self.options.tasks.stop();
         self.backend.shutdown();
     }
 }
 
 #[async_trait]
 impl SocketFrontend for RepSocket {
-    fn new() -> Self {
-        // TODO define buffer size
-        let default_queue_size = 100;
-        let (queue_sender, fair_queue) = mpsc::channel(default_queue_size);
-        let (peer_in, peer_out) = mpsc::channel(default_queue_size);
-        let (fair_queue_close_handle, fqueue_close_recevier) = oneshot::channel();
-        tokio::spawn(process_fair_queue_messages(FairQueueProcessor {
-            fair_queue_stream: FairQueue::new(),
-            socket_incoming_queue: queue_sender,
-            peer_queue_in: peer_out,
-            _io_close_handle: fqueue_close_recevier,
-        }));
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
         Self {
             backend: Arc::new(RepSocketBackend {
                 peers: DashMap::new(),
                 peer_queue_in: peer_in,
             }),
-            _accept_close_handle: None,
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
             _fair_queue_close_handle: fair_queue_close_handle,
             current_request: None,
             fair_queue,
         }
     }
 
-    async fn bind(&mut self, endpoint: &str) -> ZmqResult<()> {
-        let stop_handle = util::start_accepting_connections(endpoint, self.backend.clone()).await?;
-        self._accept_close_handle = Some(stop_handle);
-        Ok(())
+    fn socket_type() -> SocketType {
+        SocketType::REP
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
     }
 
     async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
-        let addr = endpoint.parse::<SocketAddr>()?;
-        let raw_socket = tokio::net::TcpStream::connect(addr).await?;
-        util::peer_connected(raw_socket, self.backend.clone()).await;
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
         Ok(())
     }
-}
 
-async fn process_fair_queue_messages(mut processor: FairQueueProcessor) {
-    let mut stop_callback = processor._io_close_handle;
-    let mut waiting_for_clients = true;
-    let mut waiting_for_data = true;
-    loop {
-        tokio::select! {
-            _ = &mut stop_callback => {
-                break;
-            },
-            peer_in = processor.peer_queue_in.next(), if waiting_for_clients => {
-                match peer_in {
-                    Some((peer_id, receiver)) => {
-                        processor.fair_queue_stream.insert(peer_id, receiver);
-                        waiting_for_data = true;
-                    },
-                    None => {
-                        // Channel for newly connected clients was closed
-                        // so we no longer wait for the to arrive
-                        waiting_for_clients = false;
-                    },
-                };
-            },
-            message = processor.fair_queue_stream.next(), if waiting_for_data => {
-                match message {
-                    Some(m) => {
-                        processor.socket_incoming_queue.send(m).await.expect("Failed to deliver message");
-                    },
-                    None => {
-                        // This is the case when there are no connected clients
-                        // We should sleep and wait for new clients to connect
-                        // This is handled by 2nd branch of select
-                        if waiting_for_clients {
-                            waiting_for_data = false;
-                        } else {
-                            // We're not waiting for client and have no data...
-                            // stop the loop and cleanup
-                            break;
-                        };
-                    }
-                };
-            }
-        }
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
     }
 }
 
@@ -132,10 +150,11 @@ impl MultiPeer for RepSocketBackend {
     async fn peer_connected(
         &self,
         peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
     ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
-        let default_queue_size = 100;
-        let (out_queue, out_queue_receiver) = mpsc::channel(default_queue_size);
-        let (in_queue, in_queue_receiver) = mpsc::channel::<Message>(default_queue_size);
+        let (out_queue, out_queue_receiver) = bounded_queue(hwm.send);
+        let (in_queue, in_queue_receiver) = bounded_queue::<Message>(hwm.recv);
         let (stop_handle, stop_callback) = oneshot::channel::<bool>();
 
         self.peers.insert(
@@ -147,10 +166,15 @@ impl MultiPeer for RepSocketBackend {
                 _io_close_handle: stop_handle,
             },
         );
-        self.peer_queue_in
+        let registered = self
+            .peer_queue_in
             .clone()
-            .try_send((peer_id.clone(), in_queue_receiver))
-            .unwrap();
+            .try_send((peer_id.clone(), in_queue_receiver));
+        if registered.is_err() {
+            // Fair queue is stopped once socket is dropped. Connection finished
+            // handshake too late, so it gets closed right away
+            self.peers.remove(peer_id);
+        }
 
         (out_queue_receiver, stop_callback)
     }
@@ -158,6 +182,10 @@ impl MultiPeer for RepSocketBackend {
     async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
         self.peers.remove(peer_id);
     }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
 }
 
 #[async_trait]
@@ -182,45 +210,83 @@ impl SocketBackend for RepSocketBackend {
 }
 
 impl NonBlockingSend for RepSocket {
-    fn send(&mut self, message: ZmqMessage) -> ZmqResult<()> {
-        match self.current_request.take() {
-            Some(peer_id) => {
-                if let Some(mut peer) = self.backend.peers.get_mut(&peer_id) {
-                    let frames = vec![
-                        "".into(), // delimiter frame
-                        message,
-                    ];
-                    peer.send_queue
-                        .try_send(Message::MultipartMessage(frames))?;
-                    Ok(())
-                } else {
-                    Err(ZmqError::ReturnToSender {
-                        reason: "Client disconnected",
-                        message,
-                    })
-                }
+    fn send<M: Into<ZmqMessage>>(&mut self, message: M) -> ZmqResult<()> {
+        let message = message.into();
+        match self.take_request() {
+            Ok((peer_id, mut send_queue, mut envelope)) => {
+                envelope.push(message);
+                self.try_send(&peer_id, &mut send_queue, envelope)
             }
-            None => Err(ZmqError::ReturnToSender {
-                reason: "Unable to send reply. No request in progress",
-                message,
-            }),
+            Err(reason) => Err(ZmqError::ReturnToSender { reason, message }),
+        }
+    }
+
+    fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        let (peer_id, mut send_queue, mut envelope) =
+            self.take_request().map_err(ZmqError::Socket)?;
+        envelope.extend(frames);
+        self.try_send(&peer_id, &mut send_queue, envelope)
+    }
+}
+
+impl RepSocket {
+    /// Routing frames of the request being served, up to and including the delimiter.
+    /// Reply is prefixed with them. None if there is no request to reply to
+    pub fn envelope(&self) -> Option<&[ZmqMessage]> {
+        self.current_request
+            .as_ref()
+            .map(|(_peer_id, envelope)| envelope.as_slice())
+    }
+
+    /// Peer to reply to, their queue and routing frames reply should be prefixed with
+    fn take_request(
+        &mut self,
+    ) -> Result<(PeerIdentity, mpsc::Sender<Message>, Vec<ZmqMessage>), &'static str> {
+        match self.current_request.take() {
+            Some((peer_id, envelope)) => match self.backend.peers.get(&peer_id) {
+                Some(peer) => Ok((peer_id.clone(), peer.send_queue.clone(), envelope)),
+                None => Err("Client disconnected"),
+            },
+            None => Err("Unable to send reply. No request in progress"),
         }
     }
+
+    fn try_send(
+        &self,
+        peer_id: &PeerIdentity,
+        send_queue: &mut mpsc::Sender<Message>,
+        envelope: Vec<ZmqMessage>,
+    ) -> ZmqResult<()> {
+        self.options.stats.enqueued(peer_id);
+        send_queue
+            .try_send(Message::MultipartMessage(envelope))
+            .map_err(|e| {
+                self.options.stats.not_enqueued(peer_id);
+                e.into()
+            })
+    }
 }
 
 #[async_trait]
 impl BlockingRecv for RepSocket {
-    async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+    /// Receives all frames of the request. Routing frames up to the delimiter are kept
+    /// for the reply and are not returned
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
         loop {
-            match self.fair_queue.next().await {
-                Some((peer_id, Message::MultipartMessage(mut messages))) => {
-                    assert!(messages.len() == 2);
-                    assert!(messages[0].data.is_empty()); // Ensure that we have delimeter as first part
-                    self.current_request = Some(peer_id);
-                    return Ok(messages.pop().unwrap());
+            match util::with_timeout(self.options.recv_timeout, self.fair_queue.next()).await? {
+                Some((peer_id, message)) => {
+                    let mut frames = match message.into_frames() {
+                        Some(frames) => frames,
+                        None => continue,
+                    };
+                    // Requests without delimiter are malformed and silently dropped
+                    if let Some(delimiter) = frames.iter().position(|f| f.data.is_empty()) {
+                        let body = frames.split_off(delimiter + 1);
+                        self.current_request = Some((peer_id, frames));
+                        return Ok(body);
+                    }
                 }
-                Some((_peer_id, _)) => todo!(),
-                None => todo!(),
+                None => return Err(ZmqError::NoMessage),
             };
         }
     }
@@ -1,5 +1,8 @@
+use crate::close::CloseReport;
 use crate::codec::*;
+use crate::endpoint::Endpoint;
 use crate::error::*;
+use crate::options::{HighWaterMarks, SocketOptions};
 use crate::util::{self, Peer, PeerIdentity};
 use crate::*;
 use crate::{SocketType, ZmqResult};
@@ -10,6 +13,7 @@ use futures::channel::{mpsc, oneshot};
 use futures::lock::Mutex;
 use futures_util::sink::SinkExt;
 use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::stream::StreamExt;
 
 struct ReqSocketBackend {
@@ -20,66 +24,45 @@ struct ReqSocketBackend {
 
 pub struct ReqSocket {
     backend: Arc<ReqSocketBackend>,
-    _accept_close_handle: Option<oneshot::Sender<bool>>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
     current_request: Option<PeerIdentity>,
+    /// Id of the last request sent, see `SocketOptions::req_correlate`
+    request_id: u32,
 }
 
 impl Drop for ReqSocket {
     fn drop(&mut self) {
+        self.options.tasks.stop();
         self.backend.shutdown();
     }
 }
 
 #[async_trait]
 impl BlockingSend for ReqSocket {
-    async fn send(&mut self, message: ZmqMessage) -> ZmqResult<()> {
-        if self.current_request.is_some() {
-            return Err(ZmqError::ReturnToSender {
-                reason: "Unable to send message. Request already in progress",
-                message,
-            });
-        }
-        // In normal scenario this will always be only 1 iteration
-        // There can be special case when peer has disconnected and his id is still in RR queue
-        // This happens because SegQueue don't have an api to delete items from queue.
-        // So in such case we'll just pop item and skip it if we don't have a matching peer in peers map
-        loop {
-            let next_peer_id = match self.backend.round_robin.pop() {
-                Ok(peer) => peer,
-                Err(_) => {
-                    return Err(ZmqError::ReturnToSender {
-                        reason: "Not connected to peers. Unable to send messages",
-                        message,
-                    })
-                }
-            };
-            match self.backend.peers.get_mut(&next_peer_id) {
-                Some(mut peer) => {
-                    self.backend.round_robin.push(next_peer_id.clone());
-                    let frames = vec![
-                        "".into(), // delimiter frame
-                        message,
-                    ];
-                    peer.send_queue
-                        .send(Message::MultipartMessage(frames))
-                        .await?;
-                    self.backend
-                        .current_request_peer_id
-                        .lock()
-                        .await
-                        .replace(next_peer_id.clone());
-                    self.current_request = Some(next_peer_id);
-                    return Ok(());
-                }
-                None => continue,
-            }
+    async fn send<M>(&mut self, message: M) -> ZmqResult<()>
+    where
+        M: Into<ZmqMessage> + Send,
+    {
+        let message = message.into();
+        match self.next_peer().await {
+            Ok(peer_id) => self.send_request(peer_id, vec![message]).await,
+            Err(reason) => Err(ZmqError::ReturnToSender { reason, message }),
         }
     }
+
+    async fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        let peer_id = self.next_peer().await.map_err(ZmqError::Socket)?;
+        self.send_request(peer_id, frames).await
+    }
 }
 
 #[async_trait]
 impl BlockingRecv for ReqSocket {
-    async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+    /// Receives all frames of the reply. Delimiter frame is stripped
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
         match self.current_request.take() {
             Some(peer_id) => {
                 if let Some(recv_queue) = self
@@ -88,18 +71,31 @@ impl BlockingRecv for ReqSocket {
                     .get(&peer_id)
                     .map(|p| p.recv_queue.clone())
                 {
-                    let message = recv_queue.lock().await.next().await;
-                    match message {
-                        Some(Message::MultipartMessage(mut message)) => {
-                            assert!(message.len() == 2);
-                            assert!(message[0].data.is_empty()); // Ensure that we have delimeter as first part
-                            Ok(message.pop().unwrap())
+                    let timeout = self.options.recv_timeout;
+                    let mut recv_queue = recv_queue.lock().await;
+                    loop {
+                        let next = match util::with_timeout(timeout, recv_queue.next()).await {
+                            Ok(next) => next,
+                            Err(e) => {
+                                // Reply may still arrive, so request stays in progress
+                                self.current_request = Some(peer_id);
+                                return Err(e);
+                            }
+                        };
+                        match next.map(Message::into_frames) {
+                            Some(Some(frames)) => match self.strip_envelope(frames) {
+                                Some(frames) => return Ok(frames),
+                                None => continue,
+                            },
+                            Some(None) => {
+                                return Err(ZmqError::Other("Wrong message type received"))
+                            }
+                            // Queue is closed once peer is removed from backend
+                            None => return Err(ZmqError::ConnectionLost),
                         }
-                        Some(_) => Err(ZmqError::Other("Wrong message type received")),
-                        None => Err(ZmqError::NoMessage),
                     }
                 } else {
-                    Err(ZmqError::Other("Server disconnected"))
+                    Err(ZmqError::ConnectionLost)
                 }
             }
             None => Err(ZmqError::Other("Unable to recv. No request in progress")),
@@ -107,37 +103,197 @@ impl BlockingRecv for ReqSocket {
     }
 }
 
+impl ReqSocket {
+    /// Request still waiting for reply blocks the next one unless `req_relaxed` is set,
+    /// in which case it is abandoned and reply that already arrived is discarded
+    async fn abandon_request(&mut self) -> Result<(), &'static str> {
+        let peer_id = match self.current_request.take() {
+            Some(peer_id) => peer_id,
+            None => return Ok(()),
+        };
+        if !self.options.req_relaxed {
+            self.current_request = Some(peer_id);
+            return Err("Unable to send message. Request already in progress");
+        }
+        // Replies arriving from now on are not expected, see `message_received`
+        self.backend.current_request_peer_id.lock().await.take();
+        if let Some(recv_queue) = self
+            .backend
+            .peers
+            .get(&peer_id)
+            .map(|p| p.recv_queue.clone())
+        {
+            let mut recv_queue = recv_queue.lock().await;
+            while recv_queue.try_recv().is_ok() {}
+        }
+        Ok(())
+    }
+
+    /// Removes delimiter frame, preceded by request id with `req_correlate`, from the reply.
+    /// Malformed replies and replies to other requests are silently dropped
+    fn strip_envelope(&self, mut frames: Vec<ZmqMessage>) -> Option<Vec<ZmqMessage>> {
+        let envelope_len = if self.options.req_correlate {
+            if frames.first()?.data.as_ref() != self.request_id.to_be_bytes() {
+                return None;
+            }
+            2
+        } else {
+            1
+        };
+        if !frames.get(envelope_len - 1)?.data.is_empty() {
+            return None;
+        }
+        Some(frames.split_off(envelope_len))
+    }
+
+    /// Picks peer for the next request in round robin order
+    async fn next_peer(&mut self) -> Result<PeerIdentity, &'static str> {
+        self.abandon_request().await?;
+        // In normal scenario this will always be only 1 iteration
+        // There can be special case when peer has disconnected and his id is still in RR queue
+        // This happens because SegQueue don't have an api to delete items from queue.
+        // So in such case we'll just pop item and skip it if we don't have a matching peer in peers map
+        loop {
+            let next_peer_id = match self.backend.round_robin.pop() {
+                Ok(peer) => peer,
+                Err(_) => return Err("Not connected to peers. Unable to send messages"),
+            };
+            if self.backend.peers.contains_key(&next_peer_id) {
+                self.backend.round_robin.push(next_peer_id.clone());
+                return Ok(next_peer_id);
+            }
+        }
+    }
+
+    /// Sends request frames prefixed with delimiter frame.
+    /// Request is only in progress once it is queued, so timed out send can be retried
+    async fn send_request(
+        &mut self,
+        peer_id: PeerIdentity,
+        frames: Vec<ZmqMessage>,
+    ) -> ZmqResult<()> {
+        let mut request = Vec::with_capacity(frames.len() + 2);
+        let request_id = self.request_id.wrapping_add(1);
+        if self.options.req_correlate {
+            request.push(ZmqMessage::from(request_id.to_be_bytes().to_vec()));
+        }
+        request.push(ZmqMessage::from("")); // delimiter frame
+        request.extend(frames);
+        let send = util::send_to_peer(
+            &self.backend.peers,
+            &peer_id,
+            |peer| &mut peer.send_queue,
+            Message::MultipartMessage(request),
+            &self.options.stats,
+        );
+        util::with_timeout(self.options.send_timeout, send).await??;
+        self.request_id = request_id;
+        self.backend
+            .current_request_peer_id
+            .lock()
+            .await
+            .replace(peer_id.clone());
+        self.current_request = Some(peer_id);
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl SocketFrontend for ReqSocket {
-    fn new() -> Self {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
         Self {
             backend: Arc::new(ReqSocketBackend {
                 peers: DashMap::new(),
                 round_robin: SegQueue::new(),
                 current_request_peer_id: Mutex::new(None),
             }),
-            _accept_close_handle: None,
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
             current_request: None,
+            request_id: rand::random(),
         }
     }
 
-    async fn bind(&mut self, endpoint: &str) -> ZmqResult<()> {
-        if self._accept_close_handle.is_some() {
-            return Err(ZmqError::Other(
-                "Socket server already started. Currently only one server is supported",
-            ));
-        }
-        let stop_handle = util::start_accepting_connections(endpoint, self.backend.clone()).await?;
-        self._accept_close_handle = Some(stop_handle);
-        Ok(())
+    fn socket_type() -> SocketType {
+        SocketType::REQ
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
     }
 
     async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
-        let addr = endpoint.parse::<SocketAddr>()?;
-        let raw_socket = tokio::net::TcpStream::connect(addr).await?;
-        util::peer_connected(raw_socket, self.backend.clone()).await;
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
         Ok(())
     }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
 }
 
 #[async_trait]
@@ -145,6 +301,8 @@ impl MultiPeer for ReqSocketBackend {
     async fn peer_connected(
         &self,
         peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        _hwm: HighWaterMarks,
     ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
         let default_queue_size = 1;
         let (out_queue, out_queue_receiver) = mpsc::channel(default_queue_size);
@@ -154,7 +312,7 @@ impl MultiPeer for ReqSocketBackend {
         self.peers.insert(
             peer_id.clone(),
             Peer {
-                identity: peer_id.clone(),
+                _identity: peer_id.clone(),
                 send_queue: out_queue,
                 recv_queue: Arc::new(Mutex::new(in_queue_receiver)),
                 recv_queue_in: in_queue,
@@ -169,6 +327,10 @@ impl MultiPeer for ReqSocketBackend {
     async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
         self.peers.remove(peer_id);
     }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
 }
 
 #[async_trait]
@@ -186,14 +348,14 @@ impl SocketBackend for ReqSocketBackend {
             None => return,
         }
         drop(curr_req_lock);
-        // We've got reply that we were waiting for
-        self.peers
-            .get_mut(peer_id)
-            .expect("Not found peer by id")
-            .recv_queue_in
-            .send(message)
-            .await
-            .expect("Failed to send");
+        // We've got reply that we were waiting for. Peers map must not stay locked
+        // while waiting for room in the queue, as recv looks the peer up meanwhile
+        let mut recv_queue_in = match self.peers.get(peer_id) {
+            Some(peer) => peer.recv_queue_in.clone(),
+            None => return,
+        };
+        // Receiving side might be already dropped
+        let _ = recv_queue_in.send(message).await;
     }
 
     fn socket_type(&self) -> SocketType {
@@ -0,0 +1,261 @@
+use async_trait::async_trait;
+use futures::channel::{mpsc, oneshot};
+use futures::lock::Mutex;
+use std::sync::Arc;
+
+use crate::client_server::{recv_from, ThreadSafeSocketBackend};
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::endpoint::Endpoint;
+use crate::fair_queue::start_fair_queue;
+use crate::message::*;
+use crate::options::SocketOptions;
+use crate::security::Authenticator;
+use crate::util::*;
+use crate::{util, SocketBackend, SocketFrontend};
+use crate::{SocketType, ZmqResult};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Thread safe counterpart of PushSocket.
+/// Single frame messages are distributed round robin between connected peers
+pub struct ScatterSocket {
+    backend: Arc<ThreadSafeSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+}
+
+impl Drop for ScatterSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl ScatterSocket {
+    pub async fn send(&self, message: ZmqMessage) -> ZmqResult<()> {
+        let send = self.backend.send_round_robin(message, &self.options.stats);
+        util::with_timeout(self.options.send_timeout, send).await?
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for ScatterSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        // SCATTER never receives messages but shared backend still registers peers in fair queue
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, _fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
+        Self {
+            backend: Arc::new(ThreadSafeSocketBackend::new(SocketType::SCATTER, peer_in)),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::SCATTER
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
+
+/// Thread safe counterpart of PullSocket.
+/// Single frame messages are fair queued from all connected peers
+pub struct GatherSocket {
+    backend: Arc<ThreadSafeSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    _fair_queue_close_handle: oneshot::Sender<bool>,
+    fair_queue: Mutex<mpsc::Receiver<(PeerIdentity, Message)>>,
+}
+
+impl Drop for GatherSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl GatherSocket {
+    /// Receives next message. Multipart messages are rejected with an error
+    pub async fn recv(&self) -> ZmqResult<ZmqMessage> {
+        let (_peer_id, message) = recv_from(&self.fair_queue, self.options.recv_timeout).await?;
+        Ok(message)
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for GatherSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (peer_in, fair_queue, fair_queue_close_handle) = start_fair_queue(recv_hwm);
+        Self {
+            backend: Arc::new(ThreadSafeSocketBackend::new(SocketType::GATHER, peer_in)),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            _fair_queue_close_handle: fair_queue_close_handle,
+            fair_queue: Mutex::new(fair_queue),
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::GATHER
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
@@ -0,0 +1,314 @@
+//! Security mechanisms negotiated right after greeting.
+//! NULL handshake is just READY exchange so it lives in util together with metadata checks
+use crate::codec::*;
+#[cfg(feature = "curve")]
+use crate::curve;
+use crate::error::*;
+use crate::options::SocketOptions;
+use crate::util::{self, ZmqStream};
+use crate::ZmqResult;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio_util::codec::Framed;
+
+const INVALID_CREDENTIALS: &str = "Invalid username or password";
+
+/// Credentials presented by the peer during security handshake
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    Null,
+    Plain {
+        username: String,
+        password: String,
+    },
+    /// Long term public key of CURVE client
+    Curve {
+        public_key: [u8; 32],
+    },
+}
+
+/// Everything known about the peer at the moment it has to be authenticated
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    /// Remote address for IP based transports
+    pub address: Option<SocketAddr>,
+    /// Domain configured with `SocketOptions::zap_domain`
+    pub domain: String,
+    pub credentials: Credentials,
+}
+
+impl AuthRequest {
+    /// Name of the security mechanism peer is using, e.g. "PLAIN"
+    pub fn mechanism(&self) -> &'static str {
+        match self.credentials {
+            Credentials::Null => "NULL",
+            Credentials::Plain { .. } => "PLAIN",
+            Credentials::Curve { .. } => "CURVE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthResult {
+    /// Peer may connect. User-Id is attached to peer metadata
+    Allowed { user_id: Option<String> },
+    /// Peer receives ERROR command with the reason and gets disconnected
+    Denied { reason: String },
+}
+
+impl AuthResult {
+    pub fn allow() -> Self {
+        AuthResult::Allowed { user_id: None }
+    }
+
+    pub fn deny(reason: &str) -> Self {
+        AuthResult::Denied {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Decides which peers may connect, same as ZAP handler does for libzmq.
+/// Called during handshake before peer is registered in socket
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, request: AuthRequest) -> AuthResult;
+}
+
+/// Accepts only PLAIN peers with username/password pair present in the map
+pub(crate) struct StaticPlainAuthenticator(pub(crate) HashMap<String, String>);
+
+#[async_trait]
+impl Authenticator for StaticPlainAuthenticator {
+    async fn authenticate(&self, request: AuthRequest) -> AuthResult {
+        match request.credentials {
+            Credentials::Plain { username, password }
+                if self.0.get(&username) == Some(&password) =>
+            {
+                AuthResult::allow()
+            }
+            _ => AuthResult::deny(INVALID_CREDENTIALS),
+        }
+    }
+}
+
+/// Checks PLAIN username and password with callback
+pub(crate) struct PlainCallbackAuthenticator<F>(pub(crate) F);
+
+#[async_trait]
+impl<F, Fut> Authenticator for PlainCallbackAuthenticator<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = bool> + Send,
+{
+    async fn authenticate(&self, request: AuthRequest) -> AuthResult {
+        let allowed = match request.credentials {
+            Credentials::Plain { username, password } => (self.0)(username, password).await,
+            _ => false,
+        };
+        if allowed {
+            AuthResult::allow()
+        } else {
+            AuthResult::deny(INVALID_CREDENTIALS)
+        }
+    }
+}
+
+/// Runs socket's authenticator if there is one. Returns User-Id assigned to the peer.
+/// Denied peer is notified with ERROR command
+pub(crate) async fn authenticate<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    options: &SocketOptions,
+    address: Option<SocketAddr>,
+    credentials: Credentials,
+) -> ZmqResult<Option<String>> {
+    let authenticator = match &options.authenticator {
+        Some(authenticator) => authenticator,
+        None => return Ok(None),
+    };
+    let request = AuthRequest {
+        address,
+        domain: options.zap_domain.clone(),
+        credentials,
+    };
+    match authenticator.authenticate(request).await {
+        AuthResult::Allowed { user_id } => Ok(user_id),
+        AuthResult::Denied { reason } => {
+            util::send_error(socket, &reason).await?;
+            Err(ZmqError::Authentication(reason))
+        }
+    }
+}
+
+/// Adds User-Id assigned by authenticator to peer metadata.
+/// Peer can't claim User-Id on its own
+pub(crate) fn attach_user_id(mut metadata: Properties, user_id: Option<String>) -> Properties {
+    metadata.remove("User-Id");
+    if let Some(user_id) = user_id {
+        metadata.insert("User-Id".to_string(), user_id.into_bytes());
+    }
+    metadata
+}
+
+#[derive(Clone, Default)]
+pub(crate) enum Security {
+    #[default]
+    Null,
+    PlainClient {
+        username: String,
+        password: String,
+    },
+    /// Credentials are checked by socket's authenticator
+    PlainServer,
+    #[cfg(feature = "curve")]
+    CurveClient {
+        public_key: [u8; curve::KEY_SIZE],
+        secret_key: [u8; curve::KEY_SIZE],
+        server_key: [u8; curve::KEY_SIZE],
+    },
+    #[cfg(feature = "curve")]
+    CurveServer {
+        public_key: [u8; curve::KEY_SIZE],
+        secret_key: [u8; curve::KEY_SIZE],
+    },
+}
+
+impl Security {
+    pub(crate) fn mechanism(&self) -> ZmqMechanism {
+        match self {
+            Security::Null => ZmqMechanism::NULL,
+            Security::PlainClient { .. } | Security::PlainServer => ZmqMechanism::PLAIN,
+            #[cfg(feature = "curve")]
+            Security::CurveClient { .. } | Security::CurveServer { .. } => ZmqMechanism::CURVE,
+        }
+    }
+
+    pub(crate) fn as_server(&self) -> bool {
+        match self {
+            Security::PlainServer => true,
+            #[cfg(feature = "curve")]
+            Security::CurveServer { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+async fn next_command<S: ZmqStream>(socket: &mut Framed<S, ZmqCodec>) -> ZmqResult<ZmtpCommand> {
+    match socket.next().await {
+        Some(Ok(Message::Command(ZmtpCommand::Error { reason }))) => {
+            Err(ZmqError::Rejected(reason))
+        }
+        Some(Ok(Message::Command(command))) => Ok(command),
+        Some(Ok(_)) => Err(ZmqError::Codec("Expected handshake command")),
+        Some(Err(e)) => Err(e),
+        None => Err(ZmqError::Other("No reply from server")),
+    }
+}
+
+/// Sends credentials and metadata. Returns metadata of the server
+pub(crate) async fn plain_client<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    metadata: Properties,
+    username: &str,
+    password: &str,
+) -> ZmqResult<Properties> {
+    if username.len() > 255 || password.len() > 255 {
+        return Err(ZmqError::Socket("PLAIN credentials are too long"));
+    }
+    let mut hello = BytesMut::with_capacity(2 + username.len() + password.len());
+    hello.put_u8(username.len() as u8);
+    hello.extend_from_slice(username.as_bytes());
+    hello.put_u8(password.len() as u8);
+    hello.extend_from_slice(password.as_bytes());
+    socket
+        .send(Message::Command(ZmtpCommand::Hello(hello.freeze())))
+        .await?;
+
+    if !matches!(next_command(socket).await?, ZmtpCommand::Welcome(_)) {
+        return Err(ZmqError::Codec("Expected WELCOME command"));
+    }
+    socket
+        .send(Message::Command(ZmtpCommand::Initiate(metadata)))
+        .await?;
+
+    match next_command(socket).await? {
+        ZmtpCommand::Ready(properties) => Ok(properties),
+        _ => Err(ZmqError::Codec("Expected READY command")),
+    }
+}
+
+/// Verifies client credentials and exchanges metadata. Returns metadata of the client.
+/// Rejected clients receive ERROR command before connection is dropped
+pub(crate) async fn plain_server<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    metadata: Properties,
+    options: &SocketOptions,
+    address: Option<SocketAddr>,
+) -> ZmqResult<Properties> {
+    let hello = match next_command(socket).await? {
+        ZmtpCommand::Hello(hello) => hello,
+        _ => return Err(ZmqError::Codec("Expected HELLO command")),
+    };
+    let (username, password) = match parse_plain_hello(&hello) {
+        Some(credentials) => credentials,
+        None => {
+            let error = ZmtpCommand::error("Malformed HELLO command");
+            socket.send(Message::Command(error)).await?;
+            return Err(ZmqError::Codec("Malformed HELLO command"));
+        }
+    };
+    let credentials = Credentials::Plain { username, password };
+    let user_id = authenticate(socket, options, address, credentials).await?;
+    socket
+        .send(Message::Command(ZmtpCommand::Welcome(Bytes::new())))
+        .await?;
+
+    let initiate = match next_command(socket).await? {
+        ZmtpCommand::Initiate(properties) => properties,
+        _ => return Err(ZmqError::Codec("Expected INITIATE command")),
+    };
+    socket
+        .send(Message::Command(ZmtpCommand::Ready(metadata)))
+        .await?;
+    Ok(attach_user_id(initiate, user_id))
+}
+
+fn parse_plain_hello(data: &[u8]) -> Option<(String, String)> {
+    let (username_len, rest) = data.split_first()?;
+    let username_len = *username_len as usize;
+    if rest.len() < username_len {
+        return None;
+    }
+    let (username, rest) = rest.split_at(username_len);
+    let (password_len, password) = rest.split_first()?;
+    if password.len() != *password_len as usize {
+        return None;
+    }
+    Some((
+        String::from_utf8(username.to_vec()).ok()?,
+        String::from_utf8(password.to_vec()).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_hello() {
+        assert_eq!(
+            Some(("admin".to_string(), "secret".to_string())),
+            parse_plain_hello(b"\x05admin\x06secret")
+        );
+        assert_eq!(
+            Some((String::new(), String::new())),
+            parse_plain_hello(b"\x00\x00")
+        );
+        assert_eq!(None, parse_plain_hello(b"\x05adm"));
+        assert_eq!(None, parse_plain_hello(b"\x05admin\x06sec"));
+    }
+}
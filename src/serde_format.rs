@@ -0,0 +1,42 @@
+//! Wire formats for `send_serde`/`recv_serde`. Enabled with `json` and `bincode` features
+#[cfg(any(feature = "json", feature = "bincode"))]
+use crate::error::ZmqError;
+use crate::ZmqResult;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Turns values into message payload and back
+pub trait SerdeFormat: Send + Sync {
+    fn serialize<T: Serialize + ?Sized>(&self, value: &T) -> ZmqResult<Vec<u8>>;
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> ZmqResult<T>;
+}
+
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl SerdeFormat for Json {
+    fn serialize<T: Serialize + ?Sized>(&self, value: &T) -> ZmqResult<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| ZmqError::Serde(Box::new(e)))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> ZmqResult<T> {
+        serde_json::from_slice(data).map_err(|e| ZmqError::Serde(Box::new(e)))
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl SerdeFormat for Bincode {
+    fn serialize<T: Serialize + ?Sized>(&self, value: &T) -> ZmqResult<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| ZmqError::Serde(e))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> ZmqResult<T> {
+        bincode::deserialize(data).map_err(|e| ZmqError::Serde(e))
+    }
+}
@@ -0,0 +1,202 @@
+use crate::endpoint::{EndpointError, Host};
+use crate::error::*;
+use crate::transport;
+use crate::ZmqResult;
+use std::net::SocketAddr;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT_COMMAND: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SocksError {
+    #[error("Proxy doesn't support any of offered authentication methods")]
+    NoAcceptableAuthMethod,
+    #[error("Proxy rejected provided credentials")]
+    AuthenticationFailed,
+    #[error("General proxy server failure")]
+    GeneralFailure,
+    #[error("Connection not allowed by proxy ruleset")]
+    NotAllowed,
+    #[error("Network unreachable from proxy")]
+    NetworkUnreachable,
+    #[error("Host unreachable from proxy")]
+    HostUnreachable,
+    #[error("Connection refused by destination host")]
+    ConnectionRefused,
+    #[error("TTL expired")]
+    TtlExpired,
+    #[error("Command not supported by proxy")]
+    CommandNotSupported,
+    #[error("Address type not supported by proxy")]
+    AddressTypeNotSupported,
+    #[error("{0}")]
+    Protocol(&'static str),
+}
+
+impl From<SocksError> for ZmqError {
+    fn from(e: SocksError) -> Self {
+        ZmqError::Socks(e)
+    }
+}
+
+/// SOCKS5 proxy used to establish outgoing tcp connections
+#[derive(Debug, Clone)]
+pub struct SocksProxy {
+    pub(crate) address: String,
+    pub(crate) credentials: Option<(String, String)>,
+    pub(crate) remote_dns: bool,
+}
+
+impl SocksProxy {
+    /// Proxy listening on `host:port`
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            credentials: None,
+            remote_dns: false,
+        }
+    }
+
+    /// Username/password authentication (RFC 1929)
+    pub fn credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Lets proxy resolve hostnames (socks5h behaviour) instead of resolving them locally
+    pub fn remote_dns(mut self, enabled: bool) -> Self {
+        self.remote_dns = enabled;
+        self
+    }
+}
+
+enum Destination {
+    Address(SocketAddr),
+    Domain(String),
+}
+
+/// Opens connection to host:port through the proxy
+pub(crate) async fn connect(proxy: &SocksProxy, host: Host, port: u16) -> ZmqResult<TcpStream> {
+    let addresses = match host {
+        Host::Ipv4(ip) => vec![SocketAddr::from((ip, port))],
+        Host::Ipv6(ip) => vec![SocketAddr::from((ip, port))],
+        Host::Domain(name) if proxy.remote_dns => {
+            return connect_destination(proxy, Destination::Domain(name), port).await
+        }
+        Host::Domain(name) => transport::resolve(&name, port).await?,
+        Host::Wildcard => {
+            return Err(ZmqError::Endpoint(EndpointError::Syntax(
+                "Wildcard host can only be used to bind",
+            )))
+        }
+    };
+    let mut last_error = None;
+    for address in addresses {
+        match connect_destination(proxy, Destination::Address(address), port).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.expect("At least one address is always resolved"))
+}
+
+async fn connect_destination(
+    proxy: &SocksProxy,
+    destination: Destination,
+    port: u16,
+) -> ZmqResult<TcpStream> {
+    let mut stream = TcpStream::connect(proxy.address.as_str()).await?;
+    authenticate(&mut stream, proxy).await?;
+
+    let mut request = vec![SOCKS_VERSION, CONNECT_COMMAND, 0];
+    match destination {
+        Destination::Address(SocketAddr::V4(address)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&address.ip().octets());
+        }
+        Destination::Address(SocketAddr::V6(address)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&address.ip().octets());
+        }
+        Destination::Domain(name) => {
+            if name.len() > 255 {
+                return Err(SocksError::Protocol("Hostname is too long").into());
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(SocksError::Protocol("Unexpected proxy protocol version").into());
+    }
+    match reply[1] {
+        0 => (),
+        1 => return Err(SocksError::GeneralFailure.into()),
+        2 => return Err(SocksError::NotAllowed.into()),
+        3 => return Err(SocksError::NetworkUnreachable.into()),
+        4 => return Err(SocksError::HostUnreachable.into()),
+        5 => return Err(SocksError::ConnectionRefused.into()),
+        6 => return Err(SocksError::TtlExpired.into()),
+        7 => return Err(SocksError::CommandNotSupported.into()),
+        8 => return Err(SocksError::AddressTypeNotSupported.into()),
+        _ => return Err(SocksError::Protocol("Unknown proxy reply code").into()),
+    }
+    // Bound address is of no use for us but has to be consumed from the stream
+    let address_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(SocksError::Protocol("Unknown address type in proxy reply").into()),
+    };
+    let mut bound_address = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound_address).await?;
+    Ok(stream)
+}
+
+async fn authenticate(stream: &mut TcpStream, proxy: &SocksProxy) -> ZmqResult<()> {
+    let method = match proxy.credentials {
+        Some(_) => USERNAME_PASSWORD,
+        None => NO_AUTHENTICATION,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(SocksError::Protocol("Unexpected proxy protocol version").into());
+    }
+    if choice[1] == NO_ACCEPTABLE_METHODS || choice[1] != method {
+        return Err(SocksError::NoAcceptableAuthMethod.into());
+    }
+    if let Some((username, password)) = &proxy.credentials {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(SocksError::Protocol("Proxy credentials are too long").into());
+        }
+        let mut request = vec![AUTH_VERSION, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request).await?;
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0 {
+            return Err(SocksError::AuthenticationFailed.into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! Message and byte counters of a socket and its peers, see `SocketFrontend::stats`
+use crate::codec::Message;
+use crate::util::PeerIdentity;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counters of a single peer. Multipart message counts as one message,
+/// bytes are counted as encoded on the wire. Commands aren't counted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Messages discarded because queue of the peer was full
+    pub messages_dropped: u64,
+    /// Messages waiting in the queue of the peer to be written. Totals sum up connected
+    /// peers. Conflated queues hold one message at most and aren't tracked
+    pub messages_queued: u64,
+}
+
+/// Counters of the socket since it was created, together with breakdown
+/// of peers that are currently connected
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketStats {
+    pub totals: PeerStats,
+    pub connected_peers: usize,
+    /// Peers refused because socket reached its peer limit
+    pub rejected_peers: u64,
+    pub peers: HashMap<PeerIdentity, PeerStats>,
+}
+
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_dropped: AtomicU64,
+}
+
+impl Counters {
+    fn load(&self) -> PeerStats {
+        PeerStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            messages_queued: 0,
+        }
+    }
+}
+
+/// User data put into the queue of the peer and taken out of it. Lives as long as
+/// the queue, which may outlast connections of the peer, see `SocketOptions::immediate`
+#[derive(Default)]
+struct QueueCounters {
+    enqueued: AtomicU64,
+    taken: AtomicU64,
+}
+
+impl QueueCounters {
+    fn queued(&self) -> u64 {
+        // Message is counted in before it enters the queue and out after it leaves,
+        // so reading taken first never sees more of them than were counted in
+        let taken = self.taken.load(Ordering::SeqCst);
+        self.enqueued.load(Ordering::SeqCst) - taken
+    }
+}
+
+/// Counts message if it carries user data. Returns its size on the wire
+fn data_len(message: &Message) -> Option<u64> {
+    match message {
+        Message::Message(_) | Message::MultipartMessage(_) | Message::Shared(_) => {
+            Some(message.encoded_len() as u64)
+        }
+        Message::Greeting(_) | Message::Command(_) => None,
+    }
+}
+
+/// Counters shared by socket and its connections
+#[derive(Clone, Default)]
+pub(crate) struct Stats {
+    totals: Arc<Counters>,
+    peers: Arc<DashMap<PeerIdentity, Arc<Counters>>>,
+    queues: Arc<DashMap<PeerIdentity, Arc<QueueCounters>>>,
+    rejected_peers: Arc<AtomicU64>,
+}
+
+impl Stats {
+    /// Counters of the connection, peer is listed until they are dropped.
+    /// Messages the connection takes from the queue are counted out of it
+    pub(crate) fn connection(
+        &self,
+        peer_id: PeerIdentity,
+        queue: Option<&QueueStats>,
+    ) -> PeerCounters {
+        let counters = Arc::new(Counters::default());
+        self.peers.insert(peer_id.clone(), counters.clone());
+        PeerCounters {
+            stats: self.clone(),
+            peer_id,
+            counters,
+            queue: queue.map(|queue| queue.counters.clone()),
+        }
+    }
+
+    /// Tracks queue of the peer until dropped. Has to be registered before backend
+    /// gets the peer, so nothing is put in the queue uncounted.
+    /// Conflated queues hold one message at most and aren't tracked
+    pub(crate) fn queue(&self, peer_id: PeerIdentity) -> QueueStats {
+        let counters = Arc::new(QueueCounters::default());
+        self.queues.insert(peer_id.clone(), counters.clone());
+        QueueStats {
+            stats: self.clone(),
+            peer_id,
+            counters,
+        }
+    }
+
+    /// Message for the peer was discarded as their queue is full
+    pub(crate) fn dropped(&self, peer_id: &PeerIdentity) {
+        self.totals.messages_dropped.fetch_add(1, Ordering::Relaxed);
+        if let Some(counters) = self.peers.get(peer_id) {
+            counters.messages_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Message for the peer is about to be put into their queue. Connection could take
+    /// it out right away, so it is counted beforehand. Queue of the peer is tracked before
+    /// backend gets it, so callers that got hold of the queue first never count message
+    /// for an older queue than it goes to
+    pub(crate) fn enqueued(&self, peer_id: &PeerIdentity) {
+        if let Some(queue) = self.queues.get(peer_id) {
+            queue.enqueued.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Queue of the peer didn't take message counted by `enqueued`
+    pub(crate) fn not_enqueued(&self, peer_id: &PeerIdentity) {
+        if let Some(queue) = self.queues.get(peer_id) {
+            queue.enqueued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn peer_rejected(&self) {
+        self.rejected_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> SocketStats {
+        let peers: HashMap<_, _> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let mut stats = peer.value().load();
+                if let Some(queue) = self.queues.get(peer.key()) {
+                    stats.messages_queued = queue.queued();
+                }
+                (peer.key().clone(), stats)
+            })
+            .collect();
+        let mut totals = self.totals.load();
+        totals.messages_queued = peers.values().map(|peer| peer.messages_queued).sum();
+        SocketStats {
+            totals,
+            connected_peers: peers.len(),
+            rejected_peers: self.rejected_peers.load(Ordering::Relaxed),
+            peers,
+        }
+    }
+}
+
+/// Updates counters of the peer and the socket together
+pub(crate) struct PeerCounters {
+    stats: Stats,
+    peer_id: PeerIdentity,
+    counters: Arc<Counters>,
+    queue: Option<Arc<QueueCounters>>,
+}
+
+/// Keeps queue of the peer tracked, see `Stats::queue`
+pub(crate) struct QueueStats {
+    stats: Stats,
+    peer_id: PeerIdentity,
+    counters: Arc<QueueCounters>,
+}
+
+impl Drop for QueueStats {
+    fn drop(&mut self) {
+        // New queue might be registered for the peer already
+        let counters = &self.counters;
+        self.stats.queues.remove_if(&self.peer_id, |_, registered| {
+            Arc::ptr_eq(registered, counters)
+        });
+    }
+}
+
+impl PeerCounters {
+    pub(crate) fn sent(&self, message: &Message) {
+        if let Some(len) = data_len(message) {
+            for counters in [&self.counters, &self.stats.totals].iter() {
+                counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+                counters.bytes_sent.fetch_add(len, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Same as `sent` for message taken from the queue of the peer
+    pub(crate) fn written(&self, message: &Message) {
+        if data_len(message).is_some() {
+            self.taken();
+        }
+        self.sent(message);
+    }
+
+    fn taken(&self) {
+        if let Some(queue) = &self.queue {
+            queue.taken.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Chunk of data written to a raw STREAM connection, taken from its queue
+    pub(crate) fn raw_written(&self, len: usize) {
+        self.taken();
+        for counters in [&self.counters, &self.stats.totals].iter() {
+            counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn raw_received(&self, len: usize) {
+        for counters in [&self.counters, &self.stats.totals].iter() {
+            counters.messages_received.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes_received
+                .fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn received(&self, message: &Message) {
+        if let Some(len) = data_len(message) {
+            for counters in [&self.counters, &self.stats.totals].iter() {
+                counters.messages_received.fetch_add(1, Ordering::Relaxed);
+                counters.bytes_received.fetch_add(len, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for PeerCounters {
+    fn drop(&mut self) {
+        // Peer might have reconnected with the same identity already
+        let counters = &self.counters;
+        self.stats.peers.remove_if(&self.peer_id, |_, registered| {
+            Arc::ptr_eq(registered, counters)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::ZmqMessage;
+
+    #[test]
+    fn test_peer_stats_outlive_reconnect() {
+        let stats = Stats::default();
+        let peer_id = PeerIdentity::new();
+        let old = stats.connection(peer_id.clone(), None);
+        old.sent(&Message::Message(ZmqMessage::from("hello")));
+        stats.dropped(&peer_id);
+
+        let new = stats.connection(peer_id.clone(), None);
+        new.received(&Message::Message(ZmqMessage::from("hi")));
+        drop(old);
+        let snapshot = stats.snapshot();
+        assert_eq!(1, snapshot.connected_peers);
+        assert_eq!(
+            PeerStats {
+                messages_received: 1,
+                bytes_received: 4,
+                ..PeerStats::default()
+            },
+            snapshot.peers[&peer_id]
+        );
+        assert_eq!(1, snapshot.totals.messages_sent);
+        assert_eq!(7, snapshot.totals.bytes_sent);
+        assert_eq!(1, snapshot.totals.messages_dropped);
+
+        drop(new);
+        assert_eq!(0, stats.snapshot().connected_peers);
+    }
+
+    #[test]
+    fn test_queued_messages() {
+        let stats = Stats::default();
+        let peer_id = PeerIdentity::new();
+        let queue = stats.queue(peer_id.clone());
+        let counters = stats.connection(peer_id.clone(), Some(&queue));
+        for _ in 0..4 {
+            stats.enqueued(&peer_id);
+        }
+        stats.not_enqueued(&peer_id);
+        counters.written(&Message::Message(ZmqMessage::from("hello")));
+        // Commands don't go through the queue as user data
+        counters.written(&Message::Command(crate::codec::ZmtpCommand::subscribe(b"")));
+        let snapshot = stats.snapshot();
+        assert_eq!(2, snapshot.peers[&peer_id].messages_queued);
+        assert_eq!(2, snapshot.totals.messages_queued);
+        assert_eq!(1, snapshot.totals.messages_sent);
+
+        // Queue outlives the connection, messages put while peer is away are counted
+        drop(counters);
+        stats.enqueued(&peer_id);
+        let counters = stats.connection(peer_id.clone(), Some(&queue));
+        counters.written(&Message::Message(ZmqMessage::from("hello")));
+        assert_eq!(2, stats.snapshot().totals.messages_queued);
+
+        drop(queue);
+        assert_eq!(0, stats.snapshot().totals.messages_queued);
+    }
+}
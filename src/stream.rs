@@ -0,0 +1,414 @@
+use crate::close::{CloseReport, ConnectionReport};
+use crate::context::Terminate;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::message::*;
+use crate::options::SocketOptions;
+use crate::security::Authenticator;
+use crate::stats::{PeerCounters, Stats};
+use crate::util::*;
+use crate::{util, SocketEvent, SocketFrontend, SocketType, ZmqResult};
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::{future, FutureExt, SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{BytesCodec, Framed};
+
+pub(crate) struct StreamPeer {
+    pub(crate) send_queue: mpsc::Sender<Bytes>,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+pub(crate) struct StreamSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, StreamPeer>,
+    pub(crate) queue_sender: mpsc::Sender<(PeerIdentity, ZmqMessage)>,
+    /// Chunks of data queued for each connection, see `SocketOptions::send_hwm`
+    send_hwm: usize,
+    stats: Stats,
+}
+
+impl StreamSocketBackend {
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
+
+impl Terminate for StreamSocketBackend {
+    fn terminate(&self) {
+        self.shutdown();
+    }
+}
+
+/// Registers raw connection and starts a coroutine passing data between it and the socket.
+/// Empty messages are queued when peer connects and disconnects
+/// Returns identity the connection is registered with
+async fn raw_peer_connected(
+    socket: BoxedStream,
+    backend: Arc<StreamSocketBackend>,
+    options: SocketOptions,
+) -> PeerIdentity {
+    let task = options.track_task();
+    let monitor = options.monitor.clone();
+    // Stopped socket might never take what is queued for it, so that isn't waited for
+    let stopped = options.stopped().boxed().shared();
+    let mut raw_socket = Framed::new(socket, BytesCodec::new());
+    let peer_id = PeerIdentity::new();
+    let (out_queue, mut outgoing_queue) = bounded_queue::<Bytes>(backend.send_hwm);
+    let (stop_handle, mut stop_callback) = oneshot::channel::<bool>();
+    let queue_stats = backend.stats.queue(peer_id.clone());
+    let counters = backend
+        .stats
+        .connection(peer_id.clone(), Some(&queue_stats));
+    backend.peers.insert(
+        peer_id.clone(),
+        StreamPeer {
+            send_queue: out_queue,
+            _io_close_handle: stop_handle,
+        },
+    );
+    let mut incoming_queue = backend.queue_sender.clone();
+    // Receiving side might be already dropped. It's fine to ignore errors in such case
+    tokio::select! {
+        _ = incoming_queue.send((peer_id.clone(), ZmqMessage::from(Bytes::new()))) => {},
+        _ = stopped.clone() => {},
+    }
+
+    let mut linger = options.effective_linger();
+    let mut report = options.close_reports.connection(peer_id.clone());
+    let registered_id = peer_id.clone();
+    tokio::spawn(async move {
+        let _task = task;
+        let failed = |reason: String| {
+            monitor.emit(SocketEvent::ConnectionFailed {
+                peer_id: peer_id.clone(),
+                peer_address: None,
+                reason,
+            })
+        };
+        // Set once socket or context stops, queued data then gets linger period to be written
+        let mut closing = false;
+        loop {
+            tokio::select! {
+                _ = &mut stop_callback => {
+                    closing = true;
+                    break;
+                },
+                _ = stopped.clone() => {
+                    closing = true;
+                    break;
+                },
+                outgoing = outgoing_queue.next() => {
+                    match outgoing {
+                        // Empty message is a request to close connection
+                        Some(data) if data.is_empty() => break,
+                        Some(data) => {
+                            counters.raw_written(data.len());
+                            let mut send = raw_socket.send(data);
+                            let stopping = future::select(&mut stop_callback, stopped.clone());
+                            let result = tokio::select! {
+                                result = &mut send => result,
+                                // Peer is slow to read, chunk being written gets linger period too
+                                _ = stopping => {
+                                    let started = tokio::time::Instant::now();
+                                    let sent = match linger {
+                                        Some(linger) => {
+                                            matches!(tokio::time::timeout(linger, &mut send).await, Ok(Ok(())))
+                                        }
+                                        None => false,
+                                    };
+                                    if sent {
+                                        report.flushed += 1;
+                                        linger = linger.map(|linger| linger.checked_sub(started.elapsed()).unwrap_or_default());
+                                    } else {
+                                        report.dropped += 1;
+                                        linger = None;
+                                    }
+                                    closing = true;
+                                    Ok(())
+                                },
+                            };
+                            if let Err(e) = result {
+                                failed(e.to_string());
+                                break;
+                            }
+                            if closing {
+                                break;
+                            }
+                        },
+                        None => break,
+                    }
+                },
+                incoming = raw_socket.next() => {
+                    match incoming {
+                        Some(Ok(data)) => {
+                            counters.raw_received(data.len());
+                            tokio::select! {
+                                _ = incoming_queue.send((peer_id.clone(), data.into())) => {},
+                                _ = &mut stop_callback => {
+                                    closing = true;
+                                    break;
+                                },
+                                _ = stopped.clone() => {
+                                    closing = true;
+                                    break;
+                                },
+                            }
+                        }
+                        _ => break,
+                    }
+                },
+            }
+        }
+        // Nothing gets queued for connection once it's gone from peers
+        backend.peers.remove(&peer_id);
+        if closing {
+            if let Err(e) = drain_on_close(
+                &mut raw_socket,
+                &mut outgoing_queue,
+                linger,
+                &mut report,
+                &counters,
+            )
+            .await
+            {
+                failed(e.to_string());
+            }
+        }
+        drop(report);
+        drop(counters);
+        drop(queue_stats);
+        tokio::select! {
+            _ = incoming_queue.send((peer_id, ZmqMessage::from(Bytes::new()))) => {},
+            _ = stopped => {},
+        }
+    });
+    registered_id
+}
+
+/// Writes out data still queued for the connection within linger period, then closes it.
+/// Counts of flushed and dropped chunks go to close report
+async fn drain_on_close(
+    socket: &mut Framed<BoxedStream, BytesCodec>,
+    queue: &mut mpsc::Receiver<Bytes>,
+    linger: Option<Duration>,
+    report: &mut ConnectionReport,
+    counters: &PeerCounters,
+) -> Result<(), std::io::Error> {
+    let mut result = Ok(());
+    if let Some(linger) = linger {
+        let mut written = 0;
+        let drain = async {
+            while let Some(Some(data)) = queue.next().now_or_never() {
+                // Empty message is a request to close connection, nothing after it is written
+                if data.is_empty() {
+                    break;
+                }
+                counters.raw_written(data.len());
+                socket.feed(data).await?;
+                written += 1;
+            }
+            socket.close().await
+        };
+        match tokio::time::timeout(linger, drain).await {
+            Ok(Ok(())) => report.flushed += written,
+            Ok(Err(e)) => {
+                report.dropped += written;
+                result = Err(e);
+            }
+            // Data might still sit in write buffer, it is never sent now
+            Err(_) => report.dropped += written,
+        }
+    }
+    while let Some(Some(_)) = queue.next().now_or_never() {
+        report.dropped += 1;
+    }
+    result
+}
+
+/// Socket for communication with plain TCP peers that don't speak ZMTP.
+/// Each received message is tagged with identity of the connection.
+/// Empty message is received when peer connects or disconnects
+pub struct StreamSocket {
+    backend: Arc<StreamSocketBackend>,
+    binds: util::Binds,
+    /// Connections made by connect, see `disconnect`
+    connects: Vec<(Endpoint, PeerIdentity)>,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    queue: mpsc::Receiver<(PeerIdentity, ZmqMessage)>,
+}
+
+impl Drop for StreamSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl StreamSocket {
+    /// Receives data read from one of connections together with identity of that connection
+    pub async fn recv(&mut self) -> ZmqResult<(PeerIdentity, ZmqMessage)> {
+        util::with_timeout(self.options.recv_timeout, self.queue.next())
+            .await?
+            .ok_or(ZmqError::NoMessage)
+    }
+
+    /// Writes data to connection with given identity.
+    /// Sending empty message closes connection
+    pub async fn send_to(&mut self, peer_id: &PeerIdentity, message: ZmqMessage) -> ZmqResult<()> {
+        if !self.backend.peers.contains_key(peer_id) {
+            return Err(ZmqError::ReturnToSender {
+                reason: "Connection not found by identity",
+                message,
+            });
+        }
+        let peers = &self.backend.peers;
+        let send = util::send_to_peer(
+            peers,
+            peer_id,
+            |peer| &mut peer.send_queue,
+            message.data,
+            &self.options.stats,
+        );
+        util::with_timeout(self.options.send_timeout, send).await?
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for StreamSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let hwm = options.high_water_marks();
+        let (queue_sender, queue) = mpsc::channel(hwm.recv);
+        let backend = Arc::new(StreamSocketBackend {
+            peers: DashMap::new(),
+            queue_sender,
+            send_hwm: hwm.send,
+            stats: options.stats.clone(),
+        });
+        // Raw connections aren't tied to bind or connect, so context knows the socket from start
+        if let Some(context) = &options.context {
+            context.register(backend.clone());
+        }
+        Self {
+            backend,
+            binds: util::Binds::default(),
+            connects: Vec::new(),
+            last_endpoint: None,
+            options,
+            queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::STREAM
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        // Raw connections are never reestablished
+        self.connects
+            .iter()
+            .map(|(endpoint, peer_id)| ConnectionInfo {
+                endpoint: endpoint.clone(),
+                peer_id: peer_id.clone(),
+                state: if self.backend.peers.contains_key(peer_id) {
+                    ConnectionState::Connected
+                } else {
+                    ConnectionState::Disconnected
+                },
+            })
+            .collect()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let backend = self.backend.clone();
+        let options = self.options.clone();
+        let (bound, stop_handle) =
+            util::start_listener(endpoint, &self.options, move |socket, _| {
+                tokio::spawn(raw_peer_connected(socket, backend.clone(), options.clone()));
+            })
+            .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let raw_socket = util::connect_endpoint(endpoint, &self.options).await?;
+        let peer_id =
+            raw_peer_connected(raw_socket, self.backend.clone(), self.options.clone()).await;
+        self.connects.push((endpoint.parse()?, peer_id));
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let backend = self.backend.clone();
+        let options = self.options.clone();
+        let (endpoint, stop_handle) =
+            util::start_listener_on(listener, &self.options, move |socket, _| {
+                tokio::spawn(raw_peer_connected(socket, backend.clone(), options.clone()));
+            })?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let endpoint = endpoint.parse::<Endpoint>()?;
+        let position = self
+            .connects
+            .iter()
+            .position(|(connected_to, _)| connected_to == &endpoint)
+            .ok_or(ZmqError::NotConnected(endpoint))?;
+        let (_, peer_id) = self.connects.remove(position);
+        // Dropped peer closes its connection, which is never reestablished for STREAM
+        self.backend.peers.remove(&peer_id);
+        Ok(())
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        raw_peer_connected(Box::new(stream), self.backend.clone(), self.options.clone()).await;
+        Ok(())
+    }
+}
@@ -1,62 +1,341 @@
 use async_trait::async_trait;
-use futures::SinkExt;
-use futures::StreamExt;
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+use std::sync::{Arc, Mutex};
 
+use crate::close::CloseReport;
 use crate::codec::*;
+use crate::conflate::{self, QueueReceiver};
+use crate::endpoint::Endpoint;
 use crate::error::*;
 use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::security::Authenticator;
 use crate::util::*;
-use crate::{Socket, SocketType, ZmqResult};
-use bytes::{BufMut, BytesMut};
+use crate::{util, BlockingRecv, MultiPeer, SocketBackend, SocketFrontend};
+use crate::{SocketType, ZmqResult};
+use tokio::net::{TcpListener, TcpStream};
+
+pub(crate) struct SubPeer {
+    pub(crate) send_queue: mpsc::Sender<Message>,
+    /// Subscription changes replayed to the peer when it connected
+    pub(crate) replayed: u64,
+    pub(crate) _io_close_handle: oneshot::Sender<bool>,
+}
+
+/// Subscriptions in order they were made, along with count of changes made to them
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    pub(crate) topics: Vec<Vec<u8>>,
+    changes: u64,
+}
+
+impl Subscriptions {
+    /// Applies the change and returns its number. Cancelling unknown topic is still a change
+    /// as it goes to peers anyway
+    fn apply(&mut self, subscribe: bool, topic: &[u8]) -> u64 {
+        if subscribe {
+            self.topics.push(topic.to_vec());
+        } else if let Some(index) = self.topics.iter().position(|s| s.as_slice() == topic) {
+            self.topics.remove(index);
+        }
+        self.changes += 1;
+        self.changes
+    }
+}
+
+pub(crate) struct SubSocketBackend {
+    pub(crate) peers: DashMap<PeerIdentity, SubPeer>,
+    /// Never held across await, peers are sent changes after they are recorded
+    pub(crate) subscriptions: Mutex<Subscriptions>,
+    pub(crate) queue_sender: mpsc::Sender<Message>,
+    pub(crate) socket_type: SocketType,
+}
+
+/// Builds a SUBSCRIBE or CANCEL command.
+/// Codec turns it into 1 or 0 prefixed message for ZMTP 3.0 peers
+fn subscription_message(subscribe: bool, topic: &[u8]) -> Message {
+    if subscribe {
+        Message::Command(ZmtpCommand::subscribe(topic))
+    } else {
+        Message::Command(ZmtpCommand::cancel(topic))
+    }
+}
+
+impl SubSocketBackend {
+    pub(crate) fn new(socket_type: SocketType, queue_sender: mpsc::Sender<Message>) -> Self {
+        Self {
+            peers: DashMap::new(),
+            subscriptions: Mutex::new(Subscriptions::default()),
+            queue_sender,
+            socket_type,
+        }
+    }
+
+    /// Remembers subscription change and forwards it to all connected peers
+    async fn update_subscription(
+        &self,
+        subscribe: bool,
+        topic: &[u8],
+        options: &SocketOptions,
+    ) -> ZmqResult<()> {
+        let change = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            if !subscribe && !subscriptions.topics.iter().any(|s| s.as_slice() == topic) {
+                return Err(ZmqError::Socket("Unknown subscription"));
+            }
+            subscriptions.apply(subscribe, topic)
+        };
+        self.deliver(subscription_message(subscribe, topic), change, options)
+            .await;
+        Ok(())
+    }
+
+    /// Passes message to all connected peers as is.
+    /// Subscription messages are still recorded to be replayed for new peers.
+    /// Those are always single frame, multipart messages are just forwarded
+    pub(crate) async fn forward(&self, frames: Vec<ZmqMessage>, options: &SocketOptions) {
+        let change = match frames.as_slice() {
+            [message] if matches!(message.data.first(), Some(0) | Some(1)) => self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .apply(message.data[0] == 1, &message.data[1..]),
+            // Every connected peer gets the message
+            _ => u64::MAX,
+        };
+        self.deliver(frames.into(), change, options).await;
+    }
+
+    /// Sends message to peers that connected before the change was made, later ones
+    /// got it in the replay. Waits for room up to send timeout. Peer that stays full
+    /// is dropped, as it would keep stale subscriptions otherwise. Its reconnect replays them
+    async fn deliver(&self, message: Message, change: u64, options: &SocketOptions) {
+        let peer_ids: Vec<PeerIdentity> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.replayed < change)
+            .map(|peer| peer.key().clone())
+            .collect();
+        let stats = match message {
+            Message::Command(_) => None,
+            _ => Some(&options.stats),
+        };
+        util::send_to_each(
+            &self.peers,
+            peer_ids,
+            |peer| &mut peer.send_queue,
+            message,
+            options,
+            stats,
+        )
+        .await;
+    }
+}
+
+#[async_trait]
+impl MultiPeer for SubSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        // Peer is registered under the lock, so changes made later are sent to it
+        let subscriptions = self.subscriptions.lock().unwrap();
+        // Queue should be big enough to replay all subscriptions to a new peer
+        let (mut out_queue, out_queue_receiver) =
+            bounded_queue(hwm.send.max(subscriptions.topics.len()));
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+
+        for topic in subscriptions.topics.iter() {
+            out_queue
+                .try_send(subscription_message(true, topic))
+                .expect("Failed to queue subscription");
+        }
+        self.peers.insert(
+            peer_id.clone(),
+            SubPeer {
+                send_queue: out_queue,
+                replayed: subscriptions.changes,
+                _io_close_handle: stop_handle,
+            },
+        );
+        (out_queue_receiver, stop_callback)
+    }
+
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        self.peers.remove(peer_id);
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+}
+
+#[async_trait]
+impl SocketBackend for SubSocketBackend {
+    async fn message_received(&self, _peer_id: &PeerIdentity, message: Message) {
+        // Receiving side might be already dropped. Nothing to do with message in such case
+        let _ = self.queue_sender.clone().send(message).await;
+    }
+
+    fn socket_type(&self) -> SocketType {
+        self.socket_type
+    }
+
+    fn shutdown(&self) {
+        self.peers.clear();
+    }
+}
 
 pub struct SubSocket {
-    pub(crate) _inner: Framed<TcpStream, ZmqCodec>,
+    backend: Arc<SubSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    queue: QueueReceiver<Message>,
+}
+
+impl Drop for SubSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl SubSocket {
+    /// Subscribes to messages starting with given prefix.
+    /// Can be called before connect. Subscriptions are sent to every peer right after
+    /// the handshake, including peers that reconnect
+    pub async fn subscribe(&mut self, subscription: &[u8]) -> ZmqResult<()> {
+        self.backend
+            .update_subscription(true, subscription, &self.options)
+            .await
+    }
+
+    pub async fn unsubscribe(&mut self, subscription: &[u8]) -> ZmqResult<()> {
+        self.backend
+            .update_subscription(false, subscription, &self.options)
+            .await
+    }
+
+    /// Subscriptions sent to every peer, in order they were made
+    pub async fn subscriptions(&self) -> Vec<Vec<u8>> {
+        self.backend.subscriptions.lock().unwrap().topics.clone()
+    }
 }
 
 #[async_trait]
-impl Socket for SubSocket {
-    async fn send(&mut self, _m: ZmqMessage) -> ZmqResult<()> {
-        Err(ZmqError::Socket(
-            "This socket doesn't support sending messages",
-        ))
-    }
-
-    async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
-        let message: Option<ZmqResult<Message>> = self._inner.next().await;
-        match message {
-            Some(Ok(Message::Message(m))) => Ok(m),
-            Some(Ok(_)) => Err(ZmqError::Other("Wrong message type received")),
-            Some(Err(e)) => Err(e),
+impl BlockingRecv for SubSocket {
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        match util::with_timeout(self.options.recv_timeout, self.queue.next()).await? {
+            Some(message) => {
+                let frames = message
+                    .into_frames()
+                    .ok_or(ZmqError::Other("Wrong message type received"))?;
+                conflate::check_frames(self.options.conflate, &frames)?;
+                Ok(frames)
+            }
             None => Err(ZmqError::NoMessage),
         }
     }
 }
 
-impl SubSocket {
-    pub async fn connect(endpoint: &str) -> ZmqResult<Self> {
-        let raw_socket = raw_connect(SocketType::SUB, endpoint).await?;
-        Ok(Self { _inner: raw_socket })
-    }
-
-    pub async fn subscribe(&mut self, subscription: &str) -> ZmqResult<()> {
-        let mut sub = BytesMut::with_capacity(subscription.len() + 1);
-        sub.put_u8(1);
-        sub.extend_from_slice(subscription.as_bytes());
-        self._inner
-            .send(Message::Message(ZmqMessage { data: sub.freeze() }))
-            .await?;
-        Ok(())
+#[async_trait]
+impl SocketFrontend for SubSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (queue_sender, queue) = mpsc::channel(recv_hwm);
+        let queue = QueueReceiver::new(queue, options.conflates_recv(SocketType::SUB));
+        Self {
+            backend: Arc::new(SubSocketBackend::new(SocketType::SUB, queue_sender)),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::SUB
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
     }
 
-    pub async fn unsubscribe(&mut self, subscription: &str) -> ZmqResult<()> {
-        let mut sub = BytesMut::with_capacity(subscription.len() + 1);
-        sub.put_u8(0);
-        sub.extend_from_slice(subscription.as_bytes());
-        self._inner
-            .send(Message::Message(ZmqMessage { data: sub.freeze() }))
-            .await?;
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
         Ok(())
     }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
 }
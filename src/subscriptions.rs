@@ -0,0 +1,207 @@
+//! Subscriptions of PUB and XPUB peers, see `Subscriptions`
+use crate::util::PeerIdentity;
+use std::collections::{HashMap, HashSet};
+
+/// Peers subscribed to the prefix spelled by path to the node, together with
+/// number of times each of them subscribed to it
+#[derive(Default)]
+struct Node {
+    peers: HashMap<PeerIdentity, usize>,
+    children: HashMap<u8, Node>,
+}
+
+/// Prefix trie of subscriptions of all peers of a socket. Matching a message
+/// walks its first frame once no matter how many peers and subscriptions there are
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    root: Node,
+}
+
+impl Subscriptions {
+    fn node(&self, prefix: &[u8]) -> Option<&Node> {
+        let mut node = &self.root;
+        for byte in prefix {
+            node = node.children.get(byte)?;
+        }
+        Some(node)
+    }
+
+    /// Peer has to cancel the prefix as many times as they subscribed to it
+    pub(crate) fn subscribe(&mut self, peer_id: &PeerIdentity, prefix: &[u8]) {
+        let mut node = &mut self.root;
+        for byte in prefix {
+            node = node.children.entry(*byte).or_default();
+        }
+        *node.peers.entry(peer_id.clone()).or_insert(0) += 1;
+    }
+
+    /// Returns false if peer isn't subscribed to the prefix. Other peers
+    /// subscribed to the same prefix keep their subscriptions
+    pub(crate) fn cancel(&mut self, peer_id: &PeerIdentity, prefix: &[u8]) -> bool {
+        let mut node = &mut self.root;
+        for byte in prefix {
+            node = match node.children.get_mut(byte) {
+                Some(child) => child,
+                None => return false,
+            };
+        }
+        match node.peers.get_mut(peer_id) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                node.peers.remove(peer_id);
+                self.prune(prefix);
+            }
+            None => return false,
+        }
+        true
+    }
+
+    /// Removes every subscription of the peer. Returns prefixes they were subscribed to
+    pub(crate) fn remove_peer(&mut self, peer_id: &PeerIdentity) -> Vec<Vec<u8>> {
+        let mut removed = Vec::new();
+        let mut pending = vec![(Vec::new(), &mut self.root)];
+        while let Some((prefix, node)) = pending.pop() {
+            if node.peers.remove(peer_id).is_some() {
+                removed.push(prefix.clone());
+            }
+            for (byte, child) in node.children.iter_mut() {
+                let mut child_prefix = prefix.clone();
+                child_prefix.push(*byte);
+                pending.push((child_prefix, child));
+            }
+        }
+        for prefix in &removed {
+            self.prune(prefix);
+        }
+        removed
+    }
+
+    /// Removes node of the prefix if it has no peers or children left,
+    /// together with ancestors that were only kept for it
+    fn prune(&mut self, prefix: &[u8]) {
+        match self.node(prefix) {
+            Some(node)
+                if !prefix.is_empty() && node.peers.is_empty() && node.children.is_empty() => {}
+            _ => return,
+        }
+        // Deepest ancestor that stays, nodes below it lead to the prefix only
+        let mut kept = 0;
+        let mut node = &self.root;
+        for (depth, byte) in prefix.iter().enumerate() {
+            if depth == 0 || !node.peers.is_empty() || node.children.len() > 1 {
+                kept = depth;
+            }
+            node = &node.children[byte];
+        }
+        let mut node = &mut self.root;
+        for byte in &prefix[..kept] {
+            node = node.children.get_mut(byte).expect("Prefix was just walked");
+        }
+        node.children.remove(&prefix[kept]);
+    }
+
+    pub(crate) fn contains(&self, peer_id: &PeerIdentity, prefix: &[u8]) -> bool {
+        self.node(prefix)
+            .is_some_and(|node| node.peers.contains_key(peer_id))
+    }
+
+    /// Peers with at least one subscription that is a prefix of the topic
+    pub(crate) fn matching(&self, topic: &[u8]) -> HashSet<&PeerIdentity> {
+        let mut matched = HashSet::new();
+        let mut node = &self.root;
+        matched.extend(node.peers.keys());
+        for byte in topic {
+            node = match node.children.get(byte) {
+                Some(child) => child,
+                None => break,
+            };
+            matched.extend(node.peers.keys());
+        }
+        matched
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.root = Node::default();
+    }
+
+    /// Number of distinct prefixes peers are subscribed to, counted per peer
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        let mut len = 0;
+        let mut pending = vec![&self.root];
+        while let Some(node) = pending.pop() {
+            len += node.peers.len();
+            pending.extend(node.children.values());
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancel_keeps_other_peers_of_prefix() {
+        let mut subscriptions = Subscriptions::default();
+        let first = PeerIdentity::new();
+        let second = PeerIdentity::new();
+        subscriptions.subscribe(&first, b"topic");
+        subscriptions.subscribe(&second, b"topic");
+        subscriptions.subscribe(&second, b"top");
+        assert_eq!(2, subscriptions.matching(b"topical").len());
+
+        assert!(subscriptions.cancel(&first, b"topic"));
+        assert!(!subscriptions.cancel(&first, b"topic"));
+        assert!(!subscriptions.cancel(&first, b"to"));
+        let matched = subscriptions.matching(b"topical");
+        assert_eq!(vec![&second], matched.into_iter().collect::<Vec<_>>());
+        assert!(subscriptions.contains(&second, b"topic"));
+
+        assert!(subscriptions.cancel(&second, b"topic"));
+        assert!(subscriptions.contains(&second, b"top"));
+        assert!(subscriptions.node(b"topi").is_none());
+        assert!(subscriptions.cancel(&second, b"top"));
+        assert!(subscriptions.root.children.is_empty());
+    }
+
+    #[test]
+    fn test_repeated_subscription_needs_as_many_cancels() {
+        let mut subscriptions = Subscriptions::default();
+        let peer_id = PeerIdentity::new();
+        subscriptions.subscribe(&peer_id, b"");
+        subscriptions.subscribe(&peer_id, b"");
+        assert!(subscriptions.cancel(&peer_id, b""));
+        assert_eq!(1, subscriptions.matching(b"anything").len());
+        assert!(subscriptions.cancel(&peer_id, b""));
+        assert!(subscriptions.matching(b"anything").is_empty());
+    }
+
+    #[test]
+    fn test_remove_peer() {
+        let mut subscriptions = Subscriptions::default();
+        let removed = PeerIdentity::new();
+        let kept = PeerIdentity::new();
+        for prefix in &[&b"a"[..], b"ab", b"abc", b"b"] {
+            subscriptions.subscribe(&removed, prefix);
+        }
+        subscriptions.subscribe(&removed, b"ab");
+        subscriptions.subscribe(&kept, b"ab");
+
+        let mut prefixes = subscriptions.remove_peer(&removed);
+        prefixes.sort();
+        assert_eq!(
+            vec![
+                b"a".to_vec(),
+                b"ab".to_vec(),
+                b"abc".to_vec(),
+                b"b".to_vec()
+            ],
+            prefixes
+        );
+        assert_eq!(1, subscriptions.len());
+        assert!(subscriptions.contains(&kept, b"ab"));
+        assert!(subscriptions.node(b"abc").is_none());
+        assert!(subscriptions.node(b"b").is_none());
+    }
+}
@@ -1,10 +1,37 @@
 use crate::{BlockingRecv, BlockingSend, NonBlockingSend, Socket, SocketFrontend};
+use chrono::Utc;
 use futures::channel::{mpsc, oneshot};
+use futures::{FutureExt, SinkExt, StreamExt};
 use std::convert::TryInto;
 use std::error::Error;
 use std::time::Duration;
-use futures::{StreamExt, SinkExt};
-use chrono::Utc;
+
+/// Connects to endpoint and performs handshake without any socket logic on top.
+/// Useful to check socket behaviour from the other side
+async fn raw_peer(
+    endpoint: &str,
+    socket_type: crate::SocketType,
+) -> tokio_util::codec::Framed<tokio::net::TcpStream, crate::codec::ZmqCodec> {
+    let stream = tokio::net::TcpStream::connect(endpoint)
+        .await
+        .expect("Failed to connect");
+    let mut raw_socket = tokio_util::codec::Framed::new(stream, crate::codec::ZmqCodec::new());
+    let options = crate::SocketOptions::default();
+    crate::util::greet_exchange(&mut raw_socket, &options)
+        .await
+        .expect("Failed to exchange greetings");
+    crate::util::ready_exchange(&mut raw_socket, socket_type, &options, None)
+        .await
+        .expect("Failed to exchange ready messages");
+    raw_socket
+}
+
+/// READY command raw peers send to pretend being a socket of given type
+fn ready(socket_type: crate::SocketType) -> crate::codec::Message {
+    let mut properties = crate::Properties::new();
+    properties.insert("Socket-Type".into(), socket_type.to_string().into_bytes());
+    crate::codec::Message::Command(crate::codec::ZmtpCommand::Ready(properties))
+}
 
 #[tokio::test]
 async fn test_pub_sub_sockets() {
@@ -21,7 +48,9 @@ async fn test_pub_sub_sockets() {
             if let Ok(Some(_)) = server_stop.try_recv() {
                 break;
             }
-            pub_socket.send(Utc::now().to_rfc2822().into()).expect("Failed to send");
+            pub_socket
+                .send(Utc::now().to_rfc2822())
+                .expect("Failed to send");
             tokio::time::delay_for(Duration::from_millis(100)).await;
         }
     });
@@ -29,11 +58,16 @@ async fn test_pub_sub_sockets() {
     for _ in 0..10 {
         let mut client_sender = results_sender.clone();
         tokio::spawn(async move {
-            let mut sub_socket = crate::SubSocket::connect("127.0.0.1:5556")
+            let mut sub_socket = crate::SubSocket::new();
+            sub_socket
+                .connect("127.0.0.1:5556")
                 .await
                 .expect("Failed to connect");
 
-            sub_socket.subscribe("").await.expect("Failed to subscribe");
+            sub_socket
+                .subscribe(b"")
+                .await
+                .expect("Failed to subscribe");
 
             for _ in 0..10i32 {
                 let repl: String = sub_socket
@@ -61,7 +95,7 @@ async fn run_rep_server() -> Result<(), Box<dyn Error>> {
 
     for i in 0..10i32 {
         let mess: String = rep_socket.recv().await?.try_into()?;
-        rep_socket.send(format!("{} Rep - {}", mess, i).into())?;
+        rep_socket.send(format!("{} Rep - {}", mess, i))?;
     }
     // yield for a moment to ensure that server has some time to flush socket
     tokio::time::delay_for(Duration::from_millis(100)).await;
@@ -80,7 +114,7 @@ async fn test_req_rep_sockets() -> Result<(), Box<dyn Error>> {
     req_socket.connect("127.0.0.1:5557").await?;
 
     for i in 0..10i32 {
-        req_socket.send(format!("Req - {}", i).into()).await?;
+        req_socket.send(format!("Req - {}", i)).await?;
         let repl: String = req_socket.recv().await?.try_into()?;
         assert_eq!(format!("Req - {} Rep - {}", i, i), repl)
     }
@@ -98,7 +132,7 @@ async fn test_many_req_rep_sockets() -> Result<(), Box<dyn Error>> {
 
             for j in 0..100i32 {
                 req_socket
-                    .send(format!("Socket {} Req - {}", i, j).into())
+                    .send(format!("Socket {} Req - {}", i, j))
                     .await
                     .unwrap();
                 let repl: String = req_socket.recv().await.unwrap().try_into().unwrap();
@@ -114,7 +148,3939 @@ async fn test_many_req_rep_sockets() -> Result<(), Box<dyn Error>> {
 
     for _ in 0..10000i32 {
         let mess: String = rep_socket.recv().await?.try_into()?;
-        rep_socket.send(format!("{} Rep", mess).into())?;
+        rep_socket.send(format!("{} Rep", mess))?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sub_socket_subscriptions() -> Result<(), Box<dyn Error>> {
+    let (server_stop_sender, mut server_stop) = oneshot::channel::<()>();
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("127.0.0.1:5560").await?;
+    tokio::spawn(async move {
+        loop {
+            if let Ok(Some(_)) = server_stop.try_recv() {
+                break;
+            }
+            for topic in &["topic-a", "topic-b", "other"] {
+                pub_socket
+                    .send(format!("{} message", topic))
+                    .expect("Failed to send");
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    });
+
+    let mut sub_socket = crate::SubSocket::new();
+    // Subscription made before connect should be delivered after handshake
+    sub_socket.subscribe(b"topic-a").await?;
+    sub_socket.connect("127.0.0.1:5560").await?;
+    sub_socket.subscribe(b"topic-b").await?;
+
+    let mut seen_a = false;
+    let mut seen_b = false;
+    while !(seen_a && seen_b) {
+        let repl: String = sub_socket.recv().await?.try_into()?;
+        match repl.as_str() {
+            "topic-a message" => seen_a = true,
+            "topic-b message" => seen_b = true,
+            _ => panic!("Received unexpected message {}", repl),
+        }
+    }
+
+    sub_socket.unsubscribe(b"topic-a").await?;
+    // Some messages could already be in flight so just wait until only topic-b is left
+    let mut topic_b_count = 0;
+    while topic_b_count < 20 {
+        let repl: String = sub_socket.recv().await?.try_into()?;
+        match repl.as_str() {
+            "topic-a message" => topic_b_count = 0,
+            "topic-b message" => topic_b_count += 1,
+            _ => panic!("Received unexpected message {}", repl),
+        }
+    }
+    server_stop_sender.send(()).unwrap();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_xpub_socket_subscriptions() -> Result<(), Box<dyn Error>> {
+    let mut xpub_socket = crate::XPubSocket::new();
+    xpub_socket.bind("127.0.0.1:5561").await?;
+
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("127.0.0.1:5561").await?;
+    sub_socket.subscribe(b"weather").await?;
+
+    let (_peer_id, subscription) = xpub_socket.recv_from().await?;
+    assert_eq!(b"\x01weather", subscription.data.as_ref());
+
+    // Subscription table is updated before message reaches application
+    xpub_socket.send("weather sunny")?;
+    xpub_socket.send("news none")?;
+    xpub_socket.send("weather rainy")?;
+    let repl: String = sub_socket.recv().await?.try_into()?;
+    assert_eq!("weather sunny", repl);
+    let repl: String = sub_socket.recv().await?.try_into()?;
+    assert_eq!("weather rainy", repl);
+
+    sub_socket.unsubscribe(b"weather").await?;
+    let unsubscription = xpub_socket.recv().await?;
+    assert_eq!(b"\x00weather", unsubscription.data.as_ref());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sub_connects_while_subscription_waits() -> Result<(), Box<dyn Error>> {
+    let endpoint = "127.0.0.1:5680";
+    let mut sub_socket =
+        crate::SubSocket::with_options(crate::SocketOptions::default().send_hwm(1));
+    sub_socket.bind(endpoint).await?;
+    sub_socket.subscribe(b"").await?;
+
+    // Raw publisher never reads, so subscriptions pile up in its queue
+    let _stuck = raw_peer(endpoint, crate::SocketType::PUB).await;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let subscribing = tokio::spawn(async move {
+        for _ in 0..256 {
+            sub_socket.subscribe(&vec![b'x'; 64 * 1024]).await.unwrap();
+        }
+    });
+    tokio::time::delay_for(Duration::from_millis(200)).await;
+
+    // Late peer is replayed every subscription made so far
+    let mut late = raw_peer(endpoint, crate::SocketType::PUB).await;
+    match tokio::time::timeout(Duration::from_secs(1), late.next()).await? {
+        Some(Ok(crate::codec::Message::Command(command))) => {
+            assert_eq!(crate::codec::ZmtpCommand::subscribe(b""), command)
+        }
+        other => panic!("Expected subscription, got {:?}", other),
+    }
+    assert!(
+        subscribing.now_or_never().is_none(),
+        "Stuck peer should still block"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_xsub_socket_passthrough() -> Result<(), Box<dyn Error>> {
+    let (server_stop_sender, mut server_stop) = oneshot::channel::<()>();
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("127.0.0.1:5562").await?;
+    tokio::spawn(async move {
+        loop {
+            if let Ok(Some(_)) = server_stop.try_recv() {
+                break;
+            }
+            pub_socket.send("news message").expect("Failed to send");
+            pub_socket.send("other message").expect("Failed to send");
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    });
+
+    let mut xsub_socket = crate::XSubSocket::new();
+    xsub_socket.connect("127.0.0.1:5562").await?;
+    xsub_socket.send(b"\x01news".to_vec()).await?;
+
+    for _ in 0..10 {
+        let repl: String = xsub_socket.recv().await?.try_into()?;
+        assert_eq!("news message", repl);
+    }
+    server_stop_sender.send(()).unwrap();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_xsub_counts_queued_messages() -> Result<(), Box<dyn Error>> {
+    let endpoint = "127.0.0.1:5682";
+    let mut xsub_socket = crate::XSubSocket::new();
+    xsub_socket.bind(endpoint).await?;
+    let _publisher = raw_peer(endpoint, crate::SocketType::PUB).await;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    // Connection task doesn't get to run in between, so messages stay queued
+    xsub_socket.send(b"\x01news".to_vec()).await?;
+    xsub_socket.send("upstream message").await?;
+    xsub_socket
+        .send_multipart(vec!["multipart".into(), "message".into()])
+        .await?;
+    let stats = xsub_socket.stats();
+    assert_eq!(1, stats.connected_peers);
+    assert_eq!(3, stats.totals.messages_queued);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_socket_round_robin() -> Result<(), Box<dyn Error>> {
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.bind("127.0.0.1:5563").await?;
+
+    let first = raw_peer("127.0.0.1:5563", crate::SocketType::PULL).await;
+    let second = raw_peer("127.0.0.1:5563", crate::SocketType::PULL).await;
+    // yield for a moment to ensure that server has registered both peers
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    for i in 0..10i32 {
+        push_socket.send(format!("{}", i))?;
+    }
+
+    let mut received = Vec::new();
+    for peer in &mut [first, second] {
+        let mut numbers = Vec::new();
+        for _ in 0..5 {
+            match peer.next().await {
+                Some(Ok(crate::codec::Message::Message(m))) => {
+                    let number: String = m.try_into()?;
+                    numbers.push(number.parse::<i32>()?);
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+        received.push(numbers);
+    }
+    // Messages should alternate between peers
+    assert_ne!(received[0][0] % 2, received[1][0] % 2);
+    for numbers in received {
+        assert!(numbers.windows(2).all(|w| w[1] - w[0] == 2));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pull_socket_fair_queue() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("127.0.0.1:5564").await?;
+
+    let mut chatty = raw_peer("127.0.0.1:5564", crate::SocketType::PUSH).await;
+    let mut quiet = raw_peer("127.0.0.1:5564", crate::SocketType::PUSH).await;
+    for i in 0..50i32 {
+        chatty
+            .send(crate::codec::Message::Message(
+                format!("chatty {}", i).into(),
+            ))
+            .await?;
+    }
+    for i in 0..5i32 {
+        quiet
+            .send(crate::codec::Message::Message(
+                format!("quiet {}", i).into(),
+            ))
+            .await?;
+    }
+
+    let mut received = Vec::new();
+    for _ in 0..55 {
+        let repl: String = pull_socket.recv().await?.try_into()?;
+        received.push(repl);
+    }
+    assert_eq!(
+        50,
+        received.iter().filter(|m| m.starts_with("chatty")).count()
+    );
+    assert_eq!(
+        5,
+        received.iter().filter(|m| m.starts_with("quiet")).count()
+    );
+
+    // Disconnected peer should not block receiving from others
+    drop(chatty);
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    quiet
+        .send(crate::codec::Message::Message("quiet last".into()))
+        .await?;
+    let repl: String = pull_socket.recv().await?.try_into()?;
+    assert_eq!("quiet last", repl);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dealer_socket_round_robin() -> Result<(), Box<dyn Error>> {
+    for (port, name) in &[(5565, "first"), (5566, "second")] {
+        let mut rep_socket = crate::RepSocket::new();
+        rep_socket.bind(&format!("127.0.0.1:{}", port)).await?;
+        tokio::spawn(async move {
+            loop {
+                let mess: String = rep_socket.recv().await.unwrap().try_into().unwrap();
+                rep_socket.send(format!("{} {}", mess, name)).unwrap();
+            }
+        });
+    }
+
+    let mut dealer_socket = crate::DealerSocket::new();
+    dealer_socket.connect("127.0.0.1:5565").await?;
+    dealer_socket.connect("127.0.0.1:5566").await?;
+
+    // Dealer doesn't wait for replies before sending next request
+    for i in 0..4i32 {
+        dealer_socket
+            .send_multipart(vec!["".into(), format!("Req {}", i).into()])
+            .await?;
+    }
+    let mut replies = Vec::new();
+    for _ in 0..4i32 {
+        let mut reply = dealer_socket.recv_multipart().await?;
+        assert_eq!(2, reply.len());
+        assert!(reply[0].data.is_empty());
+        let reply: String = reply.pop().unwrap().try_into()?;
+        replies.push(reply);
+    }
+    replies.sort();
+    assert_eq!(
+        vec!["Req 0 first", "Req 1 second", "Req 2 first", "Req 3 second"],
+        replies
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_router_socket_identities() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("127.0.0.1:5567").await?;
+
+    let mut clients = Vec::new();
+    for i in 0..3i32 {
+        let mut req_socket = crate::ReqSocket::new();
+        req_socket.connect("127.0.0.1:5567").await?;
+        req_socket.send(format!("Client {}", i)).await?;
+        clients.push(req_socket);
+    }
+
+    for _ in 0..3i32 {
+        let (peer_id, mut messages) = router_socket.recv().await?;
+        assert_eq!(2, messages.len());
+        let request: String = messages.pop().unwrap().try_into()?;
+        messages.push(format!("{} Reply", request).into());
+        router_socket.send_to(&peer_id, messages).await?;
+    }
+    // Messages for unknown peers are silently dropped
+    router_socket
+        .send_to(&crate::PeerIdentity::new(), vec!["".into(), "Lost".into()])
+        .await?;
+
+    for (i, client) in clients.iter_mut().enumerate() {
+        let repl: String = client.recv().await?.try_into()?;
+        assert_eq!(format!("Client {} Reply", i), repl);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pair_socket_exclusive() -> Result<(), Box<dyn Error>> {
+    let mut server = crate::PairSocket::new();
+    server.bind("127.0.0.1:5568").await?;
+    let mut client = crate::PairSocket::new();
+    client.connect("127.0.0.1:5568").await?;
+    // yield for a moment to ensure that server has registered the peer
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    // Second peer is disconnected right after the handshake.
+    // Without immediate its messages would be queued until it gets through
+    let mut intruder =
+        crate::PairSocket::with_options(crate::SocketOptions::default().immediate(true));
+    intruder.connect("127.0.0.1:5568").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert!(intruder.send("Intrusion".into()).await.is_err());
+
+    // No strict send/recv alternation is required
+    Socket::send(&mut client, "Ping 1".into()).await?;
+    Socket::send(&mut client, "Ping 2".into()).await?;
+    Socket::send(&mut server, "Pong".into()).await?;
+    let repl: String = Socket::recv(&mut server).await?.try_into()?;
+    assert_eq!("Ping 1", repl);
+    let repl: String = Socket::recv(&mut server).await?.try_into()?;
+    assert_eq!("Ping 2", repl);
+    let repl: String = Socket::recv(&mut client).await?.try_into()?;
+    assert_eq!("Pong", repl);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_socket_raw_tcp() -> Result<(), Box<dyn Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream_socket = crate::StreamSocket::new();
+    stream_socket.bind("127.0.0.1:5569").await?;
+
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:5569").await?;
+    let (peer_id, connected) = stream_socket.recv().await?;
+    assert!(connected.data.is_empty());
+
+    client.write_all(b"hello").await?;
+    let (data_peer_id, data) = stream_socket.recv().await?;
+    assert_eq!(peer_id, data_peer_id);
+    assert_eq!(b"hello", data.data.as_ref());
+
+    stream_socket.send_to(&peer_id, "world".into()).await?;
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await?;
+    assert_eq!(b"world", &buf);
+
+    drop(client);
+    let (disconnected_peer_id, disconnected) = stream_socket.recv().await?;
+    assert_eq!(peer_id, disconnected_peer_id);
+    assert!(disconnected.data.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_socket_send_hwm() -> Result<(), Box<dyn Error>> {
+    let endpoint = "127.0.0.1:5677";
+    let listener = tokio::net::TcpListener::bind(endpoint).await?;
+    for &(hwm, queued) in &[(1, 1), (3, 3)] {
+        let options = crate::SocketOptions::default()
+            .send_hwm(hwm)
+            .send_timeout(Duration::from_secs(0));
+        let mut stream_socket = crate::StreamSocket::with_options(options);
+        stream_socket.connect(endpoint).await?;
+        let (peer_id, _connected) = stream_socket.recv().await?;
+        // Connection task doesn't get to run in between, so queue fills up
+        let mut sent = 0;
+        while stream_socket.send_to(&peer_id, "data".into()).await.is_ok() {
+            sent += 1;
+            assert!(sent < 100, "Send should time out once queue is full");
+        }
+        assert_eq!(queued, sent);
+        let stats = stream_socket.stats();
+        assert_eq!(queued as u64, stats.peers[&peer_id].messages_queued);
+        assert_eq!(queued as u64, stats.totals.messages_queued);
+    }
+    drop(listener);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_server_shared_between_tasks() -> Result<(), Box<dyn Error>> {
+    let mut server = crate::ServerSocket::new();
+    server.bind("127.0.0.1:5570").await?;
+    let server = std::sync::Arc::new(server);
+    for _ in 0..4 {
+        let server = server.clone();
+        tokio::spawn(async move {
+            loop {
+                let (routing_id, message) = server.recv().await.unwrap();
+                let request: String = message.try_into().unwrap();
+                server
+                    .send(&routing_id, format!("{} Reply", request).into())
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+
+    let mut client = crate::ClientSocket::new();
+    client.connect("127.0.0.1:5570").await?;
+    let client = std::sync::Arc::new(client);
+    let mut handles = Vec::new();
+    for i in 0..10i32 {
+        let client = client.clone();
+        handles.push(tokio::spawn(async move {
+            client.send(format!("Req {}", i).into()).await.unwrap();
+            let repl: String = client.recv().await.unwrap().try_into().unwrap();
+            repl
+        }));
+    }
+    let mut replies = Vec::new();
+    for handle in handles {
+        replies.push(handle.await?);
+    }
+    replies.sort();
+    let mut expected: Vec<String> = (0..10).map(|i| format!("Req {} Reply", i)).collect();
+    expected.sort();
+    assert_eq!(expected, replies);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_send_waits_per_peer() -> Result<(), Box<dyn Error>> {
+    let endpoint = "127.0.0.1:5678";
+    let mut server = crate::ServerSocket::with_options(crate::SocketOptions::default().send_hwm(1));
+    server.bind(endpoint).await?;
+    let server = std::sync::Arc::new(server);
+
+    // Raw client never reads, so once kernel buffers fill up its queue stays full
+    let mut stuck = raw_peer(endpoint, crate::SocketType::CLIENT).await;
+    stuck
+        .send(crate::codec::Message::Message("stuck".into()))
+        .await?;
+    let (stuck_id, _) = server.recv().await?;
+    let mut client = crate::ClientSocket::new();
+    client.connect(endpoint).await?;
+    client.send("hello".into()).await?;
+    let (client_id, _) = server.recv().await?;
+
+    let flooding = server.clone();
+    let flood = tokio::spawn(async move {
+        for _ in 0..256 {
+            let chunk = crate::ZmqMessage::from(vec![0u8; 1024 * 1024]);
+            flooding.send(&stuck_id, chunk).await.unwrap();
+        }
+    });
+    tokio::time::delay_for(Duration::from_millis(200)).await;
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        server.send(&client_id, "reply".into()),
+    )
+    .await??;
+    let reply: String = client.recv().await?.try_into()?;
+    assert_eq!("reply", reply);
+    assert!(
+        flood.now_or_never().is_none(),
+        "Stuck peer should still block"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_radio_dish_groups() -> Result<(), Box<dyn Error>> {
+    let mut radio = crate::RadioSocket::new();
+    radio.bind("127.0.0.1:5571").await?;
+
+    let mut dish = crate::DishSocket::new();
+    dish.join("weather").await?;
+    dish.connect("127.0.0.1:5571").await?;
+    // Give radio some time to process join command
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    assert!(radio
+        .send("this group name is too long", "".into())
+        .is_err());
+    radio.send("sports", "Goal".into())?;
+    radio.send("weather", "Sunny".into())?;
+
+    let (group, message) = dish.recv().await?;
+    assert_eq!("weather", group);
+    assert_eq!(b"Sunny", message.data.as_ref());
+
+    dish.leave("weather").await?;
+    assert!(dish.leave("weather").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dish_connects_while_join_waits() -> Result<(), Box<dyn Error>> {
+    let endpoint = "127.0.0.1:5681";
+    let mut dish = crate::DishSocket::with_options(crate::SocketOptions::default().send_hwm(1));
+    dish.bind(endpoint).await?;
+
+    // Raw radio never reads, so joins pile up in its queue
+    let _stuck = raw_peer(endpoint, crate::SocketType::RADIO).await;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let joining = tokio::spawn(async move {
+        for i in 0..1_000_000 {
+            dish.join(&format!("{:016}", i)).await.unwrap();
+        }
+    });
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+
+    // Late peer is replayed groups joined so far
+    let mut late = raw_peer(endpoint, crate::SocketType::RADIO).await;
+    match tokio::time::timeout(Duration::from_secs(1), late.next()).await? {
+        Some(Ok(crate::codec::Message::Command(crate::codec::ZmtpCommand::Join(_)))) => (),
+        other => panic!("Expected join, got {:?}", other),
+    }
+    assert!(
+        joining.now_or_never().is_none(),
+        "Stuck peer should still block"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scatter_gather_shared_between_tasks() -> Result<(), Box<dyn Error>> {
+    let mut gather = crate::GatherSocket::new();
+    gather.bind("127.0.0.1:5572").await?;
+
+    let mut scatter = crate::ScatterSocket::new();
+    assert!(scatter.send("Nobody listens".into()).await.is_err());
+    scatter.connect("127.0.0.1:5572").await?;
+    let scatter = std::sync::Arc::new(scatter);
+    for i in 0..10i32 {
+        let scatter = scatter.clone();
+        tokio::spawn(async move {
+            scatter.send(format!("Task {}", i).into()).await.unwrap();
+        });
+    }
+
+    let mut received = Vec::new();
+    for _ in 0..10 {
+        let message: String = gather.recv().await?.try_into()?;
+        received.push(message);
+    }
+    received.sort();
+    let mut expected: Vec<String> = (0..10).map(|i| format!("Task {}", i)).collect();
+    expected.sort();
+    assert_eq!(expected, received);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_rejects_multipart() -> Result<(), Box<dyn Error>> {
+    let mut gather = crate::GatherSocket::new();
+    gather.bind("127.0.0.1:5573").await?;
+
+    let mut peer = raw_peer("127.0.0.1:5573", crate::SocketType::SCATTER).await;
+    peer.send(crate::codec::Message::MultipartMessage(vec![
+        "a".into(),
+        "b".into(),
+    ]))
+    .await?;
+    assert!(matches!(
+        gather.recv().await,
+        Err(crate::ZmqError::Socket(_))
+    ));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_req_rep_over_ipc() -> Result<(), Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("zmq-rs-{}.sock", uuid::Uuid::new_v4()));
+    let endpoint = format!("ipc://{}", path.display());
+
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind(&endpoint).await?;
+    assert!(path.exists());
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect(&endpoint).await?;
+    for i in 0..10i32 {
+        req_socket.send(format!("Req - {}", i)).await?;
+        let mess: String = rep_socket.recv().await?.try_into()?;
+        rep_socket.send(format!("{} Rep", mess))?;
+        let repl: String = req_socket.recv().await?.try_into()?;
+        assert_eq!(format!("Req - {} Rep", i), repl)
+    }
+
+    drop(rep_socket);
+    // Socket file is removed by accept loop once it notices socket was closed
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pub_sub_over_inproc() -> Result<(), Box<dyn Error>> {
+    // Connect made before bind waits until somebody binds to the endpoint
+    let subscriber = tokio::spawn(async move {
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.connect("inproc://test-pub-sub").await.unwrap();
+        sub_socket.subscribe(b"").await.unwrap();
+        let message: String = sub_socket.recv().await.unwrap().try_into().unwrap();
+        message
+    });
+    tokio::time::delay_for(Duration::from_millis(10)).await;
+
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("inproc://test-pub-sub").await?;
+    let mut other_socket = crate::PubSocket::new();
+    assert!(other_socket.bind("inproc://test-pub-sub").await.is_err());
+
+    // Give subscriber some time to finish handshake and subscribe
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("Hello in process")?;
+    assert_eq!("Hello in process", subscriber.await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_scheme_endpoints() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5574").await?;
+    assert!(matches!(
+        rep_socket.bind("udt://127.0.0.1:5575").await,
+        Err(crate::ZmqError::Endpoint(
+            crate::EndpointError::UnknownTransport(_)
+        ))
+    ));
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect("tcp://127.0.0.1:5574").await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_by_hostname() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("127.0.0.1:5575").await?;
+
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://localhost:5575").await?;
+    push_socket.send("Resolved")?;
+    let message: String = pull_socket.recv().await?.try_into()?;
+    assert_eq!("Resolved", message);
+
+    let mut unresolved = crate::PushSocket::new();
+    match unresolved.connect("tcp://no-such-host.invalid:5575").await {
+        Err(crate::ZmqError::HostResolution(host)) => assert_eq!("no-such-host.invalid", host),
+        other => panic!("Unexpected connect result: {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+/// Finds address of a non loopback interface if machine has one
+fn concrete_interface_ip() -> Option<std::net::IpAddr> {
+    // Connecting UDP socket doesn't send anything but selects outgoing interface
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    if ip.is_loopback() || ip.is_unspecified() {
+        None
+    } else {
+        Some(ip)
+    }
+}
+
+#[tokio::test]
+async fn test_pub_wildcard_bind() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket = crate::PubSocket::new();
+    let bound = pub_socket.bind("tcp://*:5576").await?;
+    assert_eq!(
+        crate::Endpoint::Tcp(crate::Host::Ipv4(std::net::Ipv4Addr::UNSPECIFIED), 5576),
+        bound
+    );
+
+    let mut endpoints = vec!["tcp://127.0.0.1:5576".to_string()];
+    if let Some(ip) = concrete_interface_ip() {
+        endpoints.push(format!("{}:5576", ip));
+    }
+    let mut sub_sockets = Vec::new();
+    for endpoint in &endpoints {
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.connect(endpoint).await?;
+        sub_socket.subscribe(b"").await?;
+        sub_sockets.push(sub_socket);
+    }
+    // Give publisher some time to process subscriptions
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("Everyone")?;
+    for sub_socket in sub_sockets.iter_mut() {
+        let message: String = sub_socket.recv().await?.try_into()?;
+        assert_eq!("Everyone", message);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bind_to_random_port() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    assert!(rep_socket.last_endpoint().is_none());
+    let bound = rep_socket.bind("tcp://127.0.0.1:0").await?;
+    assert_eq!(Some(&bound), rep_socket.last_endpoint());
+    match &bound {
+        crate::Endpoint::Tcp(_, port) => assert_ne!(0, *port),
+        other => panic!("Unexpected endpoint: {}", other),
     }
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect(&bound.to_string()).await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_req_rep_over_ipv6() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://[::1]:5577").await?;
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect("tcp://[::1]:5577").await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pub_dual_stack_wildcard_bind() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket = crate::PubSocket::with_options(crate::SocketOptions::default().ipv6(true));
+    let bound = pub_socket.bind("tcp://*:5578").await?;
+    assert_eq!(
+        crate::Endpoint::Tcp(crate::Host::Ipv6(std::net::Ipv6Addr::UNSPECIFIED), 5578),
+        bound
+    );
+
+    let mut sub_sockets = Vec::new();
+    for endpoint in &["tcp://127.0.0.1:5578", "tcp://[::1]:5578"] {
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.connect(endpoint).await?;
+        sub_socket.subscribe(b"").await?;
+        sub_sockets.push(sub_socket);
+    }
+    // Give publisher some time to process subscriptions
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("Both stacks")?;
+    for sub_socket in sub_sockets.iter_mut() {
+        let message: String = sub_socket.recv().await?.try_into()?;
+        assert_eq!("Both stacks", message);
+    }
+
+    let options = crate::SocketOptions::default().ipv6(true).ipv6_only(true);
+    let mut v6_only_socket = crate::PubSocket::with_options(options);
+    v6_only_socket.bind("tcp://*:5579").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    assert!(sub_socket.connect("tcp://127.0.0.1:5579").await.is_err());
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn tls_configs() -> (
+    std::sync::Arc<crate::rustls::ServerConfig>,
+    crate::rustls::ClientConfig,
+) {
+    use crate::rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey, ServerConfig};
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_der = Certificate(cert.serialize_der().unwrap());
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(
+            vec![cert_der.clone()],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    let mut client_config = ClientConfig::new();
+    client_config.root_store.add(&cert_der).unwrap();
+    (std::sync::Arc::new(server_config), client_config)
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_req_rep_over_tls() -> Result<(), Box<dyn Error>> {
+    let (server_config, client_config) = tls_configs();
+    let mut rep_socket = crate::RepSocket::with_options(
+        crate::SocketOptions::default().tls_server_config(server_config),
+    );
+    rep_socket.bind("tls://127.0.0.1:5580").await?;
+
+    let mut req_socket = crate::ReqSocket::with_options(
+        crate::SocketOptions::default().tls_client_config(std::sync::Arc::new(client_config)),
+    );
+    req_socket.connect("tls://localhost:5580").await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+
+    // Client that doesn't trust server certificate fails during handshake
+    let mut untrusted = crate::ReqSocket::with_options(
+        crate::SocketOptions::default()
+            .tls_client_config(std::sync::Arc::new(crate::rustls::ClientConfig::new())),
+    );
+    match untrusted.connect("tls://localhost:5580").await {
+        Err(crate::ZmqError::Tls(reason)) => assert!(reason.contains("UnknownIssuer")),
+        other => panic!("Unexpected connect result: {:?}", other),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ws")]
+#[tokio::test]
+async fn test_req_rep_over_ws() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("ws://127.0.0.1:5581/zmq").await?;
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect("ws://127.0.0.1:5581/zmq").await?;
+    for i in 0..10i32 {
+        req_socket.send(format!("Req - {}", i)).await?;
+        let mess: String = rep_socket.recv().await?.try_into()?;
+        rep_socket.send(format!("{} Rep", mess))?;
+        let repl: String = req_socket.recv().await?.try_into()?;
+        assert_eq!(format!("Req - {} Rep", i), repl)
+    }
+
+    let mut wrong_path = crate::ReqSocket::new();
+    assert!(matches!(
+        wrong_path.connect("ws://127.0.0.1:5581/other").await,
+        Err(crate::ZmqError::WebSocket(_))
+    ));
+    Ok(())
+}
+
+#[cfg(all(feature = "ws", feature = "tls"))]
+#[tokio::test]
+async fn test_req_rep_over_wss() -> Result<(), Box<dyn Error>> {
+    let (server_config, client_config) = tls_configs();
+    let mut rep_socket = crate::RepSocket::with_options(
+        crate::SocketOptions::default().tls_server_config(server_config),
+    );
+    rep_socket.bind("wss://127.0.0.1:5582/zmq").await?;
+
+    let mut req_socket = crate::ReqSocket::with_options(
+        crate::SocketOptions::default().tls_client_config(std::sync::Arc::new(client_config)),
+    );
+    req_socket.connect("wss://localhost:5582/zmq").await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+    Ok(())
+}
+
+/// Minimal SOCKS5 proxy accepting only given credentials.
+/// Reports every requested destination as `host:port`
+async fn run_socks_proxy(
+    address: &str,
+    username: &'static str,
+    password: &'static str,
+) -> mpsc::UnboundedReceiver<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut listener = tokio::net::TcpListener::bind(address)
+        .await
+        .expect("Failed to bind proxy");
+    let (destinations, destinations_receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.expect("Failed to accept");
+            let destinations = destinations.clone();
+            tokio::spawn(async move {
+                let mut header = [0u8; 2];
+                client.read_exact(&mut header).await?;
+                let mut methods = vec![0u8; header[1] as usize];
+                client.read_exact(&mut methods).await?;
+                if !methods.contains(&2) {
+                    client.write_all(&[5, 0xff]).await?;
+                    return Ok::<(), std::io::Error>(());
+                }
+                client.write_all(&[5, 2]).await?;
+
+                let mut credentials = vec![0u8; 2];
+                client.read_exact(&mut credentials).await?;
+                let mut user = vec![0u8; credentials[1] as usize];
+                client.read_exact(&mut user).await?;
+                let mut pass = vec![0u8; client.read_u8().await? as usize];
+                client.read_exact(&mut pass).await?;
+                if user != username.as_bytes() || pass != password.as_bytes() {
+                    client.write_all(&[1, 1]).await?;
+                    return Ok(());
+                }
+                client.write_all(&[1, 0]).await?;
+
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await?;
+                let host = match request[3] {
+                    1 => {
+                        let mut ip = [0u8; 4];
+                        client.read_exact(&mut ip).await?;
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    }
+                    3 => {
+                        let mut name = vec![0u8; client.read_u8().await? as usize];
+                        client.read_exact(&mut name).await?;
+                        String::from_utf8(name).unwrap()
+                    }
+                    _ => panic!("Unexpected address type"),
+                };
+                let port = client.read_u16().await?;
+                let destination = format!("{}:{}", host, port);
+                destinations.unbounded_send(destination.clone()).unwrap();
+                let mut target = match tokio::net::TcpStream::connect(destination).await {
+                    Ok(target) => target,
+                    Err(_) => {
+                        client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                        return Ok(());
+                    }
+                };
+                client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+                let (mut client_read, mut client_write) = client.split();
+                let (mut target_read, mut target_write) = target.split();
+                let _ = futures::future::join(
+                    tokio::io::copy(&mut client_read, &mut target_write),
+                    tokio::io::copy(&mut target_read, &mut client_write),
+                )
+                .await;
+                Ok(())
+            });
+        }
+    });
+    destinations_receiver
+}
+
+#[tokio::test]
+async fn test_connect_through_socks_proxy() -> Result<(), Box<dyn Error>> {
+    let mut destinations = run_socks_proxy("127.0.0.1:5583", "user", "secret").await;
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5584").await?;
+
+    let proxy = crate::SocksProxy::new("127.0.0.1:5583")
+        .credentials("user", "secret")
+        .remote_dns(true);
+    let mut req_socket =
+        crate::ReqSocket::with_options(crate::SocketOptions::default().socks_proxy(proxy));
+    req_socket.connect("tcp://localhost:5584").await?;
+    // Hostname has to reach the proxy unresolved
+    assert_eq!(
+        Some("localhost:5584".to_string()),
+        destinations.next().await
+    );
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+
+    let proxy = crate::SocksProxy::new("127.0.0.1:5583").credentials("user", "wrong");
+    let mut rejected =
+        crate::ReqSocket::with_options(crate::SocketOptions::default().socks_proxy(proxy));
+    assert!(matches!(
+        rejected.connect("tcp://127.0.0.1:5584").await,
+        Err(crate::ZmqError::Socks(
+            crate::SocksError::AuthenticationFailed
+        ))
+    ));
+
+    let proxy = crate::SocksProxy::new("127.0.0.1:5583").credentials("user", "secret");
+    let mut refused =
+        crate::ReqSocket::with_options(crate::SocketOptions::default().socks_proxy(proxy));
+    assert!(matches!(
+        refused.connect("tcp://127.0.0.1:5585").await,
+        Err(crate::ZmqError::Socks(crate::SocksError::ConnectionRefused))
+    ));
+    assert_eq!(
+        Some("127.0.0.1:5585".to_string()),
+        destinations.next().await
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_radio_dish_over_udp() -> Result<(), Box<dyn Error>> {
+    let mut dish = crate::DishSocket::new();
+    dish.join("weather").await?;
+    let endpoint = dish.bind("udp://127.0.0.1:5586").await?;
+    assert_eq!("udp://127.0.0.1:5586", endpoint.to_string());
+
+    let mut radio = crate::RadioSocket::new();
+    assert!(radio.bind("udp://127.0.0.1:5587").await.is_err());
+    radio.connect("udp://127.0.0.1:5586").await?;
+    radio.send("sports", "Goal".into())?;
+    radio.send("weather", "Sunny".into())?;
+
+    let (group, message) = dish.recv().await?;
+    assert_eq!("weather", group);
+    assert_eq!(b"Sunny", message.data.as_ref());
+
+    let oversized = vec![0u8; crate::MAX_DATAGRAM_SIZE];
+    assert!(radio.send("weather", oversized.into()).is_err());
+
+    let mut push_socket = crate::PushSocket::new();
+    assert!(push_socket.connect("udp://127.0.0.1:5586").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bind_listener_and_connect_stream() -> Result<(), Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let mut rep_socket = crate::RepSocket::new();
+    let endpoint = rep_socket.bind_listener(listener).await?;
+    assert_eq!(format!("tcp://{}", address), endpoint.to_string());
+    assert_eq!(Some(&endpoint), rep_socket.last_endpoint());
+
+    let stream = tokio::net::TcpStream::connect(address).await?;
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect_stream(stream).await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_req_rep_over_abstract_ipc() -> Result<(), Box<dyn Error>> {
+    let name = format!("@zmq-rs-{}", uuid::Uuid::new_v4());
+    let endpoint = format!("ipc://{}", name);
+
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind(&endpoint).await?;
+    assert!(!std::path::Path::new(&name).exists());
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect(&endpoint).await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_req_rep_with_plain_mechanism() -> Result<(), Box<dyn Error>> {
+    let mut users = std::collections::HashMap::new();
+    users.insert("admin".to_string(), "secret".to_string());
+    let mut rep_socket =
+        crate::RepSocket::with_options(crate::SocketOptions::default().plain_server(users));
+    rep_socket.bind("tcp://127.0.0.1:5588").await?;
+
+    let mut req_socket = crate::ReqSocket::with_options(
+        crate::SocketOptions::default().plain_credentials("admin", "secret"),
+    );
+    req_socket.connect("tcp://127.0.0.1:5588").await?;
+    req_socket.send("Ping").await?;
+    let mess: String = rep_socket.recv().await?.try_into()?;
+    rep_socket.send(format!("{} Pong", mess))?;
+    let repl: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("Ping Pong", repl);
+
+    let mut wrong_password = crate::ReqSocket::with_options(
+        crate::SocketOptions::default().plain_credentials("admin", "guess"),
+    );
+    match wrong_password.connect("tcp://127.0.0.1:5588").await {
+        Err(crate::ZmqError::Rejected(reason)) => {
+            assert_eq!("Invalid username or password", reason)
+        }
+        other => panic!("Unexpected connect result: {:?}", other),
+    }
+
+    let mut null_mechanism = crate::ReqSocket::new();
+    assert!(matches!(
+        null_mechanism.connect("tcp://127.0.0.1:5588").await,
+        Err(crate::ZmqError::MechanismMismatch { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_plain_mechanism_with_callback() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::with_options(
+        crate::SocketOptions::default()
+            .plain_server_with(|username, password| async move { username == password }),
+    );
+    pull_socket.bind("tcp://127.0.0.1:5589").await?;
+
+    let mut push_socket = crate::PushSocket::with_options(
+        crate::SocketOptions::default().plain_credentials("same", "same"),
+    );
+    push_socket.connect("tcp://127.0.0.1:5589").await?;
+    push_socket.send("Authenticated")?;
+    let message: String = pull_socket.recv().await?.try_into()?;
+    assert_eq!("Authenticated", message);
+    Ok(())
+}
+
+#[cfg(feature = "curve")]
+#[tokio::test]
+async fn test_req_rep_with_curve_mechanism() -> Result<(), Box<dyn Error>> {
+    use crypto_box::aead::OsRng;
+    use crypto_box::SecretKey;
+
+    let server_secret = SecretKey::generate(&mut OsRng);
+    let server_public = *server_secret.public_key().as_bytes();
+    let client_secret = SecretKey::generate(&mut OsRng);
+    let client_public = *client_secret.public_key().as_bytes();
+
+    let mut rep_socket = crate::RepSocket::with_options(
+        crate::SocketOptions::default().curve_server(server_public, *server_secret.as_bytes()),
+    );
+    rep_socket.bind("tcp://127.0.0.1:5590").await?;
+
+    let mut req_socket =
+        crate::ReqSocket::with_options(crate::SocketOptions::default().curve_client(
+            client_public,
+            *client_secret.as_bytes(),
+            server_public,
+        ));
+    req_socket.connect("tcp://127.0.0.1:5590").await?;
+    for i in 0..3 {
+        req_socket.send(format!("Ping {}", i)).await?;
+        let request: String = rep_socket.recv().await?.try_into()?;
+        assert_eq!(format!("Ping {}", i), request);
+        rep_socket.send(format!("Pong {}", i))?;
+        let reply: String = req_socket.recv().await?.try_into()?;
+        assert_eq!(format!("Pong {}", i), reply);
+    }
+
+    let mut wrong_server_key =
+        crate::ReqSocket::with_options(crate::SocketOptions::default().curve_client(
+            client_public,
+            *client_secret.as_bytes(),
+            client_public,
+        ));
+    assert!(wrong_server_key
+        .connect("tcp://127.0.0.1:5590")
+        .await
+        .is_err());
+
+    let mut null_mechanism = crate::ReqSocket::new();
+    assert!(matches!(
+        null_mechanism.connect("tcp://127.0.0.1:5590").await,
+        Err(crate::ZmqError::MechanismMismatch { .. })
+    ));
+
+    let authenticator = std::sync::Arc::new(DomainAuthenticator {
+        domain: "curve",
+        requests: std::sync::Mutex::new(Vec::new()),
+    });
+    let mut pull_socket = crate::PullSocket::with_options(
+        crate::SocketOptions::default()
+            .curve_server(server_public, *server_secret.as_bytes())
+            .authenticator(authenticator.clone()),
+    );
+    pull_socket.bind("tcp://127.0.0.1:5593").await?;
+    let mut push_socket =
+        crate::PushSocket::with_options(crate::SocketOptions::default().curve_client(
+            client_public,
+            *client_secret.as_bytes(),
+            server_public,
+        ));
+    match push_socket.connect("tcp://127.0.0.1:5593").await {
+        Err(crate::ZmqError::Rejected(reason)) => assert_eq!("Unknown domain", reason),
+        other => panic!("Unexpected connect result: {:?}", other),
+    }
+    let requests = authenticator.requests.lock().unwrap();
+    assert_eq!(
+        crate::Credentials::Curve {
+            public_key: client_public
+        },
+        requests[0].credentials
+    );
+    Ok(())
+}
+
+/// Allows only peers from the domain and remembers every request it was asked about
+struct DomainAuthenticator {
+    domain: &'static str,
+    requests: std::sync::Mutex<Vec<crate::AuthRequest>>,
+}
+
+#[async_trait::async_trait]
+impl crate::Authenticator for DomainAuthenticator {
+    async fn authenticate(&self, request: crate::AuthRequest) -> crate::AuthResult {
+        let allowed = request.domain == self.domain;
+        self.requests.lock().unwrap().push(request);
+        if allowed {
+            crate::AuthResult::Allowed {
+                user_id: Some("tester".to_string()),
+            }
+        } else {
+            crate::AuthResult::deny("Unknown domain")
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_authenticator() -> Result<(), Box<dyn Error>> {
+    let authenticator = std::sync::Arc::new(DomainAuthenticator {
+        domain: "trusted",
+        requests: std::sync::Mutex::new(Vec::new()),
+    });
+    let mut pull_socket =
+        crate::PullSocket::with_options(crate::SocketOptions::default().zap_domain("trusted"));
+    pull_socket.set_authenticator(authenticator.clone());
+    pull_socket.bind("tcp://127.0.0.1:5591").await?;
+
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5591").await?;
+    push_socket.send("Authenticated")?;
+    let message = pull_socket.recv().await?;
+    assert_eq!(Some(&b"tester"[..]), message.metadata("User-Id").as_deref());
+    assert_eq!(Some(&b"NULL"[..]), message.metadata("Mechanism").as_deref());
+    assert_eq!(
+        Some(&b"127.0.0.1"[..]),
+        message.metadata("Peer-Address").as_deref()
+    );
+    assert_eq!(
+        message.peer_id().map(AsRef::as_ref),
+        message.metadata("Routing-Id").as_deref()
+    );
+    assert_eq!(
+        Some(&b"PUSH"[..]),
+        message.metadata("Socket-Type").as_deref()
+    );
+    let message: String = message.try_into()?;
+    assert_eq!("Authenticated", message);
+    {
+        let requests = authenticator.requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        assert_eq!("NULL", requests[0].mechanism());
+        assert_eq!(crate::Credentials::Null, requests[0].credentials);
+        assert_eq!(
+            Some("127.0.0.1".parse::<std::net::IpAddr>()?),
+            requests[0].address.map(|a| a.ip())
+        );
+    }
+
+    let mut untrusted_pull = crate::PullSocket::new();
+    untrusted_pull.set_authenticator(authenticator.clone());
+    untrusted_pull.bind("tcp://127.0.0.1:5592").await?;
+    let mut denied_push = crate::PushSocket::new();
+    match denied_push.connect("tcp://127.0.0.1:5592").await {
+        Err(crate::ZmqError::Rejected(reason)) => assert_eq!("Unknown domain", reason),
+        other => panic!("Unexpected connect result: {:?}", other),
+    }
+    assert_eq!(2, authenticator.requests.lock().unwrap().len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accept_filter() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5594").await?;
+    // Lists are applied to listener that is already running
+    pull_socket.accept_filter().allow("10.0.0.0/8")?;
+    assert!(matches!(
+        pull_socket.accept_filter().deny("127.0.0.300"),
+        Err(crate::ZmqError::IpNetwork(_))
+    ));
+
+    let mut denied_push = crate::PushSocket::new();
+    assert!(denied_push.connect("tcp://127.0.0.1:5594").await.is_err());
+    assert_eq!(1, pull_socket.accept_filter().denied_count());
+
+    pull_socket.accept_filter().allow("127.0.0.0/8")?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5594").await?;
+    push_socket.send("Allowed")?;
+    let message: String = pull_socket.recv().await?.try_into()?;
+    assert_eq!("Allowed", message);
+    assert_eq!(1, pull_socket.accept_filter().denied_count());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pub_with_zmtp_3_1_subscriber() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmtpCommand};
+
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("tcp://127.0.0.1:5595").await?;
+    let mut peer = raw_peer("127.0.0.1:5595", crate::SocketType::SUB).await;
+    peer.send(Message::Command(ZmtpCommand::subscribe(b"topic")))
+        .await?;
+    peer.send(Message::Command(ZmtpCommand::ping(10, b"ctx")))
+        .await?;
+    match peer.next().await {
+        Some(Ok(Message::Command(ZmtpCommand::Pong { context }))) => {
+            assert_eq!(b"ctx", context.as_ref())
+        }
+        other => panic!("Expected PONG, got {:?}", other),
+    }
+
+    pub_socket.send("other")?;
+    pub_socket.send("topic-1")?;
+    match peer.next().await {
+        Some(Ok(Message::Message(message))) => assert_eq!(b"topic-1", message.data.as_ref()),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+
+    peer.send(Message::Command(ZmtpCommand::cancel(b"topic")))
+        .await?;
+    // Legacy subscription messages are still understood
+    peer.send(Message::Message("\x01other".into())).await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    pub_socket.send("topic-2")?;
+    pub_socket.send("other-2")?;
+    match peer.next().await {
+        Some(Ok(Message::Message(message))) => assert_eq!(b"other-2", message.data.as_ref()),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sub_with_zmtp_3_0_publisher() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmqCodec, ZmqGreeting, ZmqMechanism};
+
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5596").await?;
+    let publisher = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.expect("Failed to accept");
+        let mut peer = tokio_util::codec::Framed::new(stream, ZmqCodec::new());
+        let mut greeting = ZmqGreeting::new(ZmqMechanism::NULL, false);
+        greeting.version = (3, 0);
+        peer.send(Message::Greeting(greeting)).await.unwrap();
+        match peer.next().await {
+            Some(Ok(Message::Greeting(greeting))) => assert_eq!((3, 1), greeting.version),
+            other => panic!("Expected greeting, got {:?}", other),
+        }
+        peer.send(ready(crate::SocketType::PUB)).await.unwrap();
+        peer.next().await.unwrap().unwrap();
+        peer.next().await.unwrap().unwrap()
+    });
+
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.subscribe(b"topic").await?;
+    sub_socket.connect("tcp://127.0.0.1:5596").await?;
+    match publisher.await? {
+        Message::Message(message) => assert_eq!(b"\x01topic", message.data.as_ref()),
+        other => panic!("Expected legacy subscription message, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat_evicts_dead_subscriber() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmtpCommand};
+
+    let mut pub_socket = crate::PubSocket::with_options(
+        crate::SocketOptions::default()
+            .heartbeat_interval(Duration::from_millis(50))
+            .heartbeat_timeout(Duration::from_millis(200))
+            .heartbeat_ttl(Duration::from_secs(1)),
+    );
+    pub_socket.bind("tcp://127.0.0.1:5597").await?;
+
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.subscribe(b"").await?;
+    sub_socket.connect("tcp://127.0.0.1:5597").await?;
+    let mut silent_peer = raw_peer("127.0.0.1:5597", crate::SocketType::SUB).await;
+    match silent_peer.next().await {
+        Some(Ok(Message::Command(ZmtpCommand::Ping { ttl, .. }))) => assert_eq!(10, ttl),
+        other => panic!("Expected PING, got {:?}", other),
+    }
+    assert_eq!(2, pub_socket.backend.subscribers.len());
+
+    // SubSocket answers PINGs on its own while raw peer stays silent
+    tokio::time::delay_for(Duration::from_millis(400)).await;
+    assert_eq!(1, pub_socket.backend.subscribers.len());
+    pub_socket.send("Still alive")?;
+    let message: String = sub_socket.recv().await?.try_into()?;
+    assert_eq!("Still alive", message);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat_fails_pending_request() -> Result<(), Box<dyn Error>> {
+    let mut req_socket = crate::ReqSocket::with_options(
+        crate::SocketOptions::default().heartbeat_interval(Duration::from_millis(50)),
+    );
+    req_socket.bind("tcp://127.0.0.1:5598").await?;
+    let _silent_peer = raw_peer("127.0.0.1:5598", crate::SocketType::REP).await;
+    tokio::time::delay_for(Duration::from_millis(20)).await;
+
+    req_socket.send("Ping").await?;
+    assert!(matches!(
+        req_socket.recv().await,
+        Err(crate::ZmqError::ConnectionLost)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() -> Result<(), Box<dyn Error>> {
+    use tokio::io::AsyncReadExt;
+
+    let options = crate::SocketOptions::default().handshake_timeout(Duration::from_millis(100));
+    let mut pull_socket = crate::PullSocket::with_options(options.clone());
+    pull_socket.bind("tcp://127.0.0.1:5599").await?;
+
+    // Peers that connect and never write anything get disconnected
+    let mut silent_peers = Vec::new();
+    for _ in 0..3 {
+        silent_peers.push(tokio::net::TcpStream::connect("127.0.0.1:5599").await?);
+    }
+    for peer in silent_peers.iter_mut() {
+        let mut received = Vec::new();
+        // Only greeting arrives before connection is closed
+        tokio::time::timeout(Duration::from_secs(1), peer.read_to_end(&mut received)).await??;
+        assert_eq!(64, received.len());
+    }
+
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5600").await?;
+    // Accepted stream is kept inside join handle so connection stays open
+    let _silent_server = tokio::spawn(async move { listener.accept().await });
+    let mut push_socket = crate::PushSocket::with_options(options);
+    assert!(matches!(
+        push_socket.connect("tcp://127.0.0.1:5600").await,
+        Err(crate::ZmqError::HandshakeTimeout)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incompatible_peer_socket_type() -> Result<(), Box<dyn Error>> {
+    let all_types: Vec<crate::SocketType> = (0..18)
+        .map(|i| num_traits::FromPrimitive::from_u8(i).unwrap())
+        .collect();
+    for one in &all_types {
+        for another in &all_types {
+            assert_eq!(
+                crate::util::sockets_compatible(*one, *another),
+                crate::util::sockets_compatible(*another, *one),
+                "{} and {}",
+                one,
+                another
+            );
+        }
+    }
+
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5601").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    match sub_socket.connect("tcp://127.0.0.1:5601").await {
+        Err(crate::ZmqError::InvalidPeerSocketType { ours, theirs }) => {
+            assert_eq!(crate::SocketType::SUB, ours);
+            assert_eq!(crate::SocketType::REP, theirs);
+        }
+        other => panic!("Unexpected connect result: {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_command() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmqCodec, ZmqGreeting, ZmqMechanism, ZmtpCommand};
+
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5602").await?;
+    let rejecting_peer = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.expect("Failed to accept");
+        let mut peer = tokio_util::codec::Framed::new(stream, ZmqCodec::new());
+        peer.send(Message::Greeting(ZmqGreeting::new(
+            ZmqMechanism::NULL,
+            false,
+        )))
+        .await
+        .unwrap();
+        peer.next().await.unwrap().unwrap();
+        peer.send(Message::Command(ZmtpCommand::error("Go away")))
+            .await
+            .unwrap();
+    });
+    let mut req_socket = crate::ReqSocket::new();
+    match req_socket.connect("tcp://127.0.0.1:5602").await {
+        Err(crate::ZmqError::Rejected(reason)) => assert_eq!("Go away", reason),
+        other => panic!("Unexpected connect result: {:?}", other),
+    }
+    rejecting_peer.await?;
+
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5603").await?;
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:5603").await?;
+    let mut peer = tokio_util::codec::Framed::new(stream, ZmqCodec::new());
+    peer.send(Message::Greeting(ZmqGreeting::new(
+        ZmqMechanism::NULL,
+        false,
+    )))
+    .await?;
+    peer.next().await.unwrap()?;
+    peer.send(ready(crate::SocketType::PUB)).await?;
+    peer.next().await.unwrap()?;
+    match peer.next().await {
+        Some(Ok(Message::Command(ZmtpCommand::Error { reason }))) => {
+            assert_eq!("Invalid socket type", reason)
+        }
+        other => panic!("Expected ERROR command, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version_negotiation() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmqCodec, ZmqGreeting, ZmqMechanism, ZmtpCommand};
+
+    // Subscriptions travel as commands since 3.1 and as prefixed messages before
+    for (peer_version, negotiated) in &[((3, 0), (3, 0)), ((3, 1), (3, 1)), ((3, 2), (3, 1))] {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5604").await?;
+        let peer_version = *peer_version;
+        let publisher = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("Failed to accept");
+            let mut peer = tokio_util::codec::Framed::new(stream, ZmqCodec::new());
+            let mut greeting = ZmqGreeting::new(ZmqMechanism::NULL, false);
+            greeting.version = peer_version;
+            peer.send(Message::Greeting(greeting)).await.unwrap();
+            match peer.next().await {
+                Some(Ok(Message::Greeting(greeting))) => assert_eq!((3, 1), greeting.version),
+                other => panic!("Expected greeting, got {:?}", other),
+            }
+            peer.send(ready(crate::SocketType::PUB)).await.unwrap();
+            peer.next().await.unwrap().unwrap();
+            peer.next().await.unwrap().unwrap()
+        });
+
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.subscribe(b"topic").await?;
+        sub_socket.connect("tcp://127.0.0.1:5604").await?;
+        match publisher.await? {
+            Message::Message(message) if *negotiated < (3, 1) => {
+                assert_eq!(b"\x01topic", message.data.as_ref())
+            }
+            Message::Command(command) if *negotiated >= (3, 1) => {
+                assert_eq!(ZmtpCommand::subscribe(b"topic"), command)
+            }
+            other => panic!(
+                "Unexpected subscription for version {:?}: {:?}",
+                peer_version, other
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_zmtp2_fallback() -> Result<(), Box<dyn Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Behaves like libzmq 3.2 PUB socket: whole greeting and identity go out at once
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5605").await?;
+    let publisher = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("Failed to accept");
+        stream
+            .write_all(&[0xff, 0, 0, 0, 0, 0, 0, 0, 1, 0x7f, 1, 1, 0, 0])
+            .await
+            .unwrap();
+        let mut greeting = [0u8; 12];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(crate::SocketType::SUB as u8, greeting[11]);
+        let mut identity = [0u8; 2];
+        stream.read_exact(&mut identity).await.unwrap();
+        assert_eq!([0, 0], identity);
+        let mut subscription = [0u8; 8];
+        stream.read_exact(&mut subscription).await.unwrap();
+        assert_eq!(b"\x00\x06\x01topic", &subscription);
+        stream.write_all(b"\x00\x0btopic hello").await.unwrap();
+        stream
+    });
+
+    let mut sub_socket =
+        crate::SubSocket::with_options(crate::SocketOptions::default().zmtp2_fallback(true));
+    sub_socket.subscribe(b"topic").await?;
+    sub_socket.connect("tcp://127.0.0.1:5605").await?;
+    let message: String = sub_socket.recv().await?.try_into()?;
+    assert_eq!("topic hello", message);
+    drop(publisher.await?);
+
+    // ZMTP 3 peers are not affected by the fallback
+    let fallback = crate::SocketOptions::default().zmtp2_fallback(true);
+    let mut rep_socket = crate::RepSocket::with_options(fallback.clone());
+    rep_socket.bind("tcp://127.0.0.1:5606").await?;
+    for options in [fallback, crate::SocketOptions::default()] {
+        let mut req_socket = crate::ReqSocket::with_options(options);
+        req_socket.connect("tcp://127.0.0.1:5606").await?;
+        req_socket.send("Ping").await?;
+        let mess: String = rep_socket.recv().await?.try_into()?;
+        rep_socket.send(format!("{} Pong", mess))?;
+        let repl: String = req_socket.recv().await?.try_into()?;
+        assert_eq!("Ping Pong", repl);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_identity_in_ready_metadata() -> Result<(), Box<dyn Error>> {
+    let identity: crate::PeerIdentity = b"worker".to_vec().try_into()?;
+    let worker_options = crate::SocketOptions::default().identity(identity.clone());
+
+    for (port, handover) in &[(5607, false), (5608, true)] {
+        let endpoint = format!("tcp://127.0.0.1:{}", port);
+        let mut router_socket = crate::RouterSocket::with_options(
+            crate::SocketOptions::default().identity_handover(*handover),
+        );
+        router_socket.bind(&endpoint).await?;
+
+        let mut first = crate::DealerSocket::with_options(worker_options.clone());
+        first.connect(&endpoint).await?;
+        first.send_multipart(vec!["first".into()]).await?;
+        let (peer_id, messages) = router_socket.recv().await?;
+        assert_eq!(identity, peer_id);
+        assert_eq!(Some(&identity), messages[0].peer_id());
+        assert_eq!("first", String::from_utf8(messages[0].data.to_vec())?);
+
+        let mut second = crate::DealerSocket::with_options(worker_options.clone());
+        second.connect(&endpoint).await?;
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        router_socket
+            .send_to(&identity, vec!["reply".into()])
+            .await?;
+        let (receiver, idle) = if *handover {
+            (&mut second, &mut first)
+        } else {
+            (&mut first, &mut second)
+        };
+        let reply = receiver.recv_multipart().await?;
+        assert_eq!("reply", String::from_utf8(reply[0].data.to_vec())?);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), idle.recv_multipart())
+                .await
+                .is_err()
+        );
+        if *handover {
+            // Closing replaced connection leaves the new one routable
+            drop(first);
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            router_socket
+                .send_to(&identity, vec!["again".into()])
+                .await?;
+            let reply = second.recv_multipart().await?;
+            assert_eq!("again", String::from_utf8(reply[0].data.to_vec())?);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_properties() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.set_handshake_property("X-Service", b"echo")?;
+    rep_socket.bind("tcp://127.0.0.1:5609").await?;
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.set_handshake_property("Resource", b"/ping")?;
+    req_socket.connect("tcp://127.0.0.1:5609").await?;
+    req_socket.send("Ping").await?;
+    let request = rep_socket.recv().await?;
+    assert_eq!(Some(&b"REQ"[..]), request.property("Socket-Type"));
+    assert_eq!(Some(&b"/ping"[..]), request.property("Resource"));
+    rep_socket.send("Pong")?;
+    let reply = req_socket.recv().await?;
+    assert_eq!(Some(&b"echo"[..]), reply.property("X-Service"));
+    assert_eq!(None, crate::ZmqMessage::from("local").properties());
+    assert_eq!(None, crate::ZmqMessage::from("local").peer_id());
+    assert_eq!(
+        None,
+        crate::ZmqMessage::from("local").metadata("Peer-Address")
+    );
+
+    for (name, value) in &[
+        ("Socket-Type", &b"PUB"[..]),
+        ("identity", b"spoofed"),
+        ("No spaces", b""),
+        ("", b""),
+        ("X-Large", &[0u8; 64 * 1024 + 1][..]),
+    ] {
+        assert!(matches!(
+            req_socket.set_handshake_property(name, value),
+            Err(crate::ZmqError::InvalidProperty(_))
+        ));
+    }
+
+    let mut body = bytes::BytesMut::new();
+    let properties: crate::Properties = (0..65).map(|i| (format!("X-{}", i), Vec::new())).collect();
+    crate::codec::encode_properties(&properties, &mut body);
+    assert!(crate::codec::parse_properties(body.freeze()).is_err());
+    let truncated = bytes::Bytes::from_static(b"\x05X-Bad\x00\x00\x00\x10short");
+    assert!(crate::codec::parse_properties(truncated).is_err());
+    Ok(())
+}
+
+/// Hands out scripted input one byte per read, stalling in between, like a slow TCP link.
+/// Stays pending forever once input is exhausted. Written data is discarded
+struct TrickleStream {
+    input: bytes::Bytes,
+    stalled: bool,
+}
+
+impl tokio::io::AsyncRead for TrickleStream {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use bytes::Buf;
+        self.stalled = !self.stalled;
+        if self.stalled || self.input.is_empty() {
+            if !self.input.is_empty() {
+                cx.waker().wake_by_ref();
+            }
+            return std::task::Poll::Pending;
+        }
+        buf[0] = self.input[0];
+        self.input.advance(1);
+        std::task::Poll::Ready(Ok(1))
+    }
+}
+
+impl tokio::io::AsyncWrite for TrickleStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_with_partial_reads() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmqCodec, ZmqGreeting, ZmqMechanism};
+    use tokio_util::codec::{Encoder, Framed};
+
+    let mut input = bytes::BytesMut::new();
+    let mut codec = ZmqCodec::new();
+    codec.encode(
+        Message::Greeting(ZmqGreeting::new(ZmqMechanism::NULL, false)),
+        &mut input,
+    )?;
+    codec.encode(ready(crate::SocketType::ROUTER), &mut input)?;
+    codec.encode(Message::Message("Hello".into()), &mut input)?;
+    let stream = TrickleStream {
+        input: input.freeze(),
+        stalled: false,
+    };
+    let mut socket = Framed::new(stream, ZmqCodec::new());
+    let options = crate::SocketOptions::default();
+    crate::util::greet_exchange(&mut socket, &options).await?;
+    crate::util::ready_exchange(&mut socket, crate::SocketType::DEALER, &options, None).await?;
+    match socket.next().await {
+        Some(Ok(Message::Message(message))) => assert_eq!(b"Hello", message.data.as_ref()),
+        other => panic!("Expected message, got {:?}", other),
+    }
+
+    // Garbage is rejected after the signature without waiting for the rest of greeting
+    let stream = TrickleStream {
+        input: bytes::Bytes::from_static(b"GET / HTTP"),
+        stalled: false,
+    };
+    let mut socket = Framed::new(stream, ZmqCodec::new());
+    let result = tokio::time::timeout(
+        Duration::from_secs(1),
+        crate::util::greet_exchange(&mut socket, &options),
+    )
+    .await?;
+    assert!(matches!(result, Err(crate::ZmqError::Codec(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_message_size() -> Result<(), Box<dyn Error>> {
+    use crate::codec::ZmqCodec;
+    use tokio_util::codec::Decoder;
+
+    // Long frame declaring 8 GB payload
+    let mut codec = ZmqCodec::zmtp2();
+    codec.set_max_message_size(1024);
+    let mut input = bytes::BytesMut::from(&b"\x02\x00\x00\x00\x02\x00\x00\x00\x00"[..]);
+    match codec.decode(&mut input) {
+        Err(crate::ZmqError::MessageTooLarge { size, limit }) => {
+            assert_eq!(8 << 30, size);
+            assert_eq!(1024, limit);
+        }
+        other => panic!("Unexpected decode result: {:?}", other),
+    }
+    assert!(input.capacity() < 1024);
+
+    // Every frame fits but multipart message as a whole doesn't
+    let mut codec = ZmqCodec::zmtp2();
+    codec.set_max_message_size(16);
+    let mut input = bytes::BytesMut::new();
+    input.extend_from_slice(b"\x01\x0a0123456789\x00\x0a0123456789");
+    assert!(matches!(
+        codec.decode(&mut input),
+        Err(crate::ZmqError::MessageTooLarge { size: 20, .. })
+    ));
+
+    let mut pull_socket =
+        crate::PullSocket::with_options(crate::SocketOptions::default().max_message_size(1024));
+    pull_socket.bind("tcp://127.0.0.1:5610").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5610").await?;
+    push_socket.send(vec![0u8; 1024])?;
+    assert_eq!(1024, pull_socket.recv().await?.data.len());
+    push_socket.send(vec![0u8; 1025])?;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), pull_socket.recv())
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_malformed_input_from_peer() -> Result<(), Box<dyn Error>> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("tcp://127.0.0.1:5611").await?;
+
+    // Valid signature followed by garbage, and a greeting with frames using reserved flag bits
+    let garbage: [&[u8]; 2] = [
+        b"\xff\x00\x00\x00\x00\x00\x00\x00\x01\x7f\x03\x01NULL\x00\xfe\xfe\xfe\xfe",
+        b"\xff\x00\x00\x00\x00\x00\x00\x00\x01\x7f\x03\x01NULL\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xf8\xff\x04\x00",
+    ];
+    for bytes in garbage.iter() {
+        let mut raw = tokio::net::TcpStream::connect("127.0.0.1:5611").await?;
+        raw.write_all(bytes).await?;
+    }
+
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5611").await?;
+    sub_socket.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("still alive")?;
+    let message = tokio::time::timeout(Duration::from_secs(1), sub_socket.recv()).await??;
+    assert_eq!("still alive", String::from_utf8(message.data.to_vec())?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batched_flush() -> Result<(), Box<dyn Error>> {
+    let options =
+        crate::SocketOptions::default().flush_strategy(crate::FlushStrategy::Batched(4096));
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5614").await?;
+    let mut push_socket = crate::PushSocket::with_options(options);
+    push_socket.connect("tcp://127.0.0.1:5614").await?;
+
+    // Lone message is written right away even though batch is far from full
+    push_socket.send("first")?;
+    let message = tokio::time::timeout(Duration::from_millis(500), pull_socket.recv()).await??;
+    assert_eq!("first", String::from_utf8(message.data.to_vec())?);
+
+    for i in 0..50 {
+        push_socket.send(format!("message {}", i))?;
+    }
+    for i in 0..50 {
+        let message = pull_socket.recv().await?;
+        assert_eq!(
+            format!("message {}", i),
+            String::from_utf8(message.data.to_vec())?
+        );
+    }
+    Ok(())
+}
+
+/// Run with `cargo test --release -- --ignored --nocapture bench_flush_strategy`
+#[tokio::test]
+#[ignore]
+async fn bench_flush_strategy() -> Result<(), Box<dyn Error>> {
+    const COUNT: usize = 200_000;
+    let strategies = [
+        (crate::FlushStrategy::PerMessage, "tcp://127.0.0.1:5612"),
+        (
+            crate::FlushStrategy::Batched(64 * 1024),
+            "tcp://127.0.0.1:5613",
+        ),
+    ];
+    for (strategy, endpoint) in strategies.iter() {
+        let mut pull_socket = crate::PullSocket::new();
+        pull_socket.bind(endpoint).await?;
+        let mut push_socket = crate::PushSocket::with_options(
+            crate::SocketOptions::default().flush_strategy(*strategy),
+        );
+        push_socket.connect(endpoint).await?;
+
+        let started = std::time::Instant::now();
+        let receiver = tokio::spawn(async move {
+            for _ in 0..COUNT {
+                pull_socket.recv().await.expect("Failed to receive");
+            }
+        });
+        let payload = vec![0u8; 64];
+        let mut sent = 0;
+        while sent < COUNT {
+            match push_socket.send(payload.clone()) {
+                Ok(()) => sent += 1,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        }
+        receiver.await?;
+        let elapsed = started.elapsed();
+        println!(
+            "{:?}: {:.0} msg/s of 64 B",
+            strategy,
+            COUNT as f64 / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Run with `cargo test --release -- --ignored --nocapture bench_large_frames`
+#[tokio::test]
+#[ignore]
+async fn bench_large_frames() -> Result<(), Box<dyn Error>> {
+    const COUNT: usize = 2_000;
+    const SIZE: usize = 1024 * 1024;
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5624").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5624").await?;
+
+    let started = std::time::Instant::now();
+    let receiver = tokio::spawn(async move {
+        for _ in 0..COUNT {
+            pull_socket.recv().await.expect("Failed to receive");
+        }
+    });
+    // Payload is produced once, every send shares it
+    let payload = bytes::Bytes::from(vec![0u8; SIZE]);
+    let mut sent = 0;
+    while sent < COUNT {
+        match push_socket.send(payload.clone()) {
+            Ok(()) => sent += 1,
+            Err(_) => tokio::task::yield_now().await,
+        }
+    }
+    receiver.await?;
+    let elapsed = started.elapsed();
+    println!(
+        "{:.0} MB/s in 1 MB frames",
+        (COUNT * SIZE) as f64 / elapsed.as_secs_f64() / 1e6
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_frames_keep_order() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5625").await?;
+    let mut push_socket = crate::PushSocket::with_options(
+        crate::SocketOptions::default().flush_strategy(crate::FlushStrategy::Batched(1 << 20)),
+    );
+    push_socket.connect("tcp://127.0.0.1:5625").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    let large: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    push_socket.send("before")?;
+    push_socket.send_multipart(vec!["header".into(), large.clone().into()])?;
+    push_socket.send("after")?;
+
+    assert_eq!("before", pull_socket.recv_string().await?);
+    let frames = pull_socket.recv_multipart().await?;
+    assert_eq!(2, frames.len());
+    assert_eq!(&b"header"[..], &frames[0].data[..]);
+    assert_eq!(&large[..], &frames[1].data[..]);
+    assert_eq!("after", pull_socket.recv_string().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_buffer_pool() -> Result<(), Box<dyn Error>> {
+    let pool = crate::BufferPool::new(4096, 16);
+    let mut pub_socket =
+        crate::PubSocket::with_options(crate::SocketOptions::default().buffer_pool(pool.clone()));
+    pub_socket.bind("tcp://127.0.0.1:5615").await?;
+
+    let mut sub_sockets = Vec::new();
+    for _ in 0..3 {
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.connect("tcp://127.0.0.1:5615").await?;
+        sub_sockets.push(sub_socket);
+    }
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(0, pool.stats().idle);
+    // Closed connections give buffers back
+    drop(sub_sockets);
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let stats = pool.stats();
+    assert_eq!(6, stats.allocated);
+    assert_eq!(6, stats.idle);
+
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.subscribe(b"").await?;
+    sub_socket.connect("tcp://127.0.0.1:5615").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(2, pool.stats().reused);
+
+    // Write buffer grown for large message is swapped for a pooled one.
+    // Even larger messages bypass write buffer entirely
+    pub_socket.send(vec![0u8; 32 * 1024])?;
+    assert_eq!(32 * 1024, sub_socket.recv().await?.data.len());
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let stats = pool.stats();
+    assert_eq!(1, stats.trimmed);
+    assert_eq!(3, stats.reused);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_large_frame() -> Result<(), Box<dyn Error>> {
+    let frame: Vec<u8> = (0..4_000_000u32).map(|i| i as u8).collect();
+    let mut pull_socket = crate::PullSocket::with_options(
+        crate::SocketOptions::default().streaming_threshold(1 << 20),
+    );
+    pull_socket.bind("tcp://127.0.0.1:5616").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5616").await?;
+    push_socket.send(frame.clone())?;
+    push_socket.send("after")?;
+
+    let mut received = Vec::new();
+    loop {
+        let message = pull_socket.recv().await?;
+        let chunk = message.chunk().expect("Large frame is delivered in chunks");
+        assert_eq!(received.len() as u64, chunk.offset);
+        assert_eq!(frame.len() as u64, chunk.frame_len);
+        received.extend_from_slice(&message.data);
+        if chunk.last {
+            break;
+        }
+    }
+    assert_eq!(frame, received);
+    let message = pull_socket.recv().await?;
+    assert_eq!(None, message.chunk());
+    assert_eq!("after", String::from_utf8(message.data.to_vec())?);
+    Ok(())
+}
+
+fn frames_to_strings(frames: Vec<crate::ZmqMessage>) -> Vec<String> {
+    frames
+        .into_iter()
+        .map(|frame| String::from_utf8(frame.data.to_vec()).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_req_rep_multipart() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5617").await?;
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect("tcp://127.0.0.1:5617").await?;
+
+    req_socket
+        .send_multipart(crate::ZmqMessage::multipart(["a", "", "c"]))
+        .await?;
+    let request = rep_socket.recv_multipart().await?;
+    assert_eq!(vec!["a", "", "c"], frames_to_strings(request));
+    rep_socket.send_multipart(vec!["x".into(), "y".into(), "z".into()])?;
+    let reply = req_socket.recv_multipart().await?;
+    assert_eq!(vec!["x", "y", "z"], frames_to_strings(reply));
+
+    // Single frame recv refuses to drop frames
+    req_socket.send("ping").await?;
+    assert_eq!(
+        "ping",
+        String::from_utf8(rep_socket.recv().await?.data.to_vec())?
+    );
+    rep_socket.send_multipart(vec!["po".into(), "ng".into()])?;
+    assert!(matches!(
+        req_socket.recv().await,
+        Err(crate::ZmqError::Other(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_req_with_raw_rep_peer() -> Result<(), Box<dyn Error>> {
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.bind("tcp://127.0.0.1:5618").await?;
+    let mut peer = raw_peer("127.0.0.1:5618", crate::SocketType::REP).await;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    req_socket.send("request").await?;
+    match peer.next().await {
+        Some(Ok(crate::codec::Message::MultipartMessage(frames))) => {
+            assert_eq!(vec!["", "request"], frames_to_strings(frames))
+        }
+        other => panic!("Unexpected message {:?}", other),
+    }
+    peer.send(crate::codec::Message::MultipartMessage(vec![
+        "".into(),
+        "one".into(),
+        "two".into(),
+        "three".into(),
+    ]))
+    .await?;
+    let reply = req_socket.recv_multipart().await?;
+    assert_eq!(vec!["one", "two", "three"], frames_to_strings(reply));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rep_keeps_routing_envelope() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5619").await?;
+    let mut peer = raw_peer("127.0.0.1:5619", crate::SocketType::DEALER).await;
+
+    // Request without delimiter is dropped
+    peer.send(crate::codec::Message::Message("malformed".into()))
+        .await?;
+    peer.send(crate::codec::Message::MultipartMessage(vec![
+        "hop1".into(),
+        "hop2".into(),
+        "".into(),
+        "request".into(),
+    ]))
+    .await?;
+    let request = rep_socket.recv_multipart().await?;
+    assert!(request[0].peer_id().is_some());
+    assert_eq!(vec!["request"], frames_to_strings(request));
+    let envelope = rep_socket.envelope().expect("Request in progress").to_vec();
+    assert_eq!(vec!["hop1", "hop2", ""], frames_to_strings(envelope));
+    rep_socket.send_multipart(vec!["a".into(), "b".into()])?;
+    assert!(rep_socket.envelope().is_none());
+    match peer.next().await {
+        Some(Ok(crate::codec::Message::MultipartMessage(frames))) => {
+            assert_eq!(
+                vec!["hop1", "hop2", "", "a", "b"],
+                frames_to_strings(frames)
+            )
+        }
+        other => panic!("Unexpected message {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recv_string() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5620").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5620").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    push_socket.send_str("Grüße")?;
+    assert_eq!("Grüße", pull_socket.recv_string().await?);
+
+    push_socket.send(vec![b'o', b'k', 0xff])?;
+    match pull_socket.recv_string().await {
+        Err(crate::ZmqError::InvalidUtf8 { message, .. }) => {
+            assert_eq!(&[b'o', b'k', 0xff][..], &message.data[..])
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    push_socket.send_multipart(crate::ZmqMessage::multipart(["a", "b"]))?;
+    assert!(matches!(
+        pull_socket.recv_string().await,
+        Err(crate::ZmqError::Other(_))
+    ));
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn test_send_recv_json() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5621").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5621").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    push_socket.send_serde(crate::Json, &("first", 1u32))?;
+    push_socket.send("{not json")?;
+    push_socket.send_serde(crate::Json, &("second", 2u32))?;
+
+    let value: (String, u32) = pull_socket.recv_serde(crate::Json).await?;
+    assert_eq!(("first".to_string(), 1), value);
+    let malformed = pull_socket
+        .recv_serde::<_, (String, u32)>(crate::Json)
+        .await;
+    assert!(matches!(malformed, Err(crate::ZmqError::Serde(_))));
+    // Socket stays usable after malformed message
+    let value: (String, u32) = pull_socket.recv_serde(crate::Json).await?;
+    assert_eq!(("second".to_string(), 2), value);
+    Ok(())
+}
+
+#[cfg(feature = "bincode")]
+#[tokio::test]
+async fn test_pub_sub_bincode_with_topic() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("tcp://127.0.0.1:5622").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5622").await?;
+    sub_socket.subscribe(b"weather").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    pub_socket.send_serde_with_topic("traffic", crate::Bincode, &vec![1u8, 2, 3])?;
+    pub_socket.send_serde_with_topic("weather", crate::Bincode, &(21.5f64, "sunny"))?;
+    let (topic, value): (_, (f64, String)) =
+        sub_socket.recv_serde_with_topic(crate::Bincode).await?;
+    assert_eq!(&b"weather"[..], &topic.data[..]);
+    assert_eq!((21.5, "sunny".to_string()), value);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recv_into() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5623").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5623").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    push_socket.send("first")?;
+    push_socket.send("second")?;
+    let mut buf = bytes::BytesMut::with_capacity(64);
+    let capacity = buf.capacity();
+    assert_eq!(5, pull_socket.recv_into(&mut buf).await?);
+    assert_eq!(&b"first"[..], &buf[..]);
+    buf.clear();
+    assert_eq!(6, pull_socket.recv_into(&mut buf).await?);
+    assert_eq!(&b"second"[..], &buf[..]);
+    assert_eq!(capacity, buf.capacity());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_try_with_options() {
+    let handover = || crate::SocketOptions::default().identity_handover(true);
+    assert!(crate::RouterSocket::try_with_options(handover()).is_ok());
+    assert!(matches!(
+        crate::DealerSocket::try_with_options(handover()),
+        Err(crate::ZmqError::UnsupportedOption {
+            option: "identity_handover",
+            socket_type: crate::SocketType::DEALER,
+        })
+    ));
+    assert!(matches!(
+        crate::PushSocket::try_with_options(crate::SocketOptions::default().queue_capacity(0)),
+        Err(crate::ZmqError::InvalidOption("send_hwm"))
+    ));
+    assert!(matches!(
+        crate::StreamSocket::try_with_options(
+            crate::SocketOptions::default().plain_credentials("admin", "secret")
+        ),
+        Err(crate::ZmqError::UnsupportedOption {
+            option: "security",
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+#[should_panic(expected = "Invalid options for PUB socket")]
+async fn test_with_options_validates() {
+    let _pub_socket =
+        crate::PubSocket::with_options(crate::SocketOptions::default().probe_router(true));
+}
+
+#[tokio::test]
+async fn test_queue_capacity() -> Result<(), Box<dyn Error>> {
+    let options = || crate::SocketOptions::default().queue_capacity(1);
+    let mut receiver = crate::DealerSocket::try_with_options(options())?;
+    receiver.bind("tcp://127.0.0.1:5626").await?;
+    let mut dealer = crate::DealerSocket::try_with_options(options())?;
+    dealer.connect("tcp://127.0.0.1:5626").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    // Sender waits for free slot instead of growing any queue
+    let sender = tokio::spawn(async move {
+        for i in 0..50u32 {
+            dealer.send(i.to_string().into()).await?;
+        }
+        Ok::<_, crate::ZmqError>(dealer)
+    });
+    for i in 0..50u32 {
+        assert_eq!(i.to_string(), receiver.recv_string().await?);
+    }
+    sender.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pub_drops_at_send_hwm() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket =
+        crate::PubSocket::try_with_options(crate::SocketOptions::default().send_hwm(10))?;
+    pub_socket.bind("tcp://127.0.0.1:5627").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5627").await?;
+    sub_socket.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    // Nothing gets written to the connection until sender yields
+    for i in 0..100u32 {
+        pub_socket.send(i.to_string())?;
+    }
+    for i in 0..10u32 {
+        assert_eq!(i.to_string(), sub_socket.recv_string().await?);
+    }
+    let next = tokio::time::timeout(Duration::from_millis(100), sub_socket.recv()).await;
+    assert!(
+        next.is_err(),
+        "Messages above high water mark should be dropped"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_fails_at_send_hwm() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5628").await?;
+    let mut push_socket =
+        crate::PushSocket::try_with_options(crate::SocketOptions::default().send_hwm(3))?;
+    push_socket.connect("tcp://127.0.0.1:5628").await?;
+
+    for i in 0..3u32 {
+        push_socket.send(i.to_string())?;
+    }
+    match push_socket.send("3") {
+        Err(crate::ZmqError::ReturnToSender { message, .. }) => {
+            assert_eq!(&b"3"[..], &message.data[..])
+        }
+        other => panic!("Expected message to be returned, got {:?}", other),
+    }
+    for i in 0..3u32 {
+        assert_eq!(i.to_string(), pull_socket.recv_string().await?);
+    }
+    // Queue has room again once messages are written out
+    push_socket.send("4")?;
+    assert_eq!("4", pull_socket.recv_string().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_router_drops_at_send_hwm() -> Result<(), Box<dyn Error>> {
+    let mut router_socket =
+        crate::RouterSocket::try_with_options(crate::SocketOptions::default().send_hwm(2))?;
+    router_socket.bind("tcp://127.0.0.1:5629").await?;
+    let mut dealer_socket = crate::DealerSocket::with_options(
+        crate::SocketOptions::default().identity(b"dealer".to_vec().try_into()?),
+    );
+    dealer_socket.connect("tcp://127.0.0.1:5629").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    let peer_id: crate::PeerIdentity = b"dealer".to_vec().try_into()?;
+    for i in 0..5u32 {
+        router_socket
+            .send_to(&peer_id, vec![i.to_string().into()])
+            .await?;
+    }
+    assert_eq!("0", dealer_socket.recv_string().await?);
+    assert_eq!("1", dealer_socket.recv_string().await?);
+    let next = tokio::time::timeout(Duration::from_millis(100), dealer_socket.recv()).await;
+    assert!(
+        next.is_err(),
+        "Messages above high water mark should be dropped"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_linger_on_close() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default().linger(Duration::from_secs(1));
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    pub_socket.bind("tcp://127.0.0.1:5630").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5630").await?;
+    sub_socket.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    // Messages are still queued when socket gets closed
+    for i in 0..50u32 {
+        pub_socket.send(i.to_string())?;
+    }
+    pub_socket.close().await;
+    for i in 0..50u32 {
+        assert_eq!(i.to_string(), sub_socket.recv_string().await?);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_linger_on_drop() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5631").await?;
+    let options = crate::SocketOptions::default().linger(Duration::from_secs(1));
+    let mut push_socket = crate::PushSocket::with_options(options);
+    push_socket.connect("tcp://127.0.0.1:5631").await?;
+
+    for i in 0..50u32 {
+        push_socket.send(i.to_string())?;
+    }
+    // Queued messages are written out in background
+    drop(push_socket);
+    for i in 0..50u32 {
+        assert_eq!(i.to_string(), pull_socket.recv_string().await?);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_identity_option() -> Result<(), Box<dyn Error>> {
+    let identity: crate::PeerIdentity = b"client-1".to_vec().try_into()?;
+    let dealer = crate::DealerSocket::try_with_options(
+        crate::SocketOptions::default().identity(identity.clone()),
+    )?;
+    assert_eq!(Some(&identity), dealer.identity());
+    assert_eq!(None, crate::DealerSocket::new().identity());
+
+    let too_long: Result<crate::PeerIdentity, _> = vec![7u8; 256].try_into();
+    assert!(too_long.is_err());
+    let reserved: crate::PeerIdentity = vec![0u8, 1, 2].try_into()?;
+    assert!(matches!(
+        crate::DealerSocket::try_with_options(crate::SocketOptions::default().identity(reserved)),
+        Err(crate::ZmqError::InvalidOption("identity"))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recv_timeout() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default().recv_timeout(Duration::from_millis(50));
+    let mut pull_socket = crate::PullSocket::with_options(options);
+    pull_socket.bind("tcp://127.0.0.1:5633").await?;
+    assert!(matches!(
+        pull_socket.recv().await,
+        Err(crate::ZmqError::Timeout)
+    ));
+
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect("tcp://127.0.0.1:5633").await?;
+    push_socket.send("first")?;
+    assert_eq!("first", pull_socket.recv_string().await?);
+
+    // Zero timeout only takes messages that are already queued
+    let options = crate::SocketOptions::default().recv_timeout(Duration::from_secs(0));
+    let mut sub_socket = crate::SubSocket::with_options(options);
+    assert!(matches!(
+        sub_socket.recv().await,
+        Err(crate::ZmqError::Timeout)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_req_recv_timeout_keeps_request() -> Result<(), Box<dyn Error>> {
+    let mut rep_socket = crate::RepSocket::new();
+    rep_socket.bind("tcp://127.0.0.1:5634").await?;
+    let options = crate::SocketOptions::default().recv_timeout(Duration::from_millis(100));
+    let mut req_socket = crate::ReqSocket::with_options(options);
+    req_socket.connect("tcp://127.0.0.1:5634").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    req_socket.send("ping").await?;
+    assert_eq!("ping", rep_socket.recv_string().await?);
+    assert!(matches!(
+        req_socket.recv().await,
+        Err(crate::ZmqError::Timeout)
+    ));
+    // Still waiting for the reply, so another request is refused
+    assert!(req_socket.send("again").await.is_err());
+    rep_socket.send("pong")?;
+    assert_eq!("pong", req_socket.recv_string().await?);
+    req_socket.send("again").await?;
+    assert_eq!("again", rep_socket.recv_string().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_timeout() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default()
+        .send_hwm(1)
+        .send_timeout(Duration::from_millis(100));
+    let mut dealer_socket = crate::DealerSocket::with_options(options);
+    dealer_socket.bind("tcp://127.0.0.1:5635").await?;
+    // Peer completes handshake but never reads, so connection buffers fill up
+    let _peer = raw_peer("127.0.0.1:5635", crate::SocketType::ROUTER).await;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    let payload = vec![0u8; 1024 * 1024];
+    let mut timed_out = false;
+    for _ in 0..100 {
+        match dealer_socket.send(payload.clone().into()).await {
+            Ok(()) => {}
+            Err(crate::ZmqError::Timeout) => {
+                timed_out = true;
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    assert!(timed_out, "Send should time out once peer stops reading");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_keepalive() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default()
+        .tcp_keepalive(true)
+        .tcp_keepalive_idle(Duration::from_secs(30))
+        .tcp_keepalive_interval(Duration::from_secs(5))
+        .tcp_keepalive_count(3);
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5636").await?;
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:5636").await?;
+    let _accepted = listener.accept().await?;
+    crate::transport::set_keepalive(&stream, &options.tcp_keepalive)?;
+
+    let socket = socket2::SockRef::from(&stream);
+    assert!(socket.keepalive()?);
+    assert_eq!(Duration::from_secs(30), socket.keepalive_time()?);
+    assert_eq!(Duration::from_secs(5), socket.keepalive_interval()?);
+    assert_eq!(3, socket.keepalive_retries()?);
+
+    let options = crate::SocketOptions::default().tcp_keepalive(false);
+    crate::transport::set_keepalive(&stream, &options.tcp_keepalive)?;
+    assert!(!socket.keepalive()?);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tos() -> Result<(), Box<dyn Error>> {
+    // Expedited forwarding class
+    let tos = 46 << 2;
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5655").await?;
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:5655").await?;
+    let _accepted = listener.accept().await?;
+    crate::transport::set_tos(&stream, tos)?;
+    assert_eq!(tos as u32, socket2::SockRef::from(&stream).tos()?);
+
+    let mut listener = tokio::net::TcpListener::bind("[::1]:5655").await?;
+    let stream = tokio::net::TcpStream::connect("[::1]:5655").await?;
+    let _accepted = listener.accept().await?;
+    crate::transport::set_tos(&stream, tos)?;
+
+    // Option is applied to connections made by sockets
+    let mut rep_socket =
+        crate::RepSocket::try_with_options(crate::SocketOptions::default().tos(tos))?;
+    rep_socket.bind("tcp://127.0.0.1:5656").await?;
+    let mut req_socket =
+        crate::ReqSocket::try_with_options(crate::SocketOptions::default().tos(tos))?;
+    req_socket.connect("tcp://127.0.0.1:5656").await?;
+    req_socket.send("ping").await?;
+    assert_eq!("ping", rep_socket.recv_string().await?);
+    Ok(())
+}
+
+/// Request goes out in two small writes, which Nagle's algorithm holds back
+/// until the peer acknowledges the first one
+async fn split_request_round_trips(
+    options: crate::SocketOptions,
+) -> Result<Duration, Box<dyn Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; 8];
+        while stream.read_exact(&mut request).await.is_ok() {
+            if stream.write_all(&request).await.is_err() {
+                break;
+            }
+        }
+    });
+    let host = crate::endpoint::Host::Ipv4(std::net::Ipv4Addr::LOCALHOST);
+    let mut stream = crate::transport::connect_tcp(host, port, &options).await?;
+    let mut reply = [0u8; 8];
+    let started = std::time::Instant::now();
+    for _ in 0..10u32 {
+        stream.write_all(b"head").await?;
+        stream.write_all(b"body").await?;
+        stream.read_exact(&mut reply).await?;
+    }
+    Ok(started.elapsed())
+}
+
+#[tokio::test]
+async fn test_tcp_nodelay_latency() -> Result<(), Box<dyn Error>> {
+    let with_nagle =
+        split_request_round_trips(crate::SocketOptions::default().tcp_nodelay(false)).await?;
+    let nodelay_default = split_request_round_trips(crate::SocketOptions::default()).await?;
+    assert!(
+        nodelay_default * 4 < with_nagle,
+        "TCP_NODELAY should avoid delayed ACK stalls: {:?} vs {:?} with Nagle",
+        nodelay_default,
+        with_nagle
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect_after_peer_restart() -> Result<(), Box<dyn Error>> {
+    async fn start_rep_server() -> Result<crate::RepSocket, Box<dyn Error>> {
+        let mut rep_socket = crate::RepSocket::new();
+        rep_socket.bind("tcp://127.0.0.1:5639").await?;
+        Ok(rep_socket)
+    }
+    async fn echo(rep_socket: &mut crate::RepSocket) -> Result<(), Box<dyn Error>> {
+        let request: String = rep_socket.recv().await?.try_into()?;
+        rep_socket.send(request)?;
+        Ok(())
+    }
+
+    let mut rep_socket = start_rep_server().await?;
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_millis(10));
+    let mut req_socket = crate::ReqSocket::with_options(options);
+    req_socket.connect("tcp://127.0.0.1:5639").await?;
+    req_socket.send("first".to_string()).await?;
+    echo(&mut rep_socket).await?;
+    let reply: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("first", reply);
+
+    drop(rep_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let mut rep_socket = start_rep_server().await?;
+    // Request has nowhere to go until connection is reestablished
+    let mut attempts = 0;
+    while req_socket.send("second".to_string()).await.is_err() {
+        attempts += 1;
+        assert!(attempts < 100, "REQ socket should reconnect");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    echo(&mut rep_socket).await?;
+    let reply: String = req_socket.recv().await?.try_into()?;
+    assert_eq!("second", reply);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect_stops_when_socket_dropped() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5640").await?;
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_millis(10));
+    let mut dealer_socket = crate::DealerSocket::with_options(options);
+    dealer_socket.connect("tcp://127.0.0.1:5640").await?;
+
+    drop(dealer_socket);
+    drop(router_socket);
+    tokio::time::delay_for(Duration::from_millis(20)).await;
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:5640").await?;
+    let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+    assert!(accepted.is_err(), "Dropped socket should not reconnect");
+    Ok(())
+}
+
+#[test]
+fn test_reconnect_backoff() {
+    let options = crate::SocketOptions::default();
+    let interval = options.effective_reconnect_interval().unwrap();
+    assert_eq!(interval, options.next_reconnect_interval(interval));
+
+    let options = options.reconnect_interval_max(Duration::from_millis(300));
+    let interval = options.next_reconnect_interval(interval);
+    assert_eq!(Duration::from_millis(200), interval);
+    let interval = options.next_reconnect_interval(interval);
+    assert_eq!(Duration::from_millis(300), interval);
+    assert_eq!(interval, options.next_reconnect_interval(interval));
+
+    let options = options.reconnect_interval(Duration::from_secs(0));
+    assert_eq!(None, options.effective_reconnect_interval());
+}
+
+#[tokio::test]
+async fn test_queue_kept_while_reconnecting() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5641").await?;
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_millis(10));
+    let mut dealer_socket = crate::DealerSocket::with_options(options);
+    dealer_socket.connect("tcp://127.0.0.1:5641").await?;
+
+    drop(router_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    dealer_socket.send("queued".into()).await?;
+
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5641").await?;
+    let received =
+        tokio::time::timeout(Duration::from_secs(1), router_socket.recv_multipart()).await??;
+    assert_eq!(2, received.len());
+    assert_eq!(b"queued", received[1].data.as_ref());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_immediate_skips_disconnected_peers() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5642").await?;
+    let options = crate::SocketOptions::default()
+        .reconnect_interval(Duration::from_millis(10))
+        .immediate(true);
+    let mut dealer_socket = crate::DealerSocket::with_options(options);
+    dealer_socket.connect("tcp://127.0.0.1:5642").await?;
+    dealer_socket.send("delivered".into()).await?;
+    let received = router_socket.recv_multipart().await?;
+    assert_eq!(b"delivered", received[1].data.as_ref());
+
+    drop(router_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert!(dealer_socket.send("dropped".into()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conflate_keeps_latest_message() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("tcp://127.0.0.1:5643").await?;
+    let options = crate::SocketOptions::default()
+        .conflate(true)
+        .recv_timeout(Duration::from_millis(100));
+    let mut sub_socket = crate::SubSocket::with_options(options);
+    sub_socket.connect("tcp://127.0.0.1:5643").await?;
+    sub_socket.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    for i in 0..1000u32 {
+        pub_socket.send(i.to_string())?;
+        // Lets connection write out messages before queue of the subscriber fills up
+        if i % 50 == 0 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+    // Slow consumer wakes up once everything is delivered
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let latest: String = sub_socket.recv().await?.try_into()?;
+    assert_eq!("999", latest);
+    assert!(matches!(
+        sub_socket.recv().await,
+        Err(crate::ZmqError::Timeout)
+    ));
+
+    let mut conflating_pub =
+        crate::PubSocket::with_options(crate::SocketOptions::default().conflate(true));
+    assert!(matches!(
+        conflating_pub.send_multipart(vec!["topic".into(), "value".into()]),
+        Err(crate::ZmqError::ConflateMultipart)
+    ));
+    assert!(matches!(
+        crate::RepSocket::try_with_options(crate::SocketOptions::default().conflate(true)),
+        Err(crate::ZmqError::UnsupportedOption { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscriptions_sent_on_every_connection() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_millis(10));
+    let mut sub_socket = crate::SubSocket::with_options(options);
+    sub_socket.subscribe(b"topic").await?;
+    assert_eq!(vec![b"topic".to_vec()], sub_socket.subscriptions().await);
+
+    for payload in &["first", "second"] {
+        let mut pub_socket = crate::PubSocket::new();
+        pub_socket.bind("tcp://127.0.0.1:5644").await?;
+        if *payload == "first" {
+            sub_socket.connect("tcp://127.0.0.1:5644").await?;
+        }
+        // Subscription reaches publisher right after the handshake
+        let mut attempts = 0;
+        while pub_socket.backend.subscriptions.read().unwrap().len() == 0 {
+            attempts += 1;
+            assert!(attempts < 100, "Subscription should be sent to publisher");
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        pub_socket.send_multipart(vec!["topic".into(), (*payload).into()])?;
+        let frames = sub_socket.recv_multipart().await?;
+        assert_eq!(payload.as_bytes(), frames[1].data.as_ref());
+        drop(pub_socket);
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscription_changes_while_disconnected() -> Result<(), Box<dyn Error>> {
+    let endpoint = "tcp://127.0.0.1:5663";
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_millis(10));
+    let mut sub_socket = crate::SubSocket::with_options(options);
+    sub_socket.subscribe(b"dropped").await?;
+    sub_socket.subscribe(b"kept").await?;
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind(endpoint).await?;
+    sub_socket.connect(endpoint).await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    drop(pub_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    // Publisher started anew gets the current set rather than changes made meanwhile
+    sub_socket.unsubscribe(b"dropped").await?;
+    sub_socket.subscribe(b"added").await?;
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind(endpoint).await?;
+    let mut attempts = 0;
+    while pub_socket.backend.subscriptions.read().unwrap().len() < 2 {
+        attempts += 1;
+        assert!(
+            attempts < 100,
+            "Subscriptions should be sent to restarted publisher"
+        );
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    for topic in &["dropped", "kept", "added"] {
+        pub_socket.send(*topic)?;
+    }
+    assert_eq!("kept", sub_socket.recv_string().await?);
+    assert_eq!("added", sub_socket.recv_string().await?);
+    Ok(())
+}
+
+async fn xpub_received_subscriptions(
+    options: crate::SocketOptions,
+    endpoint: &str,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut xpub_socket =
+        crate::XPubSocket::with_options(options.recv_timeout(Duration::from_millis(200)));
+    xpub_socket.bind(endpoint).await?;
+
+    let mut first = crate::SubSocket::new();
+    first.connect(endpoint).await?;
+    let mut second = crate::SubSocket::new();
+    second.connect(endpoint).await?;
+    first.subscribe(b"topic").await?;
+    second.subscribe(b"topic").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    second.unsubscribe(b"topic").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    // Disconnected peer cancels its subscriptions
+    drop(first);
+
+    let mut received = Vec::new();
+    while let Ok(message) = xpub_socket.recv().await {
+        received.push(message.data.to_vec());
+    }
+    Ok(received)
+}
+
+#[tokio::test]
+async fn test_xpub_verbosity() -> Result<(), Box<dyn Error>> {
+    let subscribe = b"\x01topic".to_vec();
+    let cancel = b"\x00topic".to_vec();
+
+    let received =
+        xpub_received_subscriptions(crate::SocketOptions::default(), "tcp://127.0.0.1:5645")
+            .await?;
+    assert_eq!(vec![subscribe.clone(), cancel.clone()], received);
+
+    let options = crate::SocketOptions::default().xpub_verbose(true);
+    let received = xpub_received_subscriptions(options, "tcp://127.0.0.1:5646").await?;
+    assert_eq!(
+        vec![subscribe.clone(), subscribe.clone(), cancel.clone()],
+        received
+    );
+
+    let options = crate::SocketOptions::default().xpub_verboser(true);
+    let received = xpub_received_subscriptions(options, "tcp://127.0.0.1:5647").await?;
+    assert_eq!(
+        vec![subscribe.clone(), subscribe, cancel.clone(), cancel],
+        received
+    );
+
+    assert!(matches!(
+        crate::PubSocket::try_with_options(crate::SocketOptions::default().xpub_verbose(true)),
+        Err(crate::ZmqError::UnsupportedOption { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_xpub_drops_subscriptions_application_doesnt_receive() -> Result<(), Box<dyn Error>> {
+    let endpoint = "tcp://127.0.0.1:5679";
+    let mut xpub_socket = crate::XPubSocket::with_options(
+        crate::SocketOptions::default()
+            .recv_hwm(2)
+            .recv_timeout(Duration::from_millis(100)),
+    );
+    xpub_socket.bind(endpoint).await?;
+    let mut sub_socket = crate::SubSocket::with_options(
+        crate::SocketOptions::default().recv_timeout(Duration::from_millis(500)),
+    );
+    sub_socket.connect(endpoint).await?;
+    for i in 0..10 {
+        sub_socket
+            .subscribe(format!("topic {}", i).as_bytes())
+            .await?;
+    }
+    let mut attempts = 0;
+    while xpub_socket.subscribe_count(b"topic 9") == 0 {
+        attempts += 1;
+        assert!(attempts < 100, "Subscriptions should be applied");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(8, xpub_socket.dropped_subscriptions());
+
+    // Peer connection keeps working while application doesn't receive
+    xpub_socket.send("topic 9 data")?;
+    assert_eq!("topic 9 data", sub_socket.recv_string().await?);
+    let mut received = 0;
+    while xpub_socket.recv_from().await.is_ok() {
+        received += 1;
+    }
+    assert_eq!(2, received);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_xpub_subscribe_count() -> Result<(), Box<dyn Error>> {
+    let endpoint = "tcp://127.0.0.1:5676";
+    let mut xpub_socket = crate::XPubSocket::with_options(
+        crate::SocketOptions::default().recv_timeout(Duration::from_millis(200)),
+    );
+    xpub_socket.bind(endpoint).await?;
+    let mut first = crate::SubSocket::new();
+    first.connect(endpoint).await?;
+    let mut second = crate::SubSocket::new();
+    second.connect(endpoint).await?;
+
+    // Repeated subscription of a peer doesn't count again
+    first.subscribe(b"topic").await?;
+    first.subscribe(b"topic").await?;
+    first.subscribe(b"other").await?;
+    second.subscribe(b"topic").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(2, xpub_socket.subscribe_count(b"topic"));
+    assert_eq!(1, xpub_socket.subscribe_count(b"other"));
+    assert_eq!(0, xpub_socket.subscribe_count(b"top"));
+
+    second.unsubscribe(b"topic").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(1, xpub_socket.subscribe_count(b"topic"));
+    // Disconnect cancels every topic of the peer
+    drop(first);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(0, xpub_socket.subscribe_count(b"topic"));
+    assert_eq!(0, xpub_socket.subscribe_count(b"other"));
+
+    // Only the first subscribe and the last cancel of each topic reach the application
+    let mut received = Vec::new();
+    while let Ok(message) = xpub_socket.recv().await {
+        received.push(message.data.to_vec());
+    }
+    received.sort();
+    let mut expected = vec![
+        b"\x01topic".to_vec(),
+        b"\x01other".to_vec(),
+        b"\x00topic".to_vec(),
+        b"\x00other".to_vec(),
+    ];
+    expected.sort();
+    assert_eq!(expected, received);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_welcome_message_sent_on_every_connection() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default()
+        .welcome_message("welcome".into())
+        .reconnect_interval(Duration::from_millis(10));
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    for attempt in 0..2 {
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.bind("tcp://127.0.0.1:5648").await?;
+        if attempt == 0 {
+            pub_socket.connect("tcp://127.0.0.1:5648").await?;
+        }
+        // No subscriptions needed to get welcome message
+        let welcome: String = sub_socket.recv().await?.try_into()?;
+        assert_eq!("welcome", welcome);
+        drop(sub_socket);
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+
+    assert!(matches!(
+        crate::SubSocket::try_with_options(
+            crate::SocketOptions::default().welcome_message("welcome".into())
+        ),
+        Err(crate::ZmqError::UnsupportedOption { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_xpub_nodrop() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default()
+        .xpub_nodrop(true)
+        .send_hwm(2);
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    pub_socket.bind("tcp://127.0.0.1:5649").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5649").await?;
+    sub_socket.subscribe(b"").await?;
+    let mut attempts = 0;
+    while pub_socket.backend.subscriptions.read().unwrap().len() == 0 {
+        attempts += 1;
+        assert!(attempts < 100, "Subscription should reach publisher");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    // Connection task doesn't get to run in between, so queue fills up
+    let mut sent = 0;
+    let error = loop {
+        match pub_socket.send(format!("message {}", sent)) {
+            Ok(()) => sent += 1,
+            Err(e) => break e,
+        }
+        assert!(sent < 100, "Send should fail once queue is full");
+    };
+    assert!(matches!(error, crate::ZmqError::PeerQueueFull(_)));
+    // Nothing was dropped, and once subscriber catches up sending works again
+    for i in 0..sent {
+        let message: String = sub_socket.recv().await?.try_into()?;
+        assert_eq!(format!("message {}", i), message);
+    }
+    pub_socket.send("after")?;
+    let message: String = sub_socket.recv().await?.try_into()?;
+    assert_eq!("after", message);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_oldest() -> Result<(), Box<dyn Error>> {
+    let endpoint = "tcp://127.0.0.1:5674";
+    let options = crate::SocketOptions::default()
+        .queue_full_policy(crate::QueueFullPolicy::DropOldest)
+        .xpub_nodrop(false)
+        .send_hwm(10);
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    let mut pub_monitor = pub_socket.monitor();
+    pub_socket.bind(endpoint).await?;
+    let mut sub_socket = crate::SubSocket::with_options(
+        crate::SocketOptions::default().recv_timeout(Duration::from_millis(200)),
+    );
+    sub_socket.connect(endpoint).await?;
+    sub_socket.subscribe(b"").await?;
+    let mut attempts = 0;
+    while pub_socket.backend.subscriptions.read().unwrap().len() == 0 {
+        attempts += 1;
+        assert!(attempts < 100, "Subscription should reach publisher");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    // Connection task doesn't get to run in between, so older messages make room
+    for i in 0..1000 {
+        pub_socket.send(format!("message {}", i))?;
+    }
+    let mut received = Vec::new();
+    while let Ok(message) = sub_socket.recv_string().await {
+        received.push(message);
+    }
+    assert_eq!(Some("message 999"), received.last().map(String::as_str));
+    assert!(
+        received.len() <= 10,
+        "{} messages got through",
+        received.len()
+    );
+    let dropped = pub_socket.stats().totals.messages_dropped;
+    assert_eq!(1000, dropped as usize + received.len());
+
+    // Dropping is reported once for the whole run of dropped messages
+    let mut reported = 0;
+    while let Some(Some(event)) = pub_monitor.next().now_or_never() {
+        if let crate::SocketEvent::MessagesDropped { .. } = event {
+            reported += 1;
+        }
+    }
+    assert_eq!(1, reported);
+
+    assert!(matches!(
+        crate::SubSocket::try_with_options(
+            crate::SocketOptions::default().queue_full_policy(crate::QueueFullPolicy::Error)
+        ),
+        Err(crate::ZmqError::UnsupportedOption { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pub_send_await() -> Result<(), Box<dyn Error>> {
+    let endpoint = "tcp://127.0.0.1:5675";
+    let mut pub_socket =
+        crate::PubSocket::with_options(crate::SocketOptions::default().send_hwm(1));
+    pub_socket.bind(endpoint).await?;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect(endpoint).await?;
+    sub_socket.subscribe(b"").await?;
+    let mut attempts = 0;
+    while pub_socket.backend.subscriptions.read().unwrap().len() == 0 {
+        attempts += 1;
+        assert!(attempts < 100, "Subscription should reach publisher");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    // Plain send would drop most of these as the queue holds a single message
+    for i in 0..200 {
+        let timed_out = pub_socket.send_await(format!("message {}", i)).await?;
+        assert!(timed_out.is_empty());
+    }
+    for i in 0..200 {
+        assert_eq!(format!("message {}", i), sub_socket.recv_string().await?);
+    }
+    assert_eq!(0, pub_socket.stats().totals.messages_dropped);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_router_mandatory() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default()
+        .router_mandatory(true)
+        .send_hwm(2);
+    let mut router_socket = crate::RouterSocket::try_with_options(options)?;
+    router_socket.bind("tcp://127.0.0.1:5650").await?;
+
+    let peer_id: crate::PeerIdentity = b"dealer".to_vec().try_into()?;
+    let result = router_socket.send_to(&peer_id, vec!["0".into()]).await;
+    assert!(matches!(result, Err(crate::ZmqError::PeerUnreachable(_))));
+
+    let mut dealer_socket = crate::DealerSocket::with_options(
+        crate::SocketOptions::default().identity(peer_id.clone()),
+    );
+    dealer_socket.connect("tcp://127.0.0.1:5650").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    router_socket.send_to(&peer_id, vec!["0".into()]).await?;
+    router_socket.send_to(&peer_id, vec!["1".into()]).await?;
+    let result = router_socket.send_to(&peer_id, vec!["2".into()]).await;
+    assert!(matches!(result, Err(crate::ZmqError::PeerQueueFull(_))));
+
+    assert_eq!("0", dealer_socket.recv_string().await?);
+    assert_eq!("1", dealer_socket.recv_string().await?);
+    // Queue has room again once peer catches up
+    router_socket.send_to(&peer_id, vec!["2".into()]).await?;
+    assert_eq!("2", dealer_socket.recv_string().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_probe_router() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5651").await?;
+    let peer_id: crate::PeerIdentity = b"probe".to_vec().try_into()?;
+    let options = crate::SocketOptions::default()
+        .identity(peer_id.clone())
+        .probe_router(true);
+    let mut dealer_socket = crate::DealerSocket::with_options(options);
+    dealer_socket.connect("tcp://127.0.0.1:5651").await?;
+
+    // Router learns about the peer before it sends anything
+    let (probed_id, messages) = router_socket.recv().await?;
+    assert_eq!(peer_id, probed_id);
+    assert_eq!(1, messages.len());
+    assert!(messages[0].data.is_empty());
+
+    assert!(matches!(
+        crate::PubSocket::try_with_options(crate::SocketOptions::default().probe_router(true)),
+        Err(crate::ZmqError::UnsupportedOption { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_req_relaxed() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5652").await?;
+    let mut req_socket =
+        crate::ReqSocket::try_with_options(crate::SocketOptions::default().req_relaxed(true))?;
+    req_socket.connect("tcp://127.0.0.1:5652").await?;
+
+    req_socket.send("first").await?;
+    let (peer_id, request) = router_socket.recv().await?;
+    assert_eq!("first", String::from_utf8(request[1].data.to_vec())?);
+    router_socket
+        .send_to(&peer_id, vec!["".into(), "late".into()])
+        .await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    // Retry abandons first request together with reply that arrived meanwhile
+    req_socket.send("second").await?;
+    let (peer_id, request) = router_socket.recv().await?;
+    assert_eq!("second", String::from_utf8(request[1].data.to_vec())?);
+    router_socket
+        .send_to(&peer_id, vec!["".into(), "reply".into()])
+        .await?;
+    assert_eq!("reply", req_socket.recv_string().await?);
+
+    let mut strict = crate::ReqSocket::new();
+    strict.connect("tcp://127.0.0.1:5652").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    strict.send("first").await?;
+    assert!(strict.send("second").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_req_correlate() -> Result<(), Box<dyn Error>> {
+    let mut router_socket = crate::RouterSocket::new();
+    router_socket.bind("tcp://127.0.0.1:5653").await?;
+    let options = crate::SocketOptions::default()
+        .req_relaxed(true)
+        .req_correlate(true);
+    let mut req_socket = crate::ReqSocket::try_with_options(options)?;
+    req_socket.connect("tcp://127.0.0.1:5653").await?;
+
+    req_socket.send("first").await?;
+    let (peer_id, first) = router_socket.recv().await?;
+    assert_eq!(3, first.len());
+    assert_eq!(4, first[0].data.len());
+    assert!(first[1].data.is_empty());
+    req_socket.send("second").await?;
+    let (_, second) = router_socket.recv().await?;
+    assert_ne!(first[0].data, second[0].data);
+    assert_eq!("second", String::from_utf8(second[2].data.to_vec())?);
+
+    // Late reply to abandoned request arrives first and is dropped
+    let mut late = first;
+    late[2] = "late".into();
+    router_socket.send_to(&peer_id, late).await?;
+    let mut reply = second;
+    reply[2] = "reply".into();
+    router_socket.send_to(&peer_id, reply).await?;
+    assert_eq!("reply", req_socket.recv_string().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_buffer_sizes() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default()
+        .send_buffer_size(16 * 1024)
+        .recv_buffer_size(16 * 1024);
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    pub_socket.bind("tcp://127.0.0.1:5654").await?;
+    assert_eq!(None, pub_socket.socket_buffers().send_buffer_size());
+
+    let mut first = crate::SubSocket::new();
+    first.connect("tcp://127.0.0.1:5654").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let send = pub_socket.socket_buffers().send_buffer_size().unwrap();
+    let recv = pub_socket.socket_buffers().recv_buffer_size().unwrap();
+    assert!(send >= 16 * 1024);
+    assert!(recv >= 16 * 1024);
+
+    // Changing sizes after bind applies to connections accepted afterwards
+    pub_socket.socket_buffers().set_send_buffer_size(32 * 1024);
+    assert_eq!(Some(send), pub_socket.socket_buffers().send_buffer_size());
+    let mut second = crate::SubSocket::new();
+    second.connect("tcp://127.0.0.1:5654").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert!(pub_socket.socket_buffers().send_buffer_size().unwrap() > send);
+    assert_eq!(Some(recv), pub_socket.socket_buffers().recv_buffer_size());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_backlog() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default().listen_backlog(1);
+    let mut rep_socket = crate::RepSocket::try_with_options(options)?;
+    assert_eq!(None, rep_socket.local_addr());
+    rep_socket.bind("tcp://localhost:0").await?;
+    let local_addr = rep_socket.local_addr().expect("Bound to tcp endpoint");
+    assert!(local_addr.ip().is_loopback());
+    assert_ne!(0, local_addr.port());
+
+    let mut req_socket = crate::ReqSocket::new();
+    req_socket.connect(&format!("tcp://{}", local_addr)).await?;
+    req_socket.send("ping").await?;
+    assert_eq!("ping", rep_socket.recv_string().await?);
+
+    assert!(matches!(
+        crate::RepSocket::try_with_options(crate::SocketOptions::default().listen_backlog(-1)),
+        Err(crate::ZmqError::InvalidOption("listen_backlog"))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unbind() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    let endpoint = pull_socket.bind("tcp://127.0.0.1:5657").await?;
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.connect(&endpoint.to_string()).await?;
+    push_socket.send("before")?;
+    assert_eq!("before", pull_socket.recv_string().await?);
+
+    pull_socket.unbind(&endpoint.to_string()).await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    // Peer connected before unbind keeps its connection and queue
+    push_socket.send("after")?;
+    assert_eq!("after", pull_socket.recv_string().await?);
+    assert!(crate::PushSocket::new()
+        .connect(&endpoint.to_string())
+        .await
+        .is_err());
+
+    assert!(matches!(
+        pull_socket.unbind(&endpoint.to_string()).await,
+        Err(crate::ZmqError::NotBound(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disconnect() -> Result<(), Box<dyn Error>> {
+    let mut sub_socket = crate::SubSocket::new();
+    let endpoint = sub_socket.bind("tcp://127.0.0.1:5658").await?;
+    sub_socket.subscribe(b"").await?;
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.connect(&endpoint.to_string()).await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("connected")?;
+    assert_eq!("connected", sub_socket.recv_string().await?);
+
+    pub_socket.disconnect(&endpoint.to_string()).await?;
+    // Connection isn't reestablished after reconnect interval passes
+    tokio::time::delay_for(Duration::from_millis(300)).await;
+    pub_socket.send("disconnected")?;
+    let received = tokio::time::timeout(Duration::from_millis(200), sub_socket.recv()).await;
+    assert!(received.is_err());
+
+    assert!(matches!(
+        pub_socket.disconnect(&endpoint.to_string()).await,
+        Err(crate::ZmqError::NotConnected(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiple_binds() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket = crate::PubSocket::new();
+    let first = pub_socket.bind("tcp://127.0.0.1:5659").await?;
+    let second = pub_socket.bind("tcp://127.0.0.1:5660").await?;
+    assert_eq!(
+        vec![first.clone(), second.clone()],
+        pub_socket.bound_endpoints()
+    );
+
+    let mut subscribers = Vec::new();
+    for endpoint in &[&first, &second] {
+        let mut sub_socket = crate::SubSocket::new();
+        sub_socket.connect(&endpoint.to_string()).await?;
+        sub_socket.subscribe(b"").await?;
+        subscribers.push(sub_socket);
+    }
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("both")?;
+    for sub_socket in &mut subscribers {
+        assert_eq!("both", sub_socket.recv_string().await?);
+    }
+
+    pub_socket.unbind(&first.to_string()).await?;
+    assert_eq!(vec![second], pub_socket.bound_endpoints());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connections() -> Result<(), Box<dyn Error>> {
+    use crate::ConnectionState;
+
+    let endpoint = "tcp://127.0.0.1:5661";
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.bind(endpoint).await?;
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.connect(endpoint).await?;
+    let connections = pub_socket.connections();
+    assert_eq!(1, connections.len());
+    assert_eq!(endpoint, connections[0].endpoint.to_string());
+    assert_eq!(ConnectionState::Connected, connections[0].state);
+    let peer_id = connections[0].peer_id.clone();
+
+    // Lets the accepted side register its peer, so dropping it closes the connection
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    drop(sub_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(
+        ConnectionState::Reconnecting,
+        pub_socket.connections()[0].state
+    );
+
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.bind(endpoint).await?;
+    tokio::time::delay_for(Duration::from_millis(300)).await;
+    let connections = pub_socket.connections();
+    assert_eq!(ConnectionState::Connected, connections[0].state);
+    // Kept pipe stays registered under identity of the first connection
+    assert_eq!(peer_id, connections[0].peer_id);
+
+    pub_socket.disconnect(endpoint).await?;
+    assert!(pub_socket.connections().is_empty());
+
+    // Without reconnecting lost connection stays listed until disconnect
+    let endpoint = "tcp://127.0.0.1:5662";
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.bind(endpoint).await?;
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_secs(0));
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    pub_socket.connect(endpoint).await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    drop(sub_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(
+        ConnectionState::Disconnected,
+        pub_socket.connections()[0].state
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_monitor() -> Result<(), Box<dyn Error>> {
+    use crate::SocketEvent;
+    use tokio::io::AsyncWriteExt;
+
+    async fn next_event(monitor: &mut crate::SocketMonitor) -> SocketEvent {
+        tokio::time::timeout(Duration::from_secs(1), monitor.next())
+            .await
+            .expect("Event should be reported")
+            .expect("Monitor should stay open")
+    }
+
+    let mut pub_socket = crate::PubSocket::new();
+    let mut pub_monitor = pub_socket.monitor();
+    let endpoint = pub_socket.bind("tcp://127.0.0.1:5664").await?;
+    let options = crate::SocketOptions::default().reconnect_interval(Duration::from_millis(50));
+    let mut sub_socket = crate::SubSocket::with_options(options);
+    let mut sub_monitor = sub_socket.monitor();
+    sub_socket.connect(&endpoint.to_string()).await?;
+
+    assert_eq!(
+        SocketEvent::Connected {
+            endpoint: endpoint.clone()
+        },
+        next_event(&mut sub_monitor).await
+    );
+    let peer_address = match next_event(&mut pub_monitor).await {
+        SocketEvent::Accepted {
+            endpoint: accepted_on,
+            peer_address,
+        } => {
+            assert_eq!(endpoint, accepted_on);
+            peer_address.expect("Accepted TCP connection has peer address")
+        }
+        event => panic!("Unexpected event {:?}", event),
+    };
+    assert!(matches!(
+        next_event(&mut pub_monitor).await,
+        SocketEvent::HandshakeSucceeded { peer_address: Some(address), .. } if address == peer_address
+    ));
+    assert!(matches!(
+        next_event(&mut sub_monitor).await,
+        SocketEvent::HandshakeSucceeded {
+            peer_address: None,
+            ..
+        }
+    ));
+
+    let mut garbage = tokio::net::TcpStream::connect("127.0.0.1:5664").await?;
+    garbage.write_all(&[0u8; 64]).await?;
+    assert!(matches!(
+        next_event(&mut pub_monitor).await,
+        SocketEvent::Accepted { .. }
+    ));
+    assert!(matches!(
+        next_event(&mut pub_monitor).await,
+        SocketEvent::HandshakeFailed { .. }
+    ));
+
+    drop(pub_socket);
+    let mut pub_events = vec![
+        next_event(&mut pub_monitor).await,
+        next_event(&mut pub_monitor).await,
+    ];
+    pub_events.retain(|event| !matches!(event, SocketEvent::Disconnected { .. }));
+    assert_eq!(
+        vec![SocketEvent::Closed {
+            endpoint: endpoint.clone()
+        }],
+        pub_events
+    );
+    assert!(matches!(
+        next_event(&mut sub_monitor).await,
+        SocketEvent::Disconnected { .. }
+    ));
+    assert_eq!(
+        SocketEvent::ConnectRetried {
+            endpoint: endpoint.clone(),
+            delay: Duration::from_millis(50)
+        },
+        next_event(&mut sub_monitor).await
+    );
+    // Nobody listens anymore, so the attempt fails and is retried again
+    assert!(matches!(
+        next_event(&mut sub_monitor).await,
+        SocketEvent::ConnectFailed { endpoint: failed_on, .. } if failed_on == endpoint
+    ));
+    assert!(matches!(
+        next_event(&mut sub_monitor).await,
+        SocketEvent::ConnectRetried { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_monitor_reports_connection_failure() -> Result<(), Box<dyn Error>> {
+    use crate::codec::{Message, ZmtpCommand};
+    use crate::SocketEvent;
+
+    let endpoint = "127.0.0.1:5685";
+    let mut pull_socket = crate::PullSocket::new();
+    let mut monitor = pull_socket.monitor();
+    pull_socket.bind(endpoint).await?;
+    let mut peer = raw_peer(endpoint, crate::SocketType::PUSH).await;
+    peer.send(Message::Command(ZmtpCommand::Error {
+        reason: "Going away".into(),
+    }))
+    .await?;
+
+    let reason = loop {
+        match tokio::time::timeout(Duration::from_secs(1), monitor.next()).await? {
+            Some(SocketEvent::ConnectionFailed { reason, .. }) => break reason,
+            Some(_) => {}
+            None => panic!("Monitor should stay open"),
+        }
+    };
+    assert_eq!("Peer rejected connection: Going away", reason);
+    assert!(matches!(
+        tokio::time::timeout(Duration::from_secs(1), monitor.next()).await?,
+        Some(SocketEvent::Disconnected { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_context_term() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5665").await?;
+
+    let context = crate::Context::with_options(
+        crate::SocketOptions::default().linger(Duration::from_secs(1)),
+    );
+    let mut bound_socket = context.socket::<crate::PullSocket>()?;
+    bound_socket.bind("tcp://127.0.0.1:5666").await?;
+    let mut push_socket = context.socket::<crate::PushSocket>()?;
+    push_socket.connect("tcp://127.0.0.1:5665").await?;
+    for i in 0..10 {
+        push_socket.send(format!("message {}", i))?;
+    }
+
+    tokio::time::timeout(Duration::from_secs(2), context.term()).await?;
+    // Messages queued before term are written out within linger
+    for i in 0..10 {
+        assert_eq!(format!("message {}", i), pull_socket.recv_string().await?);
+    }
+    assert!(tokio::net::TcpStream::connect("127.0.0.1:5666")
+        .await
+        .is_err());
+    assert!(matches!(
+        push_socket.connect("tcp://127.0.0.1:5665").await,
+        Err(crate::ZmqError::Terminated)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_context_term_stops_unread_stream() -> Result<(), Box<dyn Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let context = crate::Context::new();
+    let mut stream_socket = context
+        .socket_with_options::<crate::StreamSocket>(crate::SocketOptions::default().recv_hwm(1))?;
+    stream_socket.bind("127.0.0.1:5684").await?;
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:5684").await?;
+    // Nobody reads the socket, so connection waits to pass data on
+    for _ in 0..10 {
+        client.write_all(b"data").await?;
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    tokio::time::timeout(Duration::from_secs(1), context.term()).await?;
+    let mut buf = [0u8; 4];
+    // Data left unread makes the close a reset rather than EOF
+    let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    drop(stream_socket);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_context_term_lingers_on_stream() -> Result<(), Box<dyn Error>> {
+    use tokio::io::AsyncReadExt;
+
+    let context = crate::Context::new();
+    let options = crate::SocketOptions::default().linger(Duration::from_secs(5));
+    let mut stream_socket = context.socket_with_options::<crate::StreamSocket>(options)?;
+    stream_socket.bind("127.0.0.1:5686").await?;
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:5686").await?;
+    let (peer_id, _) = stream_socket.recv().await?;
+    // Client doesn't read yet, so most of the data is still queued once term starts
+    let chunk = vec![7u8; 1024 * 1024];
+    for _ in 0..8 {
+        stream_socket
+            .send_to(&peer_id, crate::ZmqMessage::from(chunk.clone()))
+            .await?;
+    }
+
+    let term = tokio::spawn(context.term());
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received)).await??;
+    assert_eq!(8 * chunk.len(), received.len());
+    tokio::time::timeout(Duration::from_secs(1), term).await??;
+    drop(stream_socket);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_report() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5667").await?;
+    let options = crate::SocketOptions::default().linger(Duration::from_secs(1));
+    let mut push_socket = crate::PushSocket::with_options(options);
+    push_socket.connect("tcp://127.0.0.1:5667").await?;
+    for i in 0..10 {
+        push_socket.send(format!("message {}", i))?;
+    }
+    let report = push_socket.close().await;
+    assert_eq!(1, report.peers.len());
+    assert_eq!(0, report.dropped());
+    // Messages already written out before close aren't counted
+    assert!(report.flushed() <= 10);
+    for i in 0..10 {
+        assert_eq!(format!("message {}", i), pull_socket.recv_string().await?);
+    }
+
+    // Peer that never reads leaves messages queued, without linger they are dropped
+    let mut push_socket = crate::PushSocket::new();
+    push_socket.bind("tcp://127.0.0.1:5668").await?;
+    let _peer = raw_peer("127.0.0.1:5668", crate::SocketType::PULL).await;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let payload = vec![0u8; 1024 * 1024];
+    let mut queued = 0;
+    while push_socket.send(payload.clone()).is_ok() && queued < 100 {
+        queued += 1;
+    }
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let report = push_socket.close().await;
+    assert_eq!(1, report.peers.len());
+    assert_eq!(0, report.flushed());
+    assert!(report.dropped() > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stats() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket =
+        crate::PubSocket::try_with_options(crate::SocketOptions::default().send_hwm(10))?;
+    pub_socket.bind("tcp://127.0.0.1:5669").await?;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5669").await?;
+    sub_socket.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    for i in 0..100u32 {
+        pub_socket.send(format!("{:03}", i))?;
+    }
+    for i in 0..10u32 {
+        assert_eq!(format!("{:03}", i), sub_socket.recv_string().await?);
+    }
+    let stats = pub_socket.stats();
+    assert_eq!(1, stats.connected_peers);
+    assert_eq!(10, stats.totals.messages_sent);
+    assert_eq!(50, stats.totals.bytes_sent);
+    assert_eq!(90, stats.totals.messages_dropped);
+    let peer = stats.peers.values().next().unwrap();
+    assert_eq!(stats.totals, *peer);
+
+    let stats = sub_socket.stats();
+    assert_eq!(10, stats.totals.messages_received);
+    assert_eq!(50, stats.totals.bytes_received);
+    // Subscription went out as a command
+    assert_eq!(0, stats.totals.messages_sent);
+
+    drop(sub_socket);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let stats = pub_socket.stats();
+    assert_eq!(0, stats.connected_peers);
+    assert!(stats.peers.is_empty());
+    // Totals stay after peers are gone
+    assert_eq!(10, stats.totals.messages_sent);
+    Ok(())
+}
+
+/// Fails given accepts before handing out connections of the wrapped listener
+#[cfg(unix)]
+struct FlakyListener {
+    listener: Box<dyn crate::transport::Listener>,
+    errors: Vec<std::io::Error>,
+}
+
+#[cfg(unix)]
+#[async_trait::async_trait]
+impl crate::transport::Listener for FlakyListener {
+    async fn accept(
+        &mut self,
+    ) -> crate::ZmqResult<(
+        crate::transport::PendingStream,
+        Option<std::net::SocketAddr>,
+    )> {
+        match self.errors.pop() {
+            Some(e) => Err(e.into()),
+            None => self.listener.accept().await,
+        }
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_listener_survives_accept_errors() -> Result<(), Box<dyn Error>> {
+    let mut pub_socket = crate::PubSocket::new();
+    let mut monitor = pub_socket.monitor();
+    let endpoint: crate::Endpoint = "tcp://127.0.0.1:5670".parse()?;
+    let (listener, endpoint) = crate::transport::transport_for(&endpoint)?
+        .bind(endpoint, pub_socket.options())
+        .await?;
+    let listener = Box::new(FlakyListener {
+        listener,
+        errors: vec![
+            std::io::Error::from_raw_os_error(libc::ECONNABORTED),
+            std::io::Error::from_raw_os_error(libc::EMFILE),
+        ],
+    });
+    let backend = pub_socket.backend.clone();
+    let options = pub_socket.options().clone();
+    let accepted = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = accepted.clone();
+    let _stop_handle = crate::util::run_listener(
+        listener,
+        endpoint.clone(),
+        pub_socket.options(),
+        move |socket, peer_address| {
+            // Panic in connection task must not take the listener down
+            if counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                panic!("First connection fails");
+            }
+            tokio::spawn(crate::util::accepted_peer_connected(
+                socket,
+                peer_address,
+                backend.clone(),
+                options.clone(),
+            ));
+        },
+    );
+
+    let _failed = tokio::net::TcpStream::connect("127.0.0.1:5670").await?;
+    tokio::time::delay_for(Duration::from_millis(200)).await;
+    let mut sub_socket = crate::SubSocket::new();
+    sub_socket.connect("tcp://127.0.0.1:5670").await?;
+    sub_socket.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    pub_socket.send("still serving")?;
+    assert_eq!("still serving", sub_socket.recv_string().await?);
+    assert_eq!(2, accepted.load(std::sync::atomic::Ordering::SeqCst));
+
+    let mut failures = 0;
+    while let Ok(Some(event)) =
+        tokio::time::timeout(Duration::from_millis(10), monitor.next()).await
+    {
+        match event {
+            crate::SocketEvent::AcceptFailed { .. } => failures += 1,
+            crate::SocketEvent::Closed { .. } => panic!("Listener stopped"),
+            _ => {}
+        }
+    }
+    assert_eq!(2, failures);
+
+    // Listener that is itself broken stops instead of failing forever
+    let endpoint: crate::Endpoint = "tcp://127.0.0.1:5683".parse()?;
+    let (listener, endpoint) = crate::transport::transport_for(&endpoint)?
+        .bind(endpoint, pub_socket.options())
+        .await?;
+    let listener = Box::new(FlakyListener {
+        listener,
+        errors: vec![std::io::Error::from_raw_os_error(libc::EBADF)],
+    });
+    let _stop_handle =
+        crate::util::run_listener(listener, endpoint, pub_socket.options(), |_, _| {});
+    let mut failures = 0;
+    loop {
+        match tokio::time::timeout(Duration::from_secs(1), monitor.next()).await? {
+            Some(crate::SocketEvent::AcceptFailed { .. }) => failures += 1,
+            Some(crate::SocketEvent::Closed { .. }) => break,
+            _ => {}
+        }
+    }
+    assert_eq!(1, failures);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_peers() -> Result<(), Box<dyn Error>> {
+    let options = crate::SocketOptions::default().max_peers(1);
+    let mut pub_socket = crate::PubSocket::with_options(options);
+    let mut monitor = pub_socket.monitor();
+    pub_socket.bind("tcp://127.0.0.1:5671").await?;
+    let mut first = crate::SubSocket::new();
+    first.connect("tcp://127.0.0.1:5671").await?;
+    first.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let mut second = crate::SubSocket::new();
+    second.connect("tcp://127.0.0.1:5671").await?;
+    second.subscribe(b"").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    loop {
+        match monitor.next().await {
+            Some(crate::SocketEvent::PeerRejected { .. }) => break,
+            Some(_) => {}
+            None => panic!("Monitor ended"),
+        }
+    }
+    assert_eq!(1, pub_socket.peer_limit().connected());
+    assert!(pub_socket.stats().rejected_peers >= 1);
+    pub_socket.send("first only")?;
+    assert_eq!("first only", first.recv_string().await?);
+    let next = tokio::time::timeout(Duration::from_millis(100), second.recv()).await;
+    assert!(next.is_err(), "Rejected peer shouldn't receive messages");
+
+    // Raised limit lets rejected peer in once it reconnects
+    pub_socket.peer_limit().set_max_peers(Some(2));
+    assert_eq!(Some(2), pub_socket.peer_limit().max_peers());
+    while pub_socket.peer_limit().connected() < 2 {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    pub_socket.send("both")?;
+    assert_eq!("both", first.recv_string().await?);
+    assert_eq!("both", second.recv_string().await?);
+
+    drop(first);
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(1, pub_socket.peer_limit().connected());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rebind_after_drop() -> Result<(), Box<dyn Error>> {
+    let mut sub_socket = crate::SubSocket::new();
+    for i in 0..20 {
+        let mut pub_socket = crate::PubSocket::new();
+        pub_socket.bind("tcp://127.0.0.1:5672").await?;
+        if i == 0 {
+            sub_socket.connect("tcp://127.0.0.1:5672").await?;
+            sub_socket.subscribe(b"").await?;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        if i % 2 == 0 {
+            drop(pub_socket);
+        } else {
+            pub_socket.close().await;
+        }
+    }
+    let mut pub_socket = crate::PubSocket::new();
+    pub_socket.bind("tcp://127.0.0.1:5672").await?;
+    tokio::time::delay_for(Duration::from_millis(300)).await;
+    pub_socket.send("rebound")?;
+    assert_eq!("rebound", sub_socket.recv_string().await?);
+
+    // Unbind releases endpoint for the next bind as well
+    pub_socket.unbind("tcp://127.0.0.1:5672").await?;
+    pub_socket.bind("tcp://127.0.0.1:5672").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_abandons_handshakes() -> Result<(), Box<dyn Error>> {
+    let mut pull_socket = crate::PullSocket::new();
+    pull_socket.bind("tcp://127.0.0.1:5673").await?;
+    // Peer that never sends its greeting keeps handshake waiting
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:5673").await?;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    pull_socket.close().await;
+
+    let mut buffer = [0u8; 64];
+    let closed = async {
+        loop {
+            match tokio::io::AsyncReadExt::read(&mut stream, &mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(1), closed)
+        .await
+        .expect("Connection should be closed together with the socket");
     Ok(())
 }
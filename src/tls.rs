@@ -0,0 +1,85 @@
+use crate::error::*;
+use crate::options::SocketOptions;
+use crate::util::BoxedStream;
+use crate::ZmqResult;
+use tokio::net::TcpStream;
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ServerConfig;
+#[cfg(feature = "tls")]
+use tokio_rustls::webpki::DNSNameRef;
+
+#[cfg(feature = "tls")]
+fn handshake_error(e: std::io::Error) -> ZmqError {
+    ZmqError::Tls(e.to_string())
+}
+
+/// Performs client side TLS handshake over already connected stream
+#[cfg(feature = "tls")]
+pub(crate) async fn connect(
+    stream: TcpStream,
+    hostname: &str,
+    options: &SocketOptions,
+) -> ZmqResult<BoxedStream> {
+    let config = options
+        .tls_client_config
+        .clone()
+        .ok_or(ZmqError::Socket("tls connect requires client config"))?;
+    let name = DNSNameRef::try_from_ascii_str(hostname)
+        .map_err(|_| ZmqError::Tls(format!("{} is not a valid DNS name", hostname)))?;
+    let stream = tokio_rustls::TlsConnector::from(config)
+        .connect(name, stream)
+        .await
+        .map_err(handshake_error)?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "tls"))]
+pub(crate) async fn connect(
+    _stream: TcpStream,
+    _hostname: &str,
+    _options: &SocketOptions,
+) -> ZmqResult<BoxedStream> {
+    Err(ZmqError::Socket("tls transport requires tls feature"))
+}
+
+/// Server side of TLS handshake for accepted connections
+#[derive(Clone)]
+pub(crate) struct Acceptor {
+    #[cfg(feature = "tls")]
+    config: Arc<ServerConfig>,
+    #[cfg(not(feature = "tls"))]
+    _never: std::convert::Infallible,
+}
+
+impl Acceptor {
+    #[cfg(feature = "tls")]
+    pub(crate) fn new(options: &SocketOptions) -> ZmqResult<Self> {
+        let config = options
+            .tls_server_config
+            .clone()
+            .ok_or(ZmqError::Socket("tls bind requires server config"))?;
+        Ok(Self { config })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub(crate) fn new(_options: &SocketOptions) -> ZmqResult<Self> {
+        Err(ZmqError::Socket("tls transport requires tls feature"))
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) async fn accept(&self, stream: TcpStream) -> ZmqResult<BoxedStream> {
+        let stream = tokio_rustls::TlsAcceptor::from(self.config.clone())
+            .accept(stream)
+            .await
+            .map_err(handshake_error)?;
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub(crate) async fn accept(&self, _stream: TcpStream) -> ZmqResult<BoxedStream> {
+        match self._never {}
+    }
+}
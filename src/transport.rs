@@ -0,0 +1,575 @@
+//! Transports carrying ZMTP byte streams. Sockets only deal with `BoxedStream`
+//! so supporting new transport comes down to implementing `Transport` for it
+//! and returning it from `transport_for`
+use crate::endpoint::{Endpoint, EndpointError, Host};
+use crate::error::*;
+use crate::options::{SocketBuffers, SocketOptions, TcpKeepalive};
+use crate::util::BoxedStream;
+use crate::{inproc, socks, tls, ws, ZmqResult};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/// Connection accepted by listener. Resolves once transport handshakes are done
+pub(crate) type PendingStream = BoxFuture<'static, ZmqResult<BoxedStream>>;
+
+#[async_trait]
+pub(crate) trait Listener: Send {
+    /// Waits for the next incoming connection. Remote address is returned for IP based transports.
+    /// Handshakes are left to returned future so slow clients don't block accepting others
+    async fn accept(&mut self) -> ZmqResult<(PendingStream, Option<SocketAddr>)>;
+}
+
+#[async_trait]
+pub(crate) trait Transport: Send + Sync {
+    async fn connect(&self, endpoint: Endpoint, options: &SocketOptions) -> ZmqResult<BoxedStream>;
+
+    /// Returns listener together with endpoint that was actually bound.
+    /// Resources held by listener are released once it is dropped
+    async fn bind(
+        &self,
+        endpoint: Endpoint,
+        options: &SocketOptions,
+    ) -> ZmqResult<(Box<dyn Listener>, Endpoint)>;
+}
+
+/// Picks transport serving endpoint's scheme
+pub(crate) fn transport_for(endpoint: &Endpoint) -> ZmqResult<&'static dyn Transport> {
+    match endpoint {
+        Endpoint::Tcp(..) | Endpoint::Tls(..) | Endpoint::Ws(..) | Endpoint::Wss(..) => {
+            Ok(&TcpTransport)
+        }
+        Endpoint::Ipc(_) => Ok(&IpcTransport),
+        Endpoint::Inproc(_) => Ok(&inproc::InprocTransport),
+        Endpoint::Udp(..) => Err(ZmqError::Socket(
+            "udp transport is only supported by RADIO and DISH sockets",
+        )),
+    }
+}
+
+/// Resolves hostname to the list of addresses in the order resolver returned them
+pub(crate) async fn resolve(name: &str, port: u16) -> ZmqResult<Vec<SocketAddr>> {
+    let resolved = tokio::net::lookup_host(format!("{}:{}", name, port)).await;
+    match resolved {
+        Ok(addresses) => {
+            let addresses: Vec<SocketAddr> = addresses.collect();
+            if addresses.is_empty() {
+                Err(ZmqError::HostResolution(name.to_string()))
+            } else {
+                Ok(addresses)
+            }
+        }
+        Err(_) => Err(ZmqError::HostResolution(name.to_string())),
+    }
+}
+
+/// TCP and protocols layered on top of it (`tls://`, `ws://` and `wss://`)
+struct TcpTransport;
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn connect(&self, endpoint: Endpoint, options: &SocketOptions) -> ZmqResult<BoxedStream> {
+        match endpoint {
+            Endpoint::Tcp(host, port) => Ok(Box::new(connect_tcp(host, port, options).await?)),
+            Endpoint::Tls(host, port) => {
+                let hostname = tls_hostname(&host)?;
+                let stream = connect_tcp(host, port, options).await?;
+                tls::connect(stream, &hostname, options).await
+            }
+            Endpoint::Ws(host, port, path) => {
+                let url = format!("ws://{}:{}{}", host, port, path);
+                let stream = connect_tcp(host, port, options).await?;
+                ws::connect(Box::new(stream), &url).await
+            }
+            Endpoint::Wss(host, port, path) => {
+                let hostname = tls_hostname(&host)?;
+                let url = format!("wss://{}:{}{}", host, port, path);
+                let stream = connect_tcp(host, port, options).await?;
+                let stream = tls::connect(stream, &hostname, options).await?;
+                ws::connect(stream, &url).await
+            }
+            _ => unreachable!("Endpoint is not served by tcp transport"),
+        }
+    }
+
+    async fn bind(
+        &self,
+        endpoint: Endpoint,
+        options: &SocketOptions,
+    ) -> ZmqResult<(Box<dyn Listener>, Endpoint)> {
+        let (host, port, upgrade) = match endpoint {
+            Endpoint::Tcp(host, port) => (host, port, Upgrade::default()),
+            Endpoint::Tls(host, port) => {
+                let upgrade = Upgrade {
+                    tls: Some(tls::Acceptor::new(options)?),
+                    ws_path: None,
+                };
+                (host, port, upgrade)
+            }
+            Endpoint::Ws(host, port, path) => {
+                ws::ensure_supported()?;
+                let upgrade = Upgrade {
+                    tls: None,
+                    ws_path: Some(path),
+                };
+                (host, port, upgrade)
+            }
+            Endpoint::Wss(host, port, path) => {
+                ws::ensure_supported()?;
+                let upgrade = Upgrade {
+                    tls: Some(tls::Acceptor::new(options)?),
+                    ws_path: Some(path),
+                };
+                (host, port, upgrade)
+            }
+            _ => unreachable!("Endpoint is not served by tcp transport"),
+        };
+        let listener = bind_tcp(host, port, options).await?;
+        let local_addr = listener.local_addr()?;
+        let (host, port) = (local_addr.ip().into(), local_addr.port());
+        let bound_endpoint = match (&upgrade.tls, upgrade.ws_path.clone()) {
+            (None, None) => Endpoint::Tcp(host, port),
+            (Some(_), None) => Endpoint::Tls(host, port),
+            (None, Some(path)) => Endpoint::Ws(host, port, path),
+            (Some(_), Some(path)) => Endpoint::Wss(host, port, path),
+        };
+        let listener = TcpListener {
+            listener,
+            upgrade,
+            nodelay: options.effective_tcp_nodelay(),
+            keepalive: options.tcp_keepalive,
+            buffers: options.socket_buffers.clone(),
+            tos: options.tos,
+        };
+        Ok((Box::new(listener), bound_endpoint))
+    }
+}
+
+/// Wraps listener bound by the caller. No transport handshakes are done on its connections
+pub(crate) fn tcp_listener(
+    listener: tokio::net::TcpListener,
+    options: &SocketOptions,
+) -> ZmqResult<(Box<dyn Listener>, Endpoint)> {
+    let local_addr = listener.local_addr()?;
+    let bound_endpoint = Endpoint::Tcp(local_addr.ip().into(), local_addr.port());
+    let listener = TcpListener {
+        listener,
+        upgrade: Upgrade::default(),
+        nodelay: options.effective_tcp_nodelay(),
+        keepalive: options.tcp_keepalive,
+        buffers: options.socket_buffers.clone(),
+        tos: options.tos,
+    };
+    Ok((Box::new(listener), bound_endpoint))
+}
+
+struct TcpListener {
+    listener: tokio::net::TcpListener,
+    upgrade: Upgrade,
+    nodelay: bool,
+    keepalive: TcpKeepalive,
+    buffers: SocketBuffers,
+    tos: Option<u8>,
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&mut self) -> ZmqResult<(PendingStream, Option<SocketAddr>)> {
+        let (socket, address) = self.listener.accept().await?;
+        let (nodelay, keepalive, tos) = (self.nodelay, self.keepalive, self.tos);
+        let buffers = self.buffers.clone();
+        let upgrade = self.upgrade.clone();
+        // Failing to configure the connection only drops it, listener keeps accepting
+        let pending = async move {
+            configure_tcp(&socket, nodelay, &keepalive, &buffers, tos)?;
+            upgrade.apply(socket).await
+        };
+        Ok((pending.boxed(), Some(address)))
+    }
+}
+
+/// Handshakes performed on accepted TCP connection before ZMTP starts
+#[derive(Clone, Default)]
+struct Upgrade {
+    tls: Option<tls::Acceptor>,
+    ws_path: Option<String>,
+}
+
+impl Upgrade {
+    async fn apply(&self, socket: tokio::net::TcpStream) -> ZmqResult<BoxedStream> {
+        let stream = match &self.tls {
+            Some(acceptor) => acceptor.accept(socket).await?,
+            None => Box::new(socket),
+        };
+        match &self.ws_path {
+            Some(path) => ws::accept(stream, path).await,
+            None => Ok(stream),
+        }
+    }
+}
+
+/// TLS certificates are verified against hostname so IP addresses can't be used
+fn tls_hostname(host: &Host) -> ZmqResult<String> {
+    match host {
+        Host::Domain(name) => Ok(name.clone()),
+        _ => Err(ZmqError::Tls(
+            "tls endpoint requires a hostname".to_string(),
+        )),
+    }
+}
+
+pub(crate) async fn connect_tcp(
+    host: Host,
+    port: u16,
+    options: &SocketOptions,
+) -> ZmqResult<tokio::net::TcpStream> {
+    let stream = open_tcp(host, port, options).await?;
+    configure_tcp(
+        &stream,
+        options.effective_tcp_nodelay(),
+        &options.tcp_keepalive,
+        &options.socket_buffers,
+        options.tos,
+    )?;
+    Ok(stream)
+}
+
+async fn open_tcp(
+    host: Host,
+    port: u16,
+    options: &SocketOptions,
+) -> ZmqResult<tokio::net::TcpStream> {
+    if let Some(proxy) = &options.socks_proxy {
+        return socks::connect(proxy, host, port).await;
+    }
+    let name = match host {
+        Host::Ipv4(ip) => return Ok(tokio::net::TcpStream::connect((ip, port)).await?),
+        Host::Ipv6(ip) => return Ok(tokio::net::TcpStream::connect((ip, port)).await?),
+        Host::Domain(name) => name,
+        Host::Wildcard => {
+            return Err(ZmqError::Endpoint(EndpointError::Syntax(
+                "Wildcard host can only be used to bind",
+            )))
+        }
+    };
+    // Addresses are tried in the order resolver returned them.
+    // First one that accepts connection wins regardless of its address family
+    for address in resolve(&name, port).await? {
+        if let Ok(stream) = tokio::net::TcpStream::connect(address).await {
+            return Ok(stream);
+        }
+    }
+    Err(ZmqError::HostUnreachable(name))
+}
+
+/// Applies per-connection TCP settings to freshly accepted or connected stream
+fn configure_tcp(
+    stream: &tokio::net::TcpStream,
+    nodelay: bool,
+    keepalive: &TcpKeepalive,
+    buffers: &SocketBuffers,
+    tos: Option<u8>,
+) -> ZmqResult<()> {
+    stream.set_nodelay(nodelay)?;
+    buffers.apply(&SockRef::from(stream))?;
+    if let Some(tos) = tos {
+        set_tos(stream, tos)?;
+    }
+    set_keepalive(stream, keepalive)
+}
+
+/// Applies keepalive settings to TCP connection. Settings left unset keep OS defaults,
+/// as do probe interval and count on systems that don't support changing them
+pub(crate) fn set_keepalive(
+    stream: &tokio::net::TcpStream,
+    keepalive: &TcpKeepalive,
+) -> ZmqResult<()> {
+    let socket = SockRef::from(stream);
+    match keepalive.enabled {
+        Some(true) => {}
+        Some(false) => return Ok(socket.set_keepalive(false)?),
+        None => return Ok(()),
+    }
+    let mut params = socket2::TcpKeepalive::new();
+    if let Some(idle) = keepalive.idle {
+        params = params.with_time(idle);
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_vendor = "apple",
+        windows
+    ))]
+    if let Some(interval) = keepalive.interval {
+        params = params.with_interval(interval);
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_vendor = "apple"
+    ))]
+    if let Some(count) = keepalive.count {
+        params = params.with_retries(count);
+    }
+    Ok(socket.set_tcp_keepalive(&params)?)
+}
+
+/// Platforms `set_tos` can mark connections on
+pub(crate) const TOS_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_vendor = "apple"
+));
+
+/// Sets type of service byte, i.e. DSCP and ECN bits, of packets sent over TCP connection.
+/// IPv6 connections use IPV6_TCLASS, IPv4 ones including those of dual-stack sockets use IP_TOS
+pub(crate) fn set_tos(stream: &tokio::net::TcpStream, tos: u8) -> ZmqResult<()> {
+    let ipv4 = match stream.local_addr()? {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(address) => address.ip().to_ipv4_mapped().is_some(),
+    };
+    set_tos_sockopt(stream, ipv4, tos)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_vendor = "apple"
+))]
+fn set_tos_sockopt(stream: &tokio::net::TcpStream, ipv4: bool, tos: u8) -> ZmqResult<()> {
+    use std::os::unix::io::AsRawFd;
+    let (level, name) = if ipv4 {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+    let value = tos as libc::c_int;
+    // socket2 can set IP_TOS only, so both go through setsockopt directly.
+    // Value outlives the call and length matches its type
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_vendor = "apple"
+)))]
+fn set_tos_sockopt(_stream: &tokio::net::TcpStream, _ipv4: bool, _tos: u8) -> ZmqResult<()> {
+    Err(ZmqError::Socket(
+        "tos option is not supported on this platform",
+    ))
+}
+
+async fn bind_tcp(
+    host: Host,
+    port: u16,
+    options: &SocketOptions,
+) -> ZmqResult<tokio::net::TcpListener> {
+    let ip: IpAddr = match host {
+        Host::Ipv4(ip) => ip.into(),
+        Host::Ipv6(ip) => ip.into(),
+        Host::Wildcard if options.ipv6 => Ipv6Addr::UNSPECIFIED.into(),
+        Host::Wildcard => Ipv4Addr::UNSPECIFIED.into(),
+        Host::Domain(name) => {
+            // First address that can be bound wins, same as for std listeners
+            let mut last_error = None;
+            for address in resolve(&name, port).await? {
+                match listen_tcp(address, options) {
+                    Ok(listener) => return Ok(listener),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            return Err(last_error.expect("Resolved at least one address"));
+        }
+    };
+    listen_tcp(SocketAddr::new(ip, port), options)
+}
+
+/// Listener is created through socket2 so IPV6_V6ONLY can be set before bind
+/// and backlog chosen for listen
+fn listen_tcp(address: SocketAddr, options: &SocketOptions) -> ZmqResult<tokio::net::TcpListener> {
+    let domain = if address.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(options.ipv6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(options.effective_listen_backlog())?;
+    let listener: std::net::TcpListener = socket.into();
+    listener.set_nonblocking(true)?;
+    Ok(tokio::net::TcpListener::from_std(listener)?)
+}
+
+/// Unix domain sockets. Socket file is created on bind and removed once listener is dropped.
+/// `ipc://@name` endpoints use Linux abstract namespace so no file is created at all
+struct IpcTransport;
+
+/// Name of abstract namespace socket if path starts with `@`
+#[cfg(unix)]
+fn abstract_name(path: &Path) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().strip_prefix(b"@")
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &[u8]) -> ZmqResult<tokio::net::UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    // Connecting unix socket never blocks for long so it's fine to do it synchronously
+    let stream = std::os::unix::net::UnixStream::connect_addr(&address)?;
+    stream.set_nonblocking(true)?;
+    Ok(tokio::net::UnixStream::from_std(stream)?)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &[u8]) -> ZmqResult<tokio::net::UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&address)?;
+    listener.set_nonblocking(true)?;
+    Ok(tokio::net::UnixListener::from_std(listener)?)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn connect_abstract(_name: &[u8]) -> ZmqResult<tokio::net::UnixStream> {
+    Err(ZmqError::Socket(
+        "abstract ipc endpoints are only supported on linux",
+    ))
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn bind_abstract(_name: &[u8]) -> ZmqResult<tokio::net::UnixListener> {
+    Err(ZmqError::Socket(
+        "abstract ipc endpoints are only supported on linux",
+    ))
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Transport for IpcTransport {
+    async fn connect(
+        &self,
+        endpoint: Endpoint,
+        _options: &SocketOptions,
+    ) -> ZmqResult<BoxedStream> {
+        let path = match endpoint {
+            Endpoint::Ipc(path) => path,
+            _ => unreachable!("Endpoint is not served by ipc transport"),
+        };
+        match abstract_name(&path) {
+            Some(name) => Ok(Box::new(connect_abstract(name)?)),
+            None => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        }
+    }
+
+    async fn bind(
+        &self,
+        endpoint: Endpoint,
+        _options: &SocketOptions,
+    ) -> ZmqResult<(Box<dyn Listener>, Endpoint)> {
+        let path = match endpoint {
+            Endpoint::Ipc(path) => path,
+            _ => unreachable!("Endpoint is not served by ipc transport"),
+        };
+        let bound_endpoint = Endpoint::Ipc(path.clone());
+        if let Some(name) = abstract_name(&path) {
+            let listener = IpcListener {
+                listener: bind_abstract(name)?,
+                path: None,
+            };
+            return Ok((Box::new(listener), bound_endpoint));
+        }
+        // Stale socket file left by previous process would make bind fail
+        let _ = std::fs::remove_file(&path);
+        let listener = IpcListener {
+            listener: tokio::net::UnixListener::bind(&path)?,
+            path: Some(path),
+        };
+        Ok((Box::new(listener), bound_endpoint))
+    }
+}
+
+#[cfg(unix)]
+struct IpcListener {
+    listener: tokio::net::UnixListener,
+    /// Socket file to remove. Abstract sockets don't have any
+    path: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for IpcListener {
+    async fn accept(&mut self) -> ZmqResult<(PendingStream, Option<SocketAddr>)> {
+        let (socket, _) = self.listener.accept().await?;
+        let stream: BoxedStream = Box::new(socket);
+        Ok((futures::future::ok(stream).boxed(), None))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for IpcListener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+#[async_trait]
+impl Transport for IpcTransport {
+    async fn connect(
+        &self,
+        _endpoint: Endpoint,
+        _options: &SocketOptions,
+    ) -> ZmqResult<BoxedStream> {
+        Err(ZmqError::Socket(
+            "ipc transport is not supported on this platform",
+        ))
+    }
+
+    async fn bind(
+        &self,
+        _endpoint: Endpoint,
+        _options: &SocketOptions,
+    ) -> ZmqResult<(Box<dyn Listener>, Endpoint)> {
+        Err(ZmqError::Socket(
+            "ipc transport is not supported on this platform",
+        ))
+    }
+}
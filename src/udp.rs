@@ -0,0 +1,118 @@
+use crate::endpoint::{Endpoint, EndpointError, Host};
+use crate::error::*;
+use crate::message::ZmqMessage;
+use crate::options::SocketOptions;
+use crate::transport;
+use crate::ZmqResult;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Maximum size of a single datagram including group header. Same limit as libzmq uses.
+/// Bigger messages are rejected since they are never fragmented
+pub const MAX_DATAGRAM_SIZE: usize = 8192;
+
+/// Packs message into datagram.
+/// Datagram starts with group length byte followed by group and message body
+pub(crate) fn encode(group: &str, message: &ZmqMessage) -> ZmqResult<Bytes> {
+    let size = 1 + group.len() + message.data.len();
+    if size > MAX_DATAGRAM_SIZE {
+        return Err(ZmqError::Socket("Message is too large for udp datagram"));
+    }
+    let mut datagram = BytesMut::with_capacity(size);
+    datagram.put_u8(group.len() as u8);
+    datagram.extend_from_slice(group.as_bytes());
+    datagram.extend_from_slice(&message.data);
+    Ok(datagram.freeze())
+}
+
+/// Unpacks group and message from datagram. Returns None for malformed datagrams
+pub(crate) fn decode(datagram: &[u8]) -> Option<(String, ZmqMessage)> {
+    let (group_len, rest) = datagram.split_first()?;
+    let group_len = *group_len as usize;
+    if rest.len() < group_len {
+        return None;
+    }
+    let group = String::from_utf8(rest[..group_len].to_vec()).ok()?;
+    Some((group, Bytes::copy_from_slice(&rest[group_len..]).into()))
+}
+
+/// Creates socket sending datagrams to host:port. Multicast addresses are fine here as well
+pub(crate) async fn connect(host: Host, port: u16) -> ZmqResult<UdpSocket> {
+    let address = match host {
+        Host::Ipv4(ip) => SocketAddr::new(ip.into(), port),
+        Host::Ipv6(ip) => SocketAddr::new(ip.into(), port),
+        Host::Domain(name) => transport::resolve(&name, port).await?[0],
+        Host::Wildcard => {
+            return Err(ZmqError::Endpoint(EndpointError::Syntax(
+                "Wildcard host can only be used to bind",
+            )))
+        }
+    };
+    let local: IpAddr = match address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0)).await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
+/// Binds socket receiving datagrams. Binding to multicast address joins the multicast group
+/// on the default interface. Returns endpoint that was actually bound
+pub(crate) async fn bind(
+    host: Host,
+    port: u16,
+    options: &SocketOptions,
+) -> ZmqResult<(UdpSocket, Endpoint)> {
+    let ip: IpAddr = match host {
+        Host::Ipv4(ip) => ip.into(),
+        Host::Ipv6(ip) => ip.into(),
+        Host::Wildcard if options.ipv6 => Ipv6Addr::UNSPECIFIED.into(),
+        Host::Wildcard => Ipv4Addr::UNSPECIFIED.into(),
+        Host::Domain(name) => transport::resolve(&name, port).await?[0].ip(),
+    };
+    let socket = match ip {
+        IpAddr::V4(group) if group.is_multicast() => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+            socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?;
+            socket
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket
+        }
+        ip => UdpSocket::bind((ip, port)).await?,
+    };
+    let bound_ip = if ip.is_multicast() {
+        ip
+    } else {
+        socket.local_addr()?.ip()
+    };
+    let bound_endpoint = Endpoint::Udp(bound_ip.into(), socket.local_addr()?.port());
+    Ok((socket, bound_endpoint))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_datagram_roundtrip() {
+        let datagram = encode("weather", &"Sunny".into()).unwrap();
+        assert_eq!(b"\x07weatherSunny", datagram.as_ref());
+        let (group, message) = decode(&datagram).unwrap();
+        assert_eq!("weather", group);
+        assert_eq!(b"Sunny", message.data.as_ref());
+
+        assert!(decode(b"").is_none());
+        assert!(decode(b"\x07short").is_none());
+    }
+
+    #[test]
+    fn test_oversized_datagram() {
+        let body = vec![0u8; MAX_DATAGRAM_SIZE];
+        assert!(encode("group", &body.into()).is_err());
+    }
+}
@@ -1,16 +1,41 @@
+use crate::close::{CloseReports, ConnectionReport};
+use crate::conflate::QueueReceiver;
+#[cfg(feature = "curve")]
+use crate::curve;
+use crate::endpoint::Endpoint;
+use crate::heartbeat::Heartbeat;
+use crate::options::SocketOptions;
+use crate::security::{self, Credentials, Security};
+use crate::stats::{PeerCounters, QueueStats, Stats};
+use crate::transport::{self, transport_for, Listener};
+use crate::zmtp2::{self, Detected};
 use crate::*;
 use bytes::Bytes;
+use dashmap::DashMap;
+use futures::future::Shared;
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
 use futures::{select, SinkExt};
 use futures_util::future::FutureExt;
+use lazy_static::lazy_static;
 use std::convert::{TryFrom, TryInto};
-use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use uuid::Uuid;
 
+/// Byte stream of any supported transport (TCP, Unix domain sockets, etc)
+pub(crate) trait ZmqStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> ZmqStream for T {}
+
+pub(crate) type BoxedStream = Box<dyn ZmqStream>;
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone)]
-pub(crate) struct PeerIdentity(Vec<u8>);
+pub struct PeerIdentity(Vec<u8>);
 
 impl PeerIdentity {
     pub fn new() -> Self {
@@ -19,11 +44,17 @@ impl PeerIdentity {
     }
 }
 
+impl Default for PeerIdentity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TryFrom<Vec<u8>> for PeerIdentity {
     type Error = ZmqError;
 
     fn try_from(data: Vec<u8>) -> Result<Self, ZmqError> {
-        if data.len() == 0 {
+        if data.is_empty() {
             Ok(PeerIdentity::new())
         } else if data.len() > 255 {
             Err(ZmqError::Other(
@@ -35,6 +66,12 @@ impl TryFrom<Vec<u8>> for PeerIdentity {
     }
 }
 
+impl AsRef<[u8]> for PeerIdentity {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl From<PeerIdentity> for Vec<u8> {
     fn from(p_id: PeerIdentity) -> Self {
         p_id.0
@@ -48,28 +85,13 @@ impl From<PeerIdentity> for Bytes {
 }
 
 pub(crate) struct Peer {
-    pub(crate) identity: PeerIdentity,
+    pub(crate) _identity: PeerIdentity,
     pub(crate) send_queue: mpsc::Sender<Message>,
     pub(crate) recv_queue: Arc<Mutex<mpsc::Receiver<Message>>>,
     pub(crate) recv_queue_in: mpsc::Sender<Message>,
     pub(crate) _io_close_handle: futures::channel::oneshot::Sender<bool>,
 }
 
-const COMPATIBILITY_MATRIX: [u8; 121] = [
-    // PAIR, PUB, SUB, REQ, REP, DEALER, ROUTER, PULL, PUSH, XPUB, XSUB
-    1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // PAIR
-    0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, // PUB
-    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, // SUB
-    0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, // REQ
-    0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, // REP
-    0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, // DEALER
-    0, 0, 0, 1, 0, 1, 1, 0, 0, 0, 0, // ROUTER
-    0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, // PULL
-    0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, // PUSH
-    0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, // XPUB
-    0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, // XSUB
-];
-
 /// Checks if two sokets are compatible with each other
 /// ```
 /// use zeromq::SocketType;
@@ -77,127 +99,802 @@ const COMPATIBILITY_MATRIX: [u8; 121] = [
 /// assert!(sockets_compatible(SocketType::PUB, SocketType::SUB));
 /// assert!(sockets_compatible(SocketType::REQ, SocketType::REP));
 /// assert!(sockets_compatible(SocketType::DEALER, SocketType::ROUTER));
+/// assert!(sockets_compatible(SocketType::CLIENT, SocketType::SERVER));
 /// assert!(!sockets_compatible(SocketType::PUB, SocketType::REP));
 /// ```
 pub fn sockets_compatible(one: SocketType, another: SocketType) -> bool {
-    let row_index = one.to_usize().unwrap();
-    let col_index = another.to_usize().unwrap();
-    COMPATIBILITY_MATRIX[row_index * 11 + col_index] != 0
+    one.compatible_peers().contains(&another)
+}
+
+/// Tells the peer why it is about to be disconnected, so the remote side
+/// gets a diagnosable reason instead of a bare connection reset
+pub(crate) async fn send_error<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    reason: &str,
+) -> ZmqResult<()> {
+    socket
+        .send(Message::Command(ZmtpCommand::error(reason)))
+        .await
 }
 
-pub(crate) async fn greet_exchange(socket: &mut Framed<TcpStream, ZmqCodec>) -> ZmqResult<()> {
+pub(crate) async fn greet_exchange<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    options: &SocketOptions,
+) -> ZmqResult<()> {
+    let mechanism = options.security.mechanism();
+    let as_server = options.security.as_server();
     socket
-        .send(Message::Greeting(ZmqGreeting::default()))
+        .send(Message::Greeting(ZmqGreeting::new(mechanism, as_server)))
         .await?;
+    receive_greeting(socket, options).await
+}
 
+/// Validates peer's greeting against ours, which is already sent
+pub(crate) async fn receive_greeting<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    options: &SocketOptions,
+) -> ZmqResult<()> {
+    let mechanism = options.security.mechanism();
+    let as_server = options.security.as_server();
     let greeting: Option<Result<Message, ZmqError>> = socket.next().await;
 
     match greeting {
-        Some(Ok(Message::Greeting(greet))) => match greet.version {
-            (3, 0) => Ok(()),
-            _ => Err(ZmqError::Other("Unsupported protocol version")),
-        },
+        Some(Ok(Message::Greeting(greet))) => {
+            if greet.version.0 < 3 {
+                return Err(ZmqError::Other("Unsupported protocol version"));
+            }
+            socket.codec_mut().negotiate_version(greet.version);
+            if greet.mechanism != mechanism {
+                send_error(socket, "Security mechanism mismatch").await?;
+                return Err(ZmqError::MechanismMismatch {
+                    expected: mechanism.to_string(),
+                    actual: greet.mechanism.to_string(),
+                });
+            }
+            if mechanism != ZmqMechanism::NULL && greet.as_server == as_server {
+                send_error(socket, "Both peers have the same security role").await?;
+                return Err(ZmqError::Authentication(
+                    "Both peers have the same security role".to_string(),
+                ));
+            }
+            Ok(())
+        }
         _ => Err(ZmqError::Codec("Failed Greeting exchange")),
     }
 }
 
-pub(crate) async fn ready_exchange(
-    socket: &mut Framed<TcpStream, ZmqCodec>,
+/// Performs security handshake of the negotiated mechanism and exchanges socket metadata.
+/// Returns identity of the peer together with all properties it sent
+pub(crate) async fn ready_exchange<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
     socket_type: SocketType,
-) -> ZmqResult<PeerIdentity> {
-    let ready = ZmqCommand::ready(socket_type);
-    socket.send(Message::Command(ready)).await?;
-
-    let ready_repl: Option<ZmqResult<Message>> = socket.next().await;
-    match ready_repl {
-        Some(Ok(Message::Command(command))) => match command.name {
-            ZmqCommandName::READY => {
-                let other_sock_type = command
-                    .properties
-                    .get("Socket-Type")
-                    .map(|x| SocketType::try_from(x.as_str()))
-                    .unwrap_or(Err(ZmqError::Codec("Failed to parse other socket type")))?;
-
-                let peer_id = command.properties.get("Identity").map_or_else(
-                    || PeerIdentity::new(),
-                    |x| x.clone().into_bytes().try_into().unwrap(),
-                );
-
-                if sockets_compatible(socket_type, other_sock_type) {
-                    Ok(peer_id)
-                } else {
-                    Err(ZmqError::Other(
-                        "Provided sockets combination is not compatible",
-                    ))
+    options: &SocketOptions,
+    peer_address: Option<SocketAddr>,
+) -> ZmqResult<(PeerIdentity, Properties)> {
+    let mut metadata = options.handshake_properties.clone();
+    metadata.insert("Socket-Type".into(), socket_type.to_string().into_bytes());
+    if let Some(identity) = &options.identity {
+        metadata.insert("Identity".into(), identity.0.clone());
+    }
+    let peer_metadata = match &options.security {
+        Security::Null => {
+            let user_id =
+                security::authenticate(socket, options, peer_address, Credentials::Null).await?;
+            socket
+                .send(Message::Command(ZmtpCommand::Ready(metadata)))
+                .await?;
+            match socket.next().await {
+                Some(Ok(Message::Command(ZmtpCommand::Ready(properties)))) => {
+                    security::attach_user_id(properties, user_id)
+                }
+                Some(Ok(Message::Command(ZmtpCommand::Error { reason }))) => {
+                    return Err(ZmqError::Rejected(reason))
                 }
+                Some(Ok(_)) => return Err(ZmqError::Codec("Failed to confirm ready state")),
+                Some(Err(e)) => return Err(e),
+                None => return Err(ZmqError::Other("No reply from server")),
             }
-        },
-        Some(Ok(_)) => Err(ZmqError::Codec("Failed to confirm ready state")),
-        Some(Err(e)) => Err(e),
-        None => Err(ZmqError::Other("No reply from server")),
+        }
+        Security::PlainClient { username, password } => {
+            security::plain_client(socket, metadata, username, password).await?
+        }
+        Security::PlainServer => {
+            security::plain_server(socket, metadata, options, peer_address).await?
+        }
+        #[cfg(feature = "curve")]
+        Security::CurveClient {
+            public_key,
+            secret_key,
+            server_key,
+        } => curve::client(socket, metadata, public_key, secret_key, server_key).await?,
+        #[cfg(feature = "curve")]
+        Security::CurveServer {
+            public_key,
+            secret_key,
+        } => {
+            curve::server(
+                socket,
+                metadata,
+                public_key,
+                secret_key,
+                options,
+                peer_address,
+            )
+            .await?
+        }
+    };
+
+    let other_sock_type = peer_metadata
+        .get("Socket-Type")
+        .map(|x| SocketType::try_from(String::from_utf8_lossy(x).as_ref()))
+        .unwrap_or(Err(ZmqError::Codec("Failed to parse other socket type")))?;
+
+    let peer_id = match peer_metadata.get("Identity") {
+        Some(identity) => identity.clone().try_into()?,
+        None => PeerIdentity::new(),
+    };
+
+    if sockets_compatible(socket_type, other_sock_type) {
+        Ok((peer_id, peer_metadata))
+    } else {
+        send_error(socket, "Invalid socket type").await?;
+        Err(ZmqError::InvalidPeerSocketType {
+            ours: socket_type,
+            theirs: other_sock_type,
+        })
     }
 }
 
-pub(crate) async fn raw_connect(
+/// Connection that completed handshake along with identity and properties of the peer
+type Handshaked<S> = (Framed<S, ZmqCodec>, PeerIdentity, Properties);
+
+/// Queue holding at most `hwm` messages while it has a single sender.
+/// Every sender of futures channel gets an extra slot, so buffer is one less.
+/// Clones made for a single send still let one more message in each
+pub(crate) fn bounded_queue<T>(
+    hwm: usize,
+) -> (
+    futures::channel::mpsc::Sender<T>,
+    futures::channel::mpsc::Receiver<T>,
+) {
+    futures::channel::mpsc::channel(hwm.saturating_sub(1))
+}
+
+/// Wraps stream into ZMTP codec, with pooled buffers if socket has a pool
+pub(crate) fn framed<S: ZmqStream>(
+    stream: S,
+    codec: ZmqCodec,
+    options: &SocketOptions,
+) -> Framed<S, ZmqCodec> {
+    match &options.buffer_pool {
+        Some(pool) => pool.framed(stream, codec),
+        None => Framed::new(stream, codec),
+    }
+}
+
+async fn handshake<S: ZmqStream>(
+    socket: S,
     socket_type: SocketType,
-    endpoint: &str,
-) -> ZmqResult<Framed<TcpStream, ZmqCodec>> {
-    let addr = endpoint.parse::<SocketAddr>()?;
-    let mut raw_socket = Framed::new(TcpStream::connect(addr).await?, ZmqCodec::new());
-    greet_exchange(&mut raw_socket).await?;
-    ready_exchange(&mut raw_socket, socket_type).await?;
-    Ok(raw_socket)
+    options: &SocketOptions,
+    peer_address: Option<SocketAddr>,
+) -> ZmqResult<Handshaked<S>> {
+    if !options.zmtp2_fallback {
+        let mut raw_socket = framed(socket, ZmqCodec::new(), options);
+        raw_socket
+            .codec_mut()
+            .set_max_message_size(options.effective_max_message_size());
+        greet_exchange(&mut raw_socket, options).await?;
+        let (peer_id, properties) =
+            ready_exchange(&mut raw_socket, socket_type, options, peer_address).await?;
+        return Ok((raw_socket, peer_id, properties));
+    }
+    match zmtp2::detect_version(socket, socket_type, options).await? {
+        Detected::Zmtp3(mut raw_socket) => {
+            receive_greeting(&mut raw_socket, options).await?;
+            let (peer_id, properties) =
+                ready_exchange(&mut raw_socket, socket_type, options, peer_address).await?;
+            Ok((raw_socket, peer_id, properties))
+        }
+        Detected::Zmtp2(mut raw_socket, peer_type) => {
+            let peer_id = zmtp2::identity_exchange(
+                &mut raw_socket,
+                socket_type,
+                peer_type,
+                options,
+                peer_address,
+            )
+            .await?;
+            Ok((raw_socket, peer_id, Properties::new()))
+        }
+    }
 }
 
-pub(crate) async fn peer_connected(socket: tokio::net::TcpStream, backend: Arc<dyn MultiPeer>) {
-    let mut raw_socket = Framed::new(socket, ZmqCodec::new());
+/// Queue of the peer together with handle backend closes it with
+pub(crate) struct Pipe {
+    peer_id: PeerIdentity,
+    outgoing_queue: QueueReceiver<Message>,
+    queue_stats: Option<QueueStats>,
+    stop_callback: oneshot::Receiver<bool>,
+}
 
-    greet_exchange(&mut raw_socket)
-        .await
-        .expect("Failed to exchange greetings");
-    let peer_id = ready_exchange(&mut raw_socket, backend.socket_type())
-        .await
-        .expect("Failed to exchange ready messages");
+/// Completes once connection is lost. Carries pipe of the peer if peer stays registered
+/// in backend until connection is reestablished, see `SocketOptions::immediate`.
+/// Cancelled if connection gets closed by the socket
+pub(crate) type Lost = oneshot::Receiver<Option<Pipe>>;
+
+/// Handshake bounded by handshake timeout.
+/// Returning error drops the stream so connection of stalled peer gets closed
+async fn timed_handshake<S: ZmqStream>(
+    socket: S,
+    socket_type: SocketType,
+    options: &SocketOptions,
+    peer_address: Option<SocketAddr>,
+) -> ZmqResult<Handshaked<S>> {
+    let handshake = handshake(socket, socket_type, options, peer_address);
+    let result = match options.effective_handshake_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .unwrap_or(Err(ZmqError::HandshakeTimeout)),
+        None => handshake.await,
+    };
+    options.monitor.emit(match &result {
+        Ok((_, peer_id, _)) => SocketEvent::HandshakeSucceeded {
+            peer_id: peer_id.clone(),
+            peer_address,
+        },
+        Err(e) => SocketEvent::HandshakeFailed {
+            peer_address,
+            reason: e.to_string(),
+        },
+    });
+    result
+}
+
+/// Performs ZMTP handshake and registers peer in backend.
+/// Handshake errors are returned and peer never reaches the backend in such case.
+/// Peers above socket's peer limit are refused once they complete the handshake
+pub(crate) async fn peer_connected<S: ZmqStream>(
+    socket: S,
+    peer_address: Option<SocketAddr>,
+    backend: Arc<dyn MultiPeer>,
+    options: &SocketOptions,
+) -> ZmqResult<()> {
+    let mut connection =
+        timed_handshake(socket, backend.socket_type(), options, peer_address).await?;
+    let slot = match options.peer_limit.acquire() {
+        Some(slot) => slot,
+        None => {
+            options.stats.peer_rejected();
+            options.monitor.emit(SocketEvent::PeerRejected {
+                peer_id: connection.1.clone(),
+                peer_address,
+            });
+            send_error(&mut connection.0, "Too many peers").await?;
+            return Err(ZmqError::PeerLimitReached);
+        }
+    };
+    let lost = register_peer(connection, peer_address, backend, options, false).await?;
+    // Place of the peer is taken until its connection is gone
+    tokio::spawn(async move {
+        let _slot = slot;
+        let _ = lost.await;
+    });
+    Ok(())
+}
+
+/// Registers peer that completed handshake in backend and starts its connection
+async fn register_peer<S: ZmqStream>(
+    connection: Handshaked<S>,
+    peer_address: Option<SocketAddr>,
+    backend: Arc<dyn MultiPeer>,
+    options: &SocketOptions,
+    keep_pipe: bool,
+) -> ZmqResult<Lost> {
+    let (mut raw_socket, peer_id, properties) = connection;
+    // With identity_handover backend replaces the peer and closes its old connection,
+    // see `hand_over`
+    if !options.identity_handover && backend.has_peer(&peer_id).await {
+        options.monitor.emit(SocketEvent::PeerRejected {
+            peer_id: peer_id.clone(),
+            peer_address,
+        });
+        send_error(&mut raw_socket, "Duplicate identity").await?;
+        return Err(ZmqError::DuplicateIdentity(peer_id));
+    }
+
+    options.check_stopped()?;
+    let version = raw_socket.codec().version();
+    let conflate = options.conflates_send(backend.socket_type());
+    let queue_stats = if conflate {
+        None
+    } else {
+        Some(options.stats.queue(peer_id.clone()))
+    };
+    let (outgoing_queue, stop_callback) = backend
+        .peer_connected(&peer_id, version, options.high_water_marks())
+        .await;
+    let pipe = Pipe {
+        peer_id,
+        outgoing_queue: QueueReceiver::new(outgoing_queue, conflate),
+        queue_stats,
+        stop_callback,
+    };
+    Ok(run_connection(
+        raw_socket,
+        pipe,
+        peer_address,
+        properties,
+        backend,
+        options,
+        keep_pipe,
+    ))
+}
+
+/// Closes connection of the peer whose identity is taken over by a new connection.
+/// Messages still queued for the old connection are dropped rather than written out,
+/// even if linger is set
+pub(crate) fn hand_over(stop_handle: oneshot::Sender<bool>) {
+    let _ = stop_handle.send(false);
+}
 
-    let (outgoing_queue, stop_callback) = backend.peer_connected(&peer_id).await;
+fn handed_over(stop_callback: &mut oneshot::Receiver<bool>) -> bool {
+    matches!(stop_callback.try_recv(), Ok(Some(false)))
+}
+
+/// Spawns task passing messages between connection and pipe of the peer.
+/// With keep_pipe lost connection leaves peer registered and hands its pipe over
+fn run_connection<S: ZmqStream>(
+    mut raw_socket: Framed<S, ZmqCodec>,
+    pipe: Pipe,
+    peer_address: Option<SocketAddr>,
+    properties: Properties,
+    backend: Arc<dyn MultiPeer>,
+    options: &SocketOptions,
+    keep_pipe: bool,
+) -> Lost {
+    // Handshake frames, such as ZMTP 2.0 identities, are never streamed
+    raw_socket
+        .codec_mut()
+        .set_streaming_threshold(options.streaming_threshold);
+    let version = raw_socket.codec().version();
+    let Pipe {
+        peer_id,
+        outgoing_queue,
+        queue_stats,
+        stop_callback,
+    } = pipe;
 
+    let mut heartbeat = Heartbeat::new(options, version);
+    let flush_strategy = options.flush_strategy;
+    let linger = options.effective_linger();
+    let buffer_pool = options.buffer_pool.clone();
+    let monitor = options.monitor.clone();
+    let origin = Arc::new(Origin {
+        peer_id: peer_id.clone(),
+        peer_address,
+        mechanism: options.security.mechanism(),
+        properties,
+    });
+    // Welcome message or probe goes out on every connection, before anything queued for the peer
+    let mut welcome = match backend.socket_type() {
+        SocketType::PUB | SocketType::XPUB => options.welcome_message.clone(),
+        SocketType::ROUTER | SocketType::DEALER | SocketType::REQ if options.probe_router => {
+            Some(ZmqMessage::from(Vec::new()))
+        }
+        _ => None,
+    };
+    let task = options.track_task();
+    let mut report = options.close_reports.connection(peer_id.clone());
+    let counters = options
+        .stats
+        .connection(peer_id.clone(), queue_stats.as_ref());
+    let (lost_handle, lost) = oneshot::channel::<Option<Pipe>>();
     tokio::spawn(async move {
+        let _task = task;
         let mut stop_callback = stop_callback;
         let mut outgoing_queue = outgoing_queue;
+        let mut stopped = false;
+        let failed = |reason: String| {
+            monitor.emit(SocketEvent::ConnectionFailed {
+                peer_id: peer_id.clone(),
+                peer_address,
+                reason,
+            })
+        };
         loop {
+            if handed_over(&mut stop_callback) {
+                stopped = true;
+                break;
+            }
+            if let Some(message) = welcome.take() {
+                let message = Message::Message(message);
+                counters.sent(&message);
+                if let Err(e) = raw_socket.send(message).await {
+                    failed(e.to_string());
+                    break;
+                }
+            }
+            // Size of the message that just passed through the connection and its direction
+            let mut message_len = 0;
+            let mut received = false;
             tokio::select! {
-                _ = &mut stop_callback => {
+                result = &mut stop_callback => {
+                    let linger = linger.filter(|_| result != Ok(false));
+                    if let Err(e) = drain_on_close(&mut raw_socket, &mut outgoing_queue, linger, &mut report, &counters).await {
+                        failed(e.to_string());
+                    }
+                    stopped = true;
                     break;
                 },
+                _ = heartbeat.tick() => {
+                    if heartbeat.expired() {
+                        // Half-open connections never report EOF so peer is dropped here
+                        break;
+                    }
+                    if let Some(ping) = heartbeat.ping() {
+                        if let Err(e) = raw_socket.send(ping).await {
+                            failed(e.to_string());
+                            break;
+                        }
+                    }
+                },
                 outgoing = outgoing_queue.next() => {
                     match outgoing {
                         Some(message) => {
-                            let result = raw_socket.send(message).await;
+                            message_len = message.encoded_len();
+                            let mut send = Box::pin(send_queued(
+                                &mut raw_socket,
+                                &mut outgoing_queue,
+                                message,
+                                flush_strategy,
+                                &counters,
+                            ));
+                            let result = tokio::select! {
+                                result = &mut send => result,
+                                // Socket closes while peer is slow to read. Partially written
+                                // message can't be dropped, so it gets linger period as well
+                                result = &mut stop_callback => {
+                                    let linger = linger.filter(|_| result != Ok(false));
+                                    let started = tokio::time::Instant::now();
+                                    let sent = match linger {
+                                        Some(linger) => {
+                                            matches!(tokio::time::timeout(linger, &mut send).await, Ok(Ok(())))
+                                        }
+                                        None => false,
+                                    };
+                                    drop(send);
+                                    if sent {
+                                        report.flushed += 1;
+                                        let left = linger.map(|linger| linger.checked_sub(started.elapsed()).unwrap_or_default());
+                                        if let Err(e) = drain_on_close(&mut raw_socket, &mut outgoing_queue, left, &mut report, &counters).await {
+                                            failed(e.to_string());
+                                        }
+                                    } else {
+                                        report.dropped += 1 + discard_queued(&mut outgoing_queue);
+                                    }
+                                    stopped = true;
+                                    break;
+                                },
+                            };
                             if let Err(e) = result {
-                                println!("{}", e);
+                                failed(e.to_string());
                                 break;
                             }
                         },
                         None => {
+                            // Backend dropped the peer
+                            stopped = true;
                             break;
                         }
                     }
                 },
                 incoming = raw_socket.next() => {
+                    if let Some(Ok(message)) = &incoming {
+                        heartbeat.received(message);
+                    }
                     match incoming {
-                        Some(Ok(message)) => {
+                        Some(Ok(Message::Command(ZmtpCommand::Ping { context, .. }))) => {
+                            let pong = ZmtpCommand::Pong { context };
+                            if let Err(e) = raw_socket.send(Message::Command(pong)).await {
+                                failed(e.to_string());
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Command(ZmtpCommand::Pong { .. }))) => {}
+                        Some(Ok(Message::Command(ZmtpCommand::Error { reason }))) => {
+                            failed(ZmqError::Rejected(reason).to_string());
+                            break;
+                        }
+                        Some(Ok(mut message)) => {
+                            message_len = message.encoded_len();
+                            received = true;
+                            counters.received(&message);
+                            message.attach_origin(&origin);
                             backend.message_received(&peer_id, message).await;
                         }
                         None => {
-                            backend.peer_disconnected(&peer_id).await;
                             break;
                         }
-                        _ => todo!(),
+                        Some(Err(e)) => {
+                            // Codec can't recover from malformed or oversized input
+                            failed(e.to_string());
+                            break;
+                        }
                     }
                 },
             }
+            if let Some(pool) = &buffer_pool {
+                if message_len > pool.capacity() {
+                    raw_socket = pool.shrink(raw_socket, received);
+                }
+            }
+        }
+        drop(report);
+        drop(counters);
+        if let Some(pool) = &buffer_pool {
+            pool.release(raw_socket);
+        }
+        monitor.emit(SocketEvent::Disconnected {
+            peer_id: peer_id.clone(),
+            peer_address,
+        });
+        // Connection lost right as peer was handed over must not unregister the new one
+        if stopped || handed_over(&mut stop_callback) {
+            return;
+        }
+        if keep_pipe {
+            let pipe = Pipe {
+                peer_id,
+                outgoing_queue,
+                queue_stats,
+                stop_callback,
+            };
+            // Nobody is going to reconnect if pipe comes back
+            if let Err(Some(pipe)) = lost_handle.send(Some(pipe)) {
+                backend.peer_disconnected(&pipe.peer_id).await;
+            }
+        } else {
+            backend.peer_disconnected(&peer_id).await;
+            let _ = lost_handle.send(None);
         }
     });
+    lost
+}
+
+/// Bounds socket operation with recv or send timeout.
+/// Zero timeout only lets through operations that complete right away
+pub(crate) async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    operation: F,
+) -> ZmqResult<F::Output> {
+    match timeout {
+        None => Ok(operation.await),
+        Some(timeout) if timeout == Duration::from_secs(0) => {
+            operation.now_or_never().ok_or(ZmqError::Timeout)
+        }
+        Some(timeout) => tokio::time::timeout(timeout, operation)
+            .await
+            .map_err(|_| ZmqError::Timeout),
+    }
+}
+
+/// Puts message into the queue once it has room. Unlike sending through a clone
+/// of the queue, message stays with the caller until then, so giving up on waiting
+/// leaves nothing queued. Queue keeps only one waiting task, so callers sharing it
+/// have to take turns. User data is counted into queue of the peer it is given for
+pub(crate) fn poll_send<T>(
+    queue: &mut mpsc::Sender<T>,
+    cx: &mut Context<'_>,
+    message: &mut Option<T>,
+    counted: Option<(&Stats, &PeerIdentity)>,
+) -> Poll<ZmqResult<()>> {
+    match queue.poll_ready(cx) {
+        Poll::Ready(Ok(())) => {}
+        Poll::Ready(Err(_)) => return Poll::Ready(Err(ZmqError::ConnectionLost)),
+        Poll::Pending => return Poll::Pending,
+    }
+    let message = message.take().expect("Message already sent");
+    if let Some((stats, peer_id)) = counted {
+        stats.enqueued(peer_id);
+    }
+    Poll::Ready(queue.start_send(message).map_err(|_| {
+        if let Some((stats, peer_id)) = counted {
+            stats.not_enqueued(peer_id);
+        }
+        ZmqError::ConnectionLost
+    }))
+}
+
+/// Waits for room in the queue of the peer and puts message there, see `poll_send`
+pub(crate) async fn send_to_peer<P, T>(
+    peers: &DashMap<PeerIdentity, P>,
+    peer_id: &PeerIdentity,
+    send_queue: fn(&mut P) -> &mut mpsc::Sender<T>,
+    message: T,
+    stats: &Stats,
+) -> ZmqResult<()> {
+    let mut message = Some(message);
+    futures::future::poll_fn(|cx| match peers.get_mut(peer_id) {
+        Some(mut peer) => poll_send(
+            send_queue(&mut peer),
+            cx,
+            &mut message,
+            Some((stats, peer_id)),
+        ),
+        None => Poll::Ready(Err(ZmqError::ConnectionLost)),
+    })
+    .await
+}
+
+/// Sends message to each of the peers at once, queues of every one are waited for up to send
+/// timeout. Peers that stay full are dropped, which closes their connection.
+/// Commands aren't user data, so they go without stats
+pub(crate) async fn send_to_each<P, T: Clone>(
+    peers: &DashMap<PeerIdentity, P>,
+    peer_ids: Vec<PeerIdentity>,
+    send_queue: fn(&mut P) -> &mut mpsc::Sender<T>,
+    message: T,
+    options: &SocketOptions,
+    stats: Option<&Stats>,
+) {
+    let sends = peer_ids.into_iter().map(|peer_id| {
+        let mut message = Some(message.clone());
+        async move {
+            let send = futures::future::poll_fn(|cx| match peers.get_mut(&peer_id) {
+                Some(mut peer) => poll_send(
+                    send_queue(&mut peer),
+                    cx,
+                    &mut message,
+                    stats.map(|stats| (stats, &peer_id)),
+                ),
+                None => Poll::Ready(Err(ZmqError::ConnectionLost)),
+            });
+            // Peer might disconnect at any moment. It's fine to skip it in such case
+            if let Err(ZmqError::Timeout) = with_timeout(options.send_timeout, send).await {
+                peers.remove(&peer_id);
+            }
+        }
+    });
+    futures::future::join_all(sends).await;
+}
+
+/// Writes out messages left in the queue of disconnected peer along with
+/// anything still buffered by the connection
+async fn drain_queued<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    queue: &mut QueueReceiver<Message>,
+    written: &mut usize,
+    counters: &PeerCounters,
+) -> ZmqResult<()> {
+    while let Some(Some(message)) = queue.next().now_or_never() {
+        counters.written(&message);
+        socket.feed(message).await?;
+        *written += 1;
+    }
+    // Flushes what is left in write buffer and shuts connection down, so peer gets FIN
+    // or closing message of the transport right after the last message
+    socket.close().await
+}
+
+/// Writes out messages still queued for the peer within linger period once socket closes.
+/// Counts of flushed and dropped messages go to close report.
+/// Returns error connection broke on while they were written
+async fn drain_on_close<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    queue: &mut QueueReceiver<Message>,
+    linger: Option<Duration>,
+    report: &mut ConnectionReport,
+    counters: &PeerCounters,
+) -> ZmqResult<()> {
+    let mut result = Ok(());
+    if let Some(linger) = linger {
+        let mut written = 0;
+        let drain = drain_queued(socket, queue, &mut written, counters);
+        match tokio::time::timeout(linger, drain).await {
+            Ok(Ok(())) => report.flushed += written,
+            Ok(Err(e)) => {
+                report.dropped += written;
+                result = Err(e);
+            }
+            // Messages might still sit in write buffer, they are never sent now
+            Err(_) => report.dropped += written,
+        }
+    }
+    report.dropped += discard_queued(queue);
+    result
+}
+
+/// Empties queue of the peer whose messages won't be written out. Returns how many there were
+fn discard_queued(queue: &mut QueueReceiver<Message>) -> usize {
+    let mut discarded = 0;
+    while let Some(Some(_)) = queue.next().now_or_never() {
+        discarded += 1;
+    }
+    discarded
+}
+
+/// Waits until connection tasks of closed socket finish, but no longer than linger period
+pub(crate) async fn wait_for_connections(reports: &CloseReports, linger: Option<Duration>) {
+    if let Some(linger) = linger {
+        let _ = tokio::time::timeout(linger, reports.finished()).await;
+    }
+}
+
+/// Frames at least this large are written straight from their payload
+/// instead of being copied into the connection write buffer first
+const DIRECT_WRITE_THRESHOLD: usize = 64 * 1024;
+
+/// Writes message to the peer. With batched flushing messages already waiting
+/// in the queue are encoded right after it and written together
+async fn send_queued<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    queue: &mut QueueReceiver<Message>,
+    message: Message,
+    strategy: FlushStrategy,
+    counters: &PeerCounters,
+) -> ZmqResult<()> {
+    counters.written(&message);
+    let message = match write_direct(socket, message).await? {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+    let limit = match strategy {
+        FlushStrategy::PerMessage => return socket.send(message).await,
+        FlushStrategy::Batched(limit) => limit,
+    };
+    let mut buffered = message.encoded_len();
+    // Framed writes out its buffer on its own once it holds 8 KiB, so it never grows unbounded
+    socket.feed(message).await?;
+    while buffered < limit {
+        match queue.next().now_or_never() {
+            Some(Some(message)) => {
+                counters.written(&message);
+                buffered += message.encoded_len();
+                if message.encoded_len() >= DIRECT_WRITE_THRESHOLD {
+                    // Keeps order of messages that are already buffered
+                    socket.flush().await?;
+                }
+                if let Some(message) = write_direct(socket, message).await? {
+                    socket.feed(message).await?;
+                }
+            }
+            // Queue is empty or closed, the latter is noticed by the caller
+            _ => break,
+        }
+    }
+    socket.flush().await
+}
+
+/// Writes large unencrypted message to the stream without copying its payload.
+/// Gives message back if it has to go through the codec.
+/// Write buffer of the codec must be empty at this point, otherwise messages get reordered
+async fn write_direct<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    message: Message,
+) -> ZmqResult<Option<Message>> {
+    if message.encoded_len() < DIRECT_WRITE_THRESHOLD || socket.codec().encrypted() {
+        return Ok(Some(message));
+    }
+    let frames = match message {
+        Message::Message(frame) => vec![frame],
+        Message::MultipartMessage(frames) => frames,
+        Message::Shared(message) => {
+            let stream = socket.get_mut();
+            stream.write_all(message.encoded()).await?;
+            stream.flush().await?;
+            return Ok(None);
+        }
+        message => return Ok(Some(message)),
+    };
+    let last = frames.len().saturating_sub(1);
+    for (idx, frame) in frames.iter().enumerate() {
+        let header = ZmqCodec::frame_header(frame.data.len(), idx != last);
+        let stream = socket.get_mut();
+        stream.write_all(&header).await?;
+        stream.write_all(&frame.data).await?;
+    }
+    socket.get_mut().flush().await?;
+    Ok(None)
 }
 
 /// Opens port described by endpoint and starts a coroutine to accept new connections on it
@@ -205,22 +902,606 @@ pub(crate) async fn peer_connected(socket: tokio::net::TcpStream, backend: Arc<d
 pub(crate) async fn start_accepting_connections(
     endpoint: &str,
     backend: Arc<dyn MultiPeer>,
-) -> ZmqResult<futures::channel::oneshot::Sender<bool>> {
-    let mut listener = tokio::net::TcpListener::bind(endpoint).await?;
-    let (stop_handle, stop_callback) = futures::channel::oneshot::channel::<bool>();
+    options: &SocketOptions,
+) -> ZmqResult<(Endpoint, ListenerHandle)> {
+    if let Some(context) = &options.context {
+        context.register(backend.clone());
+    }
+    let handshake_options = options.clone();
+    start_listener(endpoint, options, move |socket, peer_address| {
+        tokio::spawn(accepted_peer_connected(
+            socket,
+            peer_address,
+            backend.clone(),
+            handshake_options.clone(),
+        ));
+    })
+    .await
+}
+
+/// Opens connection to the endpoint using transport described by its scheme.
+/// Endpoints without scheme are treated as TCP addresses
+pub(crate) async fn connect_endpoint(
+    endpoint: &str,
+    options: &SocketOptions,
+) -> ZmqResult<BoxedStream> {
+    connect_to(&endpoint.parse()?, options).await
+}
+
+/// Reports every connection that was made to the monitor
+async fn connect_to(endpoint: &Endpoint, options: &SocketOptions) -> ZmqResult<BoxedStream> {
+    let stream = transport_for(endpoint)?
+        .connect(endpoint.clone(), options)
+        .await?;
+    options.monitor.emit(SocketEvent::Connected {
+        endpoint: endpoint.clone(),
+    });
+    Ok(stream)
+}
+
+/// Connects to the endpoint and registers new peer in backend after ZMTP handshake.
+/// Lost connection is reestablished until returned stop_handle is dropped or socket is closed
+pub(crate) async fn connect_peer(
+    endpoint: &str,
+    backend: Arc<dyn MultiPeer>,
+    options: &SocketOptions,
+) -> ZmqResult<Connected> {
+    let reconnect_interval = options.effective_reconnect_interval();
+    let keep_pipe = reconnect_interval.is_some() && keeps_pipe(options, backend.socket_type());
+    options.check_terminated()?;
+    if let Some(context) = &options.context {
+        context.register(backend.clone());
+    }
+    let endpoint = endpoint.parse::<Endpoint>()?;
+    let stream = connect_to(&endpoint, options).await?;
+    let connection = timed_handshake(stream, backend.socket_type(), options, None).await?;
+    let record = Arc::new(std::sync::Mutex::new(ConnectionRecord {
+        peer_id: connection.1.clone(),
+        state: ConnectionState::Connected,
+    }));
+    let lost = register_peer(connection, None, backend.clone(), options, keep_pipe).await?;
+    let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+    match reconnect_interval {
+        Some(interval) => {
+            tokio::spawn(reconnect(
+                endpoint,
+                Arc::downgrade(&backend),
+                options.clone(),
+                interval,
+                lost,
+                record.clone(),
+                stop_callback,
+            ));
+        }
+        None => {
+            let record = record.clone();
+            tokio::spawn(async move {
+                // Connection closed by the socket itself cancels lost
+                if lost.await.is_ok() {
+                    record.lock().unwrap().state = ConnectionState::Disconnected;
+                }
+            });
+        }
+    }
+    Ok(Connected {
+        record,
+        stop_handle,
+    })
+}
+
+/// State of connection made by connect, see `SocketFrontend::connections`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Connection was lost and is being reestablished
+    Reconnecting,
+    /// Connection was lost and won't be reestablished
+    Disconnected,
+}
+
+/// Connection made by connect as reported by `SocketFrontend::connections`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub endpoint: Endpoint,
+    /// Identity of the peer of the most recent connection to the endpoint
+    pub peer_id: PeerIdentity,
+    pub state: ConnectionState,
+}
+
+/// Peer currently registered for the connection, it changes on reconnect
+struct ConnectionRecord {
+    peer_id: PeerIdentity,
+    state: ConnectionState,
+}
+
+type SharedRecord = Arc<std::sync::Mutex<ConnectionRecord>>;
+
+/// Outgoing connection made by connect. Dropping it stops reconnecting to the endpoint
+pub(crate) struct Connected {
+    record: SharedRecord,
+    stop_handle: oneshot::Sender<bool>,
+}
+
+/// Connections made by connect together with endpoints they were made to
+#[derive(Default)]
+pub(crate) struct Connects {
+    connects: Vec<(Endpoint, Connected)>,
+}
+
+impl Connects {
+    pub(crate) fn add(&mut self, endpoint: Endpoint, connected: Connected) {
+        self.connects.push((endpoint, connected));
+    }
+
+    /// Connections in the order they were made
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        self.connects
+            .iter()
+            .map(|(endpoint, connected)| {
+                let record = connected.record.lock().unwrap();
+                ConnectionInfo {
+                    endpoint: endpoint.clone(),
+                    peer_id: record.peer_id.clone(),
+                    state: record.state,
+                }
+            })
+            .collect()
+    }
+
+    /// Stops reconnecting to the endpoint and drops its peer from backend.
+    /// Messages still queued for the peer are written out only within linger period
+    pub(crate) async fn remove(
+        &mut self,
+        endpoint: &str,
+        backend: &dyn MultiPeer,
+    ) -> ZmqResult<()> {
+        let endpoint = endpoint.parse::<Endpoint>()?;
+        let position = self
+            .connects
+            .iter()
+            .position(|(connected_to, _)| connected_to == &endpoint)
+            .ok_or(ZmqError::NotConnected(endpoint))?;
+        let (_, connected) = self.connects.remove(position);
+        // Reconnect loop has to be gone before the peer so it doesn't bring connection back
+        drop(connected.stop_handle);
+        let peer_id = connected.record.lock().unwrap().peer_id.clone();
+        backend.peer_disconnected(&peer_id).await;
+        Ok(())
+    }
+}
+
+/// Whether peer stays registered while its connection is reestablished.
+/// SUB, XSUB and DISH send their subscriptions to every new connection anyway,
+/// so queueing subscription changes for lost one would deliver them twice
+fn keeps_pipe(options: &SocketOptions, socket_type: SocketType) -> bool {
+    !options.immediate
+        && !matches!(
+            socket_type,
+            SocketType::SUB | SocketType::XSUB | SocketType::DISH
+        )
+}
+
+/// Completes once backend drops the peer which pipe is kept, never if there is none
+async fn pipe_closed(pipe: &mut Option<Pipe>) {
+    match pipe {
+        Some(pipe) => {
+            let _ = (&mut pipe.stop_callback).await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for connection to be lost and connects to the endpoint again, backing off
+/// between failed attempts. Only weak reference to the backend is kept in between,
+/// so dropped socket isn't held alive by the loop
+async fn reconnect(
+    endpoint: Endpoint,
+    backend: Weak<dyn MultiPeer>,
+    options: SocketOptions,
+    reconnect_interval: Duration,
+    mut lost: Lost,
+    record: SharedRecord,
+    mut stop_callback: oneshot::Receiver<bool>,
+) {
+    let _task = options.track_task();
+    let stopped = options.stopped();
+    futures::pin_mut!(stopped);
+    let set_state = |state| record.lock().unwrap().state = state;
+    let keep_pipe = match backend.upgrade() {
+        Some(backend) => keeps_pipe(&options, backend.socket_type()),
+        None => return,
+    };
+    loop {
+        let mut pipe = tokio::select! {
+            result = &mut lost => match result {
+                Ok(pipe) => pipe,
+                // Connection was closed by the socket itself rather than lost
+                Err(_) => return,
+            },
+            _ = &mut stop_callback => return,
+            _ = &mut stopped => return,
+        };
+        set_state(ConnectionState::Reconnecting);
+        let mut interval = reconnect_interval;
+        lost = loop {
+            options.monitor.emit(SocketEvent::ConnectRetried {
+                endpoint: endpoint.clone(),
+                delay: interval,
+            });
+            tokio::select! {
+                _ = tokio::time::delay_for(interval) => {},
+                _ = &mut stop_callback => return,
+                _ = &mut stopped => return,
+                _ = pipe_closed(&mut pipe) => {
+                    set_state(ConnectionState::Disconnected);
+                    return;
+                },
+            }
+            let backend = match backend.upgrade() {
+                Some(backend) => backend,
+                None => return,
+            };
+            let socket_type = backend.socket_type();
+            let attempt = async {
+                let stream = connect_to(&endpoint, &options).await?;
+                timed_handshake(stream, socket_type, &options, None).await
+            };
+            let connection = tokio::select! {
+                result = attempt => result,
+                _ = &mut stop_callback => return,
+                _ = &mut stopped => return,
+                _ = pipe_closed(&mut pipe) => {
+                    set_state(ConnectionState::Disconnected);
+                    return;
+                },
+            };
+            let result = match (connection, pipe.take()) {
+                // Peer is still registered under identity of the first connection
+                (Ok((raw_socket, _, properties)), Some(kept)) => Ok(run_connection(
+                    raw_socket, kept, None, properties, backend, &options, keep_pipe,
+                )),
+                (Ok(connection), None) => {
+                    let reconnected_id = connection.1.clone();
+                    let result =
+                        register_peer(connection, None, backend, &options, keep_pipe).await;
+                    if result.is_ok() {
+                        record.lock().unwrap().peer_id = reconnected_id;
+                    }
+                    result
+                }
+                (Err(e), kept) => {
+                    pipe = kept;
+                    Err(e)
+                }
+            };
+            match result {
+                Ok(lost) => {
+                    set_state(ConnectionState::Connected);
+                    break lost;
+                }
+                Err(e) => {
+                    options.monitor.emit(SocketEvent::ConnectFailed {
+                        endpoint: endpoint.clone(),
+                        reason: e.to_string(),
+                    });
+                    interval = options.next_reconnect_interval(interval);
+                }
+            }
+        };
+    }
+}
+
+/// Opens port described by endpoint and passes every accepted connection to on_connection
+/// without any ZMTP handshake. Returns endpoint that was actually bound
+/// and stop_handle channel that can be used to stop accepting
+pub(crate) async fn start_listener<F>(
+    endpoint: &str,
+    options: &SocketOptions,
+    on_connection: F,
+) -> ZmqResult<(Endpoint, ListenerHandle)>
+where
+    F: Fn(BoxedStream, Option<SocketAddr>) + Send + Sync + 'static,
+{
+    options.check_terminated()?;
+    let endpoint = endpoint.parse::<Endpoint>()?;
+    listener_closed(&endpoint).await;
+    let (listener, bound_endpoint) = transport_for(&endpoint)?.bind(endpoint, options).await?;
+    let handle = run_listener(listener, bound_endpoint.clone(), options, on_connection);
+    Ok((bound_endpoint, handle))
+}
+
+/// Same as start_listener but for TCP listener that was bound by the caller
+pub(crate) fn start_listener_on<F>(
+    listener: tokio::net::TcpListener,
+    options: &SocketOptions,
+    on_connection: F,
+) -> ZmqResult<(Endpoint, ListenerHandle)>
+where
+    F: Fn(BoxedStream, Option<SocketAddr>) + Send + Sync + 'static,
+{
+    options.check_terminated()?;
+    let (listener, bound_endpoint) = transport::tcp_listener(listener, options)?;
+    let handle = run_listener(listener, bound_endpoint.clone(), options, on_connection);
+    Ok((bound_endpoint, handle))
+}
+
+/// Same as start_accepting_connections but for TCP listener that was bound by the caller
+pub(crate) fn start_accepting_connections_on(
+    listener: tokio::net::TcpListener,
+    backend: Arc<dyn MultiPeer>,
+    options: &SocketOptions,
+) -> ZmqResult<(Endpoint, ListenerHandle)> {
+    if let Some(context) = &options.context {
+        context.register(backend.clone());
+    }
+    let handshake_options = options.clone();
+    start_listener_on(listener, options, move |socket, peer_address| {
+        tokio::spawn(accepted_peer_connected(
+            socket,
+            peer_address,
+            backend.clone(),
+            handshake_options.clone(),
+        ));
+    })
+}
+
+lazy_static! {
+    /// Listeners that were stopped but might not have released their endpoints yet
+    static ref CLOSING_LISTENERS: std::sync::Mutex<Vec<(Endpoint, Shared<oneshot::Receiver<()>>)>> =
+        std::sync::Mutex::new(Vec::new());
+}
+
+/// Stops the listener once dropped. Endpoint stays reserved until listener task releases it,
+/// so binding to the same endpoint right afterwards waits for that instead of failing
+pub(crate) struct ListenerHandle {
+    endpoint: Endpoint,
+    _stop_handle: oneshot::Sender<bool>,
+    closed: Shared<oneshot::Receiver<()>>,
+}
+
+impl ListenerHandle {
+    /// Returns stop callback for the listener task, together with the handle
+    /// task drops right after it releases the listener
+    pub(crate) fn new(endpoint: Endpoint) -> (Self, oneshot::Receiver<bool>, oneshot::Sender<()>) {
+        let (stop_handle, stop_callback) = oneshot::channel::<bool>();
+        let (closed_handle, closed) = oneshot::channel::<()>();
+        let handle = Self {
+            endpoint,
+            _stop_handle: stop_handle,
+            closed: closed.shared(),
+        };
+        (handle, stop_callback, closed_handle)
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        let mut closing = CLOSING_LISTENERS.lock().unwrap();
+        closing.retain(|(_, closed)| closed.clone().now_or_never().is_none());
+        closing.push((self.endpoint.clone(), self.closed.clone()));
+    }
+}
+
+/// Waits until listeners of the endpoint that were just stopped release it
+pub(crate) async fn listener_closed(endpoint: &Endpoint) {
+    let closing: Vec<_> = CLOSING_LISTENERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(closing, _)| closing == endpoint)
+        .map(|(_, closed)| closed.clone())
+        .collect();
+    for closed in closing {
+        let _ = closed.await;
+    }
+}
+
+struct Bind {
+    requested: Option<Endpoint>,
+    bound: Endpoint,
+    _listener: ListenerHandle,
+}
+
+/// Listeners started by bind. Dropping stop handle stops accepting new connections
+/// while peers that are already connected stay registered in backend
+#[derive(Default)]
+pub(crate) struct Binds {
+    binds: Vec<Bind>,
+}
+
+impl Binds {
+    /// `requested` is endpoint as it was passed to bind, before wildcards were resolved
+    pub(crate) fn add(
+        &mut self,
+        requested: Option<Endpoint>,
+        bound: Endpoint,
+        listener: ListenerHandle,
+    ) {
+        self.binds.push(Bind {
+            requested,
+            bound,
+            _listener: listener,
+        });
+    }
+
+    /// Endpoints listeners are bound to, in the order binds were made
+    pub(crate) fn endpoints(&self) -> Vec<Endpoint> {
+        self.binds.iter().map(|bind| bind.bound.clone()).collect()
+    }
+
+    /// Stops listener for endpoint that was either returned by bind or passed to it
+    pub(crate) fn remove(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let endpoint = endpoint.parse::<Endpoint>()?;
+        let position = self
+            .binds
+            .iter()
+            .position(|bind| bind.bound == endpoint)
+            .or_else(|| {
+                self.binds
+                    .iter()
+                    .position(|bind| bind.requested.as_ref() == Some(&endpoint))
+            })
+            .ok_or(ZmqError::NotBound(endpoint))?;
+        self.binds.remove(position);
+        Ok(())
+    }
+}
+
+/// Nobody waits for handshake of accepted connection so its errors are only reported
+pub(crate) async fn accepted_peer_connected(
+    socket: BoxedStream,
+    peer_address: Option<SocketAddr>,
+    backend: Arc<dyn MultiPeer>,
+    options: SocketOptions,
+) {
+    let _task = options.track_task();
+    let stopped = options.stopped();
+    tokio::select! {
+        // Failed handshakes and rejected peers are reported to the monitor on the way
+        _ = peer_connected(socket, peer_address, backend, &options) => {},
+        // Handshake is abandoned once socket is gone
+        _ = stopped => {},
+    }
+}
+
+/// How long listener waits before accepting again once process runs out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Errors of the connection being accepted, or of running out of resources, leave listener usable.
+/// Anything else, such as EBADF or EINVAL, means listener itself is gone
+#[cfg(unix)]
+fn accept_error_is_transient(error: &ZmqError) -> bool {
+    match error {
+        ZmqError::Network(e) => {
+            matches!(
+                e.raw_os_error(),
+                Some(libc::ECONNABORTED)
+                    | Some(libc::EINTR)
+                    | Some(libc::EPROTO)
+                    | Some(libc::EPERM)
+            ) || accept_error_needs_backoff(error)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn accept_error_is_transient(error: &ZmqError) -> bool {
+    match error {
+        ZmqError::Network(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
+
+/// Resources that free up only once some connections are closed,
+/// so accepting again right away would just spin
+#[cfg(unix)]
+fn accept_error_needs_backoff(error: &ZmqError) -> bool {
+    match error {
+        ZmqError::Network(e) => matches!(
+            e.raw_os_error(),
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn accept_error_needs_backoff(_error: &ZmqError) -> bool {
+    false
+}
+
+/// Connections rejected by filter are dropped right away so no handshake is ever started.
+/// Failed accepts are reported to the monitor, listener only stops on errors it can't recover from.
+/// Handshakes run in their own tasks so whatever happens to them never reaches the listener
+pub(crate) fn run_listener<F>(
+    mut listener: Box<dyn Listener>,
+    endpoint: Endpoint,
+    options: &SocketOptions,
+    on_connection: F,
+) -> ListenerHandle
+where
+    F: Fn(BoxedStream, Option<SocketAddr>) + Send + Sync + 'static,
+{
+    let filter = options.accept_filter.clone();
+    let monitor = options.monitor.clone();
+    let task = options.track_task();
+    let stopped = options.stopped();
+    let options = options.clone();
+    let on_connection = Arc::new(on_connection);
+    let (handle, stop_callback, closed_handle) = ListenerHandle::new(endpoint.clone());
     tokio::spawn(async move {
+        let _task = task;
         let mut stop_callback = stop_callback.fuse();
+        let stopped = stopped.fuse();
+        futures::pin_mut!(stopped);
         loop {
             select! {
                 incoming = listener.accept().fuse() => {
-                    let (socket, _) = incoming.expect("Failed to accept connection");
-                    tokio::spawn(peer_connected(socket, backend.clone()));
+                    let (pending, peer_address) = match incoming {
+                        Ok(incoming) => incoming,
+                        Err(e) => {
+                            monitor.emit(SocketEvent::AcceptFailed {
+                                endpoint: endpoint.clone(),
+                                reason: e.to_string(),
+                            });
+                            if !accept_error_is_transient(&e) {
+                                break;
+                            }
+                            if accept_error_needs_backoff(&e) {
+                                select! {
+                                    _ = tokio::time::delay_for(ACCEPT_BACKOFF).fuse() => {},
+                                    _ = stop_callback => break,
+                                    _ = stopped => break,
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    if let Some(address) = peer_address {
+                        if !filter.check(&address.ip()) {
+                            continue;
+                        }
+                    }
+                    monitor.emit(SocketEvent::Accepted {
+                        endpoint: endpoint.clone(),
+                        peer_address,
+                    });
+                    let on_connection = on_connection.clone();
+                    let monitor = monitor.clone();
+                    let task = options.track_task();
+                    let abandoned = options.stopped();
+                    tokio::spawn(async move {
+                        let _task = task;
+                        tokio::select! {
+                            upgraded = pending => match upgraded {
+                                Ok(stream) => on_connection(stream, peer_address),
+                                // Such as TLS or WebSocket handshake that comes before ZMTP
+                                Err(e) => monitor.emit(SocketEvent::HandshakeFailed {
+                                    peer_address,
+                                    reason: e.to_string(),
+                                }),
+                            },
+                            _ = abandoned => {},
+                        }
+                    });
                 },
                 _ = stop_callback => {
                     break
+                },
+                _ = stopped => {
+                    break
                 }
             }
         }
+        drop(listener);
+        drop(closed_handle);
+        monitor.emit(SocketEvent::Closed { endpoint });
     });
-    Ok(stop_handle)
+    handle
 }
@@ -0,0 +1,139 @@
+use crate::error::*;
+use crate::util::BoxedStream;
+use crate::ZmqResult;
+
+#[cfg(feature = "ws")]
+use bytes::{Buf, Bytes};
+#[cfg(feature = "ws")]
+use futures::{ready, Sink, Stream};
+#[cfg(feature = "ws")]
+use std::pin::Pin;
+#[cfg(feature = "ws")]
+use std::task::{Context, Poll};
+#[cfg(feature = "ws")]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "ws")]
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+#[cfg(feature = "ws")]
+use tokio_tungstenite::tungstenite::http::StatusCode;
+#[cfg(feature = "ws")]
+use tokio_tungstenite::tungstenite::Message;
+#[cfg(feature = "ws")]
+use tokio_tungstenite::WebSocketStream;
+
+/// Fails right away if crate was built without WebSocket support
+pub(crate) fn ensure_supported() -> ZmqResult<()> {
+    if cfg!(feature = "ws") {
+        Ok(())
+    } else {
+        Err(ZmqError::Socket("ws transport requires ws feature"))
+    }
+}
+
+/// Byte stream carried in binary WebSocket messages.
+/// ZMTP greeting and frames are written into it exactly as into TCP stream
+#[cfg(feature = "ws")]
+struct WsStream {
+    inner: WebSocketStream<BoxedStream>,
+    read_buffer: Bytes,
+}
+
+#[cfg(feature = "ws")]
+fn io_error(e: tokio_tungstenite::tungstenite::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+#[cfg(feature = "ws")]
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            if !self.read_buffer.is_empty() {
+                let len = buf.len().min(self.read_buffer.len());
+                buf[..len].copy_from_slice(&self.read_buffer[..len]);
+                self.read_buffer.advance(len);
+                return Poll::Ready(Ok(len));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buffer = Bytes::from(data),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                // Ping and pong are answered by tungstenite itself.
+                // Text messages are not used by the mapping and are skipped
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ws")]
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(io_error)
+    }
+}
+
+/// Performs client side WebSocket upgrade for given url over already connected stream
+#[cfg(feature = "ws")]
+pub(crate) async fn connect(stream: BoxedStream, url: &str) -> ZmqResult<BoxedStream> {
+    let (inner, _response) = tokio_tungstenite::client_async(url, stream)
+        .await
+        .map_err(|e| ZmqError::WebSocket(e.to_string()))?;
+    Ok(Box::new(WsStream {
+        inner,
+        read_buffer: Bytes::new(),
+    }))
+}
+
+#[cfg(not(feature = "ws"))]
+pub(crate) async fn connect(_stream: BoxedStream, _url: &str) -> ZmqResult<BoxedStream> {
+    Err(ZmqError::Socket("ws transport requires ws feature"))
+}
+
+/// Accepts WebSocket upgrade request. Requests for paths other than bound one are rejected
+#[cfg(feature = "ws")]
+// Callback signature is dictated by tungstenite
+#[allow(clippy::result_large_err)]
+pub(crate) async fn accept(stream: BoxedStream, path: &str) -> ZmqResult<BoxedStream> {
+    let expected_path = path.to_string();
+    let check_path = move |request: &Request, response: Response| {
+        if request.uri().path() == expected_path {
+            Ok(response)
+        } else {
+            let mut error = ErrorResponse::new(Some("Unknown path".to_string()));
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            Err(error)
+        }
+    };
+    let inner = tokio_tungstenite::accept_hdr_async(stream, check_path)
+        .await
+        .map_err(|e| ZmqError::WebSocket(e.to_string()))?;
+    Ok(Box::new(WsStream {
+        inner,
+        read_buffer: Bytes::new(),
+    }))
+}
+
+#[cfg(not(feature = "ws"))]
+pub(crate) async fn accept(_stream: BoxedStream, _path: &str) -> ZmqResult<BoxedStream> {
+    Err(ZmqError::Socket("ws transport requires ws feature"))
+}
@@ -0,0 +1,335 @@
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::message::*;
+use crate::options::{HighWaterMarks, SocketOptions};
+use crate::r#pub::{
+    parse_subscription, process_subscription, publish, subscriber_connected,
+    subscriber_disconnected, Subscriber, SubscriptionUpdate,
+};
+use crate::security::Authenticator;
+use crate::subscriptions::Subscriptions;
+use crate::util::*;
+use crate::{
+    util, BlockingRecv, MultiPeer, NonBlockingSend, SocketBackend, SocketFrontend, SocketType,
+    ZmqResult,
+};
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use dashmap::DashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::net::{TcpListener, TcpStream};
+
+pub(crate) struct XPubSocketBackend {
+    subscribers: DashMap<PeerIdentity, Subscriber>,
+    subscriptions: RwLock<Subscriptions>,
+    options: SocketOptions,
+    /// Number of peers subscribed to each topic
+    topics: Mutex<HashMap<Vec<u8>, usize>>,
+    verbose: bool,
+    verboser: bool,
+    /// Held while subscription is applied, counted and passed up, so subscribes and
+    /// cancels of a topic reach the application in the order they were applied.
+    /// Never waits for room, subscriptions that don't fit are dropped
+    subscriptions_queue: Mutex<mpsc::Sender<(PeerIdentity, ZmqMessage)>>,
+    /// Subscriptions application didn't receive in time
+    dropped_subscriptions: AtomicU64,
+}
+
+/// Application always gets subscriptions in message form regardless of peer's version
+fn subscription_message(update: SubscriptionUpdate, topic: &[u8]) -> ZmqMessage {
+    let mut data = BytesMut::with_capacity(topic.len() + 1);
+    data.put_u8((update == SubscriptionUpdate::Subscribe) as u8);
+    data.extend_from_slice(topic);
+    ZmqMessage::from(data.freeze())
+}
+
+impl XPubSocketBackend {
+    /// Counts peer in or out of topic subscribers.
+    /// Returns true if it was the first peer subscribed or the last one cancelled
+    fn count_subscription(&self, update: SubscriptionUpdate, topic: &[u8]) -> bool {
+        let mut topics = self.topics.lock().unwrap();
+        match update {
+            SubscriptionUpdate::Subscribe => {
+                let count = topics.entry(topic.to_vec()).or_insert(0);
+                *count += 1;
+                *count == 1
+            }
+            SubscriptionUpdate::Cancel => match topics.get_mut(topic) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                Some(_) => {
+                    topics.remove(topic);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn pass_subscription(
+        &self,
+        queue: &mut mpsc::Sender<(PeerIdentity, ZmqMessage)>,
+        peer_id: &PeerIdentity,
+        message: ZmqMessage,
+    ) {
+        match queue.try_send((peer_id.clone(), message)) {
+            Err(e) if e.is_full() => {
+                self.dropped_subscriptions.fetch_add(1, Ordering::Relaxed);
+            }
+            // Application side might be already dropped. Subscriptions table is still valid
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl SocketBackend for XPubSocketBackend {
+    async fn message_received(&self, peer_id: &PeerIdentity, message: Message) {
+        let mut queue = self.subscriptions_queue.lock().unwrap();
+        let is_subscribed =
+            |topic: &[u8]| self.subscriptions.read().unwrap().contains(peer_id, topic);
+        let subscribed_before = match parse_subscription(&message) {
+            Some((_, topic)) => is_subscribed(topic),
+            None => return,
+        };
+        let (update, topic) =
+            match process_subscription(&self.subscribers, &self.subscriptions, peer_id, &message) {
+                Some(subscription) => subscription,
+                None => return,
+            };
+        // Peer might repeat subscription, e.g. after reconnect. It's counted once
+        let subscribed_after = is_subscribed(topic);
+        let unique =
+            subscribed_before != subscribed_after && self.count_subscription(update, topic);
+        let pass = match update {
+            SubscriptionUpdate::Subscribe => unique || self.verbose || self.verboser,
+            SubscriptionUpdate::Cancel => unique || self.verboser,
+        };
+        if pass {
+            self.pass_subscription(&mut queue, peer_id, subscription_message(update, topic));
+        }
+    }
+
+    fn socket_type(&self) -> SocketType {
+        SocketType::XPUB
+    }
+
+    fn shutdown(&self) {
+        self.subscribers.clear();
+        self.subscriptions.write().unwrap().clear();
+        self.topics.lock().unwrap().clear();
+    }
+}
+
+#[async_trait]
+impl MultiPeer for XPubSocketBackend {
+    async fn peer_connected(
+        &self,
+        peer_id: &PeerIdentity,
+        _version: ZmtpVersion,
+        hwm: HighWaterMarks,
+    ) -> (mpsc::Receiver<Message>, oneshot::Receiver<bool>) {
+        subscriber_connected(&self.subscribers, peer_id, hwm.send, &self.options)
+    }
+
+    /// Subscriptions of disconnected peer are cancelled as if peer sent cancels itself
+    async fn peer_disconnected(&self, peer_id: &PeerIdentity) {
+        let mut queue = self.subscriptions_queue.lock().unwrap();
+        let topics = subscriber_disconnected(&self.subscribers, &self.subscriptions, peer_id);
+        for topic in topics {
+            if self.count_subscription(SubscriptionUpdate::Cancel, &topic) || self.verboser {
+                let message = subscription_message(SubscriptionUpdate::Cancel, &topic);
+                self.pass_subscription(&mut queue, peer_id, message);
+            }
+        }
+    }
+
+    async fn has_peer(&self, peer_id: &PeerIdentity) -> bool {
+        self.subscribers.contains_key(peer_id)
+    }
+}
+
+/// Same as PubSocket but subscription messages received from peers are
+/// also passed to the application. Repeated subscriptions to the same topic are
+/// passed up only if `xpub_verbose` or `xpub_verboser` is set. Up to `recv_hwm`
+/// subscriptions wait for the application, the rest are dropped
+pub struct XPubSocket {
+    backend: Arc<XPubSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    subscriptions: mpsc::Receiver<(PeerIdentity, ZmqMessage)>,
+}
+
+impl Drop for XPubSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+impl XPubSocket {
+    /// Receives next subscription message together with identity of the peer that sent it.
+    /// Message is passed as is, i.e. first byte is 1 for subscribe and 0 for unsubscribe
+    pub async fn recv_from(&mut self) -> ZmqResult<(PeerIdentity, ZmqMessage)> {
+        util::with_timeout(self.options.recv_timeout, self.subscriptions.next())
+            .await?
+            .ok_or(ZmqError::NoMessage)
+    }
+
+    /// Number of peers subscribed to the topic. Peer subscribing to it repeatedly counts once.
+    /// Subscribe passed to the application raises the count from zero, cancel drops it to
+    /// zero, which is what a proxy forwards upstream
+    pub fn subscribe_count(&self, topic: &[u8]) -> usize {
+        let topics = self.backend.topics.lock().unwrap();
+        topics.get(topic).copied().unwrap_or(0)
+    }
+
+    /// Number of subscriptions that were dropped rather than passed to the application,
+    /// since `recv_from` wasn't called often enough to keep up with them
+    pub fn dropped_subscriptions(&self) -> u64 {
+        self.backend.dropped_subscriptions.load(Ordering::Relaxed)
+    }
+}
+
+impl NonBlockingSend for XPubSocket {
+    fn send<M: Into<ZmqMessage>>(&mut self, message: M) -> ZmqResult<()> {
+        let message = message.into();
+        publish(
+            &self.backend.subscribers,
+            &self.backend.subscriptions,
+            vec![message],
+            &self.options,
+        )
+    }
+
+    fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        publish(
+            &self.backend.subscribers,
+            &self.backend.subscriptions,
+            frames,
+            &self.options,
+        )
+    }
+}
+
+#[async_trait]
+impl BlockingRecv for XPubSocket {
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        let (_peer_id, message) = self.recv_from().await?;
+        Ok(vec![message])
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for XPubSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (subscriptions_queue, subscriptions) = bounded_queue(recv_hwm);
+        Self {
+            backend: Arc::new(XPubSocketBackend {
+                subscribers: DashMap::new(),
+                subscriptions: RwLock::default(),
+                options: options.clone(),
+                topics: Mutex::new(HashMap::new()),
+                verbose: options.xpub_verbose,
+                verboser: options.xpub_verboser,
+                subscriptions_queue: Mutex::new(subscriptions_queue),
+                dropped_subscriptions: AtomicU64::new(0),
+            }),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            subscriptions,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::XPUB
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
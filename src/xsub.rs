@@ -0,0 +1,158 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use std::sync::Arc;
+
+use crate::close::CloseReport;
+use crate::codec::*;
+use crate::endpoint::Endpoint;
+use crate::error::*;
+use crate::message::*;
+use crate::options::SocketOptions;
+use crate::security::Authenticator;
+use crate::sub::SubSocketBackend;
+use crate::{util, BlockingRecv, BlockingSend, ConnectionInfo, SocketBackend, SocketFrontend};
+use crate::{SocketType, ZmqResult};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Same as SubSocket but subscriptions are sent by application as regular messages.
+/// Messages starting with 1 subscribe and messages starting with 0 unsubscribe
+pub struct XSubSocket {
+    backend: Arc<SubSocketBackend>,
+    binds: util::Binds,
+    connects: util::Connects,
+    last_endpoint: Option<Endpoint>,
+    options: SocketOptions,
+    queue: mpsc::Receiver<Message>,
+}
+
+impl Drop for XSubSocket {
+    fn drop(&mut self) {
+        self.options.tasks.stop();
+        self.backend.shutdown();
+    }
+}
+
+#[async_trait]
+impl BlockingSend for XSubSocket {
+    async fn send<M>(&mut self, message: M) -> ZmqResult<()>
+    where
+        M: Into<ZmqMessage> + Send,
+    {
+        let message = message.into();
+        self.backend.forward(vec![message], &self.options).await;
+        Ok(())
+    }
+
+    async fn send_multipart(&mut self, frames: Vec<ZmqMessage>) -> ZmqResult<()> {
+        self.backend.forward(frames, &self.options).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockingRecv for XSubSocket {
+    async fn recv_multipart(&mut self) -> ZmqResult<Vec<ZmqMessage>> {
+        match util::with_timeout(self.options.recv_timeout, self.queue.next()).await? {
+            Some(message) => message
+                .into_frames()
+                .ok_or(ZmqError::Other("Wrong message type received")),
+            None => Err(ZmqError::NoMessage),
+        }
+    }
+}
+
+#[async_trait]
+impl SocketFrontend for XSubSocket {
+    fn with_options(options: SocketOptions) -> Self {
+        let options = options.for_socket(Self::socket_type());
+        let recv_hwm = options.high_water_marks().recv;
+        let (queue_sender, queue) = mpsc::channel(recv_hwm);
+        Self {
+            backend: Arc::new(SubSocketBackend::new(SocketType::XSUB, queue_sender)),
+            binds: util::Binds::default(),
+            connects: util::Connects::default(),
+            last_endpoint: None,
+            options,
+            queue,
+        }
+    }
+
+    fn socket_type() -> SocketType {
+        SocketType::XSUB
+    }
+
+    fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn close(self) -> CloseReport {
+        let linger = self.options.effective_linger();
+        let reports = self.options.close_reports.start_closing();
+        let tasks = self.options.tasks.clone();
+        // Dropping the socket stops accepting connections and disconnects peers
+        drop(self);
+        util::wait_for_connections(&reports, linger).await;
+        let report = reports.collect().await;
+        tasks.finished().await;
+        report
+    }
+
+    fn last_endpoint(&self) -> Option<&Endpoint> {
+        self.last_endpoint.as_ref()
+    }
+
+    fn bound_endpoints(&self) -> Vec<Endpoint> {
+        self.binds.endpoints()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connects.list()
+    }
+
+    fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.options.authenticator = Some(authenticator);
+    }
+
+    fn set_handshake_property(&mut self, name: &str, value: &[u8]) -> ZmqResult<()> {
+        self.options.set_handshake_property(name, value)
+    }
+
+    async fn bind(&mut self, endpoint: &str) -> ZmqResult<Endpoint> {
+        let (bound, stop_handle) =
+            util::start_accepting_connections(endpoint, self.backend.clone(), &self.options)
+                .await?;
+        self.binds
+            .add(Some(endpoint.parse()?), bound.clone(), stop_handle);
+        self.last_endpoint = Some(bound.clone());
+        Ok(bound)
+    }
+
+    async fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        let connected = util::connect_peer(endpoint, self.backend.clone(), &self.options).await?;
+        self.connects.add(endpoint.parse()?, connected);
+        Ok(())
+    }
+
+    async fn bind_listener(&mut self, listener: TcpListener) -> ZmqResult<Endpoint> {
+        let (endpoint, stop_handle) =
+            util::start_accepting_connections_on(listener, self.backend.clone(), &self.options)?;
+        self.binds.add(None, endpoint.clone(), stop_handle);
+        self.last_endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    async fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.binds.remove(endpoint)
+    }
+
+    async fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.connects.remove(endpoint, &*self.backend).await
+    }
+
+    async fn connect_stream(&mut self, stream: TcpStream) -> ZmqResult<()> {
+        let peer_address = stream.peer_addr().ok();
+        // Connection made by the caller is not reestablished once lost
+        util::peer_connected(stream, peer_address, self.backend.clone(), &self.options).await
+    }
+}
@@ -0,0 +1,130 @@
+//! Z85 encoding (https://rfc.zeromq.org/spec/32/) used by libzmq to print CURVE keys
+use thiserror::Error;
+
+const ALPHABET: &[u8; 85] =
+    b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ.-:+=^!/*?&<>()[]{}@%$#";
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Z85Error {
+    #[error("Z85 input of {0} bytes is not a multiple of 4")]
+    InvalidBinaryLength(usize),
+    #[error("Z85 string of {0} characters is not a multiple of 5")]
+    InvalidTextLength(usize),
+    #[error("Invalid Z85 character {character:?} at position {position}")]
+    InvalidCharacter { character: char, position: usize },
+    #[error("Z85 chunk at position {0} does not fit in 4 bytes")]
+    Overflow(usize),
+}
+
+fn decode_char(character: char, position: usize) -> Result<u32, Z85Error> {
+    if character.is_ascii() {
+        if let Some(value) = ALPHABET.iter().position(|c| *c == character as u8) {
+            return Ok(value as u32);
+        }
+    }
+    Err(Z85Error::InvalidCharacter {
+        character,
+        position,
+    })
+}
+
+/// Encodes binary data as Z85 text. Length of data must be a multiple of 4
+pub fn z85_encode(data: &[u8]) -> Result<String, Z85Error> {
+    if !data.len().is_multiple_of(4) {
+        return Err(Z85Error::InvalidBinaryLength(data.len()));
+    }
+    let mut encoded = String::with_capacity(data.len() / 4 * 5);
+    for chunk in data.chunks(4) {
+        let mut value = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let mut digits = [0u8; 5];
+        for digit in digits.iter_mut().rev() {
+            *digit = ALPHABET[(value % 85) as usize];
+            value /= 85;
+        }
+        encoded.extend(digits.iter().map(|d| *d as char));
+    }
+    Ok(encoded)
+}
+
+/// Decodes Z85 text. Length of text must be a multiple of 5
+pub fn z85_decode(text: &str) -> Result<Vec<u8>, Z85Error> {
+    let chars: Vec<char> = text.chars().collect();
+    if !chars.len().is_multiple_of(5) {
+        return Err(Z85Error::InvalidTextLength(chars.len()));
+    }
+    let mut decoded = Vec::with_capacity(chars.len() / 5 * 4);
+    for (index, chunk) in chars.chunks(5).enumerate() {
+        let position = index * 5;
+        let mut value = 0u64;
+        for (offset, character) in chunk.iter().enumerate() {
+            value = value * 85 + decode_char(*character, position + offset)? as u64;
+        }
+        if value > u32::MAX as u64 {
+            return Err(Z85Error::Overflow(position));
+        }
+        decoded.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HELLO_WORLD: [u8; 8] = [0x86, 0x4F, 0xD2, 0x6F, 0xB5, 0x59, 0xF7, 0x5B];
+
+    #[test]
+    fn test_spec_vector() {
+        assert_eq!("HelloWorld", z85_encode(&HELLO_WORLD).unwrap());
+        assert_eq!(HELLO_WORLD.to_vec(), z85_decode("HelloWorld").unwrap());
+        assert_eq!("", z85_encode(&[]).unwrap());
+        assert_eq!(Vec::<u8>::new(), z85_decode("").unwrap());
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert_eq!(
+            Err(Z85Error::InvalidBinaryLength(3)),
+            z85_encode(&[1, 2, 3])
+        );
+        assert_eq!(Err(Z85Error::InvalidTextLength(4)), z85_decode("Hell"));
+        assert_eq!(
+            Err(Z85Error::InvalidCharacter {
+                character: '~',
+                position: 7
+            }),
+            z85_decode("HelloWo~ld")
+        );
+        assert_eq!(
+            Err(Z85Error::InvalidCharacter {
+                character: 'é',
+                position: 0
+            }),
+            z85_decode("éello")
+        );
+        // "%nSc1" is u32::MAX + 1
+        assert_eq!(Ok(vec![0xff; 4]), z85_decode("%nSc0"));
+        assert_eq!(Err(Z85Error::Overflow(5)), z85_decode("Hello%nSc1"));
+        assert_eq!(Err(Z85Error::Overflow(0)), z85_decode("#####"));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // Simple xorshift keeps inputs reproducible without pulling rng into dev dependencies
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for len in (0..256).step_by(4) {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            let encoded = z85_encode(&data).unwrap();
+            assert_eq!(len / 4 * 5, encoded.len());
+            assert!(encoded.bytes().all(|c| ALPHABET.contains(&c)));
+            assert_eq!(data, z85_decode(&encoded).unwrap());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Downgrade path for peers that still speak ZMTP 2.0 (https://rfc.zeromq.org/spec/15/).
+//! Enabled with `SocketOptions::zmtp2_fallback` since detection requires sending greeting in parts
+use crate::codec::*;
+use crate::error::*;
+use crate::options::SocketOptions;
+use crate::security::{self, Credentials};
+use crate::util::{self, sockets_compatible, PeerIdentity, ZmqStream};
+use crate::{SocketType, ZmqResult};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use num_traits::FromPrimitive;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+/// Signature and version byte are common for ZMTP 2.0 and 3.x greetings
+const GREETING_PREFIX: usize = 11;
+
+pub(crate) enum Detected<S> {
+    /// Peer speaks ZMTP 3.x. Our greeting is fully sent, peer's greeting is left for the codec
+    Zmtp3(Framed<S, ZmqCodec>),
+    /// Peer speaks ZMTP 2.0 and announced its socket type in the greeting
+    Zmtp2(Framed<S, ZmqCodec>, SocketType),
+}
+
+/// Sends greeting up to the version byte and waits for the same from the peer,
+/// so that the rest of the greeting goes out in the format peer understands
+pub(crate) async fn detect_version<S: ZmqStream>(
+    mut stream: S,
+    socket_type: SocketType,
+    options: &SocketOptions,
+) -> ZmqResult<Detected<S>> {
+    let greeting: BytesMut =
+        ZmqGreeting::new(options.security.mechanism(), options.security.as_server()).into();
+    stream.write_all(&greeting[..GREETING_PREFIX]).await?;
+    let max_message_size = options.effective_max_message_size();
+
+    let mut prefix = [0u8; GREETING_PREFIX];
+    stream.read_exact(&mut prefix).await?;
+    if prefix[0] != 0xff || prefix[9] & 0x01 == 0 {
+        return Err(ZmqError::Codec("Bad greeting signature"));
+    }
+    if prefix[10] >= 3 {
+        stream.write_all(&greeting[GREETING_PREFIX..]).await?;
+        let mut codec = ZmqCodec::new();
+        codec.set_max_message_size(max_message_size);
+        let mut parts = util::framed(stream, codec, options).into_parts();
+        parts.read_buf.extend_from_slice(&prefix);
+        return Ok(Detected::Zmtp3(Framed::from_parts(parts)));
+    }
+
+    // ZMTP 2.0 greeting ends with socket type right after revision byte
+    stream.write_all(&[socket_type as u8]).await?;
+    let peer_type = SocketType::from_u8(stream.read_u8().await?)
+        .ok_or(ZmqError::Codec("Unknown ZMTP 2.0 socket type"))?;
+    let mut codec = ZmqCodec::zmtp2();
+    codec.set_max_message_size(max_message_size);
+    Ok(Detected::Zmtp2(
+        util::framed(stream, codec, options),
+        peer_type,
+    ))
+}
+
+/// ZMTP 2.0 has neither security mechanisms nor READY command.
+/// Peers send their identity as the first frame instead. Returns identity of the peer
+pub(crate) async fn identity_exchange<S: ZmqStream>(
+    socket: &mut Framed<S, ZmqCodec>,
+    socket_type: SocketType,
+    peer_type: SocketType,
+    options: &SocketOptions,
+    peer_address: Option<SocketAddr>,
+) -> ZmqResult<PeerIdentity> {
+    let mechanism = options.security.mechanism();
+    if mechanism != ZmqMechanism::NULL {
+        return Err(ZmqError::MechanismMismatch {
+            expected: mechanism.to_string(),
+            actual: ZmqMechanism::NULL.to_string(),
+        });
+    }
+    security::authenticate(socket, options, peer_address, Credentials::Null).await?;
+    if !sockets_compatible(socket_type, peer_type) {
+        return Err(ZmqError::InvalidPeerSocketType {
+            ours: socket_type,
+            theirs: peer_type,
+        });
+    }
+
+    let identity = options.identity.clone().map(Vec::from).unwrap_or_default();
+    socket.send(Message::Message(identity.into())).await?;
+    match socket.next().await {
+        Some(Ok(Message::Message(identity))) => identity.data.to_vec().try_into(),
+        Some(Ok(_)) => Err(ZmqError::Codec("Expected identity frame")),
+        Some(Err(e)) => Err(e),
+        None => Err(ZmqError::Other("No identity from peer")),
+    }
+}